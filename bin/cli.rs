@@ -1,4 +1,7 @@
-use rs1541::{AsciiString, BusRecoveryType, Cbm, CbmString, Error, DEVICE_MAX_NUM, DEVICE_MIN_NUM};
+use rs1541::{
+    scan_surface, AsciiString, BusRecoveryType, Cbm, CbmString, DiskImageFormat, Error,
+    ReconnectPolicy, ScanOptions, TransferMode, DEVICE_MAX_NUM, DEVICE_MIN_NUM,
+};
 
 use clap::Parser;
 #[allow(unused_imports)]
@@ -31,6 +34,10 @@ struct Args {
     /// Remote server port number
     #[arg(long, default_value_t = xum1541::device::remoteusb::DEFAULT_PORT)]
     remote_port: u16,
+
+    /// Reconnect if the xum1541 is unplugged or the remote server restarts
+    #[arg(long)]
+    reconnect: bool,
 }
 
 fn main() {
@@ -77,6 +84,10 @@ fn run(args: Args) -> Result<(), Error> {
     };
     let mut cbm = Cbm::new(serial, addr)?;
     cbm.set_bus_recovery_type(BusRecoveryType::All)?;
+    if args.reconnect {
+        cbm.set_reconnect(Some(ReconnectPolicy::default()));
+        cbm.on_reconnect(|event| println!("{event}"));
+    }
 
     let mut device = args.device;
 
@@ -266,9 +277,55 @@ fn run(args: Args) -> Result<(), Error> {
                             };
 
                         let filename = AsciiString::from_ascii_str(&filename);
+                        let start = std::time::Instant::now();
                         match cbm.load_file_ascii(device, &filename) {
-                            Ok(file) => {
-                                println!("Load of {filename} complete - length {}", file.len())
+                            Ok(file) => println!(
+                                "Load of {filename} complete - length {} in {:.1?}",
+                                file.len(),
+                                start.elapsed()
+                            ),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    }
+
+                    "transfer" | "t" => {
+                        match cmd.get(1).map(|mode| mode.to_lowercase()).as_deref() {
+                            Some("standard") => cbm.set_transfer_mode(TransferMode::Standard),
+                            Some("batched") => cbm.set_transfer_mode(TransferMode::Batched),
+                            Some(_) => {
+                                println!("Usage: transfer [standard|batched]");
+                                continue;
+                            }
+                            None => (),
+                        }
+                        println!("Transfer mode: {}", cbm.transfer_mode());
+                    }
+
+                    "surface" | "v" => {
+                        let mut format = DiskImageFormat::D64;
+                        let mut json_file = None;
+                        for arg in &cmd[1..] {
+                            match arg.to_lowercase().as_str() {
+                                "d64" => format = DiskImageFormat::D64,
+                                "d71" => format = DiskImageFormat::D71,
+                                "d81" => format = DiskImageFormat::D81,
+                                _ => json_file = Some(arg.clone()),
+                            }
+                        }
+
+                        match scan_surface(&cbm, device, format, &ScanOptions::default()) {
+                            Ok(scan) => {
+                                print!("{}", scan.grid());
+                                if let Some(file) = json_file {
+                                    match scan.to_json().and_then(|json| {
+                                        std::fs::write(&file, json).map_err(|e| Error::Io {
+                                            message: e.to_string(),
+                                        })
+                                    }) {
+                                        Ok(()) => println!("Wrote scan to {file}"),
+                                        Err(e) => println!("Error: {}", e),
+                                    }
+                                }
                             }
                             Err(e) => println!("Error: {}", e),
                         }
@@ -339,6 +396,10 @@ fn run(args: Args) -> Result<(), Error> {
                         println!("  c|command <cmd>          - Send command to device");
                         println!("  f|format <name> <id>     - Format disk");
                         println!("  l|load <filename>        - Load file from disk");
+                        println!("  t|transfer [standard|batched] - Show or set the transfer mode");
+                        println!(
+                            "  v|surface [d64|d71|d81] [json-file] - Surface scan disk, optionally saving JSON"
+                        );
                         println!("  p|print                  - Print config");
                         println!("  xi|xum1541               - Print xum1541 device info");
                         println!(
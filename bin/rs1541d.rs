@@ -0,0 +1,91 @@
+use rs1541::{BusRecoveryType, Cbm, Daemon, Error, ReconnectPolicy, Timeouts, DEFAULT_SOCKET_PATH};
+
+use clap::Parser;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn, LevelFilter};
+use std::time::Duration;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// xum1541 serial number to use, 0 means use any
+    #[arg(short, long, default_value_t = 0)]
+    serial: u8,
+
+    /// Unix socket to listen on
+    #[arg(long, default_value = DEFAULT_SOCKET_PATH)]
+    socket: String,
+
+    /// Verbosity level (-v for Info, -vv for Debug, -vvv for Trace)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Use remote USB connection instead of local USB
+    #[arg(short, long)]
+    remote: bool,
+
+    /// Remote server IP address (IPv4 only)
+    #[arg(long, default_value = "127.0.0.1")]
+    remote_ip: String,
+
+    /// Remote server port number
+    #[arg(long, default_value_t = xum1541::device::remoteusb::DEFAULT_PORT)]
+    remote_port: u16,
+
+    /// Time limit for each request in seconds, 0 for none
+    #[arg(long, default_value_t = 0)]
+    timeout: u64,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    // Setup logging
+    env_logger::builder()
+        .filter_level(match args.verbose {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        })
+        .init();
+
+    info!("rs1541 daemon");
+
+    if let Err(e) = run(args) {
+        println!("Error: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn run(args: Args) -> Result<(), Error> {
+    let addr = if args.remote {
+        Some(
+            format!("{}:{}", args.remote_ip, args.remote_port)
+                .parse()
+                .map_err(|e| Error::Validation {
+                    message: format!("Invalid remote address: {}", e),
+                })?,
+        )
+    } else {
+        None
+    };
+    let serial = match args.serial {
+        0 => None,
+        s => Some(s),
+    };
+
+    // The daemon is long running, so reconnect if the xum1541 goes away
+    let mut cbm = Cbm::new(serial, addr)?;
+    cbm.set_bus_recovery_type(BusRecoveryType::All)?;
+    cbm.set_reconnect(Some(ReconnectPolicy::default()));
+    cbm.on_reconnect(|event| info!("{event}"));
+    if args.timeout > 0 {
+        cbm.set_timeouts(Timeouts {
+            operation: Some(Duration::from_secs(args.timeout)),
+            ..Default::default()
+        });
+    }
+
+    Daemon::new(cbm, &args.socket).run()
+}
@@ -0,0 +1,763 @@
+//! Mounts a Commodore disk drive as a directory using FUSE, so `ls`, `cp`
+//! and `rm` can be used on it.  See [`rs1541::mount`] for how the drive's
+//! directory is mapped to files.
+//!
+//! ```text
+//! rs1541fs /mnt/1541 --device 8
+//! cp /mnt/1541/hello.prg .
+//! fusermount -u /mnt/1541
+//! ```
+//!
+//! Files are read and written as streams, so a file can only be read from
+//! start to finish (reading backwards re-opens it), and only written from
+//! start to finish, replacing any existing contents, or appended to.  As
+//! the directory only gives each file's size in blocks, files are listed
+//! with the largest size they could be, and reads stop at the real end of
+//! the file.
+//!
+//! CBM DOS finds files by name alone, so files which share their CBM
+//! filename with another file on the disk, such as `game.prg` and
+//! `game.seq`, can't be opened or deleted, failing with EBUSY, as the drive
+//! might act on the wrong one.
+
+use rs1541::{
+    open_name, parse_fs_file_name, AsciiString, BusRecoveryType, Cbm, CbmChannelManager,
+    CbmChannelPurpose, CbmDirListing, DeviceChannel, DirCache, Error, FileMode, MountedDir,
+    MountedFile, ReconnectPolicy,
+};
+
+use clap::Parser;
+use fuser::consts::FOPEN_DIRECT_IO;
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request, TimeOrNow,
+};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn, LevelFilter};
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Inode of the mount point, which is the drive's directory for single drive
+// units, or contains a directory per drive for dual drive units
+const ROOT_INO: u64 = 1;
+
+// Inodes of the drive directories of dual drive units, 2 for drive 0 and 3
+// for drive 1
+const DRIVE_INO_BASE: u64 = 2;
+
+// Inodes from here on are allocated to files as they are looked up
+const FIRST_FILE_INO: u64 = 16;
+
+// How long the kernel caches attributes and names.  Kept short, as the disk
+// can be changed at any time.
+const ATTR_TTL: Duration = Duration::from_secs(1);
+
+const BLOCK_SIZE: u32 = 254;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Directory to mount the drive on
+    mountpoint: String,
+
+    /// Device number of the drive to mount
+    #[arg(short, long, default_value_t = 8)]
+    device: u8,
+
+    /// xum1541 serial number to use, 0 means use any
+    #[arg(short, long, default_value_t = 0)]
+    serial: u8,
+
+    /// Verbosity level (-v for Info, -vv for Debug, -vvv for Trace)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Use remote USB connection instead of local USB
+    #[arg(short, long)]
+    remote: bool,
+
+    /// Remote server IP address (IPv4 only)
+    #[arg(long, default_value = "127.0.0.1")]
+    remote_ip: String,
+
+    /// Remote server port number
+    #[arg(long, default_value_t = xum1541::device::remoteusb::DEFAULT_PORT)]
+    remote_port: u16,
+
+    /// Seconds before the directory is re-read to check for a disk change
+    #[arg(long, default_value_t = 2)]
+    cache_ttl: u64,
+
+    /// Allow other users to access the mount
+    #[arg(long)]
+    allow_other: bool,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    // Setup logging
+    env_logger::builder()
+        .filter_level(match args.verbose {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        })
+        .init();
+
+    info!("rs1541 FUSE filesystem");
+
+    if let Err(e) = run(args) {
+        println!("Error: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn run(args: Args) -> Result<(), Error> {
+    let addr = if args.remote {
+        Some(
+            format!("{}:{}", args.remote_ip, args.remote_port)
+                .parse()
+                .map_err(|e| Error::Validation {
+                    message: format!("Invalid remote address: {}", e),
+                })?,
+        )
+    } else {
+        None
+    };
+    let serial = match args.serial {
+        0 => None,
+        s => Some(s),
+    };
+
+    let mut cbm = Cbm::new(serial, addr)?;
+    cbm.set_bus_recovery_type(BusRecoveryType::All)?;
+    cbm.set_reconnect(Some(ReconnectPolicy::default()));
+
+    let info = cbm.identify(args.device)?;
+    let drives = info.device_type.num_disk_drives().max(1);
+    info!(
+        "Mounting device {} ({}) at {}",
+        args.device, info.description, args.mountpoint
+    );
+
+    let mut options = vec![
+        MountOption::FSName(format!("{}_{}", info.device_type.to_fs_name(), args.device)),
+        MountOption::Subtype("rs1541".to_string()),
+        MountOption::DefaultPermissions,
+        MountOption::NoAtime,
+    ];
+    if args.allow_other {
+        options.push(MountOption::AllowOther);
+    }
+
+    let fs = CbmFs::new(
+        cbm,
+        args.device,
+        drives,
+        Duration::from_secs(args.cache_ttl),
+    );
+    fuser::mount2(fs, &args.mountpoint, &options)?;
+    Ok(())
+}
+
+// A file opened through the mount
+#[derive(Debug)]
+struct OpenFile {
+    dc: DeviceChannel,
+    drive: u8,
+    name: String,
+    file: MountedFile,
+    mode: FileMode,
+    // The disk generation when the file was opened
+    generation: u64,
+    // Bytes read or written so far
+    pos: u64,
+    eof: bool,
+}
+
+struct CbmFs {
+    cbm: Cbm,
+    device: u8,
+    drives: u8,
+    cache: DirCache,
+    channels: CbmChannelManager,
+    // Files are identified to the kernel by inode, allocated on lookup
+    inodes: HashMap<(u8, String), u64>,
+    paths: HashMap<u64, (u8, String)>,
+    next_ino: u64,
+    files: HashMap<u64, OpenFile>,
+    next_fh: u64,
+    uid: u32,
+    gid: u32,
+}
+
+impl CbmFs {
+    fn new(cbm: Cbm, device: u8, drives: u8, cache_ttl: Duration) -> Self {
+        // SAFETY: getuid and getgid can't fail
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        Self {
+            cbm,
+            device,
+            drives,
+            cache: DirCache::new(cache_ttl),
+            channels: CbmChannelManager::new(),
+            inodes: HashMap::new(),
+            paths: HashMap::new(),
+            next_ino: FIRST_FILE_INO,
+            files: HashMap::new(),
+            next_fh: 1,
+            uid,
+            gid,
+        }
+    }
+
+    // Returns the drive whose directory has this inode
+    fn dir_drive(&self, ino: u64) -> Option<u8> {
+        if self.drives == 1 {
+            (ino == ROOT_INO).then_some(0)
+        } else if (DRIVE_INO_BASE..DRIVE_INO_BASE + self.drives as u64).contains(&ino) {
+            Some((ino - DRIVE_INO_BASE) as u8)
+        } else {
+            None
+        }
+    }
+
+    fn dir(&mut self, drive: u8) -> Result<&MountedDir, Error> {
+        let (cbm, device) = (&self.cbm, self.device);
+        let drive_num = (self.drives > 1).then_some(drive);
+        self.cache.get(drive, || -> Result<CbmDirListing, Error> {
+            cbm.dir(device, drive_num)
+        })
+    }
+
+    fn file(&mut self, drive: u8, name: &str) -> Result<MountedFile, Error> {
+        let device = self.device;
+        self.dir(drive)?
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::File {
+                device,
+                message: format!("{name} not found"),
+            })
+    }
+
+    fn inode(&mut self, drive: u8, name: &str) -> u64 {
+        let key = (drive, name.to_string());
+        if let Some(ino) = self.inodes.get(&key) {
+            return *ino;
+        }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.inodes.insert(key.clone(), ino);
+        self.paths.insert(ino, key);
+        ino
+    }
+
+    fn forget_inode(&mut self, drive: u8, name: &str) {
+        if let Some(ino) = self.inodes.remove(&(drive, name.to_string())) {
+            self.paths.remove(&ino);
+        }
+    }
+
+    fn dir_attr(&self, ino: u64) -> FileAttr {
+        self.attr(ino, FileType::Directory, 0, 0o755)
+    }
+
+    fn file_attr(&self, ino: u64, size: u64) -> FileAttr {
+        self.attr(ino, FileType::RegularFile, size, 0o644)
+    }
+
+    fn attr(&self, ino: u64, kind: FileType, size: u64, perm: u16) -> FileAttr {
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind,
+            perm,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: BLOCK_SIZE,
+            flags: 0,
+        }
+    }
+
+    // Returns the attributes of a file, including one being written which
+    // isn't in the directory yet
+    fn attr_for(&mut self, ino: u64) -> Result<FileAttr, Error> {
+        if ino == ROOT_INO || self.dir_drive(ino).is_some() {
+            return Ok(self.dir_attr(ino));
+        }
+        let (drive, name) = self.paths.get(&ino).cloned().ok_or(Error::File {
+            device: self.device,
+            message: format!("Unknown inode {ino}"),
+        })?;
+        let writing = self
+            .files
+            .values()
+            .find(|f| f.drive == drive && f.name == name && f.mode != FileMode::Read)
+            .map(|f| f.pos);
+        match writing {
+            Some(pos) => Ok(self.file_attr(ino, pos)),
+            None => {
+                let file = self.file(drive, &name)?;
+                Ok(self.file_attr(ino, file.max_size()))
+            }
+        }
+    }
+
+    // Opens a file on the drive, on a newly allocated channel
+    fn open_file(
+        &mut self,
+        drive: u8,
+        name: &str,
+        file: MountedFile,
+        mode: FileMode,
+    ) -> Result<u64, Error> {
+        let purpose = match mode {
+            FileMode::Read => CbmChannelPurpose::FileRead,
+            _ => CbmChannelPurpose::FileWrite,
+        };
+        let channel = self
+            .channels
+            .allocate(self.device, drive, purpose)
+            .ok_or_else(|| Error::File {
+                device: self.device,
+                message: "No free channels".to_string(),
+            })?;
+        let dc = DeviceChannel::new(self.device, channel)?;
+
+        let cbm_name = open_name(drive, &file.name, file.file_type, mode);
+        debug!("Opening {cbm_name} on {dc}");
+        if let Err(e) = self.cbm.open_file(dc, &ascii(&cbm_name)?) {
+            self.channels.free(channel);
+            return Err(e);
+        }
+
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.files.insert(
+            fh,
+            OpenFile {
+                dc,
+                drive,
+                name: name.to_string(),
+                file,
+                mode,
+                generation: self.cache.generation(drive),
+                pos: 0,
+                eof: false,
+            },
+        );
+        Ok(fh)
+    }
+
+    fn open_handle(&mut self, fh: u64) -> Result<&mut OpenFile, Error> {
+        let device = self.device;
+        let file = self.files.get_mut(&fh).ok_or_else(|| Error::Validation {
+            message: format!("Unknown file handle {fh}"),
+        })?;
+        if file.generation != self.cache.generation(file.drive) {
+            return Err(Error::File {
+                device,
+                message: format!("Disk changed since {} was opened", file.name),
+            });
+        }
+        Ok(file)
+    }
+
+    fn read(&mut self, fh: u64, offset: u64, size: usize) -> Result<Vec<u8>, Error> {
+        let cbm = self.cbm.clone();
+        let file = self.open_handle(fh)?;
+
+        // Files can only be read forwards, so start again to go back
+        if offset < file.pos {
+            debug!("Re-opening {} to read from {offset}", file.name);
+            cbm.close_file(file.dc)?;
+            let cbm_name = open_name(
+                file.drive,
+                &file.file.name,
+                file.file.file_type,
+                FileMode::Read,
+            );
+            cbm.open_file(file.dc, &ascii(&cbm_name)?)?;
+            file.pos = 0;
+            file.eof = false;
+        }
+
+        // And skip forwards by reading
+        while file.pos < offset && !file.eof {
+            let skip = (offset - file.pos).min(BLOCK_SIZE as u64) as usize;
+            let mut buf = vec![0; skip];
+            let read = cbm.read_from_drive(file.dc, &mut buf, false)?;
+            file.pos += read as u64;
+            file.eof = read < skip;
+        }
+
+        if file.eof || size == 0 {
+            return Ok(Vec::new());
+        }
+        let mut buf = vec![0; size];
+        let read = cbm.read_from_drive(file.dc, &mut buf, false)?;
+        buf.truncate(read);
+        file.pos += read as u64;
+        file.eof = read < size;
+        Ok(buf)
+    }
+
+    fn write(&mut self, fh: u64, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        let cbm = self.cbm.clone();
+        let file = self.open_handle(fh)?;
+        if file.mode == FileMode::Read {
+            return Err(Error::Validation {
+                message: format!("{} is open for reading", file.name),
+            });
+        }
+
+        // Appends go to the end of the file, wherever the kernel thinks that
+        // is, and other writes must carry on where the last one stopped
+        if file.mode != FileMode::Append && offset != file.pos {
+            return Err(Error::Validation {
+                message: format!(
+                    "{} can only be written in order, asked to write at {offset}, expected {}",
+                    file.name, file.pos
+                ),
+            });
+        }
+
+        cbm.write_to_drive(file.dc, data)?;
+        file.pos += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn close(&mut self, fh: u64) -> Result<(), Error> {
+        let Some(file) = self.files.remove(&fh) else {
+            return Ok(());
+        };
+        self.channels.free(file.dc.channel());
+        let result = self.cbm.close_file(file.dc);
+        if file.mode != FileMode::Read {
+            self.cache.invalidate(file.drive);
+            result?;
+            // The drive reports write errors, such as the disk being full,
+            // once the file is closed
+            return self.cbm.get_status(self.device)?.into();
+        }
+        result
+    }
+
+    // Scratches a file.  The caller must check no other file shares its
+    // CBM filename, as the scratch would delete them too.
+    fn unlink(&mut self, drive: u8, name: &str, file: &MountedFile) -> Result<(), Error> {
+        let cmd = format!("s{drive}:{}", file.name);
+        debug!("Scratching {name} using {cmd}");
+        self.cbm.send_string_command_ascii(self.device, &cmd)?;
+        self.cache.invalidate(drive);
+        self.forget_inode(drive, name);
+        self.cbm.get_status(self.device)?.into()
+    }
+}
+
+impl Filesystem for CbmFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let Some(name) = name.to_str() else {
+            return reply.error(libc::ENOENT);
+        };
+
+        if parent == ROOT_INO && self.drives > 1 {
+            return match name.parse::<u8>() {
+                Ok(drive) if drive < self.drives => {
+                    let attr = self.dir_attr(DRIVE_INO_BASE + drive as u64);
+                    reply.entry(&ATTR_TTL, &attr, 0)
+                }
+                _ => reply.error(libc::ENOENT),
+            };
+        }
+
+        let Some(drive) = self.dir_drive(parent) else {
+            return reply.error(libc::ENOENT);
+        };
+        match self.file(drive, name) {
+            Ok(file) => {
+                let ino = self.inode(drive, name);
+                let attr = self.file_attr(ino, file.max_size());
+                reply.entry(&ATTR_TTL, &attr, self.cache.generation(drive))
+            }
+            Err(Error::File { .. }) => reply.error(libc::ENOENT),
+            Err(e) => reply.error(e.to_errno()),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.attr_for(ino) {
+            Ok(attr) => reply.attr(&ATTR_TTL, &attr),
+            Err(Error::File { .. }) => reply.error(libc::ENOENT),
+            Err(e) => reply.error(e.to_errno()),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        // Truncating is only possible when opening a file, which replaces
+        // it, so only allow the truncate which follows open with O_TRUNC.
+        // Other changes, such as to times, are ignored.
+        let attr = match self.attr_for(ino) {
+            Ok(attr) => attr,
+            Err(e) => return reply.error(e.to_errno()),
+        };
+        match size {
+            Some(size) if size != attr.size => reply.error(libc::EOPNOTSUPP),
+            _ => reply.attr(&ATTR_TTL, &attr),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let mut entries = vec![
+            (ino, FileType::Directory, ".".to_string()),
+            (ROOT_INO, FileType::Directory, "..".to_string()),
+        ];
+        if ino == ROOT_INO && self.drives > 1 {
+            for drive in 0..self.drives {
+                entries.push((
+                    DRIVE_INO_BASE + drive as u64,
+                    FileType::Directory,
+                    drive.to_string(),
+                ));
+            }
+        } else {
+            let Some(drive) = self.dir_drive(ino) else {
+                return reply.error(libc::ENOTDIR);
+            };
+            let names: Vec<String> = match self.dir(drive) {
+                Ok(dir) => dir.files().map(|(name, _)| name.to_string()).collect(),
+                Err(e) => return reply.error(e.to_errno()),
+            };
+            for name in names {
+                let file_ino = self.inode(drive, &name);
+                entries.push((file_ino, FileType::RegularFile, name));
+            }
+        }
+
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok()
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let Some((drive, name)) = self.paths.get(&ino).cloned() else {
+            return reply.error(libc::ENOENT);
+        };
+        let mode = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => FileMode::Read,
+            libc::O_WRONLY if flags & libc::O_APPEND != 0 => FileMode::Append,
+            libc::O_WRONLY if flags & libc::O_TRUNC != 0 => FileMode::Replace,
+            _ => return reply.error(libc::EOPNOTSUPP),
+        };
+
+        let file = match self.file(drive, &name) {
+            Ok(file) if !file.addressable() => {
+                return reply.error(unaddressable_errno(&name, &file))
+            }
+            Ok(file) => file,
+            Err(e) => return reply.error(e.to_errno()),
+        };
+        match self.open_file(drive, &name, file, mode) {
+            Ok(fh) => reply.opened(fh, FOPEN_DIRECT_IO),
+            Err(e) => reply.error(e.to_errno()),
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let Some(drive) = self.dir_drive(parent) else {
+            return reply.error(libc::EACCES);
+        };
+        let Some(name) = name.to_str() else {
+            return reply.error(libc::EINVAL);
+        };
+        let (cbm_name, file_type) = match parse_fs_file_name(name) {
+            Ok(parsed) => parsed,
+            Err(e) => return reply.error(e.to_errno()),
+        };
+
+        let existing = match self.dir(drive) {
+            Ok(dir) => dir.get(name).cloned(),
+            Err(e) => return reply.error(e.to_errno()),
+        };
+        if let Some(file) = existing.as_ref().filter(|file| !file.addressable()) {
+            return reply.error(unaddressable_errno(name, file));
+        }
+        let exists = existing.is_some();
+        if exists && flags & libc::O_EXCL != 0 {
+            return reply.error(libc::EEXIST);
+        }
+        let mode = if exists {
+            FileMode::Replace
+        } else {
+            FileMode::Write
+        };
+
+        let file = MountedFile {
+            name: cbm_name,
+            file_type,
+            blocks: 0,
+            shared_name: false,
+            reserved_name: false,
+        };
+        match self.open_file(drive, name, file, mode) {
+            Ok(fh) => {
+                let ino = self.inode(drive, name);
+                let attr = self.file_attr(ino, 0);
+                reply.created(&ATTR_TTL, &attr, 0, fh, FOPEN_DIRECT_IO)
+            }
+            Err(e) => reply.error(e.to_errno()),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.read(fh, offset as u64, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e.to_errno()),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        match self.write(fh, offset as u64, data) {
+            Ok(written) => reply.written(written as u32),
+            Err(e) => reply.error(e.to_errno()),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.close(fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.to_errno()),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (Some(drive), Some(name)) = (self.dir_drive(parent), name.to_str()) else {
+            return reply.error(libc::ENOENT);
+        };
+        let file = match self.file(drive, name) {
+            Ok(file) if !file.addressable() => {
+                return reply.error(unaddressable_errno(name, &file))
+            }
+            Ok(file) => file,
+            Err(Error::File { .. }) => return reply.error(libc::ENOENT),
+            Err(e) => return reply.error(e.to_errno()),
+        };
+        match self.unlink(drive, name, &file) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.to_errno()),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        let drive = self.dir_drive(ino).unwrap_or(0);
+        match self.dir(drive) {
+            Ok(dir) => {
+                let free = dir.blocks_free as u64;
+                let files = dir.files().count() as u64;
+                let blocks = free + dir.files().map(|(_, f)| f.blocks as u64).sum::<u64>();
+                reply.statfs(blocks, free, free, files, 0, BLOCK_SIZE, 255, BLOCK_SIZE)
+            }
+            Err(e) => reply.error(e.to_errno()),
+        }
+    }
+}
+
+// Returns the error for an operation on a file which can't be safely named
+// to the drive: one which shares its CBM filename with another, or contains
+// characters DOS treats specially, as the drive might act on the wrong file
+fn unaddressable_errno(name: &str, file: &MountedFile) -> libc::c_int {
+    if file.shared_name {
+        warn!("Refusing to access {name}, as another file on the disk has the same name");
+        libc::EBUSY
+    } else {
+        warn!("Refusing to access {name}, as its name contains characters reserved by DOS");
+        libc::EACCES
+    }
+}
+
+fn ascii(s: &str) -> Result<AsciiString, Error> {
+    AsciiString::try_from(s).map_err(|e| Error::Validation {
+        message: format!("Invalid filename {s}: {e}"),
+    })
+}
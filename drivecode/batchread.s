@@ -0,0 +1,113 @@
+; batchread.s
+;
+; 1541/1571 drive routine used by rs1541's batched transfer mode
+; (src/transfer.rs) to read up to three sectors per run.
+;
+; Assembled to $0300 and uploaded by the host using M-W once per operation.
+; Before each run the host writes the parameters below, runs the routine
+; using M-E, and then reads the results and buffers ($03F8-$06FF) back
+; using M-R once the routine has returned.
+;
+; The sectors are read into buffers 1-3 ($0400-$06FF) using the job queue,
+; so interrupts must stay enabled for the job loop to run.  The drive
+; doesn't respond to the bus until the routine returns.
+;
+; In list mode all of the reads are queued at once, so the job loop reads
+; the sectors in whichever order they pass under the head.  In chain mode
+; each sector is read after the previous one, following the link bytes, and
+; the routine stops early at the last sector of the chain.
+
+JOBS     = $0001        ; buffer 1-3 job codes, and results once complete
+HDRS     = $0008        ; buffer 1-3 track and sector ($0008-$000D)
+BUF1     = $0400        ; buffer 1
+BUF2     = $0500        ; buffer 2
+JOBREAD  = $80
+
+; Parameters, written by the host
+mode     = $03F0        ; 0 = list of sectors, 1 = sector chain
+count    = $03F1        ; number of sectors to read (1-3)
+ts       = $03F2        ; track and sector pairs ($03F2-$03F7).  Chain mode
+                        ; only uses the first
+
+; Results, read by the host
+results  = $03F8        ; job result per buffer ($03F8-$03FA), 1 = OK
+nread    = $03FB        ; number of sectors attempted
+
+; Working storage
+nexttrk  = $03FC
+nextsec  = $03FD
+
+        .org $0300
+
+start:  LDA #$00
+        STA nread
+        CLI
+        LDA mode
+        BNE chain
+
+        ; List mode - queue all of the reads
+        LDX #$00
+        LDY #$00
+queue:  LDA ts,X
+        STA HDRS,X
+        LDA ts+1,X
+        STA HDRS+1,X
+        LDA #JOBREAD
+        STA JOBS,Y
+        INX
+        INX
+        INY
+        CPY count
+        BNE queue
+
+        ; Wait until none of the jobs are still running
+wait:   LDY #$00
+waitlp: LDA JOBS,Y
+        BMI wait
+        STA results,Y
+        INY
+        CPY count
+        BNE waitlp
+        STY nread
+        RTS
+
+        ; Chain mode - read each sector in turn
+chain:  LDA ts
+        STA nexttrk
+        LDA ts+1
+        STA nextsec
+        LDY #$00
+chainlp: TYA
+        ASL A
+        TAX
+        LDA nexttrk
+        STA HDRS,X
+        LDA nextsec
+        STA HDRS+1,X
+        LDA #JOBREAD
+        STA JOBS,Y
+chwait: LDA JOBS,Y
+        BMI chwait
+        STA results,Y
+        INY
+        STY nread
+        CMP #$01
+        BNE done
+        CPY count
+        BEQ done
+
+        ; Follow the link bytes of the sector just read.  Only buffers 1
+        ; and 2 can be followed, as buffer 3 is the last.
+        CPY #$01
+        BNE frombuf2
+        LDX BUF1+1
+        LDA BUF1
+        JMP gotlink
+frombuf2: LDX BUF2+1
+        LDA BUF2
+gotlink: STX nextsec
+        STA nexttrk
+        BEQ done
+        JMP chainlp
+
+done:   RTS
@@ -0,0 +1,149 @@
+; checksum.s
+;
+; 1541/1571 drive routine used by rs1541's checksum module (src/checksum.rs)
+; to calculate a CRC of a file's sector chain, or of a whole track, without
+; transferring the data over the bus.
+;
+; Assembled to $0300 and uploaded by the host using M-W, then run using M-E.
+; The host writes the parameters below before each run, and reads the
+; result, CRC and block count back using M-R once the routine has returned.
+;
+; The routine reads each sector into buffer 2 ($0500) using the job queue,
+; so interrupts must stay enabled for the job loop to run.  The drive
+; doesn't respond to the bus until the routine returns.
+;
+; In chain mode the CRC covers the data bytes of each sector - bytes 2-255,
+; or 2 up to the last used byte for the final sector - so matches the CRC
+; of the file's contents.  In track mode it covers all 256 bytes of sectors
+; 0 to count-1.
+;
+; The CRC is CRC-16/CCITT-FALSE (polynomial $1021, initial value $FFFF),
+; calculated a byte at a time without a table.
+
+JOBQ     = $0002        ; buffer 2 job code, and result once complete
+JOBTRK   = $000A        ; buffer 2 track
+JOBSEC   = $000B        ; buffer 2 sector
+BUFFER   = $0500        ; buffer 2
+JOBREAD  = $80
+
+; Parameters, written by the host
+mode     = $0480        ; 0 = sector chain, 1 = whole track
+track    = $0481        ; first track
+sector   = $0482        ; first sector
+count    = $0483        ; track mode: number of sectors
+                        ; chain mode: stop if blocks read reaches count*256
+
+; Results, read by the host
+result   = $0484        ; 1 = OK, other job results are read errors, $FF =
+                        ; chain too long
+crclo    = $0485
+crchi    = $0486
+blockslo = $0487        ; number of sectors read
+blockshi = $0488
+
+; Working storage
+last     = $0489        ; index of the last data byte in the sector
+tmp      = $048A
+
+        .org $0300
+
+start:  LDA #$FF
+        STA crclo
+        STA crchi
+        LDA #$00
+        STA result
+        STA blockslo
+        STA blockshi
+        CLI
+
+        ; Read the next sector using the job queue
+next:   LDA track
+        STA JOBTRK
+        LDA sector
+        STA JOBSEC
+        LDA #JOBREAD
+        STA JOBQ
+wait:   LDA JOBQ
+        BMI wait
+        STA result
+        CMP #$01
+        BNE done
+
+        INC blockslo
+        BNE counted
+        INC blockshi
+        LDA mode
+        BNE counted
+        LDA blockshi
+        CMP count
+        BNE counted
+        LDA #$FF
+        STA result
+        RTS
+
+counted: LDA mode
+        BNE trkmode
+
+        ; Chain mode - include bytes 2 to 255, or 2 to the last used byte
+        ; of the final sector
+        LDA #$FF
+        LDX BUFFER
+        BNE notlast
+        LDA BUFFER+1
+notlast: STA last
+        LDY #$01
+chain:  CPY last
+        BEQ chaindone
+        INY
+        LDA BUFFER,Y
+        JSR crc16
+        JMP chain
+chaindone: LDA BUFFER
+        BEQ done
+        STA track
+        LDA BUFFER+1
+        STA sector
+        JMP next
+
+        ; Track mode - include the whole sector
+trkmode: LDY #$00
+trkloop: LDA BUFFER,Y
+        JSR crc16
+        INY
+        BNE trkloop
+        INC sector
+        LDA sector
+        CMP count
+        BNE next
+
+done:   RTS
+
+        ; Adds the byte in A to the CRC.  Preserves Y.
+crc16:  EOR crchi
+        STA crchi
+        LSR
+        LSR
+        LSR
+        LSR
+        TAX
+        ASL
+        EOR crclo
+        STA crclo
+        TXA
+        EOR crchi
+        STA crchi
+        ASL
+        ASL
+        ASL
+        TAX
+        ASL
+        ASL
+        EOR crchi
+        STA tmp
+        TXA
+        ROL
+        EOR crclo
+        STA crchi
+        LDA tmp
+        STA crclo
+        RTS
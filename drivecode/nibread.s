@@ -0,0 +1,252 @@
+; nibread.s
+;
+; 1541 drive routine used by rs1541's nibbler (src/nibbler.rs) to capture
+; raw GCR data from the current track.
+;
+; Assembled to $0300 and uploaded by the host using M-W, then run using M-E.
+; The host writes the parameters below before each run, and reads the
+; captured data ($0500-$07FF) and result byte back using M-R.
+;
+; The routine:
+; - disables interrupts (so the drive won't respond to the bus until done)
+; - optionally overrides the density (speed zone)
+; - turns the motor and LED on (waiting for spin up if the motor was off)
+;   and sets the read head to read mode
+; - steps the head inwards by the requested number of half-tracks
+; - waits for a sync mark followed by the signature bytes (retrying on
+;   subsequent syncs if the signature doesn't match)
+; - skips the requested number of bytes
+; - captures 768 bytes into $0500-$07FF, recording each sync mark seen as a
+;   single $FF byte (the 1541 does not signal byte-ready during a sync)
+; - steps the head back out and returns
+;
+; Sync marks are counted as one byte by the skip loop, so that windows
+; captured by successive runs line up exactly.
+;
+; Timing: at density 3 a byte arrives every 26 cycles.  The capture loop
+; takes 19 cycles per byte plus up to 9 cycles latency detecting byte-ready,
+; so keeps up.
+
+VIA2PB   = $1C00        ; bit 7 SYNC (active low), 5-6 density, 3 LED, 2 motor, 0-1 stepper
+VIA2PA   = $1C01        ; GCR data from the read head
+VIA2DDRA = $1C03
+VIA2PCR  = $1C0C
+
+; Parameters, written by the host
+density  = $04E0        ; $FF to leave alone, otherwise 0-3
+steps    = $04E1        ; number of half-tracks to step inwards
+skiplo   = $04E2        ; number of bytes (including syncs) to skip after
+skiphi   = $04E3        ; the signature
+siglen   = $04E4        ; number of signature bytes (0-8)
+tries    = $04E5        ; number of syncs to try to match signature after
+result   = $04E6        ; 0 = OK, 1 = no sync found, 2 = signature not found
+tmp      = $04E7
+sig      = $04E8        ; signature bytes ($04E8-$04EF)
+
+; Working storage
+tmolo    = $04F0
+tmohi    = $04F1
+trycnt   = $04F2
+stepcnt  = $04F3
+
+        .org $0300
+
+start:  SEI
+        LDA #$00
+        STA result
+
+        ; Override the density if requested
+        LDA density
+        CMP #$FF
+        BEQ nodens
+        ASL
+        ASL
+        ASL
+        ASL
+        ASL
+        STA tmp
+        LDA VIA2PB
+        AND #$9F
+        ORA tmp
+        STA VIA2PB
+
+        ; Motor and LED on, waiting for the motor to spin up if DOS had
+        ; turned it off
+nodens: LDA VIA2PB
+        AND #$04
+        PHA
+        LDA VIA2PB
+        ORA #$0C
+        STA VIA2PB
+        PLA
+        BNE motoron
+        LDX #$00
+        JSR delay
+        LDX #$00
+        JSR delay
+
+        ; Read mode
+motoron: LDA #$EE
+        STA VIA2PCR
+        LDA #$00
+        STA VIA2DDRA
+
+        ; Step inwards
+        LDA steps
+        STA stepcnt
+stepin: LDA stepcnt
+        BEQ stepdone
+        JSR stepup
+        DEC stepcnt
+        JMP stepin
+
+stepdone: LDA tries
+        STA trycnt
+
+        ; Wait for a sync, with a timeout
+retry:  LDA #$00
+        STA tmolo
+        STA tmohi
+waitsync: BIT VIA2PB
+        BPL insync
+        DEC tmolo
+        BNE waitsync
+        DEC tmohi
+        BNE waitsync
+        JMP nosync
+
+        ; Wait for the end of the sync, with a timeout
+insync: BIT VIA2PB
+        BMI syncend
+        DEC tmolo
+        BNE insync
+        DEC tmohi
+        BNE insync
+nosync: LDA #$01
+        STA result
+        JMP finish
+
+        ; Check the signature
+syncend: CLV
+        LDX #$00
+        LDA siglen
+        BEQ sigok
+sigb:   BVC sigb
+        CLV
+        LDA VIA2PA
+        CMP sig,X
+        BNE sigfail
+        INX
+        CPX siglen
+        BNE sigb
+        JMP sigok
+sigfail: DEC trycnt
+        BNE retry
+        LDA #$02
+        STA result
+        JMP finish
+
+        ; Skip bytes - Y is the low byte of the count, X the high byte
+sigok:  LDY skiplo
+        LDX skiphi
+skloop: TYA
+        BNE skdec
+        TXA
+        BEQ capture
+        DEX
+skdec:  DEY
+skwait: BVS skgot
+        BIT VIA2PB
+        BMI skwait
+skend:  BIT VIA2PB
+        BPL skend
+        JMP skloop
+skgot:  CLV
+        JMP skloop
+
+        ; Capture 3 pages, one loop per page to avoid page turn overhead
+capture: LDY #$00
+w0:     BVS g0
+        BIT VIA2PB
+        BMI w0
+        LDA #$FF
+        STA $0500,Y
+        INY
+        BEQ e1
+e0:     BIT VIA2PB
+        BPL e0
+        JMP w0
+g0:     CLV
+        LDA VIA2PA
+        STA $0500,Y
+        INY
+        BNE w0
+w1:     BVS g1
+        BIT VIA2PB
+        BMI w1
+        LDA #$FF
+        STA $0600,Y
+        INY
+        BEQ e2
+e1:     BIT VIA2PB
+        BPL e1
+        JMP w1
+g1:     CLV
+        LDA VIA2PA
+        STA $0600,Y
+        INY
+        BNE w1
+w2:     BVS g2
+        BIT VIA2PB
+        BMI w2
+        LDA #$FF
+        STA $0700,Y
+        INY
+        BEQ finish
+e2:     BIT VIA2PB
+        BPL e2
+        JMP w2
+g2:     CLV
+        LDA VIA2PA
+        STA $0700,Y
+        INY
+        BNE w2
+
+        ; Step back out to where we started
+finish: LDA steps
+        STA stepcnt
+stepout: LDA stepcnt
+        BEQ done
+        JSR stepdown
+        DEC stepcnt
+        JMP stepout
+done:   CLI
+        RTS
+
+        ; Move the stepper motor one phase (a half-track) in or out
+stepup: LDA VIA2PB
+        TAX
+        CLC
+        ADC #$01
+        JMP stepset
+stepdown: LDA VIA2PB
+        TAX
+        SEC
+        SBC #$01
+stepset: AND #$03
+        STA tmp
+        TXA
+        AND #$FC
+        ORA tmp
+        STA VIA2PB
+
+        ; Let the head settle
+        LDX #$20
+
+        ; Delay for X * 1280 cycles (X = 0 is 256)
+delay:  LDY #$00
+dloop:  DEY
+        BNE dloop
+        DEX
+        BNE dloop
+        RTS
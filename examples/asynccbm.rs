@@ -0,0 +1,59 @@
+//! # AsyncCbm Example
+//!
+//! This example shows the same concurrent tasks as the async example, using
+//! [`AsyncCbm`] rather than calling the blocking [`rs1541::Cbm`] methods from
+//! async tasks.  Calls are queued to a worker thread which owns the bus, so
+//! the tokio runtime's threads are never blocked.
+//!
+//! * Task 1 identifies the drive and reads its directory, with a timeout
+//! * Task 2 polls the drive status multiple times at fixed intervals
+//!
+//! To run:
+//! ```bash
+//! cargo run --example asynccbm --features tokio
+//! ```
+
+use rs1541::{AsyncCbm, Cbm, Error};
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    env_logger::init();
+
+    let cbm = AsyncCbm::new(Cbm::new(None, None)?)?;
+
+    // Task 1
+    let cbm1 = cbm.with_timeout(Duration::from_secs(30));
+    let task1 = tokio::spawn(async move {
+        let id = cbm1.identify(8).await?;
+        println!("Task 1 - Drive type at device 8: {}", id);
+
+        let dir = cbm1.dir(8, None).await?;
+        println!("Task 1 - Directory listing:\n{}", dir);
+
+        Ok::<(), Error>(())
+    });
+
+    // Task 2
+    let cbm2 = cbm.clone();
+    let task2 = tokio::spawn(async move {
+        for i in 1..=3 {
+            let status = cbm2.get_status(8).await?;
+            println!("Task 2 (iteration {}) - Drive status: {}", i, status);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        Ok::<(), Error>(())
+    });
+
+    // Wait for both tasks to complete
+    let (result1, result2) = tokio::join!(task1, task2);
+    let _ = result1
+        .unwrap()
+        .inspect_err(|e| println!("Task 1 error: {}", e));
+    let _ = result2
+        .unwrap()
+        .inspect_err(|e| println!("Task 2 error: {}", e));
+
+    Ok(())
+}
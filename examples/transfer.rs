@@ -0,0 +1,92 @@
+//! Times reading a file, or a whole disk, using each transfer mode, so the
+//! modes can be compared on real hardware
+//!
+//! ```text
+//! cargo run --example transfer -- --device 8 --file "game"
+//! cargo run --example transfer -- --device 8 --image
+//! ```
+//!
+//! Each read is checked against the standard one, so this also verifies the
+//! batched mode returns the same data.
+use clap::Parser;
+use rs1541::{Cbm, DiskImageFormat, Error, PetsciiString, TransferMode};
+use std::time::{Duration, Instant};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    #[arg(short, long, default_value_t = 8, value_parser = clap::value_parser!(u8).range(8..=30))]
+    device: u8,
+
+    /// File to load, in ASCII
+    #[arg(short, long)]
+    file: Option<String>,
+
+    /// Read the whole disk as a D64 image
+    #[arg(short, long)]
+    image: bool,
+}
+
+const MODES: [TransferMode; 2] = [TransferMode::Standard, TransferMode::Batched];
+
+fn main() -> Result<(), Error> {
+    env_logger::init();
+    let args = Args::parse();
+    if args.file.is_none() && !args.image {
+        eprintln!("Error: specify --file or --image");
+        std::process::exit(1);
+    }
+
+    let cbm = Cbm::new(None, None)?;
+    println!("Drive: {}", cbm.identify(args.device)?);
+
+    if let Some(file) = &args.file {
+        let filename = PetsciiString::from_ascii_str(file);
+        compare(&format!("Load {file}"), |mode| {
+            cbm.load_file_with(args.device, &filename, mode)
+        })?;
+    }
+
+    if args.image {
+        compare("Disk image", |mode| {
+            cbm.read_disk_image_with(args.device, DiskImageFormat::D64, mode)
+                .map(|image| image.to_bytes())
+        })?;
+    }
+
+    Ok(())
+}
+
+// Runs the read in each mode, printing how long each took and the speedup
+// over standard transfers
+fn compare<F>(name: &str, read: F) -> Result<(), Error>
+where
+    F: Fn(TransferMode) -> Result<Vec<u8>, Error>,
+{
+    let mut standard: Option<(Vec<u8>, Duration)> = None;
+    for mode in MODES {
+        let start = Instant::now();
+        let data = read(mode)?;
+        let elapsed = start.elapsed();
+        let rate = data.len() as f64 / elapsed.as_secs_f64();
+        print!(
+            "{name}, {mode}: {} bytes in {elapsed:.1?} ({rate:.0} bytes/s)",
+            data.len()
+        );
+
+        match &standard {
+            None => {
+                println!();
+                standard = Some((data, elapsed));
+            }
+            Some((expected, standard_elapsed)) => {
+                let speedup = standard_elapsed.as_secs_f64() / elapsed.as_secs_f64();
+                println!(", {speedup:.2}x standard");
+                if &data != expected {
+                    println!("  Warning: data differs from the standard read");
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,122 @@
+/* OpenCBM compatible API implemented by rs1541.  Generated by cbindgen from src/lib.rs - do not edit. */
+
+#ifndef RS1541_OPENCBM_H
+#define RS1541_OPENCBM_H
+
+
+
+#include <stddef.h>
+
+// Drive types returned by [`cbm_identify`], matching OpenCBM's values
+typedef enum cbm_device_type_e {
+  cbm_dt_unknown = -1,
+  cbm_dt_cbm1540 = 0,
+  cbm_dt_cbm1541 = 1,
+  cbm_dt_cbm1570 = 2,
+  cbm_dt_cbm1571 = 3,
+  cbm_dt_cbm1581 = 4,
+  cbm_dt_cbm2040 = 5,
+  cbm_dt_cbm2031 = 6,
+  cbm_dt_cbm3040 = 7,
+  cbm_dt_cbm4040 = 8,
+  cbm_dt_cbm4031 = 9,
+  cbm_dt_cbm8050 = 10,
+  cbm_dt_cbm8250 = 11,
+  cbm_dt_sfd1001 = 12,
+  cbm_dt_fdx000 = 13,
+} cbm_device_type_e;
+
+// Handle to an open driver, as returned by [`cbm_driver_open`]
+typedef int CBM_FILE;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Opens the driver, storing the handle in `f`.  Uses the adapter in the
+// `RS1541_ADAPTER` environment variable if set, otherwise the local
+// xum1541 with serial number `port`, with 0 meaning any.
+//
+// # Safety
+// `f` must be a valid pointer to a `CBM_FILE`.
+int cbm_driver_open(CBM_FILE *f, int port);
+
+// Opens the driver for the given adapter, storing the handle in `f`.
+// `adapter` is one of `xum1541`, `xum1541:<serial>`, `remote` or
+// `remote:<ip>:<port>`, and NULL or empty uses `RS1541_ADAPTER`.
+//
+// # Safety
+// `f` must be a valid pointer to a `CBM_FILE`, and `adapter` must be NULL
+// or a valid NUL terminated string.
+int cbm_driver_open_ex(CBM_FILE *f, const char *adapter);
+
+// Closes the driver.  The xum1541 is closed once it isn't in use.
+void cbm_driver_close(CBM_FILE f);
+
+// Resets the IEC bus
+int cbm_reset(CBM_FILE f);
+
+// Instructs the device to listen on the secondary address
+int cbm_listen(CBM_FILE f, unsigned char dev, unsigned char secadr);
+
+// Instructs the device to talk on the secondary address
+int cbm_talk(CBM_FILE f, unsigned char dev, unsigned char secadr);
+
+// Instructs the listening device to unlisten
+int cbm_unlisten(CBM_FILE f);
+
+// Instructs the talking device to untalk
+int cbm_untalk(CBM_FILE f);
+
+// Opens a file on the secondary address, sending `fname` if `len` is
+// non-zero
+//
+// # Safety
+// `fname` must point to at least `len` bytes.
+int cbm_open(CBM_FILE f, unsigned char dev, unsigned char secadr, const void *fname, size_t len);
+
+// Closes the file on the secondary address
+int cbm_close(CBM_FILE f, unsigned char dev, unsigned char secadr);
+
+// Reads up to `size` bytes from the talking device, returning the number
+// read
+//
+// # Safety
+// `buf` must point to at least `size` writable bytes.
+int cbm_raw_read(CBM_FILE f, void *buf, size_t size);
+
+// Writes `size` bytes to the listening device, returning the number
+// written
+//
+// # Safety
+// `buf` must point to at least `size` bytes.
+int cbm_raw_write(CBM_FILE f, const void *buf, size_t size);
+
+// Sends a command, in PETSCII, to the device's command channel.  If `len`
+// is 0 `cmd` is NUL terminated.
+//
+// # Safety
+// `cmd` must point to at least `len` bytes, or be NUL terminated if `len`
+// is 0.
+int cbm_exec_command(CBM_FILE f, unsigned char dev, const void *cmd, size_t len);
+
+// Reads the device's status into `buf`, as a NUL terminated string such as
+// `00, OK,00,00\r`, and returns the status number.  If the status can't be
+// read, 99 and `99, DRIVER ERROR,00,00\r` are returned, as OpenCBM does.
+//
+// # Safety
+// `buf` must be NULL or point to at least `bufsize` writable bytes.
+int cbm_device_status(CBM_FILE f, unsigned char dev, void *buf, size_t bufsize);
+
+// Identifies the drive, setting `t` to its type and `type_str` to a static
+// string naming it.  Either may be NULL.
+//
+// # Safety
+// `t` and `type_str` must each be NULL or valid pointers.
+int cbm_identify(CBM_FILE f, unsigned char drv, enum cbm_device_type_e *t, const char **type_str);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* RS1541_OPENCBM_H */
@@ -0,0 +1,504 @@
+//! An OpenCBM compatible C library, built as libopencbm, so tools written
+//! against the OpenCBM API, such as cbmcopy, d64copy and nibtools, can use
+//! the pure Rust xum1541 stack, including over the remote USB transport.
+//!
+//! Each `CBM_FILE` returned by [`cbm_driver_open`] or [`cbm_driver_open_ex`]
+//! is backed by an [`rs1541::Cbm`].  include/opencbm.h is generated from
+//! this file by build.rs.
+//!
+//! As with OpenCBM, functions return 0 (or a byte count) on success and -1
+//! on failure, with errno set from [`Error::to_errno`].  Set `RUST_LOG` to
+//! see the underlying errors.
+//!
+//! # Selecting the adapter
+//!
+//! [`cbm_driver_open_ex`] takes an adapter string, which is one of:
+//! * `xum1541` - any local xum1541
+//! * `xum1541:<serial>` - the local xum1541 with this serial number, with
+//!   0 meaning any
+//! * `remote` - a remote xum1541 on the default address and port
+//! * `remote:<ip>:<port>` - a remote xum1541 on this address and port
+//!
+//! [`cbm_driver_open`], which most tools use, and [`cbm_driver_open_ex`]
+//! given NULL or an empty string, use the adapter string in the
+//! `RS1541_ADAPTER` environment variable if set, so existing tools can be
+//! pointed at a remote xum1541 without changing them.
+
+#![allow(non_camel_case_types)]
+
+use rs1541::{
+    Cbm, CbmDeviceType, DeviceChannel, Error, PetsciiString, DEFAULT_REMOTE_ADDR,
+    DEFAULT_REMOTE_PORT,
+};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use parking_lot::Mutex;
+
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_int, c_uchar, c_void, CStr};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// Environment variable holding the adapter string used by
+/// [`cbm_driver_open`]
+pub const ADAPTER_ENV_VAR: &str = "RS1541_ADAPTER";
+
+/// Handle to an open driver, as returned by [`cbm_driver_open`]
+pub type CBM_FILE = c_int;
+
+/// Drive types returned by [`cbm_identify`], matching OpenCBM's values
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum cbm_device_type_e {
+    cbm_dt_unknown = -1,
+    cbm_dt_cbm1540 = 0,
+    cbm_dt_cbm1541 = 1,
+    cbm_dt_cbm1570 = 2,
+    cbm_dt_cbm1571 = 3,
+    cbm_dt_cbm1581 = 4,
+    cbm_dt_cbm2040 = 5,
+    cbm_dt_cbm2031 = 6,
+    cbm_dt_cbm3040 = 7,
+    cbm_dt_cbm4040 = 8,
+    cbm_dt_cbm4031 = 9,
+    cbm_dt_cbm8050 = 10,
+    cbm_dt_cbm8250 = 11,
+    cbm_dt_sfd1001 = 12,
+    cbm_dt_fdx000 = 13,
+}
+
+impl From<CbmDeviceType> for cbm_device_type_e {
+    fn from(device_type: CbmDeviceType) -> Self {
+        match device_type {
+            CbmDeviceType::Unknown => Self::cbm_dt_unknown,
+            CbmDeviceType::Cbm1540 => Self::cbm_dt_cbm1540,
+            CbmDeviceType::Cbm1541 => Self::cbm_dt_cbm1541,
+            CbmDeviceType::Cbm1570 => Self::cbm_dt_cbm1570,
+            CbmDeviceType::Cbm1571 => Self::cbm_dt_cbm1571,
+            CbmDeviceType::Cbm1581 => Self::cbm_dt_cbm1581,
+            CbmDeviceType::Cbm2040 => Self::cbm_dt_cbm2040,
+            CbmDeviceType::Cbm2031 => Self::cbm_dt_cbm2031,
+            CbmDeviceType::Cbm3040 => Self::cbm_dt_cbm3040,
+            CbmDeviceType::Cbm4040 => Self::cbm_dt_cbm4040,
+            CbmDeviceType::Cbm4031 => Self::cbm_dt_cbm4031,
+            CbmDeviceType::Cbm8050 => Self::cbm_dt_cbm8050,
+            CbmDeviceType::Cbm8250 => Self::cbm_dt_cbm8250,
+            CbmDeviceType::Sfd1001 => Self::cbm_dt_sfd1001,
+            CbmDeviceType::FdX000 => Self::cbm_dt_fdx000,
+        }
+    }
+}
+
+impl cbm_device_type_e {
+    // The name OpenCBM gives each drive type
+    fn type_str(&self) -> &'static CStr {
+        match self {
+            Self::cbm_dt_unknown => c"*unknown*",
+            Self::cbm_dt_cbm1540 => c"1540",
+            Self::cbm_dt_cbm1541 => c"1541",
+            Self::cbm_dt_cbm1570 => c"1570",
+            Self::cbm_dt_cbm1571 => c"1571",
+            Self::cbm_dt_cbm1581 => c"1581",
+            Self::cbm_dt_cbm2040 => c"2040",
+            Self::cbm_dt_cbm2031 => c"2031",
+            Self::cbm_dt_cbm3040 => c"3040",
+            Self::cbm_dt_cbm4040 => c"4040",
+            Self::cbm_dt_cbm4031 => c"4031",
+            Self::cbm_dt_cbm8050 => c"8050",
+            Self::cbm_dt_cbm8250 => c"8250",
+            Self::cbm_dt_sfd1001 => c"SFD-1001",
+            Self::cbm_dt_fdx000 => c"FDX000",
+        }
+    }
+}
+
+// The open drivers, by handle
+static DRIVERS: Mutex<BTreeMap<CBM_FILE, Cbm>> = Mutex::new(BTreeMap::new());
+static NEXT_HANDLE: AtomicI32 = AtomicI32::new(1);
+
+// Returns the Cbm for a handle.  Cbm is cheap to clone, and cloning it means
+// the drivers aren't locked during bus operations.
+fn driver(f: CBM_FILE) -> Result<Cbm, Error> {
+    DRIVERS
+        .lock()
+        .get(&f)
+        .cloned()
+        .ok_or_else(|| Error::Validation {
+            message: format!("Invalid CBM_FILE {f}"),
+        })
+}
+
+// Converts a result to OpenCBM's convention of a non-negative value on
+// success and -1 with errno set on failure
+fn to_c<T: Into<c_int>>(function: &str, result: Result<T, Error>) -> c_int {
+    match result {
+        Ok(value) => value.into(),
+        Err(e) => {
+            debug!("{function} failed: {e}");
+            set_errno(e.to_errno());
+            -1
+        }
+    }
+}
+
+fn len_to_c(len: usize) -> c_int {
+    c_int::try_from(len).unwrap_or(c_int::MAX)
+}
+
+#[cfg(target_os = "linux")]
+fn set_errno(errno: c_int) {
+    // SAFETY: __errno_location always returns a valid pointer to this
+    // thread's errno
+    unsafe { *libc::__errno_location() = errno };
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+fn set_errno(errno: c_int) {
+    // SAFETY: __error always returns a valid pointer to this thread's errno
+    unsafe { *libc::__error() = errno };
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd"
+)))]
+fn set_errno(_errno: c_int) {}
+
+/// Parses an adapter string, returning the serial number and remote
+/// address to pass to [`Cbm::new`].  See the [crate documentation](crate).
+pub fn parse_adapter(adapter: &str) -> Result<(Option<u8>, Option<SocketAddr>), Error> {
+    let invalid = |message: String| Error::Validation { message };
+    let (kind, arg) = match adapter.split_once(':') {
+        Some((kind, arg)) => (kind, Some(arg)),
+        None => (adapter, None),
+    };
+
+    match (kind, arg) {
+        ("" | "xum1541", None) => Ok((None, None)),
+        ("xum1541", Some(serial)) => match serial.parse::<u8>() {
+            Ok(0) => Ok((None, None)),
+            Ok(serial) => Ok((Some(serial), None)),
+            Err(e) => Err(invalid(format!("Invalid serial number {serial}: {e}"))),
+        },
+        ("remote", arg) => {
+            let addr = arg
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("{DEFAULT_REMOTE_ADDR}:{DEFAULT_REMOTE_PORT}"));
+            addr.parse()
+                .map(|addr| (None, Some(addr)))
+                .map_err(|e| invalid(format!("Invalid remote address {addr}: {e}")))
+        }
+        _ => Err(invalid(format!("Unknown adapter {adapter}"))),
+    }
+}
+
+fn open_driver(f: *mut CBM_FILE, adapter: Option<&str>) -> Result<c_int, Error> {
+    if f.is_null() {
+        return Err(Error::Validation {
+            message: "NULL CBM_FILE pointer".to_string(),
+        });
+    }
+
+    // Tools don't initialise logging, so do it here
+    let _ = env_logger::try_init();
+
+    let adapter = match adapter.filter(|adapter| !adapter.is_empty()) {
+        Some(adapter) => adapter.to_string(),
+        None => std::env::var(ADAPTER_ENV_VAR).unwrap_or_default(),
+    };
+    let (serial, remote) = parse_adapter(&adapter)?;
+    info!("Opening xum1541 {adapter}");
+    let cbm = Cbm::new(serial, remote)?;
+
+    let handle = NEXT_HANDLE.fetch_add(1, Ordering::SeqCst);
+    DRIVERS.lock().insert(handle, cbm);
+    // SAFETY: checked for NULL above, and the caller guarantees it is
+    // otherwise valid
+    unsafe { *f = handle };
+    Ok(0)
+}
+
+/// Opens the driver, storing the handle in `f`.  Uses the adapter in the
+/// `RS1541_ADAPTER` environment variable if set, otherwise the local
+/// xum1541 with serial number `port`, with 0 meaning any.
+///
+/// # Safety
+/// `f` must be a valid pointer to a `CBM_FILE`.
+#[no_mangle]
+pub unsafe extern "C" fn cbm_driver_open(f: *mut CBM_FILE, port: c_int) -> c_int {
+    let adapter = std::env::var(ADAPTER_ENV_VAR).unwrap_or_else(|_| format!("xum1541:{port}"));
+    to_c("cbm_driver_open", open_driver(f, Some(&adapter)))
+}
+
+/// Opens the driver for the given adapter, storing the handle in `f`.
+/// `adapter` is one of `xum1541`, `xum1541:<serial>`, `remote` or
+/// `remote:<ip>:<port>`, and NULL or empty uses `RS1541_ADAPTER`.
+///
+/// # Safety
+/// `f` must be a valid pointer to a `CBM_FILE`, and `adapter` must be NULL
+/// or a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cbm_driver_open_ex(f: *mut CBM_FILE, adapter: *const c_char) -> c_int {
+    let adapter = if adapter.is_null() {
+        None
+    } else {
+        // SAFETY: the caller guarantees adapter is a valid string
+        match unsafe { CStr::from_ptr(adapter) }.to_str() {
+            Ok(adapter) => Some(adapter),
+            Err(e) => {
+                debug!("cbm_driver_open_ex failed: invalid adapter string: {e}");
+                set_errno(libc::EINVAL);
+                return -1;
+            }
+        }
+    };
+    to_c("cbm_driver_open_ex", open_driver(f, adapter))
+}
+
+/// Closes the driver.  The xum1541 is closed once it isn't in use.
+#[no_mangle]
+pub extern "C" fn cbm_driver_close(f: CBM_FILE) {
+    if DRIVERS.lock().remove(&f).is_none() {
+        debug!("cbm_driver_close called with invalid CBM_FILE {f}");
+    }
+}
+
+/// Resets the IEC bus
+#[no_mangle]
+pub extern "C" fn cbm_reset(f: CBM_FILE) -> c_int {
+    to_c(
+        "cbm_reset",
+        driver(f).and_then(|cbm| cbm.reset_bus()).map(|_| 0),
+    )
+}
+
+/// Instructs the device to listen on the secondary address
+#[no_mangle]
+pub extern "C" fn cbm_listen(f: CBM_FILE, dev: c_uchar, secadr: c_uchar) -> c_int {
+    let result = driver(f).and_then(|cbm| cbm.listen(DeviceChannel::new(dev, secadr)?));
+    to_c("cbm_listen", result.map(|_| 0))
+}
+
+/// Instructs the device to talk on the secondary address
+#[no_mangle]
+pub extern "C" fn cbm_talk(f: CBM_FILE, dev: c_uchar, secadr: c_uchar) -> c_int {
+    let result = driver(f).and_then(|cbm| cbm.talk(DeviceChannel::new(dev, secadr)?));
+    to_c("cbm_talk", result.map(|_| 0))
+}
+
+/// Instructs the listening device to unlisten
+#[no_mangle]
+pub extern "C" fn cbm_unlisten(f: CBM_FILE) -> c_int {
+    to_c(
+        "cbm_unlisten",
+        driver(f).and_then(|cbm| cbm.unlisten()).map(|_| 0),
+    )
+}
+
+/// Instructs the talking device to untalk
+#[no_mangle]
+pub extern "C" fn cbm_untalk(f: CBM_FILE) -> c_int {
+    to_c(
+        "cbm_untalk",
+        driver(f).and_then(|cbm| cbm.untalk()).map(|_| 0),
+    )
+}
+
+/// Opens a file on the secondary address, sending `fname` if `len` is
+/// non-zero
+///
+/// # Safety
+/// `fname` must point to at least `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cbm_open(
+    f: CBM_FILE,
+    dev: c_uchar,
+    secadr: c_uchar,
+    fname: *const c_void,
+    len: usize,
+) -> c_int {
+    let fname = if fname.is_null() || len == 0 {
+        &[][..]
+    } else {
+        // SAFETY: the caller guarantees fname points to len bytes
+        unsafe { std::slice::from_raw_parts(fname as *const u8, len) }
+    };
+    let result = driver(f).and_then(|cbm| {
+        cbm.open_channel(DeviceChannel::new(dev, secadr)?)?;
+        if !fname.is_empty() {
+            cbm.raw_write(fname).inspect_err(|_| {
+                let _ = cbm.unlisten();
+            })?;
+        }
+        cbm.unlisten()
+    });
+    to_c("cbm_open", result.map(|_| 0))
+}
+
+/// Closes the file on the secondary address
+#[no_mangle]
+pub extern "C" fn cbm_close(f: CBM_FILE, dev: c_uchar, secadr: c_uchar) -> c_int {
+    let result = driver(f).and_then(|cbm| cbm.close_file(DeviceChannel::new(dev, secadr)?));
+    to_c("cbm_close", result.map(|_| 0))
+}
+
+/// Reads up to `size` bytes from the talking device, returning the number
+/// read
+///
+/// # Safety
+/// `buf` must point to at least `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cbm_raw_read(f: CBM_FILE, buf: *mut c_void, size: usize) -> c_int {
+    if buf.is_null() || size == 0 {
+        return 0;
+    }
+    // SAFETY: the caller guarantees buf points to size bytes
+    let buf = unsafe { std::slice::from_raw_parts_mut(buf as *mut u8, size) };
+    let result = driver(f).and_then(|cbm| cbm.raw_read(buf));
+    to_c("cbm_raw_read", result.map(len_to_c))
+}
+
+/// Writes `size` bytes to the listening device, returning the number
+/// written
+///
+/// # Safety
+/// `buf` must point to at least `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cbm_raw_write(f: CBM_FILE, buf: *const c_void, size: usize) -> c_int {
+    if buf.is_null() || size == 0 {
+        return 0;
+    }
+    // SAFETY: the caller guarantees buf points to size bytes
+    let buf = unsafe { std::slice::from_raw_parts(buf as *const u8, size) };
+    let result = driver(f).and_then(|cbm| cbm.raw_write(buf));
+    to_c("cbm_raw_write", result.map(len_to_c))
+}
+
+/// Sends a command, in PETSCII, to the device's command channel.  If `len`
+/// is 0 `cmd` is NUL terminated.
+///
+/// # Safety
+/// `cmd` must point to at least `len` bytes, or be NUL terminated if `len`
+/// is 0.
+#[no_mangle]
+pub unsafe extern "C" fn cbm_exec_command(
+    f: CBM_FILE,
+    dev: c_uchar,
+    cmd: *const c_void,
+    len: usize,
+) -> c_int {
+    if cmd.is_null() {
+        set_errno(libc::EINVAL);
+        return -1;
+    }
+    let cmd = if len == 0 {
+        // SAFETY: the caller guarantees cmd is NUL terminated
+        unsafe { CStr::from_ptr(cmd as *const c_char) }.to_bytes()
+    } else {
+        // SAFETY: the caller guarantees cmd points to len bytes
+        unsafe { std::slice::from_raw_parts(cmd as *const u8, len) }
+    };
+    let cmd = PetsciiString::from_petscii_bytes(cmd);
+    let result = driver(f).and_then(|cbm| cbm.send_command_petscii(dev, &cmd));
+    to_c("cbm_exec_command", result.map(|_| 0))
+}
+
+/// Reads the device's status into `buf`, as a NUL terminated string such as
+/// `00, OK,00,00\r`, and returns the status number.  If the status can't be
+/// read, 99 and `99, DRIVER ERROR,00,00\r` are returned, as OpenCBM does.
+///
+/// # Safety
+/// `buf` must be NULL or point to at least `bufsize` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cbm_device_status(
+    f: CBM_FILE,
+    dev: c_uchar,
+    buf: *mut c_void,
+    bufsize: usize,
+) -> c_int {
+    let (number, status) = match driver(f).and_then(|cbm| cbm.get_status(dev)) {
+        Ok(status) => (status.number as c_int, format!("{status}\r")),
+        Err(e) => {
+            debug!("cbm_device_status failed: {e}");
+            set_errno(e.to_errno());
+            (99, "99, DRIVER ERROR,00,00\r".to_string())
+        }
+    };
+
+    if !buf.is_null() && bufsize > 0 {
+        let len = status.len().min(bufsize - 1);
+        // SAFETY: the caller guarantees buf points to bufsize bytes, and
+        // len + 1 <= bufsize
+        unsafe {
+            let buf = buf as *mut u8;
+            std::ptr::copy_nonoverlapping(status.as_ptr(), buf, len);
+            *buf.add(len) = 0;
+        }
+    }
+    number
+}
+
+/// Identifies the drive, setting `t` to its type and `type_str` to a static
+/// string naming it.  Either may be NULL.
+///
+/// # Safety
+/// `t` and `type_str` must each be NULL or valid pointers.
+#[no_mangle]
+pub unsafe extern "C" fn cbm_identify(
+    f: CBM_FILE,
+    drv: c_uchar,
+    t: *mut cbm_device_type_e,
+    type_str: *mut *const c_char,
+) -> c_int {
+    let result = driver(f).and_then(|cbm| cbm.identify(drv)).map(|info| {
+        let device_type = cbm_device_type_e::from(info.device_type);
+        // SAFETY: the caller guarantees the pointers are NULL or valid
+        unsafe {
+            if !t.is_null() {
+                *t = device_type;
+            }
+            if !type_str.is_null() {
+                *type_str = device_type.type_str().as_ptr();
+            }
+        }
+        0
+    });
+    to_c("cbm_identify", result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_adapter() {
+        assert_eq!(parse_adapter("").unwrap(), (None, None));
+        assert_eq!(parse_adapter("xum1541:0").unwrap(), (None, None));
+        assert_eq!(parse_adapter("xum1541:3").unwrap(), (Some(3), None));
+        assert_eq!(
+            parse_adapter("remote:192.168.0.2:1541").unwrap(),
+            (None, Some("192.168.0.2:1541".parse().unwrap()))
+        );
+        assert!(parse_adapter("remote").unwrap().1.is_some());
+        assert!(parse_adapter("xum1541:256").is_err());
+        assert!(parse_adapter("parallel:0").is_err());
+    }
+
+    #[test]
+    fn test_invalid_handle() {
+        assert_eq!(cbm_listen(-1, 8, 15), -1);
+        assert_eq!(
+            std::io::Error::last_os_error().raw_os_error(),
+            Some(libc::EINVAL)
+        );
+
+        let mut buf = [0u8; 32];
+        // SAFETY: buf is 32 bytes
+        let number = unsafe { cbm_device_status(-1, 8, buf.as_mut_ptr() as *mut c_void, 4) };
+        assert_eq!(number, 99);
+        assert_eq!(&buf[..4], b"99,\0");
+    }
+}
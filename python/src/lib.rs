@@ -0,0 +1,450 @@
+//! Python bindings for rs1541, so drive operations can be scripted from
+//! Python.  Build and install with maturin:
+//!
+//! ```text
+//! cd python && maturin develop --release
+//! ```
+//!
+//! ```python
+//! import rs1541
+//!
+//! cbm = rs1541.Cbm()                        # or rs1541.Cbm(remote="192.168.0.2")
+//! print(cbm.get_status(8))
+//! for entry in cbm.dir(8).files:
+//!     data = cbm.read_file(8, entry.filename, entry.file_type)
+//! ```
+//!
+//! Errors are raised as `rs1541.CbmError`, a subclass of `OSError`, with
+//! `errno` set from [`Error::to_errno`], and `error_number` set to the
+//! drive's [`CbmErrorNumber`] if the drive reported the error, or None
+//! otherwise.
+//!
+//! The GIL is released while talking to the drive, so other Python threads
+//! keep running.
+
+use ::rs1541::{
+    AsciiString, Cbm, CbmDeviceInfo, CbmDirListing, CbmErrorNumber, CbmErrorNumberOk, CbmFileEntry,
+    CbmFileType, CbmStatus, Error, PetsciiString, DEFAULT_DEVICE_NUM, DEFAULT_REMOTE_PORT,
+    DEVICE_MAX_NUM, DEVICE_MIN_NUM,
+};
+
+use pyo3::create_exception;
+use pyo3::exceptions::PyOSError;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+create_exception!(
+    rs1541,
+    CbmError,
+    PyOSError,
+    "Error from rs1541.  error_number is the drive's CBM DOS error number, or None."
+);
+
+// Converts an rs1541 Error to a CbmError
+fn to_py_err(py: Python<'_>, e: Error) -> PyErr {
+    let error_number = match &e {
+        Error::Status { status } => Some(error_number(&status.error_number)),
+        _ => None,
+    };
+    let err = CbmError::new_err((e.to_errno(), e.to_string()));
+    if let Err(set_err) = err.value(py).setattr("error_number", error_number) {
+        return set_err;
+    }
+    err
+}
+
+fn error_number(error_number: &CbmErrorNumber) -> u8 {
+    error_number.clone() as u8
+}
+
+fn ascii(s: &str) -> Result<AsciiString, Error> {
+    AsciiString::try_from(s).map_err(|e| Error::Validation {
+        message: format!("Invalid filename {s}: {e}"),
+    })
+}
+
+fn petscii(s: &str) -> Result<PetsciiString, Error> {
+    ascii(s).map(|s| s.into())
+}
+
+fn file_type(file_type: &str) -> Result<CbmFileType, Error> {
+    match CbmFileType::from(file_type) {
+        CbmFileType::Unknown => Err(Error::Validation {
+            message: format!("Unknown file type {file_type}"),
+        }),
+        file_type => Ok(file_type),
+    }
+}
+
+// Parses an address of the form "ip" or "ip:port"
+fn parse_remote(remote: &str) -> Result<SocketAddr, Error> {
+    remote
+        .parse::<SocketAddr>()
+        .or_else(|_| {
+            remote
+                .parse::<IpAddr>()
+                .map(|ip| SocketAddr::new(ip, DEFAULT_REMOTE_PORT))
+        })
+        .map_err(|e| Error::Validation {
+            message: format!("Invalid remote address {remote}: {e}"),
+        })
+}
+
+fn device_info_dict<'py>(py: Python<'py>, info: &CbmDeviceInfo) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new(py);
+    dict.set_item("device_type", info.device_type.to_string())?;
+    dict.set_item("description", &info.description)?;
+    Ok(dict)
+}
+
+/// A drive's status, as returned by Cbm.get_status()
+#[pyclass(name = "CbmStatus", module = "rs1541", frozen)]
+struct PyCbmStatus(CbmStatus);
+
+#[pymethods]
+impl PyCbmStatus {
+    #[getter]
+    fn number(&self) -> u8 {
+        self.0.number
+    }
+
+    /// The CBM DOS error number, 255 if unknown
+    #[getter]
+    fn error_number(&self) -> u8 {
+        error_number(&self.0.error_number)
+    }
+
+    #[getter]
+    fn message(&self) -> &str {
+        &self.0.message
+    }
+
+    #[getter]
+    fn track(&self) -> u8 {
+        self.0.track
+    }
+
+    #[getter]
+    fn sector(&self) -> u8 {
+        self.0.sector
+    }
+
+    #[getter]
+    fn device(&self) -> u8 {
+        self.0.device
+    }
+
+    /// True unless the status is an error.  73 (the DOS version, reported
+    /// after a reset) isn't an error.
+    fn is_ok(&self) -> bool {
+        self.0.is_ok() != CbmErrorNumberOk::Err
+    }
+
+    fn __str__(&self) -> String {
+        self.0.to_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("CbmStatus('{}')", self.0)
+    }
+}
+
+/// An entry in a CbmDirListing.  Entries which couldn't be parsed have
+/// is_valid False and error set, and may be missing other fields.
+#[pyclass(name = "CbmFileEntry", module = "rs1541", frozen)]
+struct PyCbmFileEntry(CbmFileEntry);
+
+#[pymethods]
+impl PyCbmFileEntry {
+    #[getter]
+    fn filename(&self) -> Option<&str> {
+        match &self.0 {
+            CbmFileEntry::ValidFile { filename, .. } => Some(filename),
+            CbmFileEntry::InvalidFile {
+                partial_filename, ..
+            } => partial_filename.as_deref(),
+        }
+    }
+
+    /// The file type, "prg", "seq", "usr" or "rel"
+    #[getter]
+    fn file_type(&self) -> Option<String> {
+        match &self.0 {
+            CbmFileEntry::ValidFile { file_type, .. } => Some(file_type.to_string()),
+            CbmFileEntry::InvalidFile { .. } => None,
+        }
+    }
+
+    #[getter]
+    fn blocks(&self) -> Option<u16> {
+        match &self.0 {
+            CbmFileEntry::ValidFile { blocks, .. } => Some(*blocks),
+            CbmFileEntry::InvalidFile { partial_blocks, .. } => *partial_blocks,
+        }
+    }
+
+    #[getter]
+    fn is_valid(&self) -> bool {
+        matches!(self.0, CbmFileEntry::ValidFile { .. })
+    }
+
+    #[getter]
+    fn error(&self) -> Option<&str> {
+        match &self.0 {
+            CbmFileEntry::ValidFile { .. } => None,
+            CbmFileEntry::InvalidFile { error, .. } => Some(error),
+        }
+    }
+
+    fn __str__(&self) -> String {
+        self.0.to_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("CbmFileEntry({:?})", self.0)
+    }
+}
+
+/// A disk's directory, as returned by Cbm.dir()
+#[pyclass(name = "CbmDirListing", module = "rs1541", frozen)]
+struct PyCbmDirListing(CbmDirListing);
+
+#[pymethods]
+impl PyCbmDirListing {
+    #[getter]
+    fn name(&self) -> &str {
+        &self.0.header.name
+    }
+
+    #[getter]
+    fn id(&self) -> &str {
+        &self.0.header.id
+    }
+
+    #[getter]
+    fn drive_number(&self) -> u8 {
+        self.0.header.drive_number
+    }
+
+    #[getter]
+    fn blocks_free(&self) -> u16 {
+        self.0.blocks_free
+    }
+
+    #[getter]
+    fn files(&self) -> Vec<PyCbmFileEntry> {
+        self.0.files.iter().cloned().map(PyCbmFileEntry).collect()
+    }
+
+    fn __len__(&self) -> usize {
+        self.0.files.len()
+    }
+
+    fn __str__(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+/// A connection to a xum1541, and the drives on its bus.
+///
+/// Cbm(serial=None, remote=None) opens the local xum1541 with this serial
+/// number, or any if None, or the remote xum1541 at "ip" or "ip:port".
+#[pyclass(name = "Cbm", module = "rs1541", frozen)]
+struct PyCbm {
+    cbm: Cbm,
+}
+
+impl PyCbm {
+    // Runs f with the GIL released, converting any error
+    fn run<T, F>(&self, py: Python<'_>, f: F) -> PyResult<T>
+    where
+        F: FnOnce(&Cbm) -> Result<T, Error> + Send,
+        T: Send,
+    {
+        let cbm = &self.cbm;
+        py.allow_threads(|| f(cbm)).map_err(|e| to_py_err(py, e))
+    }
+}
+
+#[pymethods]
+impl PyCbm {
+    #[new]
+    #[pyo3(signature = (serial=None, remote=None))]
+    fn new(py: Python<'_>, serial: Option<u8>, remote: Option<&str>) -> PyResult<Self> {
+        let remote = remote
+            .map(parse_remote)
+            .transpose()
+            .map_err(|e| to_py_err(py, e))?;
+        let cbm = py
+            .allow_threads(|| Cbm::new(serial, remote))
+            .map_err(|e| to_py_err(py, e))?;
+        Ok(Self { cbm })
+    }
+
+    /// Returns the drive's type and description as a dict
+    fn identify<'py>(&self, py: Python<'py>, device: u8) -> PyResult<Bound<'py, PyDict>> {
+        let info = self.run(py, |cbm| cbm.identify(device))?;
+        device_info_dict(py, &info)
+    }
+
+    fn get_status(&self, py: Python<'_>, device: u8) -> PyResult<PyCbmStatus> {
+        self.run(py, |cbm| cbm.get_status(device)).map(PyCbmStatus)
+    }
+
+    fn drive_exists(&self, py: Python<'_>, device: u8) -> PyResult<bool> {
+        self.run(py, |cbm| cbm.drive_exists(device))
+    }
+
+    /// Returns a dict of the drives on the bus, keyed by device number, as
+    /// identify() does
+    #[pyo3(signature = (start=DEVICE_MIN_NUM, end=DEVICE_MAX_NUM))]
+    fn scan_bus<'py>(
+        &self,
+        py: Python<'py>,
+        start: u8,
+        end: u8,
+    ) -> PyResult<HashMap<u8, Bound<'py, PyDict>>> {
+        let devices = self.run(py, |cbm| cbm.scan_bus_range(start..=end))?;
+        devices
+            .iter()
+            .map(|(device, info)| Ok((*device, device_info_dict(py, info)?)))
+            .collect()
+    }
+
+    /// Resets the IEC bus
+    fn reset_bus(&self, py: Python<'_>) -> PyResult<()> {
+        self.run(py, |cbm| cbm.reset_bus())
+    }
+
+    /// Returns the directory of the disk, in drive 0 or 1 of dual drive
+    /// units if drive is given
+    #[pyo3(signature = (device=DEFAULT_DEVICE_NUM, drive=None))]
+    fn dir(&self, py: Python<'_>, device: u8, drive: Option<u8>) -> PyResult<PyCbmDirListing> {
+        self.run(py, |cbm| cbm.dir(device, drive))
+            .map(PyCbmDirListing)
+    }
+
+    /// Reads a file, returning its contents as bytes.  If file_type ("prg",
+    /// "seq" or "usr") is given, the file must be of that type.
+    #[pyo3(signature = (device, filename, file_type=None))]
+    fn read_file<'py>(
+        &self,
+        py: Python<'py>,
+        device: u8,
+        filename: &str,
+        file_type: Option<&str>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let data = self.run(py, |cbm| match file_type {
+            Some(t) => cbm.read_file_petscii(device, &petscii(filename)?, self::file_type(t)?),
+            None => cbm.read_file(device, &ascii(filename)?),
+        })?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    /// Writes a file, replacing any existing file.  If file_type ("prg",
+    /// "seq" or "usr") is given, a new file of that type is created instead.
+    #[pyo3(signature = (device, filename, data, file_type=None))]
+    fn write_file(
+        &self,
+        py: Python<'_>,
+        device: u8,
+        filename: &str,
+        data: &[u8],
+        file_type: Option<&str>,
+    ) -> PyResult<()> {
+        self.run(py, |cbm| match file_type {
+            Some(t) => {
+                cbm.save_file_petscii(device, &petscii(filename)?, self::file_type(t)?, data)
+            }
+            None => cbm.write_file(device, &ascii(filename)?, data),
+        })
+    }
+
+    /// Loads a PRG file as the C64's LOAD does, including the load address
+    fn load_file<'py>(
+        &self,
+        py: Python<'py>,
+        device: u8,
+        filename: &str,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let data = self.run(py, |cbm| cbm.load_file_ascii(device, &ascii(filename)?))?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    /// Scratches a file
+    fn delete_file(&self, py: Python<'_>, device: u8, filename: &str) -> PyResult<()> {
+        self.run(py, |cbm| cbm.delete_file(device, &ascii(filename)?))
+    }
+
+    /// Sends a command, such as "i0" or "n0:disk,01", to the drive's command
+    /// channel, converting it to PETSCII.  Use get_status() to check the
+    /// result.
+    fn send_command(&self, py: Python<'_>, device: u8, command: &str) -> PyResult<()> {
+        self.run(py, |cbm| cbm.send_string_command_ascii(device, command))
+    }
+
+    /// Sends a command, which is already PETSCII, to the drive's command
+    /// channel
+    fn send_command_petscii(&self, py: Python<'_>, device: u8, command: &[u8]) -> PyResult<()> {
+        let command = PetsciiString::from_petscii_bytes(command);
+        self.run(py, |cbm| cbm.send_command_petscii(device, &command))
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "rs1541")]
+fn py_rs1541(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    // Log to stderr if RUST_LOG is set
+    let _ = env_logger::try_init();
+
+    m.add_class::<PyCbm>()?;
+    m.add_class::<PyCbmStatus>()?;
+    m.add_class::<PyCbmDirListing>()?;
+    m.add_class::<PyCbmFileEntry>()?;
+    m.add("CbmError", m.py().get_type::<CbmError>())?;
+    m.add("DEFAULT_DEVICE_NUM", DEFAULT_DEVICE_NUM)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors() {
+        Python::with_gil(|py| {
+            let status = CbmStatus::new("62,FILE NOT FOUND,00,00", 8).unwrap();
+            let error = Error::Status { status };
+            let errno = error.to_errno();
+
+            let err = to_py_err(py, error);
+            assert!(err.is_instance_of::<CbmError>(py));
+            assert!(err.is_instance_of::<PyOSError>(py));
+            let value = err.value(py);
+            let number: u8 = value.getattr("error_number").unwrap().extract().unwrap();
+            assert_eq!(number, 62);
+            let value_errno: i32 = value.getattr("errno").unwrap().extract().unwrap();
+            assert_eq!(value_errno, errno);
+
+            let err = to_py_err(py, Error::Cancelled);
+            assert!(err.value(py).getattr("error_number").unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_parse_remote() {
+        assert_eq!(
+            parse_remote("10.0.0.1").unwrap(),
+            SocketAddr::new("10.0.0.1".parse().unwrap(), DEFAULT_REMOTE_PORT)
+        );
+        assert_eq!(
+            parse_remote("10.0.0.1:2000").unwrap(),
+            "10.0.0.1:2000".parse().unwrap()
+        );
+        assert!(parse_remote("nowhere").is_err());
+        assert!(file_type("del").is_err());
+    }
+}
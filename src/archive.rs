@@ -0,0 +1,589 @@
+//! Contains types and functions for working with Lynx (LNX) and ARK
+//! archives
+//!
+//! Both formats store a set of files as whole 254 byte disk blocks, with a
+//! directory giving each file's name, type, size in blocks and the number
+//! of bytes used in its last block.  REL files include their side sectors in
+//! the archive - these are skipped when extracting, and rebuilt when the
+//! file is written to an image or drive.
+//!
+//! Lynx archives start with a BASIC program (so they can be loaded and run
+//! on a C64), followed by a text directory with each field terminated by a
+//! carriage return:
+//!
+//! ```text
+//! <directory blocks> *LYNX ... <CR>
+//! <number of files> <CR>
+//! <filename> <CR> <blocks> <CR> <type> <CR> [<record length> <CR>] <last block size> <CR>
+//! ```
+//!
+//! REL file side sectors are stored before the data blocks.
+//!
+//! ARK archives start with a count of files, followed by a 29 byte binary
+//! directory entry per file.  REL file side sectors are stored after the
+//! data blocks.
+
+use crate::cbm::Cbm;
+use crate::disk::{CbmFileEntry, CbmFileType, BYTES_PER_BLOCK};
+use crate::error::Error;
+use crate::image::{strip_padding, DiskImage, MAX_FILENAME_LEN};
+use crate::pc64::Pc64File;
+use crate::string::PetsciiString;
+use crate::util::petscii_str_to_ascii;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+// Text which identifies a Lynx archive
+const LYNX_SIGNATURE: &[u8] = b"LYNX";
+
+// How far into the archive to search for the Lynx signature
+const LYNX_SIGNATURE_SEARCH: usize = 1024;
+
+const CR: u8 = 0x0d;
+
+// ARK directory entry layout
+const ARK_ENTRY_SIZE: usize = 29;
+const ARK_TYPE: usize = 0;
+const ARK_FILENAME: usize = 1;
+const ARK_RECORD_LEN: usize = 0x11;
+const ARK_SIDE_SECTORS: usize = 0x19;
+const ARK_LSU: usize = 0x1a;
+const ARK_BLOCKS: usize = 0x1b;
+
+// Number of data blocks covered by each REL file side sector
+const REL_BLOCKS_PER_SIDE_SECTOR: usize = 120;
+
+/// Supported archive formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Lynx,
+    Ark,
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let output = match self {
+            ArchiveFormat::Lynx => "Lynx",
+            ArchiveFormat::Ark => "ARK",
+        };
+        write!(f, "{}", output)
+    }
+}
+
+/// A file within an archive
+///
+/// # Fields
+///
+/// * `filename` - Filename in PETSCII, with padding removed
+/// * `file_type` - Type of the file
+/// * `blocks` - Size of the file in blocks, including any side sectors
+/// * `record_len` - Record length for REL files, 0 otherwise
+/// * `offset` - Offset of the file's data (excluding side sectors) in the
+///   archive
+/// * `len` - Length of the file's data
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveEntry {
+    pub filename: PetsciiString,
+    pub file_type: CbmFileType,
+    pub blocks: u16,
+    pub record_len: u8,
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl ArchiveEntry {
+    /// The filename, converted to ASCII
+    pub fn filename_ascii(&self) -> String {
+        petscii_str_to_ascii(self.filename.as_bytes())
+    }
+
+    /// Converts to the entry type returned by directory listings
+    pub fn to_file_entry(&self) -> CbmFileEntry {
+        CbmFileEntry::ValidFile {
+            blocks: self.blocks,
+            filename: self.filename_ascii(),
+            file_type: self.file_type,
+        }
+    }
+}
+
+/// A parsed Lynx or ARK archive
+///
+/// # Example
+/// ```ignore
+/// let archive = Archive::load("demos.lnx")?;
+/// for entry in archive.entries.iter() {
+///     println!("{}", entry.to_file_entry());
+/// }
+/// archive.extract_to_drive(&cbm, 8, &archive.entries)?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct Archive {
+    pub format: ArchiveFormat,
+    pub entries: Vec<ArchiveEntry>,
+    data: Vec<u8>,
+}
+
+impl Archive {
+    /// Parses an archive, detecting whether it is a Lynx or ARK archive
+    pub fn parse(data: Vec<u8>) -> Result<Self, Error> {
+        if find_lynx_signature(&data).is_some() {
+            Self::parse_lynx(data)
+        } else {
+            Self::parse_ark(data)
+        }
+    }
+
+    /// Loads and parses an archive from a file.  Files with a `.lnx` or
+    /// `.ark` extension are parsed as that format, otherwise the format is
+    /// detected.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let ext = path
+            .as_ref()
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        let data = std::fs::read(path)?;
+        match ext.as_deref() {
+            Some("lnx") => Self::parse_lynx(data),
+            Some("ark") => Self::parse_ark(data),
+            _ => Self::parse(data),
+        }
+    }
+
+    /// Parses a Lynx archive
+    pub fn parse_lynx(data: Vec<u8>) -> Result<Self, Error> {
+        let sig = find_lynx_signature(&data).ok_or_else(|| Error::Parse {
+            message: "Not a Lynx archive".to_string(),
+        })?;
+
+        // The directory starts with the line containing the signature
+        let start = data[..sig]
+            .iter()
+            .rposition(|&c| c == CR)
+            .map(|pos| pos + 1)
+            .unwrap_or(0);
+        let mut fields = data[start..].split(|&c| c == CR);
+
+        let dir_blocks = next_lynx_number(&mut fields, "directory size")?;
+        let num_files = next_lynx_number(&mut fields, "number of files")?;
+        trace!("Lynx archive with {dir_blocks} directory blocks and {num_files} files");
+
+        // Each directory entry takes several bytes, so a larger count can
+        // only come from a corrupt header
+        if num_files > data.len() {
+            return Err(Error::Parse {
+                message: format!("Invalid Lynx directory: {num_files} files"),
+            });
+        }
+
+        let mut raw_entries = Vec::with_capacity(num_files);
+        for _ in 0..num_files {
+            let name = fields.next().ok_or_else(|| Error::Parse {
+                message: "Invalid Lynx directory: missing filename".to_string(),
+            })?;
+            let name = strip_padding(&name[..name.len().min(MAX_FILENAME_LEN)]).to_vec();
+            let blocks = next_lynx_number(&mut fields, "block count")?;
+            let file_type = fields
+                .next()
+                .and_then(|field| field.iter().find(|c| !c.is_ascii_whitespace()))
+                .map(|&c| lynx_file_type(c))
+                .ok_or_else(|| Error::Parse {
+                    message: "Invalid Lynx directory: missing file type".to_string(),
+                })?;
+            let record_len = if file_type == CbmFileType::REL {
+                next_lynx_number(&mut fields, "record length")?
+            } else {
+                0
+            };
+            // Some versions of Lynx omit the last block size of the final
+            // file - it's then worked out from the size of the archive
+            let lsu = next_lynx_number(&mut fields, "last block size").ok();
+            raw_entries.push((name, file_type, blocks, record_len, lsu));
+        }
+
+        let mut offset = block_offset(0, dir_blocks, data.len())?;
+        let mut entries = Vec::with_capacity(num_files);
+        for (name, file_type, blocks, record_len, lsu) in raw_entries {
+            let side_sectors = match file_type {
+                CbmFileType::REL => rel_side_sectors(blocks),
+                _ => 0,
+            };
+            let data_offset = block_offset(offset, side_sectors, data.len())?;
+            let next_offset = block_offset(offset, blocks, data.len())?;
+            let data_blocks = blocks - side_sectors;
+            entries.push(ArchiveEntry {
+                filename: PetsciiString::from_petscii_bytes(&name),
+                file_type,
+                blocks: blocks as u16,
+                record_len: record_len as u8,
+                offset: data_offset,
+                len: file_len(data_blocks, lsu, data_offset, data.len()),
+            });
+            offset = next_offset;
+        }
+
+        Ok(Archive {
+            format: ArchiveFormat::Lynx,
+            entries,
+            data,
+        })
+    }
+
+    /// Parses an ARK archive
+    pub fn parse_ark(data: Vec<u8>) -> Result<Self, Error> {
+        let num_files = *data.first().ok_or_else(|| Error::Parse {
+            message: "Empty ARK archive".to_string(),
+        })? as usize;
+        let dir_len = 1 + num_files * ARK_ENTRY_SIZE;
+        if num_files == 0 || data.len() < dir_len {
+            return Err(Error::Parse {
+                message: "Not an ARK archive".to_string(),
+            });
+        }
+
+        let mut offset = block_offset(0, dir_len.div_ceil(BYTES_PER_BLOCK), data.len())?;
+        let mut entries = Vec::with_capacity(num_files);
+        for raw in data[1..dir_len].chunks(ARK_ENTRY_SIZE) {
+            let type_byte = raw[ARK_TYPE];
+            let file_type = CbmFileType::from_dir_type(type_byte);
+            if type_byte & 0x80 == 0 || file_type == CbmFileType::Unknown {
+                return Err(Error::Parse {
+                    message: format!("Not an ARK archive: invalid file type {type_byte:#04x}"),
+                });
+            }
+
+            let blocks = u16::from_le_bytes([raw[ARK_BLOCKS], raw[ARK_BLOCKS + 1]]) as usize;
+            let (side_sectors, record_len) = match file_type {
+                CbmFileType::REL => (raw[ARK_SIDE_SECTORS] as usize, raw[ARK_RECORD_LEN]),
+                _ => (0, 0),
+            };
+            let data_blocks = blocks.saturating_sub(side_sectors);
+            let next_offset = block_offset(offset, blocks, data.len())?;
+            entries.push(ArchiveEntry {
+                filename: PetsciiString::from_petscii_bytes(strip_padding(
+                    &raw[ARK_FILENAME..ARK_FILENAME + MAX_FILENAME_LEN],
+                )),
+                file_type,
+                blocks: blocks as u16,
+                record_len,
+                offset,
+                len: file_len(data_blocks, Some(raw[ARK_LSU] as usize), offset, data.len()),
+            });
+            offset = next_offset;
+        }
+
+        Ok(Archive {
+            format: ArchiveFormat::Ark,
+            entries,
+            data,
+        })
+    }
+
+    /// Returns the archive's entries in the form used by directory listings
+    pub fn files(&self) -> Vec<CbmFileEntry> {
+        self.entries.iter().map(|e| e.to_file_entry()).collect()
+    }
+
+    /// Returns the contents of an entry.  For PRG files this includes the
+    /// load address, for REL files this is the records.  Entries which
+    /// weren't parsed from this archive are cut off at the end of it.
+    pub fn file_data(&self, entry: &ArchiveEntry) -> &[u8] {
+        let data = self.data.get(entry.offset..).unwrap_or_default();
+        &data[..entry.len.min(data.len())]
+    }
+
+    /// Writes the given entries to a host directory as PC64 files,
+    /// returning the paths of the files written
+    pub fn extract_to_dir<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        entries: &[ArchiveEntry],
+    ) -> Result<Vec<PathBuf>, Error> {
+        entries
+            .iter()
+            .map(|entry| {
+                Pc64File::new(
+                    entry.filename.clone(),
+                    entry.file_type,
+                    entry.record_len,
+                    self.file_data(entry).to_vec(),
+                )
+                .save_to_dir(dir.as_ref())
+            })
+            .collect()
+    }
+
+    /// Writes the given entries to a disk image
+    pub fn extract_to_image(
+        &self,
+        image: &mut DiskImage,
+        entries: &[ArchiveEntry],
+    ) -> Result<(), Error> {
+        for entry in entries {
+            debug!("Extracting {} to image", entry.filename_ascii());
+            let data = self.file_data(entry);
+            match entry.file_type {
+                CbmFileType::REL => image.write_rel_file(&entry.filename, entry.record_len, data),
+                _ => image.write_file(&entry.filename, entry.file_type, data),
+            }?;
+        }
+        Ok(())
+    }
+
+    /// Writes the given entries to a physical drive
+    pub fn extract_to_drive(
+        &self,
+        cbm: &Cbm,
+        device: u8,
+        entries: &[ArchiveEntry],
+    ) -> Result<(), Error> {
+        for entry in entries {
+            debug!("Extracting {} to device {device}", entry.filename_ascii());
+            let data = self.file_data(entry);
+            match entry.file_type {
+                CbmFileType::REL => {
+                    cbm.save_rel_file_petscii(device, &entry.filename, entry.record_len, data)
+                }
+                _ => cbm.save_file_petscii(device, &entry.filename, entry.file_type, data),
+            }?;
+        }
+        Ok(())
+    }
+}
+
+// Returns the position of the Lynx signature, if present.  The signature
+// may be in either case.
+fn find_lynx_signature(data: &[u8]) -> Option<usize> {
+    let search = &data[..data.len().min(LYNX_SIGNATURE_SEARCH)];
+    search.windows(LYNX_SIGNATURE.len()).position(|window| {
+        window
+            .iter()
+            .zip(LYNX_SIGNATURE)
+            .all(|(&c, &sig)| (c & 0x7f).to_ascii_uppercase() == sig)
+    })
+}
+
+// Parses the next Lynx directory field as a number
+fn next_lynx_number<'a, I: Iterator<Item = &'a [u8]>>(
+    fields: &mut I,
+    what: &str,
+) -> Result<usize, Error> {
+    fields
+        .next()
+        .and_then(parse_lynx_number)
+        .ok_or_else(|| Error::Parse {
+            message: format!("Invalid Lynx directory: bad {what}"),
+        })
+}
+
+// Parses a number from a Lynx directory field, ignoring any spaces and
+// trailing text
+fn parse_lynx_number(field: &[u8]) -> Option<usize> {
+    let digits: String = field
+        .iter()
+        .skip_while(|c| c.is_ascii_whitespace())
+        .take_while(|c| c.is_ascii_digit())
+        .map(|&c| c as char)
+        .collect();
+    digits.parse().ok()
+}
+
+fn lynx_file_type(c: u8) -> CbmFileType {
+    match (c & 0x7f).to_ascii_uppercase() {
+        b'P' => CbmFileType::PRG,
+        b'S' => CbmFileType::SEQ,
+        b'U' => CbmFileType::USR,
+        b'R' => CbmFileType::REL,
+        _ => CbmFileType::Unknown,
+    }
+}
+
+// Number of side sectors in a REL file of the given total size in blocks
+fn rel_side_sectors(blocks: usize) -> usize {
+    blocks.div_ceil(REL_BLOCKS_PER_SIDE_SECTOR + 1)
+}
+
+// Returns the offset the given number of blocks past `offset`, failing if
+// that is past the end of the archive.  The final file's last block is
+// often cut short, so a file may still extend beyond the end of the
+// archive - file_len() allows for that.
+fn block_offset(offset: usize, blocks: usize, archive_len: usize) -> Result<usize, Error> {
+    blocks
+        .checked_mul(BYTES_PER_BLOCK)
+        .and_then(|len| offset.checked_add(len))
+        .filter(|&end| end <= archive_len.next_multiple_of(BYTES_PER_BLOCK))
+        .ok_or_else(|| Error::Parse {
+            message: "Truncated archive: files extend past the end".to_string(),
+        })
+}
+
+// Works out the length of a file's data from its size in blocks and the
+// last block's LSU (the number of bytes used, plus 1).  Never extends past
+// the end of the archive.
+fn file_len(data_blocks: usize, lsu: Option<usize>, offset: usize, archive_len: usize) -> usize {
+    let available = archive_len.saturating_sub(offset);
+    let len = match (data_blocks, lsu) {
+        (0, _) => 0,
+        (blocks, Some(lsu)) if lsu >= 2 => {
+            (blocks - 1) * BYTES_PER_BLOCK + (lsu - 1).min(BYTES_PER_BLOCK)
+        }
+        (blocks, _) => blocks * BYTES_PER_BLOCK,
+    };
+    len.min(available)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{DiskImageFormat, PETSCII_PADDING};
+
+    // A file to put in a test Lynx archive: name, type, record length and data
+    type LynxTestFile<'a> = (&'a [u8], char, Option<u8>, &'a [u8]);
+
+    // Builds a Lynx archive with a single directory block
+    fn build_lynx(files: &[LynxTestFile]) -> Vec<u8> {
+        let mut output = vec![0x01, 0x08, 0x0b, 0x08, 0x0a, 0x00, 0x9e, 0x00, 0x00, 0x00];
+        output.push(CR);
+        output.extend_from_slice(b" 1  *LYNX XV  BY WILL CORLEY");
+        output.push(CR);
+        output.extend_from_slice(format!(" {} ", files.len()).as_bytes());
+        output.push(CR);
+        for (name, file_type, record_len, data) in files {
+            let mut padded = name.to_vec();
+            padded.resize(MAX_FILENAME_LEN, PETSCII_PADDING);
+            output.extend_from_slice(&padded);
+            output.push(CR);
+            let mut blocks = data.len().div_ceil(BYTES_PER_BLOCK);
+            if *file_type == 'R' {
+                blocks += rel_side_sectors(blocks + 1);
+            }
+            output.extend_from_slice(format!(" {blocks} ").as_bytes());
+            output.push(CR);
+            output.push(*file_type as u8);
+            output.push(CR);
+            if let Some(record_len) = record_len {
+                output.extend_from_slice(format!(" {record_len} ").as_bytes());
+                output.push(CR);
+            }
+            let lsu = (data.len() - 1) % BYTES_PER_BLOCK + 2;
+            output.extend_from_slice(format!(" {lsu} ").as_bytes());
+            output.push(CR);
+        }
+        output.resize(BYTES_PER_BLOCK, 0);
+
+        for (_, file_type, _, data) in files {
+            if *file_type == 'R' {
+                // Dummy side sector
+                output.extend_from_slice(&[0xee; BYTES_PER_BLOCK]);
+            }
+            let mut padded = data.to_vec();
+            padded.resize(data.len().div_ceil(BYTES_PER_BLOCK) * BYTES_PER_BLOCK, 0);
+            output.extend_from_slice(&padded);
+        }
+        output
+    }
+
+    #[test]
+    fn test_parse_lynx() {
+        let prg: Vec<u8> = (0..300).map(|ii| ii as u8).collect();
+        let rel: Vec<u8> = vec![0x55; 100];
+        let lynx = build_lynx(&[
+            (b"GAME", 'P', None, &prg),
+            (b"RECORDS", 'R', Some(50), &rel),
+        ]);
+        let archive = Archive::parse(lynx).unwrap();
+        assert_eq!(archive.format, ArchiveFormat::Lynx);
+        assert_eq!(archive.entries.len(), 2);
+
+        let game = &archive.entries[0];
+        assert_eq!(game.filename.as_bytes(), b"GAME");
+        assert_eq!(game.file_type, CbmFileType::PRG);
+        assert_eq!(game.blocks, 2);
+        assert_eq!(archive.file_data(game), &prg[..]);
+
+        let records = &archive.entries[1];
+        assert_eq!(records.file_type, CbmFileType::REL);
+        assert_eq!(records.blocks, 2);
+        assert_eq!(records.record_len, 50);
+        assert_eq!(archive.file_data(records), &rel[..]);
+    }
+
+    #[test]
+    fn test_parse_ark() {
+        let mut ark = vec![1u8];
+        let mut entry = [0u8; ARK_ENTRY_SIZE];
+        entry[ARK_TYPE] = 0x81;
+        entry[ARK_FILENAME..ARK_FILENAME + MAX_FILENAME_LEN].fill(PETSCII_PADDING);
+        entry[ARK_FILENAME..ARK_FILENAME + 4].copy_from_slice(b"TEXT");
+        entry[ARK_LSU] = 11;
+        entry[ARK_BLOCKS] = 1;
+        ark.extend_from_slice(&entry);
+        ark.resize(BYTES_PER_BLOCK, 0);
+        ark.extend_from_slice(b"HELLO THERE");
+
+        let archive = Archive::parse(ark).unwrap();
+        assert_eq!(archive.format, ArchiveFormat::Ark);
+        let text = &archive.entries[0];
+        assert_eq!(text.filename.as_bytes(), b"TEXT");
+        assert_eq!(text.file_type, CbmFileType::SEQ);
+        assert_eq!(archive.file_data(text), b"HELLO THER");
+    }
+
+    #[test]
+    fn test_not_archive() {
+        assert!(Archive::parse(vec![0u8; 10]).is_err());
+        assert!(Archive::parse_lynx(vec![0u8; 300]).is_err());
+    }
+
+    #[test]
+    fn test_truncated_archive() {
+        let prg: Vec<u8> = vec![0xaa; 600];
+        let lynx = build_lynx(&[(b"FIRST", 'P', None, &prg), (b"SECOND", 'P', None, &prg)]);
+
+        // Cutting the last block short is allowed, as Lynx often does
+        let short = Archive::parse(lynx[..lynx.len() - 200].to_vec()).unwrap();
+        assert_eq!(short.file_data(&short.entries[1]).len(), 562);
+
+        // Losing whole blocks isn't
+        assert!(Archive::parse(lynx[..BYTES_PER_BLOCK * 3].to_vec()).is_err());
+
+        // A block count which overflows when converted to bytes
+        let mut huge = lynx.clone();
+        let pos = huge.windows(3).position(|w| w == b" 3 ").unwrap();
+        huge.splice(pos..pos + 3, b" 9999999999999999999 ".iter().copied());
+        assert!(Archive::parse(huge).is_err());
+
+        // Entries are cut off at the end of the archive
+        let archive = Archive::parse(lynx).unwrap();
+        let mut entry = archive.entries[1].clone();
+        entry.offset = usize::MAX;
+        assert!(archive.file_data(&entry).is_empty());
+    }
+
+    #[test]
+    fn test_extract_to_image() {
+        let rel: Vec<u8> = vec![0x55; 100];
+        let lynx = build_lynx(&[(b"RECORDS", 'R', Some(50), &rel)]);
+        let archive = Archive::parse(lynx).unwrap();
+        let mut image = DiskImage::new(
+            DiskImageFormat::D64,
+            &PetsciiString::from_ascii_str("disk"),
+            &PetsciiString::from_ascii_str("01"),
+        );
+        archive
+            .extract_to_image(&mut image, &archive.entries)
+            .unwrap();
+
+        let entry = image
+            .find_file(&PetsciiString::from_petscii_bytes(b"RECORDS"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.file_type, CbmFileType::REL);
+        assert_eq!(entry.record_len, 50);
+        assert_eq!(image.read_file(&entry).unwrap(), rel);
+    }
+}
@@ -0,0 +1,298 @@
+//! Contains [`AsyncCbm`], an async interface to [`Cbm`], available with the
+//! `tokio` feature
+//!
+//! [`Cbm`] methods block the calling thread until the bus operation has
+//! finished, which can take seconds or minutes.  [`AsyncCbm`] instead
+//! queues each call to a dedicated worker thread which owns the bus, so
+//! calls are serialized in the order they're made, and no runtime threads
+//! are blocked.
+//!
+//! Calls can be cancelled by dropping the returned future, and can be given
+//! a timeout using [`AsyncCbm::with_timeout`].  A call which hasn't started
+//! when it's cancelled or times out is skipped.  One which has already
+//! started runs to completion in the background, as stopping part way
+//! through could leave the drive in an unknown state, and its result is
+//! discarded.
+//!
+//! # Example
+//! ```ignore
+//! let cbm = AsyncCbm::new(Cbm::new(None, None)?)?;
+//! let status = cbm.get_status(8).await?;
+//! let dir = cbm.with_timeout(Duration::from_secs(10)).dir(8, None).await?;
+//! ```
+
+use crate::cbm::Cbm;
+use crate::disk::{CbmDirListing, CbmFileType};
+use crate::error::Error;
+use crate::string::{AsciiString, PetsciiString};
+use crate::{CbmDeviceInfo, CbmStatus};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use tokio::sync::oneshot;
+
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+// A call queued to a worker, which is passed the worker's state
+type Job<S> = Box<dyn FnOnce(&S) + Send>;
+
+// Runs jobs against some state on a dedicated thread.  The thread exits once
+// all of the senders have been dropped.
+#[derive(Debug)]
+struct Worker<S> {
+    jobs: mpsc::Sender<Job<S>>,
+}
+
+impl<S> Clone for Worker<S> {
+    fn clone(&self) -> Self {
+        Self {
+            jobs: self.jobs.clone(),
+        }
+    }
+}
+
+impl<S: Send + 'static> Worker<S> {
+    fn spawn(state: S) -> Result<Self, Error> {
+        let (jobs, queue) = mpsc::channel::<Job<S>>();
+        thread::Builder::new()
+            .name("rs1541-bus".to_string())
+            .spawn(move || {
+                for job in queue {
+                    job(&state);
+                }
+                debug!("Bus worker exiting");
+            })
+            .map_err(|e| Error::Io {
+                message: format!("Failed to start bus worker: {e}"),
+            })?;
+        Ok(Self { jobs })
+    }
+
+    async fn run<T, F>(&self, timeout: Option<Duration>, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&S) -> Result<T, Error> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job<S> = Box::new(move |state| {
+            // Skip calls which were cancelled or timed out while queued
+            if tx.is_closed() {
+                trace!("Skipping cancelled call");
+                return;
+            }
+            let _ = tx.send(f(state));
+        });
+        self.jobs.send(job).map_err(|_| worker_stopped())?;
+
+        let result = match timeout {
+            Some(dur) => tokio::time::timeout(dur, rx)
+                .await
+                .map_err(|_| Error::Timeout { dur })?,
+            None => rx.await,
+        };
+        result.map_err(|_| worker_stopped())?
+    }
+}
+
+// Returned if the worker thread has gone, which only happens if a call
+// panicked
+fn worker_stopped() -> Error {
+    Error::Io {
+        message: "Bus worker has stopped".to_string(),
+    }
+}
+
+/// Async interface to a [`Cbm`], which serializes calls through a worker
+/// thread.  See the [module documentation](self).
+///
+/// Cloning an [`AsyncCbm`] shares the same worker, so calls made through
+/// any of the clones are queued together.
+#[derive(Debug, Clone)]
+pub struct AsyncCbm {
+    worker: Worker<Cbm>,
+    timeout: Option<Duration>,
+}
+
+impl AsyncCbm {
+    /// Creates an [`AsyncCbm`], starting a worker thread which takes
+    /// ownership of the [`Cbm`].  Calls have no timeout.
+    ///
+    /// As [`Cbm`] is a handle to a shared bus, a clone can be kept for
+    /// making blocking calls as well - these are serialized with the async
+    /// calls by the bus lock.
+    pub fn new(cbm: Cbm) -> Result<Self, Error> {
+        Ok(Self {
+            worker: Worker::spawn(cbm)?,
+            timeout: None,
+        })
+    }
+
+    /// Returns a handle sharing this worker, whose calls time out after the
+    /// given duration, including any time spent queued behind other calls.
+    /// Timed out calls return [`Error::Timeout`].
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Self {
+            worker: self.worker.clone(),
+            timeout: Some(timeout),
+        }
+    }
+
+    /// Returns the timeout applied to calls, if any
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Runs an arbitrary function against the [`Cbm`] on the worker, for
+    /// operations not otherwise provided by [`AsyncCbm`]
+    ///
+    /// # Example
+    /// ```ignore
+    /// let data = cbm.run(|cbm| cbm.read_sector(8, 18, 0)).await?;
+    /// ```
+    pub async fn run<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Cbm) -> Result<T, Error> + Send + 'static,
+    {
+        self.worker.run(self.timeout, f).await
+    }
+
+    /// See [`Cbm::identify`]
+    pub async fn identify(&self, device: u8) -> Result<CbmDeviceInfo, Error> {
+        self.run(move |cbm| cbm.identify(device)).await
+    }
+
+    /// See [`Cbm::get_status`]
+    pub async fn get_status(&self, device: u8) -> Result<CbmStatus, Error> {
+        self.run(move |cbm| cbm.get_status(device)).await
+    }
+
+    /// See [`Cbm::scan_bus`]
+    pub async fn scan_bus(&self) -> Result<HashMap<u8, CbmDeviceInfo>, Error> {
+        self.run(|cbm| cbm.scan_bus()).await
+    }
+
+    /// See [`Cbm::dir`]
+    pub async fn dir(&self, device: u8, drive_num: Option<u8>) -> Result<CbmDirListing, Error> {
+        self.run(move |cbm| cbm.dir(device, drive_num)).await
+    }
+
+    /// See [`Cbm::read_file`]
+    pub async fn read_file(&self, device: u8, filename: &AsciiString) -> Result<Vec<u8>, Error> {
+        let filename = filename.clone();
+        self.run(move |cbm| cbm.read_file(device, &filename)).await
+    }
+
+    /// See [`Cbm::write_file`]
+    pub async fn write_file(
+        &self,
+        device: u8,
+        filename: &AsciiString,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let filename = filename.clone();
+        let data = data.to_vec();
+        self.run(move |cbm| cbm.write_file(device, &filename, &data))
+            .await
+    }
+
+    /// See [`Cbm::delete_file`]
+    pub async fn delete_file(&self, device: u8, filename: &AsciiString) -> Result<(), Error> {
+        let filename = filename.clone();
+        self.run(move |cbm| cbm.delete_file(device, &filename))
+            .await
+    }
+
+    /// See [`Cbm::load_file_petscii`]
+    pub async fn load_file_petscii(
+        &self,
+        device: u8,
+        filename: &PetsciiString,
+    ) -> Result<Vec<u8>, Error> {
+        let filename = filename.clone();
+        self.run(move |cbm| cbm.load_file_petscii(device, &filename))
+            .await
+    }
+
+    /// See [`Cbm::read_file_petscii`]
+    pub async fn read_file_petscii(
+        &self,
+        device: u8,
+        filename: &PetsciiString,
+        file_type: CbmFileType,
+    ) -> Result<Vec<u8>, Error> {
+        let filename = filename.clone();
+        self.run(move |cbm| cbm.read_file_petscii(device, &filename, file_type))
+            .await
+    }
+
+    /// See [`Cbm::save_file_petscii`]
+    pub async fn save_file_petscii(
+        &self,
+        device: u8,
+        filename: &PetsciiString,
+        file_type: CbmFileType,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let filename = filename.clone();
+        let data = data.to_vec();
+        self.run(move |cbm| cbm.save_file_petscii(device, &filename, file_type, &data))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_worker_serializes_calls() {
+        let worker = Worker::spawn(AtomicU32::new(0)).unwrap();
+        let tasks: Vec<_> = (0..10)
+            .map(|_| {
+                let worker = worker.clone();
+                tokio::spawn(async move {
+                    worker
+                        .run(None, |count| Ok(count.fetch_add(1, Ordering::SeqCst)))
+                        .await
+                })
+            })
+            .collect();
+        let mut results = Vec::new();
+        for task in tasks {
+            results.push(task.await.unwrap().unwrap());
+        }
+        results.sort();
+        assert_eq!(results, (0..10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_worker_timeout_skips_queued_call() {
+        let ran = Arc::new(AtomicU32::new(0));
+        let worker = Worker::spawn(()).unwrap();
+
+        // Occupy the worker, so the next call is still queued when it
+        // times out
+        let busy = worker.run(None, |_| {
+            thread::sleep(Duration::from_millis(200));
+            Ok(())
+        });
+        let ran_clone = Arc::clone(&ran);
+        let queued = worker.run(Some(Duration::from_millis(50)), move |_| {
+            ran_clone.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        let (busy, queued) = tokio::join!(busy, queued);
+        assert!(busy.is_ok());
+        assert!(matches!(queued, Err(Error::Timeout { .. })));
+
+        // Once the worker has caught up, the timed out call was skipped
+        worker.run(None, |_| Ok(())).await.unwrap();
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,473 @@
+//! Contains types and functions for examining and editing the Block
+//! Availability Map (BAM)
+//!
+//! For each track the BAM holds a count of free sectors and a bitmap, with a
+//! bit set for each free sector.  The layout depends on the disk format:
+//!
+//! - 1541 (D64) - 4 byte entries from 0x04 in 18/0, a count followed by 3
+//!   bitmap bytes.  40 track disks store tracks 36-40 from 0xC0, as SpeedDOS
+//!   does.
+//! - 1571 (D71) - tracks 1-35 as the 1541.  The counts for tracks 36-70 are
+//!   from 0xDD in 18/0, with their 3 byte bitmaps in 53/0.
+//! - 1581 (D81) - 6 byte entries from 0x10 in 40/1 (tracks 1-40) and 40/2
+//!   (tracks 41-80), a count followed by 5 bitmap bytes.
+//!
+//! [`Bam`] decodes the whole BAM from a disk image or physical disk, checks
+//! the counts against the bitmaps, and writes changes back - directly to an
+//! image, or using the `B-A` and `B-F` commands on a drive, followed by
+//! writing the updated BAM to the disk.
+
+use crate::block::{BlockDevice, DriveBlocks};
+use crate::cbm::Cbm;
+use crate::error::Error;
+use crate::image::{DiskImageFormat, SECTOR_SIZE};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::collections::HashMap;
+use std::fmt;
+
+// 1541 layout
+const D64_BAM_ENTRIES: usize = 0x04;
+const D64_BAM_ENTRIES_EXT: usize = 0xc0;
+const D64_ENTRY_SIZE: usize = 4;
+
+// 1571 layout for the second side
+const D71_SIDE2_COUNTS: usize = 0xdd;
+const D71_SIDE2_BAM_TRACK: u8 = 53;
+
+// 1581 layout
+const D81_BAM_ENTRIES: usize = 0x10;
+const D81_ENTRY_SIZE: usize = 6;
+const D81_TRACKS_PER_BAM_SECTOR: u8 = 40;
+
+/// Where a track's entry is stored in the BAM
+///
+/// # Fields
+///
+/// * `count` - Track, sector and offset of the free sector count
+/// * `bitmap` - Track, sector and offset of the bitmap
+/// * `bitmap_len` - Length of the bitmap in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BamLocation {
+    pub count: (u8, u8, usize),
+    pub bitmap: (u8, u8, usize),
+    pub bitmap_len: usize,
+}
+
+impl BamLocation {
+    /// Returns where the given track's entry is stored, for a valid track
+    pub fn new(format: DiskImageFormat, track: u8) -> Self {
+        let dir_track = format.dir_track();
+        match format {
+            DiskImageFormat::D81 => {
+                let sector = 1 + (track - 1) / D81_TRACKS_PER_BAM_SECTOR;
+                let offset = D81_BAM_ENTRIES
+                    + ((track - 1) % D81_TRACKS_PER_BAM_SECTOR) as usize * D81_ENTRY_SIZE;
+                BamLocation {
+                    count: (dir_track, sector, offset),
+                    bitmap: (dir_track, sector, offset + 1),
+                    bitmap_len: D81_ENTRY_SIZE - 1,
+                }
+            }
+            DiskImageFormat::D71 if track > 35 => BamLocation {
+                count: (dir_track, 0, D71_SIDE2_COUNTS + (track - 36) as usize),
+                bitmap: (
+                    D71_SIDE2_BAM_TRACK,
+                    0,
+                    (track - 36) as usize * (D64_ENTRY_SIZE - 1),
+                ),
+                bitmap_len: D64_ENTRY_SIZE - 1,
+            },
+            _ => {
+                let offset = if track <= 35 {
+                    D64_BAM_ENTRIES + (track - 1) as usize * D64_ENTRY_SIZE
+                } else {
+                    D64_BAM_ENTRIES_EXT + (track - 36) as usize * D64_ENTRY_SIZE
+                };
+                BamLocation {
+                    count: (dir_track, 0, offset),
+                    bitmap: (dir_track, 0, offset + 1),
+                    bitmap_len: D64_ENTRY_SIZE - 1,
+                }
+            }
+        }
+    }
+}
+
+/// The BAM entry for a single track
+///
+/// # Fields
+///
+/// * `free_count` - The number of free sectors, as stored in the BAM
+/// * `bitmap` - The bitmap, as stored in the BAM.  Bit n of byte m is set if
+///   sector m*8+n is free.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BamTrack {
+    pub free_count: u8,
+    pub bitmap: Vec<u8>,
+}
+
+impl BamTrack {
+    /// Whether the bitmap shows the sector as free
+    pub fn is_free(&self, sector: u8) -> bool {
+        self.bitmap
+            .get(sector as usize / 8)
+            .is_some_and(|byte| byte & (1 << (sector % 8)) != 0)
+    }
+
+    /// Number of free sectors according to the bitmap
+    pub fn bitmap_free_count(&self) -> u8 {
+        self.bitmap.iter().map(|byte| byte.count_ones() as u8).sum()
+    }
+}
+
+/// A problem found by [`Bam::check`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BamProblem {
+    /// The free count doesn't match the number of free sectors in the
+    /// bitmap
+    CountMismatch {
+        track: u8,
+        count: u8,
+        bitmap_count: u8,
+    },
+    /// The bitmap marks a sector beyond the end of the track as free
+    InvalidSectorFree { track: u8, sector: u8 },
+}
+
+impl fmt::Display for BamProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BamProblem::CountMismatch {
+                track,
+                count,
+                bitmap_count,
+            } => write!(
+                f,
+                "Track {track} free count is {count}, bitmap has {bitmap_count} free sectors"
+            ),
+            BamProblem::InvalidSectorFree { track, sector } => {
+                write!(f, "Track {track} marks non-existent sector {sector} free")
+            }
+        }
+    }
+}
+
+/// A decoded Block Availability Map
+///
+/// # Example
+/// ```ignore
+/// let drive = DriveBlocks::new(&cbm, 8, DiskImageFormat::D64);
+/// let mut bam = Bam::read(&drive)?;
+/// for problem in bam.check() {
+///     println!("{problem}");
+/// }
+/// bam.set_free(17, 0, false)?;
+/// bam.apply_to_drive(&cbm, 8)?;
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bam {
+    format: DiskImageFormat,
+    tracks: Vec<BamTrack>,
+}
+
+impl Bam {
+    /// Reads the BAM from a disk image or physical disk
+    pub fn read<D: BlockDevice + ?Sized>(disk: &D) -> Result<Self, Error> {
+        let format = disk.format();
+        let sectors = format
+            .bam_sectors()
+            .into_iter()
+            .map(|(track, sector)| Ok(((track, sector), disk.read_block(track, sector)?)))
+            .collect::<Result<HashMap<_, _>, Error>>()?;
+
+        let tracks = (1..=format.num_tracks())
+            .map(|track| {
+                let location = BamLocation::new(format, track);
+                let (t, s, offset) = location.count;
+                let free_count = sectors[&(t, s)][offset];
+                let (t, s, offset) = location.bitmap;
+                let bitmap = sectors[&(t, s)][offset..offset + location.bitmap_len].to_vec();
+                BamTrack { free_count, bitmap }
+            })
+            .collect();
+
+        Ok(Bam { format, tracks })
+    }
+
+    /// Writes the BAM to a disk image or physical disk, leaving the rest of
+    /// the BAM sectors unchanged.
+    ///
+    /// When writing to a physical disk this bypasses DOS, so the drive must
+    /// be initialized afterwards (see [`DriveBlocks::initialize`]) to pick
+    /// up the changes.  Prefer
+    /// [`Bam::apply_to_drive`] unless the free counts need to be fixed.
+    pub fn write<D: BlockDevice + ?Sized>(&self, disk: &mut D) -> Result<(), Error> {
+        if disk.format() != self.format {
+            return Err(Error::Validation {
+                message: format!("Can't write {} BAM to {} disk", self.format, disk.format()),
+            });
+        }
+
+        let mut sectors = self
+            .format
+            .bam_sectors()
+            .into_iter()
+            .map(|(track, sector)| Ok(((track, sector), disk.read_block(track, sector)?)))
+            .collect::<Result<HashMap<_, _>, Error>>()?;
+
+        for (ii, entry) in self.tracks.iter().enumerate() {
+            let location = BamLocation::new(self.format, ii as u8 + 1);
+            let (t, s, offset) = location.count;
+            sectors.get_mut(&(t, s)).unwrap()[offset] = entry.free_count;
+            let (t, s, offset) = location.bitmap;
+            sectors.get_mut(&(t, s)).unwrap()[offset..offset + location.bitmap_len]
+                .copy_from_slice(&entry.bitmap);
+        }
+
+        for ((track, sector), block) in sectors {
+            debug_assert_eq!(block.len(), SECTOR_SIZE);
+            disk.write_block(track, sector, &block)?;
+        }
+        Ok(())
+    }
+
+    /// Updates the BAM on the disk in a drive to match this one, using
+    /// `B-A` and `B-F` commands for each sector whose state differs.
+    /// Returns the number of sectors changed.
+    ///
+    /// `B-A` and `B-F` only change the drive's copy of the BAM in memory,
+    /// which DOS wouldn't write back until a file is next closed, so the
+    /// changes would be lost on a reset or disk change.  Once they have
+    /// been made, the updated BAM is written to disk and the drive is
+    /// initialized, so the changes are saved before this returns.  The
+    /// track free counts are adjusted as DOS adjusts them, rather than
+    /// copied from this BAM - use [`Bam::write`] to fix those.
+    pub fn apply_to_drive(&self, cbm: &Cbm, device: u8) -> Result<usize, Error> {
+        let mut drive = DriveBlocks::new(cbm, device, self.format);
+
+        // Start from the BAM on disk, discarding any unsaved changes in the
+        // drive's memory
+        drive.initialize()?;
+        let current = Bam::read(&drive)?;
+        let changes = current.diff(self);
+        if changes.is_empty() {
+            return Ok(0);
+        }
+
+        let mut updated = current;
+        for (track, sector, free) in changes.iter().copied() {
+            if free {
+                cbm.free_block(device, track, sector)?;
+            } else {
+                cbm.allocate_block(device, track, sector)?;
+            }
+            updated.set_free(track, sector, free)?;
+        }
+
+        debug!(
+            "Writing BAM with {} changes to device {device}",
+            changes.len()
+        );
+        updated.write(&mut drive)?;
+        drive.initialize()?;
+        Ok(changes.len())
+    }
+
+    /// The format of the disk this BAM is from
+    pub fn format(&self) -> DiskImageFormat {
+        self.format
+    }
+
+    /// Returns the entry for a track
+    pub fn track(&self, track: u8) -> Option<&BamTrack> {
+        self.tracks.get((track as usize).checked_sub(1)?)
+    }
+
+    /// Whether the given sector is marked as free
+    pub fn is_free(&self, track: u8, sector: u8) -> bool {
+        sector < self.format.sectors_per_track(track)
+            && self.track(track).is_some_and(|entry| entry.is_free(sector))
+    }
+
+    /// Marks a sector as free or allocated.  As DOS does, the track's free
+    /// count is adjusted if the sector's state changes.
+    pub fn set_free(&mut self, track: u8, sector: u8, free: bool) -> Result<(), Error> {
+        if sector >= self.format.sectors_per_track(track) {
+            return Err(Error::Validation {
+                message: format!("Invalid track/sector {track}/{sector} for {}", self.format),
+            });
+        }
+        if self.is_free(track, sector) == free {
+            return Ok(());
+        }
+
+        let entry = &mut self.tracks[track as usize - 1];
+        let mask = 1 << (sector % 8);
+        if free {
+            entry.bitmap[sector as usize / 8] |= mask;
+            entry.free_count = entry.free_count.wrapping_add(1);
+        } else {
+            entry.bitmap[sector as usize / 8] &= !mask;
+            entry.free_count = entry.free_count.wrapping_sub(1);
+        }
+        Ok(())
+    }
+
+    /// Number of free blocks as reported by DOS - the sum of the free counts,
+    /// excluding the directory track(s)
+    pub fn blocks_free(&self) -> u16 {
+        (1..=self.format.num_tracks())
+            .filter(|&track| !self.format.is_system_track(track))
+            .map(|track| self.tracks[track as usize - 1].free_count as u16)
+            .sum()
+    }
+
+    /// Checks the free counts against the bitmaps, and that no sectors
+    /// beyond the end of a track are marked free
+    pub fn check(&self) -> Vec<BamProblem> {
+        let mut problems = Vec::new();
+        for (ii, entry) in self.tracks.iter().enumerate() {
+            let track = ii as u8 + 1;
+            let sectors = self.format.sectors_per_track(track);
+            let invalid = (sectors..(entry.bitmap.len() * 8) as u8)
+                .filter(|&sector| entry.is_free(sector))
+                .map(|sector| BamProblem::InvalidSectorFree { track, sector });
+            problems.extend(invalid);
+
+            let bitmap_count = (0..sectors).filter(|&s| entry.is_free(s)).count() as u8;
+            if entry.free_count != bitmap_count {
+                problems.push(BamProblem::CountMismatch {
+                    track,
+                    count: entry.free_count,
+                    bitmap_count,
+                });
+            }
+        }
+        problems
+    }
+
+    /// Fixes any problems found by [`Bam::check`], treating the bitmap of
+    /// valid sectors as correct.  Returns the number of tracks changed.
+    pub fn fix(&mut self) -> usize {
+        let mut fixed = 0;
+        for (ii, entry) in self.tracks.iter_mut().enumerate() {
+            let sectors = self.format.sectors_per_track(ii as u8 + 1);
+            let original = entry.clone();
+            for sector in sectors..(entry.bitmap.len() * 8) as u8 {
+                entry.bitmap[sector as usize / 8] &= !(1 << (sector % 8));
+            }
+            entry.free_count = entry.bitmap_free_count();
+            if *entry != original {
+                fixed += 1;
+            }
+        }
+        fixed
+    }
+
+    /// Returns the sectors whose free state differs between this BAM and
+    /// another, with their state in the other BAM
+    pub fn diff(&self, other: &Bam) -> Vec<(u8, u8, bool)> {
+        let mut changes = Vec::new();
+        for track in 1..=self.format.num_tracks() {
+            for sector in 0..self.format.sectors_per_track(track) {
+                let free = other.is_free(track, sector);
+                if self.is_free(track, sector) != free {
+                    changes.push((track, sector, free));
+                }
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::DiskImage;
+    use crate::string::PetsciiString;
+
+    fn new_image(format: DiskImageFormat) -> DiskImage {
+        DiskImage::new(
+            format,
+            &PetsciiString::from_ascii_str("bamtest"),
+            &PetsciiString::from_ascii_str("01"),
+        )
+    }
+
+    #[test]
+    fn test_locations() {
+        let location = BamLocation::new(DiskImageFormat::D64, 1);
+        assert_eq!(location.count, (18, 0, 0x04));
+        assert_eq!(location.bitmap, (18, 0, 0x05));
+        let location = BamLocation::new(DiskImageFormat::D64Ext, 36);
+        assert_eq!(location.count, (18, 0, 0xc0));
+        let location = BamLocation::new(DiskImageFormat::D71, 37);
+        assert_eq!(location.count, (18, 0, 0xde));
+        assert_eq!(location.bitmap, (53, 0, 3));
+        let location = BamLocation::new(DiskImageFormat::D81, 41);
+        assert_eq!(location.count, (40, 2, 0x10));
+        assert_eq!(location.bitmap_len, 5);
+    }
+
+    #[test]
+    fn test_read_all_formats() {
+        for (format, free) in [
+            (DiskImageFormat::D64, 664),
+            (DiskImageFormat::D64Ext, 749),
+            (DiskImageFormat::D71, 1328),
+            (DiskImageFormat::D81, 3160),
+        ] {
+            let image = new_image(format);
+            let bam = Bam::read(&image).unwrap();
+            assert_eq!(bam.blocks_free(), free, "{format}");
+            assert_eq!(image.blocks_free(), free, "{format}");
+            assert!(bam.check().is_empty(), "{format}");
+        }
+    }
+
+    #[test]
+    fn test_check_and_fix() {
+        let mut image = new_image(DiskImageFormat::D64);
+        let mut bam = Bam::read(&image).unwrap();
+        bam.tracks[0].free_count = 5;
+        bam.tracks[17].bitmap[2] |= 0x80;
+        assert_eq!(
+            bam.check(),
+            vec![
+                BamProblem::CountMismatch {
+                    track: 1,
+                    count: 5,
+                    bitmap_count: 21
+                },
+                BamProblem::InvalidSectorFree {
+                    track: 18,
+                    sector: 23
+                },
+            ]
+        );
+
+        bam.write(&mut image).unwrap();
+        let mut bam = Bam::read(&image).unwrap();
+        assert_eq!(bam.check().len(), 2);
+        assert_eq!(bam.fix(), 2);
+        assert!(bam.check().is_empty());
+    }
+
+    #[test]
+    fn test_set_free() {
+        let mut image = new_image(DiskImageFormat::D71);
+        let mut bam = Bam::read(&image).unwrap();
+        let original = bam.clone();
+        bam.set_free(40, 3, false).unwrap();
+        assert!(!bam.is_free(40, 3));
+        assert_eq!(bam.track(40).unwrap().free_count, 20);
+        assert!(bam.set_free(40, 21, false).is_err());
+        assert_eq!(original.diff(&bam), vec![(40, 3, false)]);
+
+        bam.write(&mut image).unwrap();
+        assert!(!image.is_sector_free(40, 3));
+        assert_eq!(image.blocks_free(), 1327);
+    }
+}
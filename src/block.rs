@@ -0,0 +1,277 @@
+//! Contains types and functions for block (sector) level access to disks
+//!
+//! [`BlockDevice`] is implemented both for [`DiskImage`] and for physical
+//! disks in a drive ([`DriveBlocks`]), so functions which work with raw
+//! sectors - following sector chains, reading the raw directory - work with
+//! either.
+
+use crate::cbm::Cbm;
+use crate::error::Error;
+use crate::image::{CbmDirEntry, DiskImage, DiskImageFormat, DIR_ENTRY_SIZE};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::collections::HashSet;
+
+/// Sector level access to a disk
+///
+/// Tracks start at 1 and sectors at 0.  Sectors are always
+/// [`crate::image::SECTOR_SIZE`] bytes, including the 2 link bytes.
+pub trait BlockDevice {
+    /// The layout of the disk
+    fn format(&self) -> DiskImageFormat;
+
+    /// Reads a sector
+    fn read_block(&self, track: u8, sector: u8) -> Result<Vec<u8>, Error>;
+
+    /// Writes a sector.  The BAM is not updated.
+    fn write_block(&mut self, track: u8, sector: u8, data: &[u8]) -> Result<(), Error>;
+}
+
+impl BlockDevice for DiskImage {
+    fn format(&self) -> DiskImageFormat {
+        DiskImage::format(self)
+    }
+
+    fn read_block(&self, track: u8, sector: u8) -> Result<Vec<u8>, Error> {
+        self.read_sector(track, sector).map(|block| block.to_vec())
+    }
+
+    fn write_block(&mut self, track: u8, sector: u8, data: &[u8]) -> Result<(), Error> {
+        self.write_sector(track, sector, data)
+    }
+}
+
+/// Block level access to the disk in a physical drive, using the U1 and U2
+/// commands
+///
+/// # Example
+/// ```ignore
+/// let drive = DriveBlocks::new(&cbm, 8, DiskImageFormat::D64);
+/// for entry in dir_slots(&drive)?.iter().filter(|e| !e.is_empty()) {
+///     println!("{} starts at {}/{}", entry.filename_ascii(), entry.track, entry.sector);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct DriveBlocks {
+    cbm: Cbm,
+    device: u8,
+    format: DiskImageFormat,
+}
+
+impl DriveBlocks {
+    /// Creates a new object for accessing the disk in the given device,
+    /// which must have the given layout
+    pub fn new(cbm: &Cbm, device: u8, format: DiskImageFormat) -> Self {
+        DriveBlocks {
+            cbm: cbm.clone(),
+            device,
+            format,
+        }
+    }
+
+    /// The device number
+    pub fn device(&self) -> u8 {
+        self.device
+    }
+
+    /// Makes changes to the disk's filesystem using [`DiskImage`]'s
+    /// functions, such as [`DiskImage::write_file`].
+    ///
+    /// The directory track and BAM are read into a scratch image, which `f`
+    /// is then run against.  Afterwards every sector which `f` changed, or
+    /// which it allocated in the BAM, is written back to the disk - with the
+    /// BAM last - and the drive is told to re-initialize so it picks up the
+    /// new BAM.
+    ///
+    /// As only the directory track is read from the disk, `f` must not
+    /// read sectors on other tracks.
+    pub fn update_filesystem<T, F>(&mut self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut DiskImage) -> Result<T, Error>,
+    {
+        // Make sure the BAM on disk is current
+        self.initialize()?;
+
+        let dir_track = self.format.dir_track();
+        let mut image = DiskImage::from_bytes(vec![0u8; self.format.image_size()])?;
+        let bam_sectors = self.format.bam_sectors();
+        let mut sectors: Vec<(u8, u8)> = (0..self.format.sectors_per_track(dir_track))
+            .map(|sector| (dir_track, sector))
+            .collect();
+        sectors.extend(bam_sectors.iter().filter(|(track, _)| *track != dir_track));
+        for (track, sector) in sectors {
+            let block = self.read_block(track, sector)?;
+            image.write_sector(track, sector, &block)?;
+        }
+        let original = image.clone();
+
+        let result = f(&mut image)?;
+
+        for track in 1..=self.format.num_tracks() {
+            for sector in 0..self.format.sectors_per_track(track) {
+                if bam_sectors.contains(&(track, sector)) {
+                    continue;
+                }
+                let changed =
+                    image.read_sector(track, sector)? != original.read_sector(track, sector)?;
+                let allocated =
+                    original.is_sector_free(track, sector) && !image.is_sector_free(track, sector);
+                if changed || allocated {
+                    debug!("Writing updated sector {track}/{sector}");
+                    let block = image.read_sector(track, sector)?.to_vec();
+                    self.write_block(track, sector, &block)?;
+                }
+            }
+        }
+
+        for (track, sector) in bam_sectors {
+            let bam = image.read_sector(track, sector)?;
+            if bam != original.read_sector(track, sector)? {
+                debug!("Writing updated BAM sector {track}/{sector}");
+                let bam = bam.to_vec();
+                self.write_block(track, sector, &bam)?;
+            }
+        }
+        self.initialize()?;
+
+        Ok(result)
+    }
+
+    /// Sends the I command, so the drive re-reads the BAM.  Required after
+    /// writing BAM sectors directly.
+    pub fn initialize(&self) -> Result<(), Error> {
+        self.cbm.send_string_command_ascii(self.device, "i")
+    }
+}
+
+impl BlockDevice for DriveBlocks {
+    fn format(&self) -> DiskImageFormat {
+        self.format
+    }
+
+    fn read_block(&self, track: u8, sector: u8) -> Result<Vec<u8>, Error> {
+        self.cbm.read_sector(self.device, track, sector)
+    }
+
+    fn write_block(&mut self, track: u8, sector: u8, data: &[u8]) -> Result<(), Error> {
+        self.cbm.write_sector(self.device, track, sector, data)
+    }
+}
+
+/// Returns the sectors in the chain starting at the given track and sector
+pub fn chain_sectors<D: BlockDevice + ?Sized>(
+    disk: &D,
+    track: u8,
+    sector: u8,
+) -> Result<Vec<(u8, u8)>, Error> {
+    let mut sectors = Vec::new();
+    let mut visited = HashSet::new();
+    let (mut track, mut sector) = (track, sector);
+    loop {
+        if !visited.insert((track, sector)) {
+            return Err(Error::Parse {
+                message: format!("Sector chain loops at {track}/{sector}"),
+            });
+        }
+        sectors.push((track, sector));
+        let block = disk.read_block(track, sector)?;
+        if block[0] == 0 {
+            break Ok(sectors);
+        }
+        (track, sector) = (block[0], block[1]);
+    }
+}
+
+/// Follows a chain of sectors from the given track and sector, returning
+/// the data they contain
+pub fn read_chain<D: BlockDevice + ?Sized>(
+    disk: &D,
+    track: u8,
+    sector: u8,
+) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    let mut visited = HashSet::new();
+    let (mut track, mut sector) = (track, sector);
+    loop {
+        if !visited.insert((track, sector)) {
+            return Err(Error::Parse {
+                message: format!("Sector chain loops at {track}/{sector}"),
+            });
+        }
+        let block = disk.read_block(track, sector)?;
+        if block[0] == 0 {
+            let last = (block[1] as usize).max(1);
+            data.extend_from_slice(&block[2..=last]);
+            break Ok(data);
+        }
+        data.extend_from_slice(&block[2..]);
+        (track, sector) = (block[0], block[1]);
+    }
+}
+
+/// Returns the locations of the directory sectors
+pub fn dir_sectors<D: BlockDevice + ?Sized>(disk: &D) -> Result<Vec<(u8, u8)>, Error> {
+    let format = disk.format();
+    chain_sectors(disk, format.dir_track(), format.first_dir_sector()).map_err(|e| match e {
+        Error::Parse { message } => Error::Parse {
+            message: message.replace("Sector chain", "Directory chain"),
+        },
+        e => e,
+    })
+}
+
+/// Returns all directory slots, including empty ones and deleted files
+pub fn dir_slots<D: BlockDevice + ?Sized>(disk: &D) -> Result<Vec<CbmDirEntry>, Error> {
+    let mut entries = Vec::new();
+    for (track, sector) in dir_sectors(disk)? {
+        let block = disk.read_block(track, sector)?;
+        for (index, raw) in block.chunks(DIR_ENTRY_SIZE).enumerate() {
+            entries.push(CbmDirEntry::from_raw(raw, track, sector, index as u8));
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::CbmFileType;
+    use crate::string::PetsciiString;
+
+    #[test]
+    fn test_chain_on_image() {
+        let mut image = DiskImage::new(
+            DiskImageFormat::D64,
+            &PetsciiString::from_ascii_str("disk"),
+            &PetsciiString::from_ascii_str("01"),
+        );
+        let data = vec![0x42; 600];
+        let entry = image
+            .write_file(
+                &PetsciiString::from_ascii_str("file"),
+                CbmFileType::SEQ,
+                &data,
+            )
+            .unwrap();
+
+        let sectors = chain_sectors(&image, entry.track, entry.sector).unwrap();
+        assert_eq!(sectors.len(), 3);
+        assert_eq!(read_chain(&image, entry.track, entry.sector).unwrap(), data);
+
+        let slots = dir_slots(&image).unwrap();
+        assert_eq!(slots.len(), 8);
+        assert_eq!(slots[0], entry);
+    }
+
+    #[test]
+    fn test_chain_loop() {
+        let mut image =
+            DiskImage::from_bytes(vec![0u8; DiskImageFormat::D64.image_size()]).unwrap();
+        let mut block = vec![0u8; 256];
+        block[0] = 1;
+        block[1] = 0;
+        image.write_block(1, 0, &block).unwrap();
+        assert!(chain_sectors(&image, 1, 0).is_err());
+    }
+}
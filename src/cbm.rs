@@ -107,13 +107,20 @@
 //! - Drive/DOS commands are limited to standard CBM DOS operations
 //!
 use crate::channel::{CBM_CHANNEL_CTRL, CBM_CHANNEL_LOAD};
+use crate::context::{abandon_file, FileCleanup, OpContext, ProgressUnit};
+use crate::copy::{copy_direct, DirectCopyBus, DirectCopyError};
 use crate::disk::BYTES_PER_BLOCK;
+use crate::image::SECTOR_SIZE;
+use crate::lock::AdapterLock;
+use crate::reconnect::{ReconnectEvent, ReconnectHandler, ReconnectPolicy};
 use crate::string::{AsciiString, PetsciiString};
+use crate::timeout::{TimedBus, Timeouts};
+use crate::transfer::TransferMode;
 use crate::validate::{validate_device, DeviceValidation};
 use crate::Xum1541DeviceInfo;
 use crate::{
-    BusGuardMut, BusGuardRef, CbmDeviceInfo, CbmDirListing, CbmErrorNumberOk, CbmStatus, CbmString,
-    DeviceError, Error,
+    CbmDeviceInfo, CbmDirListing, CbmErrorNumberOk, CbmFileType, CbmStatus, CbmString, DeviceError,
+    DosVersion, Error,
 };
 use crate::{DEVICE_MAX_NUM, DEVICE_MIN_NUM};
 
@@ -126,7 +133,23 @@ use xum1541::{Bus, BusBuilder, BusRecoveryType, CommunicationError, DeviceChanne
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Maximum number of bytes read by a single DOS 2 M-R command
+pub const MEMORY_READ_MAX: usize = 255;
+
+/// Maximum number of bytes written by a single M-W command.  The drive's
+/// command buffer must hold the M-W command, address and count as well as
+/// the data.
+pub const MEMORY_WRITE_MAX: usize = 32;
+
+// How often to try reading a drive routine's results once it should have
+// finished, and how long to keep trying
+const ROUTINE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const ROUTINE_POLL_TIMEOUT: Duration = Duration::from_secs(3);
 
 /// The main interface for interacting with Commodore disk drives via an XUM1541.
 ///
@@ -157,13 +180,34 @@ use std::sync::Arc;
 pub struct Cbm {
     config: CbmConfig,
     handle: Arc<Mutex<Option<Bus>>>,
+    _lock: Option<Arc<AdapterLock>>,
 }
 
 #[derive(Debug, Clone)]
-struct CbmConfig {
-    serial: Option<u8>,
-    remote: Option<SocketAddr>,
+pub(crate) struct CbmConfig {
+    pub(crate) serial: Option<u8>,
+    pub(crate) remote: Option<SocketAddr>,
     recovery_type: BusRecoveryType,
+    transfer_mode: TransferMode,
+    pub(crate) timeouts: Timeouts,
+    pub(crate) reconnect: Option<ReconnectPolicy>,
+    on_reconnect: Option<ReconnectHandler>,
+    generation: Arc<AtomicU64>,
+}
+
+impl CbmConfig {
+    fn new(serial: Option<u8>, remote: Option<SocketAddr>) -> Self {
+        CbmConfig {
+            serial,
+            remote,
+            recovery_type: BusRecoveryType::Off,
+            transfer_mode: TransferMode::Standard,
+            timeouts: Timeouts::default(),
+            reconnect: None,
+            on_reconnect: None,
+            generation: Arc::new(AtomicU64::new(0)),
+        }
+    }
 }
 
 /// Functions to manage this and the Bus object
@@ -183,7 +227,8 @@ impl Cbm {
     /// Returns `Error` if:
     /// - The driver cannot be opened
     /// - No XUM1541 device is connected
-    /// - The device is in use by another process
+    /// - The device is in use by another process - [`Error::AdapterInUse`]
+    ///   if that process is using rs1541, see [`crate::lock`]
     ///
     /// # Example
     ///
@@ -194,20 +239,105 @@ impl Cbm {
         trace!("Cbm::new");
 
         // Create the bus and initialize it
-        let config = CbmConfig {
-            serial,
-            remote,
-            recovery_type: BusRecoveryType::Off,
+        let config = CbmConfig::new(serial, remote);
+
+        // Lock local adapters, so other processes get a clear error.  Locks
+        // are keyed on the serial number, so if none was given the adapter
+        // is opened first to find out which one we got.
+        let (config, lock, bus) = match (remote, serial) {
+            (Some(_), _) => {
+                let bus = Self::open_bus(&config)?;
+                (config, None, bus)
+            }
+            (None, Some(serial)) => {
+                let lock = Self::lock_adapter(serial)?;
+                let bus = Self::open_bus(&config)?;
+                (config, lock, bus)
+            }
+            (None, None) => {
+                let bus = Self::open_bus(&config).map_err(Self::in_use_error)?;
+                let reported = bus
+                    .device_info()
+                    .and_then(|info| info.serial_number)
+                    .and_then(|serial| serial.trim().parse::<u8>().ok());
+                match reported {
+                    Some(serial) => {
+                        let lock = Self::lock_adapter(serial)?;
+                        // Reopen this adapter, rather than whichever is
+                        // found first, after a USB reset or reconnection
+                        let config = CbmConfig {
+                            serial: Some(serial),
+                            ..config
+                        };
+                        (config, lock, bus)
+                    }
+                    None => {
+                        warn!("Continuing without xum1541 lock, as its serial number is unknown");
+                        (config, None, bus)
+                    }
+                }
+            }
         };
-        let mut bus = Self::new_bus(&config)?;
-        bus.initialize()?;
 
         Ok(Self {
             config,
             handle: Arc::new(Mutex::new(Some(bus))),
+            _lock: lock,
         })
     }
 
+    // Creates a Cbm whose xum1541 has been lost, as if it had been unplugged
+    // after being opened
+    #[cfg(test)]
+    pub(crate) fn disconnected(serial: Option<u8>, remote: Option<SocketAddr>) -> Self {
+        Self {
+            config: CbmConfig::new(serial, remote),
+            handle: Arc::new(Mutex::new(None)),
+            _lock: None,
+        }
+    }
+
+    // Creates and initializes the bus
+    fn open_bus(config: &CbmConfig) -> Result<Bus, Error> {
+        let mut bus = Self::new_bus(config)?;
+        bus.initialize()?;
+        Ok(bus)
+    }
+
+    // Takes the AdapterLock for this serial number.  Carries on without the
+    // lock if the lock file can't be created.
+    fn lock_adapter(serial: u8) -> Result<Option<Arc<AdapterLock>>, Error> {
+        match AdapterLock::acquire(serial) {
+            Ok(lock) => Ok(Some(Arc::new(lock))),
+            Err(e @ Error::AdapterInUse { .. }) => Err(e),
+            Err(e) => {
+                warn!("Continuing without xum1541 lock: {e}");
+                Ok(None)
+            }
+        }
+    }
+
+    // Replaces the error from opening the adapter with Error::AdapterInUse
+    // if it is likely to have failed because another rs1541 process has it
+    fn in_use_error(e: Error) -> Error {
+        if let Error::Xum1541(Xum1541Error::DeviceAccess {
+            kind: xum1541::DeviceAccessError::NoDevice,
+        }) = e
+        {
+            return e;
+        }
+        match AdapterLock::locked_adapters().first() {
+            Some((serial, pid)) => {
+                debug!("Failed to open xum1541, which is probably in use: {e}");
+                Error::AdapterInUse {
+                    serial: Some(*serial),
+                    pid: *pid,
+                }
+            }
+            None => e,
+        }
+    }
+
     // Helper function to create bus - this is done in both new() and
     // usb_device_reset
     fn new_bus(config: &CbmConfig) -> Result<Bus, Error> {
@@ -224,11 +354,68 @@ impl Cbm {
 
     pub fn set_bus_recovery_type(&mut self, recovery_type: BusRecoveryType) -> Result<(), Error> {
         self.config.recovery_type = recovery_type.clone();
-        self.handle
-            .lock()
-            .bus_mut_or_err()?
-            .set_recovery_type(recovery_type);
-        Ok(())
+        let mut guard = self.handle.lock();
+        TimedBus::new(&mut guard, &self.config)?.set_recovery_type(recovery_type)
+    }
+
+    /// Sets the [`TransferMode`] used by operations which support it, such
+    /// as [`Cbm::load_file_petscii`] and [`Cbm::read_disk_image`].  Each
+    /// also has a `_with` form taking the mode to use for that operation.
+    ///
+    /// Defaults to [`TransferMode::Standard`].
+    pub fn set_transfer_mode(&mut self, mode: TransferMode) {
+        self.config.transfer_mode = mode;
+    }
+
+    /// Returns the [`TransferMode`] set by [`Cbm::set_transfer_mode`]
+    pub fn transfer_mode(&self) -> TransferMode {
+        self.config.transfer_mode
+    }
+
+    /// Sets the [`Timeouts`] applied to bus operations, and how far to
+    /// escalate recovery when one is exceeded.  See [`crate::timeout`].
+    ///
+    /// Defaults to no timeouts.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.config.timeouts = timeouts;
+    }
+
+    /// Returns the [`Timeouts`] set by [`Cbm::set_timeouts`]
+    pub fn timeouts(&self) -> Timeouts {
+        self.config.timeouts
+    }
+
+    /// Enables reconnecting to the xum1541 if it is lost, using the given
+    /// [`ReconnectPolicy`], or disables it if None.  See
+    /// [`crate::reconnect`].
+    ///
+    /// Defaults to disabled.
+    pub fn set_reconnect(&mut self, policy: Option<ReconnectPolicy>) {
+        self.config.reconnect = policy;
+    }
+
+    /// Returns the [`ReconnectPolicy`] set by [`Cbm::set_reconnect`]
+    pub fn reconnect(&self) -> Option<ReconnectPolicy> {
+        self.config.reconnect
+    }
+
+    /// Sets a function to be called each time this [`Cbm`] reconnects to the
+    /// xum1541
+    pub fn on_reconnect<F>(&mut self, f: F)
+    where
+        F: Fn(&ReconnectEvent) + Send + Sync + 'static,
+    {
+        self.config.on_reconnect = Some(ReconnectHandler::new(f));
+    }
+
+    /// Returns a count of the times the xum1541 has been reopened, by
+    /// reconnecting or [`Cbm::usb_device_reset`].  Shared by all clones of
+    /// this [`Cbm`].
+    ///
+    /// Drive identities obtained before the count changed may be out of date,
+    /// see [`crate::CbmDriveUnit::is_identity_current`].
+    pub fn connection_generation(&self) -> u64 {
+        self.config.generation.load(Ordering::SeqCst)
     }
 
     /// Resets the USB device connection - by closing the driver then reopening
@@ -259,17 +446,63 @@ impl Cbm {
         // Lock the old handle - will be unlocked when it goes out of scope
         let mut handle = self.handle.lock();
 
+        Self::reopen_bus(&mut handle, &self.config)
+    }
+
+    // Replaces the Bus with a new one - this is done by usb_device_reset()
+    // and when recovering from a timeout
+    pub(crate) fn reopen_bus(handle: &mut Option<Bus>, config: &CbmConfig) -> Result<(), Error> {
         // Drop the old Bus instance which will close the driver
         let old_bus = handle.take();
         drop(old_bus);
 
         // Create a new instance (can fail)
-        let mut new_bus = Self::new_bus(&self.config)?;
+        let mut new_bus = Self::new_bus(config)?;
         new_bus.initialize()?;
 
         // Set the stored handle to the new instance
         *handle = Some(new_bus);
+        config.generation.fetch_add(1, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    // Reopens a lost Bus, retrying according to the ReconnectPolicy, and
+    // notifies the on_reconnect handler if successful
+    pub(crate) fn reconnect_bus(
+        handle: &mut Option<Bus>,
+        config: &CbmConfig,
+        policy: &ReconnectPolicy,
+    ) -> Result<(), Error> {
+        let start = Instant::now();
+        let mut delays = policy.delays();
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match Self::reopen_bus(handle, config) {
+                Ok(()) => break,
+                Err(e) => match delays.next() {
+                    Some(delay) => {
+                        debug!("Reconnect attempt {attempts} failed, retrying in {delay:?}: {e}");
+                        thread::sleep(delay);
+                    }
+                    None => {
+                        warn!("Failed to reconnect after {attempts} attempt(s): {e}");
+                        return Err(e);
+                    }
+                },
+            }
+        }
 
+        let event = ReconnectEvent {
+            attempts,
+            elapsed: start.elapsed(),
+            generation: config.generation.load(Ordering::SeqCst),
+        };
+        info!("{event}");
+        if let Some(handler) = &config.on_reconnect {
+            handler.call(&event);
+        }
         Ok(())
     }
 
@@ -291,8 +524,8 @@ impl Cbm {
     /// cbm.reset_bus()?;
     /// ```
     pub fn reset_bus(&self) -> Result<(), Error> {
-        self.handle.lock().bus_mut_or_err()?.reset()?;
-        Ok(())
+        let mut guard = self.handle.lock();
+        TimedBus::new(&mut guard, &self.config)?.reset()
     }
 
     /// Gets information about the xum1541 device this [`Cbm`] instance
@@ -301,7 +534,8 @@ impl Cbm {
     /// Xum1541DeviveInfo contains information like serial number, firmware
     /// version, and device capabilities.
     pub fn xum1541_info(&self) -> Result<Option<Xum1541DeviceInfo>, Error> {
-        Ok(self.handle.lock().bus_mut_or_err()?.device_info())
+        let mut guard = self.handle.lock();
+        TimedBus::new(&mut guard, &self.config)?.device_info()
     }
 }
 
@@ -399,9 +633,9 @@ impl Cbm {
     /// ```
     pub fn get_status(&self, device: u8) -> Result<CbmStatus, Error> {
         let mut guard = self.handle.lock();
-        let mut bus = (&mut guard).bus_mut_or_err()?;
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
 
-        Self::get_status_locked(&mut bus, device)
+        Self::get_status_locked(bus, device)
     }
 
     /// Scan the bus for any devices
@@ -532,7 +766,7 @@ impl Cbm {
 
         // Construct directory command ("$" or "$0" or "$1")
         let filename = match drive_num {
-            Some(num) => PetsciiString::from_petscii_bytes(&[b'$', num]),
+            Some(num) => PetsciiString::from_petscii_bytes(&[b'$', b'0' + num]),
             None => PetsciiString::from_petscii_bytes(&[b'$']),
         };
 
@@ -720,8 +954,27 @@ impl Cbm {
     /// we retrieve the status, expecting it to fail (it will likely return)
     /// a single byte - lik `\r`.
     pub fn read_drive_memory(&self, device: u8, addr: u16, buf: &mut [u8]) -> Result<(), Error> {
+        self.read_drive_memory_dos(device, addr, buf, &DosVersion::Dos1)
+    }
+
+    /// Reads a number of consecutive bytes from a drive, using the M-R
+    /// variant supported by the specified DOS version.
+    ///
+    /// DOS 1 drives only support reading a single byte per M-R command.
+    /// DOS 2 (and later) drives accept a byte count as the final argument to
+    /// M-R, so up to [`MEMORY_READ_MAX`] bytes are read per command, which is
+    /// considerably faster when reading more than a handful of bytes.
+    ///
+    /// See [`Cbm::read_drive_memory`] for more details.
+    pub fn read_drive_memory_dos(
+        &self,
+        device: u8,
+        addr: u16,
+        buf: &mut [u8],
+        dos_version: &DosVersion,
+    ) -> Result<(), Error> {
         let size = buf.len();
-        trace!("Cbm::read_drive_memory: device {device} addr 0x{addr:04x} size {size}");
+        trace!("Cbm::read_drive_memory_dos: device {device} addr 0x{addr:04x} size {size} dos {dos_version}");
 
         // Validate arguments
         Self::validate_read_args(
@@ -729,42 +982,46 @@ impl Cbm {
             format!("Asked to read 0 bytes from device {device} memory address 0x{addr:04x}"),
         )?;
 
-        // Split address into low and high bytes
-        let mut addr_low = (addr & 0xFF) as u8;
-        let mut addr_high = ((addr >> 8) & 0xFF) as u8;
+        // DOS 1 only supports reading a single byte at a time
+        let chunk_size = match dos_version {
+            DosVersion::Dos1 => 1,
+            _ => MEMORY_READ_MAX,
+        };
 
         // We need to get the Bus lock for the whole time we're doing stuff
         // as the disk drive will be in a "peculiar" state, during and after
         // our memory read.
         {
             let mut guard = self.handle.lock();
-            let bus = (&mut guard).bus_mut_or_err()?;
+            let bus = &mut TimedBus::new(&mut guard, &self.config)?;
 
             let result = (|| {
-                // Read one byte at a time for DOS1 compatibility
-                let mut cmd = [b'M', b'-', b'R', addr_low, addr_high];
                 let dc = DeviceChannel::new(device, CBM_CHANNEL_CTRL)?;
-                for ii in 0..size {
-                    debug!("Read from memory address 0x{addr_high:02x}{addr_low:02x}");
+                let mut chunk_addr = addr;
+                for chunk in buf.chunks_mut(chunk_size) {
+                    let addr_low = (chunk_addr & 0xFF) as u8;
+                    let addr_high = ((chunk_addr >> 8) & 0xFF) as u8;
+                    debug!(
+                        "Read {} byte(s) from memory address 0x{addr_high:02x}{addr_low:02x}",
+                        chunk.len()
+                    );
+
+                    let mut cmd = vec![b'M', b'-', b'R', addr_low, addr_high];
+                    if chunk_size > 1 {
+                        cmd.push(chunk.len() as u8);
+                    }
                     Self::send_command_petscii_locked(
                         bus,
                         dc,
                         &PetsciiString::from_petscii_bytes(&cmd),
                     )?;
 
-                    Self::read_from_drive_locked(bus, dc, &mut buf[ii..ii + 1], true)?;
+                    Self::read_from_drive_locked(bus, dc, chunk, true)?;
 
-                    debug!("Read data: 0x{:02x}", buf[ii]);
+                    trace!("Read data: {:02x?}", chunk);
 
-                    // Increment and handle 16-bit address wraparound
-                    if ii < size - 1 {
-                        addr_low = addr_low.wrapping_add(1);
-                        if addr_low == 0 {
-                            addr_high = addr_high.wrapping_add(1);
-                        }
-                        cmd[3] = addr_low;
-                        cmd[4] = addr_high;
-                    }
+                    // Handle 16-bit address wraparound
+                    chunk_addr = chunk_addr.wrapping_add(chunk.len() as u16);
                 }
                 Ok(())
             })();
@@ -797,35 +1054,115 @@ impl Cbm {
     }
 
     /// Writes the required number of bytes to the device's memory
+    ///
+    /// Data is written using M-W commands, each containing up to
+    /// [`MEMORY_WRITE_MAX`] bytes.  This uses the byte count form of M-W,
+    /// so requires a DOS 2 (or later) drive - which includes all serial bus
+    /// drives.  Will wrap around from 0xffff to 0x0000 if necessary.
+    ///
+    /// # Arguments
+    /// - `device` - Device number to write to
+    /// - `addr` - [`u16`] indicating which address to start writing at
+    /// - `data` - The bytes to write
     pub fn write_drive_memory(&self, device: u8, addr: u16, data: &[u8]) -> Result<(), Error> {
-        // Split address into low and high bytes
-        let addr_low = (addr & 0xFF) as u8;
-        let addr_high = ((addr >> 8) & 0xFF) as u8;
+        trace!(
+            "Cbm::write_drive_memory: device {device} addr 0x{addr:04x} size {}",
+            data.len()
+        );
+        if data.is_empty() {
+            return Err(Error::Validation {
+                message: format!(
+                    "Asked to write 0 bytes to device {device} memory address 0x{addr:04x}"
+                ),
+            });
+        }
 
-        // Write one byte at a time for DOS1 compatibility
-        for (i, &byte) in data.iter().enumerate() {
-            let cmd = vec![
-                b'M',
-                b'-',
-                b'W',
-                addr_low.wrapping_add(i as u8),
-                addr_high,
-                byte,
-            ];
-            self.send_command_petscii(device, &PetsciiString::from_petscii_bytes(&cmd))?;
+        let dc = DeviceChannel::new(device, CBM_CHANNEL_CTRL)?;
 
-            let mut guard = self.handle.lock();
-            let bus = (&mut guard).bus_mut_or_err()?;
+        // Hold the lock for the whole upload so no other commands are
+        // interleaved with ours
+        let mut guard = self.handle.lock();
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
+
+        let mut chunk_addr = addr;
+        for chunk in data.chunks(MEMORY_WRITE_MAX) {
+            let addr_low = (chunk_addr & 0xFF) as u8;
+            let addr_high = ((chunk_addr >> 8) & 0xFF) as u8;
+            debug!(
+                "Write {} byte(s) to memory address 0x{addr_high:02x}{addr_low:02x}",
+                chunk.len()
+            );
+
+            let mut cmd = vec![b'M', b'-', b'W', addr_low, addr_high, chunk.len() as u8];
+            cmd.extend_from_slice(chunk);
+            Self::send_command_petscii_locked(bus, dc, &PetsciiString::from_petscii_bytes(&cmd))?;
+
+            chunk_addr = chunk_addr.wrapping_add(chunk.len() as u16);
+        }
 
-            let dc = DeviceChannel::new(device, CBM_CHANNEL_CTRL)?;
-            bus.talk(dc)?;
+        Ok(())
+    }
 
-            // TODO - actually write the byte
+    /// Executes code in the device's memory, using the M-E command
+    ///
+    /// Typically used after uploading a routine with
+    /// [`Cbm::write_drive_memory`].  The drive jumps to the address
+    /// specified, and returns to its normal command processing when the
+    /// routine executes an RTS.
+    ///
+    /// Note that this function does not wait for the routine to complete.
+    /// If the routine disables interrupts the drive will not respond to the
+    /// bus until it re-enables them, so the caller is responsible for
+    /// waiting an appropriate amount of time before accessing the device
+    /// again.
+    ///
+    /// # Arguments
+    /// - `device` - Device number to execute the code on
+    /// - `addr` - [`u16`] address to jump to
+    pub fn execute_drive_memory(&self, device: u8, addr: u16) -> Result<(), Error> {
+        trace!("Cbm::execute_drive_memory: device {device} addr 0x{addr:04x}");
+        let addr_low = (addr & 0xFF) as u8;
+        let addr_high = ((addr >> 8) & 0xFF) as u8;
+        let cmd = [b'M', b'-', b'E', addr_low, addr_high];
+        self.send_command_petscii(device, &PetsciiString::from_petscii_bytes(&cmd))
+    }
 
-            bus.untalk()?;
-        }
+    /// Runs a routine already uploaded to the device's memory, and reads its
+    /// results once it has finished
+    ///
+    /// The routine is started with [`Cbm::execute_drive_memory`].  Routines
+    /// which disable interrupts stop the drive responding on the bus until
+    /// they return, so this waits for as long as the routine should take,
+    /// then polls for the results using M-R, giving up if the drive still
+    /// doesn't respond after a few seconds more.
+    ///
+    /// # Arguments
+    /// - `device` - Device number to run the routine on
+    /// - `addr` - Address of the routine
+    /// - `expected` - How long the routine should take to run
+    /// - `result_addr` - Address of the results, which are read into `buf`
+    pub(crate) fn run_drive_routine(
+        &self,
+        device: u8,
+        addr: u16,
+        expected: Duration,
+        result_addr: u16,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        self.execute_drive_memory(device, addr)?;
+        thread::sleep(expected);
 
-        Ok(())
+        let start = Instant::now();
+        loop {
+            match self.read_drive_memory_dos(device, result_addr, buf, &DosVersion::Dos2) {
+                Ok(()) => return Ok(()),
+                Err(e) if start.elapsed() < ROUTINE_POLL_TIMEOUT => {
+                    debug!("Drive routine at 0x{addr:04x} not yet complete: {e}");
+                    thread::sleep(ROUTINE_POLL_INTERVAL);
+                }
+                Err(e) => return Err(e),
+            }
+        }
     }
 
     /// Send a command on a specific drive
@@ -851,7 +1188,7 @@ impl Cbm {
         let dc = DeviceChannel::new(device, CBM_CHANNEL_CTRL)?;
 
         let mut guard = self.handle.lock();
-        let bus = (&mut guard).bus_mut_or_err()?;
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
 
         Self::send_command_petscii_locked(bus, dc, cmd)
     }
@@ -912,11 +1249,25 @@ impl Cbm {
         read_all: bool,
     ) -> Result<usize, Error> {
         let mut guard = self.handle.lock();
-        let bus = (&mut guard).bus_mut_or_err()?;
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
 
         Self::read_from_drive_locked(bus, dc, buf, read_all)
     }
 
+    /// Instructs the device to listen, writes all of the data then sets the
+    /// device to unlisten.
+    /// In case of a failure, sets the device to unlisten (if possible)
+    /// before returning
+    ///
+    /// Used with [`Cbm::open_file`] and [`Cbm::close_file`] to write a file
+    /// a piece at a time.
+    pub fn write_to_drive(&self, dc: DeviceChannel, data: &[u8]) -> Result<(), Error> {
+        let mut guard = self.handle.lock();
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
+
+        Self::write_to_file_locked(bus, dc, data)
+    }
+
     /// Reads a file from the disk.
     ///
     /// Reads the entire contents of the specified file into a vector of bytes.
@@ -942,12 +1293,20 @@ impl Cbm {
     /// ```
     /// Read a file with ASCII filename
     pub fn read_file(&self, device: u8, filename: &AsciiString) -> Result<Vec<u8>, Error> {
-        let dc = {
-            let _bus = self.handle.lock().bus_ref_or_err()?;
+        self.read_file_ctx(device, filename, &OpContext::new())
+    }
 
-            // TO DO properly alllocate channels
-            DeviceChannel::new(device, 2)?
-        };
+    /// Reads a file as [`Cbm::read_file`] does, reporting progress in bytes
+    /// to the [`OpContext`], and stopping and closing the file if it is
+    /// cancelled
+    pub fn read_file_ctx(
+        &self,
+        device: u8,
+        filename: &AsciiString,
+        ctx: &OpContext,
+    ) -> Result<Vec<u8>, Error> {
+        // TO DO properly alllocate channels
+        let dc = DeviceChannel::new(device, 2)?;
 
         self.send_command_ascii(device, filename)?;
 
@@ -959,7 +1318,7 @@ impl Cbm {
 
         // Re-acquire guard for file operations
         let mut guard = self.handle.lock();
-        let bus = (&mut guard).bus_mut_or_err()?;
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
 
         // Now read the file data
         bus.talk(dc).map_err(|e| Error::File {
@@ -969,6 +1328,11 @@ impl Cbm {
 
         let mut data = Vec::new();
         loop {
+            if let Err(e) = ctx.check() {
+                abandon_file(bus, dc, false);
+                return Err(e);
+            }
+
             let buf = &mut [0u8; BYTES_PER_BLOCK];
             let count = Self::bus_read_locked(bus, dc, buf).map_err(|e| Error::File {
                 device,
@@ -976,6 +1340,7 @@ impl Cbm {
             })?;
 
             data.extend_from_slice(&buf[..count as usize]);
+            ctx.report(data.len() as u64, None, ProgressUnit::Bytes);
             if count < BYTES_PER_BLOCK {
                 debug!("Finished reading file");
                 break;
@@ -1017,12 +1382,21 @@ impl Cbm {
     /// cbm.write_file(8, "NEWFILE.PRG", &data)?;
     /// ```
     pub fn write_file(&self, device: u8, filename: &AsciiString, data: &[u8]) -> Result<(), Error> {
-        let dc = {
-            let _bus = self.handle.lock().bus_ref_or_err()?;
+        self.write_file_ctx(device, filename, data, &OpContext::new())
+    }
 
-            // TO DO properly allocate channels
-            DeviceChannel::new(device, 2)?
-        };
+    /// Writes a file as [`Cbm::write_file`] does, reporting progress in
+    /// bytes to the [`OpContext`], and stopping if it is cancelled.  A
+    /// cancelled write closes the file, leaving the data written so far.
+    pub fn write_file_ctx(
+        &self,
+        device: u8,
+        filename: &AsciiString,
+        data: &[u8],
+        ctx: &OpContext,
+    ) -> Result<(), Error> {
+        // TO DO properly allocate channels
+        let dc = DeviceChannel::new(device, 2)?;
 
         // Open file for writing with overwrite if exists
         self.send_string_command_ascii(device, &format!("@:{}", filename))?;
@@ -1035,7 +1409,7 @@ impl Cbm {
 
         // Reacquire guard for file operations
         let mut guard = self.handle.lock();
-        let bus = (&mut guard).bus_mut_or_err()?;
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
 
         // Now write the file data
         bus.listen(dc).map_err(|e| Error::File {
@@ -1044,7 +1418,13 @@ impl Cbm {
         })?;
 
         // Write data in chunks
+        let mut written = 0;
         for chunk in data.chunks(BYTES_PER_BLOCK) {
+            if let Err(e) = ctx.check() {
+                abandon_file(bus, dc, true);
+                return Err(e);
+            }
+
             let result = bus.write(chunk).map_err(|e| Error::File {
                 device,
                 message: format!("Write failed: {}", e),
@@ -1056,6 +1436,8 @@ impl Cbm {
                     message: "Failed to write complete chunk".into(),
                 });
             }
+            written += result;
+            ctx.report(written as u64, Some(data.len() as u64), ProgressUnit::Bytes);
         }
 
         // Cleanup
@@ -1067,6 +1449,102 @@ impl Cbm {
         Ok(())
     }
 
+    /// Saves a new file of the given type to the disk.
+    ///
+    /// Unlike [`Cbm::write_file`] the filename is provided in PETSCII, so
+    /// names which can't be represented in ASCII are preserved.  Fails if
+    /// the file already exists.
+    ///
+    /// # Arguments
+    /// * `device` - Device number
+    /// * `filename` - Filename in PETSCII.  Does not include suffix or file
+    ///   type
+    /// * `file_type` - Type of file to create.  REL files are not supported
+    /// * `data` - The contents of the file.  For PRG files this must start
+    ///   with the load address
+    ///
+    /// # Example
+    /// ```ignore
+    /// let filename = PetsciiString::from_ascii_str("hello");
+    /// cbm.save_file_petscii(8, &filename, CbmFileType::PRG, &[0x01, 0x08, 0x00, 0x00])?;
+    /// ```
+    pub fn save_file_petscii(
+        &self,
+        device: u8,
+        filename: &PetsciiString,
+        file_type: CbmFileType,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        validate_device(Some(device), DeviceValidation::Required)?;
+        if matches!(file_type, CbmFileType::REL | CbmFileType::Unknown) {
+            return Err(Error::Validation {
+                message: format!("Can't save file of type {file_type:?}"),
+            });
+        }
+
+        // Open with a name of the form "name,P,W"
+        let mut open_name = filename.as_bytes().to_vec();
+        open_name.extend_from_slice(file_type._to_suffix().as_bytes());
+        open_name.extend_from_slice(b",W");
+        let open_name = PetsciiString::from_petscii_bytes(&open_name);
+
+        // TO DO properly allocate channels
+        let dc = DeviceChannel::new(device, 2)?;
+
+        let mut guard = self.handle.lock();
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
+
+        Self::save_file_petscii_locked(bus, dc, &open_name, data)
+    }
+
+    /// Saves a new REL file to the disk.
+    ///
+    /// The file is created with the given record length, and each record is
+    /// then positioned using the `P` command and written in turn.
+    ///
+    /// # Arguments
+    /// * `device` - Device number
+    /// * `filename` - Filename in PETSCII.  Does not include suffix or file
+    ///   type
+    /// * `record_len` - Length of each record, 1-254
+    /// * `data` - The records, stored contiguously.  The drive pads a short
+    ///   final record with zeros
+    pub fn save_rel_file_petscii(
+        &self,
+        device: u8,
+        filename: &PetsciiString,
+        record_len: u8,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        validate_device(Some(device), DeviceValidation::Required)?;
+        if record_len == 0 || record_len as usize > BYTES_PER_BLOCK {
+            return Err(Error::Validation {
+                message: format!("Invalid REL record length {record_len}"),
+            });
+        }
+
+        // Open with a name of the form "name,L,<record length>"
+        let mut open_name = filename.as_bytes().to_vec();
+        open_name.extend_from_slice(b",L,");
+        open_name.push(record_len);
+        let open_name = PetsciiString::from_petscii_bytes(&open_name);
+
+        // TO DO properly allocate channels
+        let dc = DeviceChannel::new(device, 2)?;
+
+        let mut guard = self.handle.lock();
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
+
+        Self::open_file_petscii_locked(bus, dc, &open_name)?;
+
+        let write_result = Self::write_rel_records_locked(bus, dc, record_len, data);
+        let close_result = Self::close_file_locked(bus, dc);
+        write_result?;
+        close_result?;
+
+        Self::check_for_status_ok(bus, device, false)
+    }
+
     /// Open a file using an ASCII filename
     ///
     /// This function will convert the ASCII filename to PETSCII, and will
@@ -1088,7 +1566,7 @@ impl Cbm {
 
         {
             let mut guard = self.handle.lock();
-            let bus = (&mut guard).bus_mut_or_err()?;
+            let bus = &mut TimedBus::new(&mut guard, &self.config)?;
 
             Self::open_file_petscii_locked(bus, dc, &petscii_name)
         }
@@ -1109,11 +1587,67 @@ impl Cbm {
     /// call for this device and channel
     pub fn close_file(&self, dc: DeviceChannel) -> Result<(), Error> {
         let mut guard = self.handle.lock();
-        let bus = (&mut guard).bus_mut_or_err()?;
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
 
         Self::close_file_locked(bus, dc)
     }
 
+    /// Raw bus access - instructs the device to talk on the given channel.
+    ///
+    /// Unlike the other functions, which leave the bus idle, the raw
+    /// functions leave the bus in whatever state the caller puts it in, so
+    /// callers must follow [`Cbm::talk`] with [`Cbm::raw_read`] and
+    /// [`Cbm::untalk`], and [`Cbm::listen`] and [`Cbm::open_channel`] with
+    /// [`Cbm::raw_write`] and [`Cbm::unlisten`].  They are intended for
+    /// implementing other protocols, such as the OpenCBM API.
+    pub fn talk(&self, dc: DeviceChannel) -> Result<(), Error> {
+        let mut guard = self.handle.lock();
+        TimedBus::new(&mut guard, &self.config)?.talk(dc)
+    }
+
+    /// Raw bus access - instructs the device to listen on the given channel.
+    /// See [`Cbm::talk`].
+    pub fn listen(&self, dc: DeviceChannel) -> Result<(), Error> {
+        let mut guard = self.handle.lock();
+        TimedBus::new(&mut guard, &self.config)?.listen(dc)
+    }
+
+    /// Raw bus access - instructs the talking device to untalk.  See
+    /// [`Cbm::talk`].
+    pub fn untalk(&self) -> Result<(), Error> {
+        let mut guard = self.handle.lock();
+        TimedBus::new(&mut guard, &self.config)?.untalk()
+    }
+
+    /// Raw bus access - instructs the listening device to unlisten.  See
+    /// [`Cbm::talk`].
+    pub fn unlisten(&self) -> Result<(), Error> {
+        let mut guard = self.handle.lock();
+        TimedBus::new(&mut guard, &self.config)?.unlisten()
+    }
+
+    /// Raw bus access - instructs the device to open the given channel and
+    /// listen, so the filename can be sent with [`Cbm::raw_write`] followed
+    /// by [`Cbm::unlisten`].  See [`Cbm::talk`].
+    pub fn open_channel(&self, dc: DeviceChannel) -> Result<(), Error> {
+        let mut guard = self.handle.lock();
+        TimedBus::new(&mut guard, &self.config)?.open(dc)
+    }
+
+    /// Raw bus access - reads up to `buf.len()` bytes from the talking
+    /// device, returning the number read.  See [`Cbm::talk`].
+    pub fn raw_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut guard = self.handle.lock();
+        TimedBus::new(&mut guard, &self.config)?.read(buf)
+    }
+
+    /// Raw bus access - writes the data to the listening device, returning
+    /// the number of bytes written.  See [`Cbm::talk`].
+    pub fn raw_write(&self, data: &[u8]) -> Result<usize, Error> {
+        let mut guard = self.handle.lock();
+        TimedBus::new(&mut guard, &self.config)?.write(data)
+    }
+
     /// This function opens a file, reads in the entire contents and closes
     /// the file.
     ///
@@ -1123,18 +1657,44 @@ impl Cbm {
     /// # Arguments
     /// * `device` - Device number
     /// * `filename` - Filename to open in PETSCII format (lower case characters for regular character-based filenames).  Does not include suffix or file type
+    ///
+    /// Uses the [`TransferMode`] set by [`Cbm::set_transfer_mode`] - see
+    /// [`Cbm::load_file_with`].
     pub fn load_file_petscii(
         &self,
         device: u8,
         filename: &PetsciiString,
+    ) -> Result<Vec<u8>, Error> {
+        self.load_file_petscii_ctx(device, filename, &OpContext::new())
+    }
+
+    /// Loads a file as [`Cbm::load_file_petscii`] does, reporting progress
+    /// in bytes to the [`OpContext`], and stopping if it is cancelled.  The
+    /// total isn't known in advance, so should be set on the context, for
+    /// example from [`crate::CbmFileEntry::max_size`].
+    pub fn load_file_petscii_ctx(
+        &self,
+        device: u8,
+        filename: &PetsciiString,
+        ctx: &OpContext,
+    ) -> Result<Vec<u8>, Error> {
+        self.load_file_mode(device, filename, self.transfer_mode(), ctx)
+    }
+
+    // Loads a file using the standard serial protocol
+    pub(crate) fn load_file_standard(
+        &self,
+        device: u8,
+        filename: &PetsciiString,
+        ctx: &OpContext,
     ) -> Result<Vec<u8>, Error> {
         // Validate device
         validate_device(Some(device), DeviceValidation::Required)?;
 
         let mut guard = self.handle.lock();
-        let bus = (&mut guard).bus_mut_or_err()?;
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
 
-        Self::load_file_petscii_locked(bus, device, &filename)
+        Self::load_file_petscii_locked(bus, device, filename, ctx)
     }
 
     /// This function opens a file, reads in the entire contents and closes
@@ -1156,10 +1716,146 @@ impl Cbm {
         self.load_file_petscii(device, &filename)
     }
 
+    /// Reads the entire contents of a file of the given type.
+    ///
+    /// Unlike [`Cbm::load_file_petscii`] this opens the file on a data
+    /// channel with the file type specified, so can be used to read SEQ and
+    /// USR files as well as PRG files.
+    ///
+    /// # Arguments
+    /// * `device` - Device number
+    /// * `filename` - Filename in PETSCII.  Does not include suffix or file
+    ///   type
+    /// * `file_type` - Type of the file.  REL files are not supported
+    pub fn read_file_petscii(
+        &self,
+        device: u8,
+        filename: &PetsciiString,
+        file_type: CbmFileType,
+    ) -> Result<Vec<u8>, Error> {
+        validate_device(Some(device), DeviceValidation::Required)?;
+        if matches!(file_type, CbmFileType::REL | CbmFileType::Unknown) {
+            return Err(Error::Validation {
+                message: format!("Can't read file of type {file_type:?}"),
+            });
+        }
+
+        // Open with a name of the form "name,S,R"
+        let mut open_name = filename.as_bytes().to_vec();
+        open_name.extend_from_slice(file_type._to_suffix().as_bytes());
+        open_name.extend_from_slice(b",R");
+        let open_name = PetsciiString::from_petscii_bytes(&open_name);
+
+        // TO DO properly allocate channels
+        let dc = DeviceChannel::new(device, 2)?;
+
+        let mut guard = self.handle.lock();
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
+
+        Self::open_file_petscii_locked(bus, dc, &open_name)?;
+        Self::read_open_file_locked(bus, dc, &OpContext::new())
+    }
+
+    /// Reads a single sector from the disk, using the U1 (block read)
+    /// command.  Returns all [`SECTOR_SIZE`] bytes of the sector, including
+    /// the link bytes.
+    ///
+    /// # Arguments
+    /// * `device` - Device number
+    /// * `track` - Track number, starting from 1
+    /// * `sector` - Sector number, starting from 0
+    ///
+    /// # Errors
+    /// Returns `Error::Status` if the drive reports an error reading the
+    /// sector, for example 21, READ ERROR
+    pub fn read_sector(&self, device: u8, track: u8, sector: u8) -> Result<Vec<u8>, Error> {
+        trace!("Cbm::read_sector device: {device} track: {track} sector: {sector}");
+        validate_device(Some(device), DeviceValidation::Required)?;
+
+        // TO DO properly allocate channels
+        let dc = DeviceChannel::new(device, 2)?;
+
+        let mut guard = self.handle.lock();
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
+
+        Self::open_file_petscii_locked(bus, dc, &PetsciiString::from_petscii_bytes(b"#"))?;
+        let read_result = Self::read_sector_locked(bus, dc, track, sector);
+        let close_result = Self::close_file_locked(bus, dc);
+        let data = read_result?;
+        close_result?;
+        Ok(data)
+    }
+
+    /// Writes a single sector to the disk, using the U2 (block write)
+    /// command.  The drive's BAM is not updated - see [`crate::BlockDevice`]
+    /// for allocating sectors.
+    ///
+    /// # Arguments
+    /// * `device` - Device number
+    /// * `track` - Track number, starting from 1
+    /// * `sector` - Sector number, starting from 0
+    /// * `data` - The sector contents, which must be [`SECTOR_SIZE`] bytes
+    pub fn write_sector(
+        &self,
+        device: u8,
+        track: u8,
+        sector: u8,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        trace!("Cbm::write_sector device: {device} track: {track} sector: {sector}");
+        validate_device(Some(device), DeviceValidation::Required)?;
+        if data.len() != SECTOR_SIZE {
+            return Err(Error::Validation {
+                message: format!(
+                    "Sector data must be {SECTOR_SIZE} bytes, got {}",
+                    data.len()
+                ),
+            });
+        }
+
+        // TO DO properly allocate channels
+        let dc = DeviceChannel::new(device, 2)?;
+
+        let mut guard = self.handle.lock();
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
+
+        Self::open_file_petscii_locked(bus, dc, &PetsciiString::from_petscii_bytes(b"#"))?;
+        let write_result = Self::write_sector_locked(bus, dc, track, sector, data);
+        let close_result = Self::close_file_locked(bus, dc);
+        write_result?;
+        close_result
+    }
+
+    /// Marks a sector as allocated in the drive's BAM, using the B-A
+    /// command
+    ///
+    /// # Errors
+    /// Returns `Error::Status` with 65, NO BLOCK if the sector is already
+    /// allocated
+    pub fn allocate_block(&self, device: u8, track: u8, sector: u8) -> Result<(), Error> {
+        validate_device(Some(device), DeviceValidation::Required)?;
+
+        let mut guard = self.handle.lock();
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
+
+        Self::send_block_command_locked(bus, device, &format!("B-A 0 {track} {sector}"))
+    }
+
+    /// Marks a sector as free in the drive's BAM, using the B-F command
+    pub fn free_block(&self, device: u8, track: u8, sector: u8) -> Result<(), Error> {
+        validate_device(Some(device), DeviceValidation::Required)?;
+
+        let mut guard = self.handle.lock();
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
+
+        Self::send_block_command_locked(bus, device, &format!("B-F 0 {track} {sector}"))
+    }
+
     fn load_file_petscii_locked(
-        bus: &mut Bus,
+        bus: &mut TimedBus,
         device: u8,
         filename: &PetsciiString,
+        ctx: &OpContext,
     ) -> Result<Vec<u8>, Error> {
         debug!("Load file device: {device} filename: {filename}");
 
@@ -1167,6 +1863,17 @@ impl Cbm {
         let dc = DeviceChannel::new(device, CBM_CHANNEL_LOAD)?;
         Self::open_file_petscii_locked(bus, dc, filename)?;
 
+        Self::read_open_file_locked(bus, dc, ctx)
+    }
+
+    // Reads the entire contents of a file which has already been opened on
+    // this channel, and closes it.  If the operation is cancelled, it is
+    // cleaned up in the same way as a read error.
+    fn read_open_file_locked(
+        bus: &mut TimedBus,
+        dc: DeviceChannel,
+        ctx: &OpContext,
+    ) -> Result<Vec<u8>, Error> {
         // Talk
         bus.talk(dc).inspect_err(|_| {
             // Clean-up
@@ -1177,9 +1884,15 @@ impl Cbm {
         let mut buffer = Vec::new();
         let mut read_buf = [0u8; BYTES_PER_BLOCK];
         let read_result = loop {
-            match Self::bus_read_locked(bus, dc, &mut read_buf) {
+            match ctx
+                .check()
+                .and_then(|_| Self::bus_read_locked(bus, dc, &mut read_buf))
+            {
                 Ok(bytes_read) if bytes_read == 0 => break Ok(buffer),
-                Ok(bytes_read) => buffer.extend_from_slice(&read_buf[..bytes_read]),
+                Ok(bytes_read) => {
+                    buffer.extend_from_slice(&read_buf[..bytes_read]);
+                    ctx.report(buffer.len() as u64, None, ProgressUnit::Bytes);
+                }
                 Err(e) => {
                     // Clean-up
                     let _ = bus.untalk();
@@ -1206,33 +1919,33 @@ impl Cbm {
 impl Cbm {
     fn bus_listen(&self, dc: DeviceChannel) -> Result<(), Error> {
         let mut guard = self.handle.lock();
-        let bus = (&mut guard).bus_mut_or_err()?;
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
 
-        bus.listen(dc).map_err(|e| e.into())
+        bus.listen(dc)
     }
 
     #[allow(dead_code)]
     fn bus_unlisten(&self) -> Result<(), Error> {
         let mut guard = self.handle.lock();
-        let bus = (&mut guard).bus_mut_or_err()?;
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
 
-        bus.unlisten().map_err(|e| e.into())
+        bus.unlisten()
     }
 
     #[allow(dead_code)]
     fn bus_talk(&self, dc: DeviceChannel) -> Result<(), Error> {
         let mut guard = self.handle.lock();
-        let bus = (&mut guard).bus_mut_or_err()?;
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
 
-        bus.talk(dc).map_err(|e| e.into())
+        bus.talk(dc)
     }
 
     #[allow(dead_code)]
     fn bus_untalk(&self) -> Result<(), Error> {
         let mut guard = self.handle.lock();
-        let bus = (&mut guard).bus_mut_or_err()?;
+        let bus = &mut TimedBus::new(&mut guard, &self.config)?;
 
-        bus.untalk().map_err(|e| e.into())
+        bus.untalk()
     }
 
     // Handles figuring out if the read response means that we actually
@@ -1244,8 +1957,8 @@ impl Cbm {
     // We could also check whether the listener is the DeviceChannel
     // passed into us, but we won't bother with that.
     fn handle_read_result(
-        result: Result<usize, Xum1541Error>,
-        bus: &Bus,
+        result: Result<usize, Error>,
+        bus: &TimedBus,
         dc: DeviceChannel,
     ) -> Result<usize, Error> {
         match result {
@@ -1261,17 +1974,20 @@ impl Cbm {
                     Ok(0)
                 }
             }
-            Ok(n) => Ok(n),
-            Err(e) => Err(e.into()),
+            result => result,
         }
     }
 
-    fn bus_read_locked(bus: &mut Bus, dc: DeviceChannel, buf: &mut [u8]) -> Result<usize, Error> {
+    fn bus_read_locked(
+        bus: &mut TimedBus,
+        dc: DeviceChannel,
+        buf: &mut [u8],
+    ) -> Result<usize, Error> {
         Self::handle_read_result(bus.read(buf), bus, dc)
     }
 
     fn bus_read_until_locked(
-        bus: &mut Bus,
+        bus: &mut TimedBus,
         dc: DeviceChannel,
         buf: &mut Vec<u8>,
         pattern: &[u8],
@@ -1281,7 +1997,7 @@ impl Cbm {
 
     #[allow(dead_code)]
     fn bus_read_until_any_locked(
-        bus: &mut Bus,
+        bus: &mut TimedBus,
         dc: DeviceChannel,
         buf: &mut Vec<u8>,
         pattern: &[u8],
@@ -1289,7 +2005,7 @@ impl Cbm {
         Self::handle_read_result(bus.read_until_any(buf, pattern), bus, dc)
     }
 
-    fn check_for_status_ok(bus: &mut Bus, device: u8, accept_73: bool) -> Result<(), Error> {
+    fn check_for_status_ok(bus: &mut TimedBus, device: u8, accept_73: bool) -> Result<(), Error> {
         Self::get_status_locked(bus, device)
             .map_err(|e| {
                 let default_error =
@@ -1313,7 +2029,7 @@ impl Cbm {
     }
 
     fn send_command_petscii_locked(
-        bus: &mut Bus,
+        bus: &mut TimedBus,
         dc: DeviceChannel,
         cmd: &PetsciiString,
     ) -> Result<(), Error> {
@@ -1321,10 +2037,10 @@ impl Cbm {
         bus.write(cmd.as_bytes()).inspect_err(|_| {
             let _ = bus.unlisten();
         })?;
-        bus.unlisten().map_err(|e| e.into())
+        bus.unlisten()
     }
 
-    fn get_status_locked(bus: &mut Bus, device: u8) -> Result<CbmStatus, Error> {
+    fn get_status_locked(bus: &mut TimedBus, device: u8) -> Result<CbmStatus, Error> {
         trace!("Cbm::get_status_locked device: {device}");
 
         // Set up DeviceChannel to read the status
@@ -1353,7 +2069,7 @@ impl Cbm {
     }
 
     fn read_from_drive_locked(
-        bus: &mut Bus,
+        bus: &mut TimedBus,
         dc: DeviceChannel,
         buf: &mut [u8],
         read_all: bool,
@@ -1406,7 +2122,7 @@ impl Cbm {
     }
 
     fn open_file_petscii_locked(
-        bus: &mut Bus,
+        bus: &mut TimedBus,
         dc: DeviceChannel,
         filename: &PetsciiString,
     ) -> Result<(), Error> {
@@ -1432,7 +2148,211 @@ impl Cbm {
         })
     }
 
-    fn close_file_locked(bus: &mut Bus, dc: DeviceChannel) -> Result<(), Error> {
-        bus.close(dc).map_err(|e| e.into())
+    fn close_file_locked(bus: &mut TimedBus, dc: DeviceChannel) -> Result<(), Error> {
+        bus.close(dc)
+    }
+
+    // Opens the file using the full open name (including type and mode),
+    // writes the data and closes the file
+    fn save_file_petscii_locked(
+        bus: &mut TimedBus,
+        dc: DeviceChannel,
+        open_name: &PetsciiString,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        Self::open_file_petscii_locked(bus, dc, open_name)?;
+
+        let write_result = Self::write_to_file_locked(bus, dc, data);
+        let close_result = Self::close_file_locked(bus, dc);
+        write_result?;
+        close_result?;
+
+        // Errors such as the disk being full are reported once the file has
+        // been closed
+        Self::check_for_status_ok(bus, dc.device(), false)
+    }
+
+    // Copies a file directly from one device to another - see
+    // copy::copy_direct
+    pub(crate) fn copy_file_direct(
+        &self,
+        source: DeviceChannel,
+        source_name: &PetsciiString,
+        dest: DeviceChannel,
+        dest_name: &PetsciiString,
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<usize, DirectCopyError> {
+        let mut guard = self.handle.lock();
+        let bus =
+            &mut TimedBus::new(&mut guard, &self.config).map_err(|error| DirectCopyError {
+                error,
+                dest_created: false,
+            })?;
+        copy_direct(bus, source, source_name, dest, dest_name, progress)
+    }
+
+    // Transfers the contents of a file open on the source channel to a file
+    // open on the destination channel
+    fn copy_open_file_locked(
+        bus: &mut TimedBus,
+        source: DeviceChannel,
+        dest: DeviceChannel,
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<usize, Error> {
+        // Listeners stay addressed when another device is told to talk, so
+        // the destination must listen first
+        bus.listen(dest)?;
+        bus.talk(source).inspect_err(|_| {
+            // Clean up
+            let _ = bus.unlisten();
+        })?;
+
+        let mut copied = 0;
+        let mut read_buf = [0u8; BYTES_PER_BLOCK];
+        let read_result = loop {
+            match Self::bus_read_locked(bus, source, &mut read_buf) {
+                Ok(0) => break Ok(copied),
+                Ok(bytes_read) => {
+                    copied += bytes_read;
+                    progress(copied);
+                }
+                Err(e) => break Err(e),
+            }
+        };
+
+        let untalk_result = bus.untalk();
+        let unlisten_result = bus.unlisten();
+        let copied = read_result?;
+        untalk_result?;
+        unlisten_result?;
+        Ok(copied)
+    }
+
+    // Writes records to a REL file which is already open on this channel
+    fn write_rel_records_locked(
+        bus: &mut TimedBus,
+        dc: DeviceChannel,
+        record_len: u8,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let ctrl_dc = DeviceChannel::new(dc.device(), CBM_CHANNEL_CTRL)?;
+        for (ii, record) in data.chunks(record_len as usize).enumerate() {
+            // Position to the start of the record.  The drive reports 50,
+            // RECORD NOT PRESENT when extending the file, so the status
+            // isn't checked.
+            let record_num = (ii + 1) as u16;
+            let mut cmd = vec![b'P', 0x60 | dc.channel()];
+            cmd.extend_from_slice(&record_num.to_le_bytes());
+            cmd.push(1);
+            let cmd = PetsciiString::from_petscii_bytes(&cmd);
+            Self::send_command_petscii_locked(bus, ctrl_dc, &cmd)?;
+
+            Self::write_to_file_locked(bus, dc, record)?;
+        }
+        Ok(())
+    }
+
+    // Sends a block command (U1, U2, B-P etc) on the command channel and
+    // checks the drive reports OK
+    fn send_block_command_locked(bus: &mut TimedBus, device: u8, cmd: &str) -> Result<(), Error> {
+        trace!("Block command device: {device} command: {cmd}");
+        let ctrl_dc = DeviceChannel::new(device, CBM_CHANNEL_CTRL)?;
+        let cmd = PetsciiString::from_petscii_bytes(cmd.as_bytes());
+        Self::send_command_petscii_locked(bus, ctrl_dc, &cmd)?;
+        Self::check_for_status_ok(bus, device, false)
+    }
+
+    // Reads a sector into the buffer opened on this channel, and returns it
+    fn read_sector_locked(
+        bus: &mut TimedBus,
+        dc: DeviceChannel,
+        track: u8,
+        sector: u8,
+    ) -> Result<Vec<u8>, Error> {
+        let cmd = format!("U1 {} 0 {track} {sector}", dc.channel());
+        Self::send_block_command_locked(bus, dc.device(), &cmd)?;
+
+        // U1 leaves the buffer pointer at 0, so the whole sector is read
+        let mut buf = vec![0u8; SECTOR_SIZE];
+        Self::read_from_drive_locked(bus, dc, &mut buf, true)?;
+        Ok(buf)
+    }
+
+    // Writes a sector via the buffer opened on this channel
+    fn write_sector_locked(
+        bus: &mut TimedBus,
+        dc: DeviceChannel,
+        track: u8,
+        sector: u8,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let cmd = format!("B-P {} 0", dc.channel());
+        Self::send_block_command_locked(bus, dc.device(), &cmd)?;
+        Self::write_to_file_locked(bus, dc, data)?;
+        let cmd = format!("U2 {} 0 {track} {sector}", dc.channel());
+        Self::send_block_command_locked(bus, dc.device(), &cmd)
+    }
+
+    // Writes data to a file which is already open on this channel
+    fn write_to_file_locked(
+        bus: &mut TimedBus,
+        dc: DeviceChannel,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        bus.listen(dc)?;
+        for chunk in data.chunks(BYTES_PER_BLOCK) {
+            let written = bus.write(chunk).inspect_err(|_| {
+                let _ = bus.unlisten();
+            })?;
+            if written != chunk.len() {
+                let _ = bus.unlisten();
+                return Err(Error::File {
+                    device: dc.device(),
+                    message: format!("Wrote {written} of {} bytes", chunk.len()),
+                });
+            }
+        }
+        bus.unlisten()
+    }
+}
+
+impl DirectCopyBus for TimedBus<'_> {
+    fn open_file(&mut self, dc: DeviceChannel, name: &PetsciiString) -> Result<(), Error> {
+        Cbm::open_file_petscii_locked(self, dc, name)
+    }
+
+    fn close_file(&mut self, dc: DeviceChannel) -> Result<(), Error> {
+        Cbm::close_file_locked(self, dc)
+    }
+
+    fn transfer(
+        &mut self,
+        source: DeviceChannel,
+        dest: DeviceChannel,
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<usize, Error> {
+        Cbm::copy_open_file_locked(self, source, dest, progress)
+    }
+
+    fn check_status(&mut self, device: u8) -> Result<(), Error> {
+        Cbm::check_for_status_ok(self, device, false)
+    }
+}
+
+impl FileCleanup for TimedBus<'_> {
+    fn untalk(&mut self) -> Result<(), Error> {
+        TimedBus::untalk(self)
+    }
+
+    fn unlisten(&mut self) -> Result<(), Error> {
+        TimedBus::unlisten(self)
+    }
+
+    fn close(&mut self, dc: DeviceChannel) -> Result<(), Error> {
+        TimedBus::close(self, dc)
+    }
+
+    fn read_status(&mut self, device: u8) -> Result<CbmStatus, Error> {
+        Cbm::get_status_locked(self, device)
     }
 }
@@ -190,7 +190,7 @@ impl CbmStatus {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CbmDeviceInfo {
     pub device_type: CbmDeviceType,
     pub description: String,
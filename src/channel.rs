@@ -74,8 +74,13 @@ impl CbmChannelManager {
             return None;
         }
 
-        // Regular channel allocation
-        for i in 0..15 {
+        // Regular channel allocation.  Files are opened on 2 upwards, as
+        // the drive treats channels 0 and 1 as LOAD and SAVE.
+        let first = match purpose {
+            CbmChannelPurpose::FileRead | CbmChannelPurpose::FileWrite => 2,
+            _ => 0,
+        };
+        for i in first..15 {
             if let Some(slot) = self.channels.get_mut(&i) {
                 if slot.is_none() {
                     let _sequence = self.next_sequence.fetch_add(1, Ordering::SeqCst);
@@ -90,9 +95,41 @@ impl CbmChannelManager {
         None
     }
 
+    /// Frees a channel previously returned by
+    /// [`CbmChannelManager::allocate`], so it can be allocated again
+    pub fn free(&mut self, channel: u8) {
+        if let Some(slot) = self.channels.get_mut(&channel) {
+            *slot = None;
+        }
+    }
+
     pub fn reset(&mut self) {
         for i in 0..=15 {
             self.channels.insert(i, None);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_free() {
+        let mut manager = CbmChannelManager::new();
+        assert_eq!(manager.allocate(8, 0, CbmChannelPurpose::FileRead), Some(2));
+        assert_eq!(
+            manager.allocate(8, 0, CbmChannelPurpose::FileWrite),
+            Some(3)
+        );
+        manager.free(2);
+        assert_eq!(manager.allocate(8, 0, CbmChannelPurpose::FileRead), Some(2));
+        for channel in 4..15 {
+            assert_eq!(
+                manager.allocate(8, 0, CbmChannelPurpose::FileRead),
+                Some(channel)
+            );
+        }
+        assert_eq!(manager.allocate(8, 0, CbmChannelPurpose::FileRead), None);
+    }
+}
@@ -0,0 +1,227 @@
+//! Contains functions to checksum files and tracks inside the drive
+//!
+//! Verifying a file by reading it back over the serial bus is slow.  On
+//! 1541 and 1571 drives, [`Cbm::checksum_file`] and [`Cbm::checksum_track`]
+//! instead upload a routine which reads the sectors using the drive's job
+//! queue and calculates a CRC as it goes, so only the 16-bit result is
+//! transferred.  Other drives fall back to reading the data to the host.
+//!
+//! Both return a CRC-16/CCITT-FALSE, which can be compared with [`crc16`]
+//! of the expected data.
+
+use crate::block::{dir_slots, DriveBlocks};
+use crate::cbm::Cbm;
+use crate::cbmtype::{CbmDeviceType, DosVersion};
+use crate::channel::CBM_CHANNEL_CTRL;
+use crate::error::{DeviceError, Error};
+use crate::image::DiskImageFormat;
+use crate::string::PetsciiString;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use xum1541::DeviceChannel;
+
+use std::time::Duration;
+
+// Drive memory layout used by the drive routine - see drivecode/checksum.s
+const DRIVE_CODE_ADDR: u16 = 0x0300;
+const PARAM_ADDR: u16 = 0x0480;
+const RESULT_ADDR: u16 = 0x0484;
+const JOB_HEADER_ADDR: u16 = 0x000a;
+
+// Drive routine modes
+const MODE_CHAIN: u8 = 0;
+const MODE_TRACK: u8 = 1;
+
+// Drive routine results.  Other values are job queue errors.
+const RESULT_OK: u8 = 1;
+const RESULT_CHAIN_TOO_LONG: u8 = 0xff;
+
+// Job queue errors are numbered from 2, corresponding to DOS errors from 20
+const JOB_ERROR_OFFSET: u8 = 18;
+
+// Time to allow for the drive routine to run, per sector and in total.
+// Sectors are read one at a time, so each can take up to a revolution.
+const ROUTINE_BASE_TIME: Duration = Duration::from_millis(500);
+const SECTOR_TIME: Duration = Duration::from_millis(200);
+
+/// 1541/1571 drive routine which calculates the CRC of a sector chain or
+/// track.  Assembled from drivecode/checksum.s, to run at
+/// [`DRIVE_CODE_ADDR`].
+const CHECKSUM_CODE: [u8; 199] = [
+    0xa9, 0xff, 0x8d, 0x85, 0x04, 0x8d, 0x86, 0x04, 0xa9, 0x00, 0x8d, 0x84, 0x04, 0x8d, 0x87, 0x04,
+    0x8d, 0x88, 0x04, 0x58, 0xad, 0x81, 0x04, 0x85, 0x0a, 0xad, 0x82, 0x04, 0x85, 0x0b, 0xa9, 0x80,
+    0x85, 0x02, 0xa5, 0x02, 0x30, 0xfc, 0x8d, 0x84, 0x04, 0xc9, 0x01, 0xd0, 0x65, 0xee, 0x87, 0x04,
+    0xd0, 0x16, 0xee, 0x88, 0x04, 0xad, 0x80, 0x04, 0xd0, 0x0e, 0xad, 0x88, 0x04, 0xcd, 0x83, 0x04,
+    0xd0, 0x06, 0xa9, 0xff, 0x8d, 0x84, 0x04, 0x60, 0xad, 0x80, 0x04, 0xd0, 0x2f, 0xa9, 0xff, 0xae,
+    0x00, 0x05, 0xd0, 0x03, 0xad, 0x01, 0x05, 0x8d, 0x89, 0x04, 0xa0, 0x01, 0xcc, 0x89, 0x04, 0xf0,
+    0x0a, 0xc8, 0xb9, 0x00, 0x05, 0x20, 0x93, 0x03, 0x4c, 0x5c, 0x03, 0xad, 0x00, 0x05, 0xf0, 0x22,
+    0x8d, 0x81, 0x04, 0xad, 0x01, 0x05, 0x8d, 0x82, 0x04, 0x4c, 0x14, 0x03, 0xa0, 0x00, 0xb9, 0x00,
+    0x05, 0x20, 0x93, 0x03, 0xc8, 0xd0, 0xf7, 0xee, 0x82, 0x04, 0xad, 0x82, 0x04, 0xcd, 0x83, 0x04,
+    0xd0, 0x82, 0x60, 0x4d, 0x86, 0x04, 0x8d, 0x86, 0x04, 0x4a, 0x4a, 0x4a, 0x4a, 0xaa, 0x0a, 0x4d,
+    0x85, 0x04, 0x8d, 0x85, 0x04, 0x8a, 0x4d, 0x86, 0x04, 0x8d, 0x86, 0x04, 0x0a, 0x0a, 0x0a, 0xaa,
+    0x0a, 0x0a, 0x4d, 0x86, 0x04, 0x8d, 0x8a, 0x04, 0x8a, 0x2a, 0x4d, 0x85, 0x04, 0x8d, 0x86, 0x04,
+    0xad, 0x8a, 0x04, 0x8d, 0x85, 0x04, 0x60,
+];
+
+/// Calculates the CRC-16/CCITT-FALSE (polynomial 0x1021, initial value
+/// 0xFFFF) of some data, as returned by [`Cbm::checksum_file`] and
+/// [`Cbm::checksum_track`]
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xffff, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+// Returns the layout of disks in drives which can run the drive routine
+fn checksum_format(device_type: CbmDeviceType) -> Option<DiskImageFormat> {
+    match device_type {
+        CbmDeviceType::Cbm1540 | CbmDeviceType::Cbm1541 => Some(DiskImageFormat::D64),
+        CbmDeviceType::Cbm1570 | CbmDeviceType::Cbm1571 => Some(DiskImageFormat::D71),
+        _ => None,
+    }
+}
+
+/// Checksum functions
+impl Cbm {
+    /// Returns the CRC of a file's contents, as [`crc16`] would calculate
+    /// for the data returned by [`Cbm::load_file_petscii`].
+    ///
+    /// On 1541 and 1571 drives this is calculated by the drive, otherwise
+    /// the file is read to the host.  For REL files, the drive calculates
+    /// the CRC of the records, and other drives return an error.
+    ///
+    /// # Example
+    /// ```ignore
+    /// let data = std::fs::read("game.prg")?;
+    /// let filename = PetsciiString::from_ascii_str("game");
+    /// cbm.save_file_petscii(8, &filename, CbmFileType::PRG, &data)?;
+    /// assert_eq!(cbm.checksum_file(8, &filename)?, crc16(&data));
+    /// ```
+    pub fn checksum_file(&self, device: u8, filename: &PetsciiString) -> Result<u16, Error> {
+        let device_type = self.identify(device)?.device_type;
+        let Some(format) = checksum_format(device_type) else {
+            debug!("Device {device} can't run checksum routine, reading file");
+            return self
+                .load_file_petscii(device, filename)
+                .map(|data| crc16(&data));
+        };
+
+        let drive = DriveBlocks::new(self, device, format);
+        let entry = dir_slots(&drive)?
+            .into_iter()
+            .find(|entry| !entry.is_empty() && &entry.filename == filename)
+            .ok_or_else(|| Error::File {
+                device,
+                message: format!("File {filename} not found"),
+            })?;
+
+        // Stop the routine if the chain is longer than the disk, as it must
+        // loop
+        let max_blocks = (format.total_sectors() >> 8) as u8 + 1;
+        let params = [MODE_CHAIN, entry.track, entry.sector, max_blocks];
+        self.run_checksum_routine(device, &params, entry.blocks as u32)
+    }
+
+    /// Returns the CRC of all of the sectors on a track, in order from
+    /// sector 0, including the link bytes
+    ///
+    /// On 1541 and 1571 drives this is calculated by the drive.  On 1581
+    /// drives the sectors are read to the host.  Other drives aren't
+    /// supported.
+    pub fn checksum_track(&self, device: u8, track: u8) -> Result<u16, Error> {
+        let device_type = self.identify(device)?.device_type;
+        let routine_format = checksum_format(device_type);
+        let format = match (routine_format, device_type) {
+            (Some(format), _) => format,
+            (None, CbmDeviceType::Cbm1581) => DiskImageFormat::D81,
+            (None, _) => {
+                return Err(Error::Validation {
+                    message: format!("Track checksums not supported on {device_type}"),
+                })
+            }
+        };
+        let sectors = format.sectors_per_track(track);
+        if sectors == 0 {
+            return Err(Error::Validation {
+                message: format!("Invalid track {track} for {format}"),
+            });
+        }
+
+        if routine_format.is_none() {
+            debug!("Device {device} can't run checksum routine, reading track");
+            let mut data = Vec::new();
+            for sector in 0..sectors {
+                data.extend(self.read_sector(device, track, sector)?);
+            }
+            return Ok(crc16(&data));
+        }
+
+        let params = [MODE_TRACK, track, 0, sectors];
+        self.run_checksum_routine(device, &params, sectors as u32)
+    }
+
+    // Uploads and runs the drive routine, returning the CRC
+    fn run_checksum_routine(&self, device: u8, params: &[u8], sectors: u32) -> Result<u16, Error> {
+        debug!("Running checksum routine on device {device} with params {params:02x?}");
+        self.write_drive_memory(device, DRIVE_CODE_ADDR, &CHECKSUM_CODE)?;
+        self.write_drive_memory(device, PARAM_ADDR, params)?;
+        let mut result = [0u8; 5];
+        self.run_drive_routine(
+            device,
+            DRIVE_CODE_ADDR,
+            ROUTINE_BASE_TIME + SECTOR_TIME * sectors,
+            RESULT_ADDR,
+            &mut result,
+        )?;
+
+        let crc = u16::from_le_bytes([result[1], result[2]]);
+        let blocks = u16::from_le_bytes([result[3], result[4]]);
+        trace!(
+            "Checksum routine result {} crc {crc:04x} blocks {blocks}",
+            result[0]
+        );
+        match result[0] {
+            RESULT_OK => Ok(crc),
+            RESULT_CHAIN_TOO_LONG => Err(Error::Parse {
+                message: format!("Sector chain on device {device} loops"),
+            }),
+            job_error => {
+                let mut header = [0u8; 2];
+                self.read_drive_memory_dos(
+                    device,
+                    JOB_HEADER_ADDR,
+                    &mut header,
+                    &DosVersion::Dos2,
+                )?;
+                let dc = DeviceChannel::new(device, CBM_CHANNEL_CTRL)?;
+                Err(DeviceError::read_error(
+                    dc,
+                    format!(
+                        "Error {} reading track {} sector {}",
+                        job_error + JOB_ERROR_OFFSET,
+                        header[0],
+                        header[1]
+                    ),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(crc16(b""), 0xffff);
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+    }
+}
@@ -0,0 +1,264 @@
+//! Contains [`CbmClient`], which uses a [`crate::Cbm`] owned by a
+//! [`crate::daemon::Daemon`], so several programs can share one xum1541
+//!
+//! [`CbmClient`]'s functions mirror those of [`crate::Cbm`], so a tool can
+//! use whichever is available - for example connecting to the daemon when
+//! [`crate::Cbm::new`] fails with [`Error::AdapterInUse`].
+//!
+//! # Example
+//! ```ignore
+//! let cbm = CbmClient::connect_default()?;
+//! let status = cbm.get_status(8)?;
+//! let dir = cbm.dir(8, None)?;
+//! ```
+
+use crate::daemon::{
+    Request, RequestMessage, Response, ResponseMessage, DEFAULT_SOCKET_PATH, PROTOCOL_VERSION,
+};
+use crate::disk::{CbmDirListing, CbmFileType};
+use crate::error::Error;
+use crate::string::{AsciiString, PetsciiString};
+use crate::{CbmDeviceInfo, CbmStatus, DEVICE_MAX_NUM, DEVICE_MIN_NUM};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use parking_lot::Mutex;
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::ops::RangeInclusive;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+#[derive(Debug)]
+struct Connection {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    next_id: u64,
+}
+
+/// A connection to a [`crate::daemon::Daemon`].  See the
+/// [module documentation](self).
+///
+/// Calls made from several threads are sent one at a time.
+#[derive(Debug)]
+pub struct CbmClient {
+    connection: Mutex<Connection>,
+}
+
+impl CbmClient {
+    /// Connects to the daemon listening on the socket at `path`
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let writer = UnixStream::connect(path).map_err(|e| Error::Io {
+            message: format!("Failed to connect to daemon at {}: {e}", path.display()),
+        })?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Self {
+            connection: Mutex::new(Connection {
+                reader,
+                writer,
+                next_id: 1,
+            }),
+        })
+    }
+
+    /// Connects to the daemon on [`DEFAULT_SOCKET_PATH`]
+    pub fn connect_default() -> Result<Self, Error> {
+        Self::connect(DEFAULT_SOCKET_PATH)
+    }
+
+    // Sends a request and waits for the daemon's response
+    fn call(&self, request: Request) -> Result<Response, Error> {
+        let mut connection = self.connection.lock();
+        let id = connection.next_id;
+        connection.next_id += 1;
+
+        let message = RequestMessage {
+            version: PROTOCOL_VERSION,
+            id,
+            request,
+        };
+        let mut json = serde_json::to_string(&message).map_err(|e| Error::Parse {
+            message: format!("Failed to encode request: {e}"),
+        })?;
+        json.push('\n');
+        connection.writer.write_all(json.as_bytes())?;
+
+        let mut line = String::new();
+        if connection.reader.read_line(&mut line)? == 0 {
+            return Err(Error::Io {
+                message: "Daemon closed the connection".to_string(),
+            });
+        }
+        let response: ResponseMessage = serde_json::from_str(&line).map_err(|e| Error::Parse {
+            message: format!("Invalid response from daemon: {e}"),
+        })?;
+        if response.id != id {
+            return Err(Error::Parse {
+                message: format!("Expected response {id}, got {}", response.id),
+            });
+        }
+        response.result
+    }
+
+    /// See [`crate::Cbm::identify`]
+    pub fn identify(&self, device: u8) -> Result<CbmDeviceInfo, Error> {
+        match self.call(Request::Identify { device })? {
+            Response::DeviceInfo(info) => Ok(info),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// See [`crate::Cbm::get_status`]
+    pub fn get_status(&self, device: u8) -> Result<CbmStatus, Error> {
+        match self.call(Request::GetStatus { device })? {
+            Response::Status(status) => Ok(status),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// See [`crate::Cbm::scan_bus`]
+    pub fn scan_bus(&self) -> Result<HashMap<u8, CbmDeviceInfo>, Error> {
+        self.scan_bus_range(DEVICE_MIN_NUM..=DEVICE_MAX_NUM)
+    }
+
+    /// See [`crate::Cbm::scan_bus_range`]
+    pub fn scan_bus_range(
+        &self,
+        range: RangeInclusive<u8>,
+    ) -> Result<HashMap<u8, CbmDeviceInfo>, Error> {
+        let request = Request::ScanBusRange {
+            start: *range.start(),
+            end: *range.end(),
+        };
+        match self.call(request)? {
+            Response::Devices(devices) => Ok(devices),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// See [`crate::Cbm::drive_exists`]
+    pub fn drive_exists(&self, device: u8) -> Result<bool, Error> {
+        match self.call(Request::DriveExists { device })? {
+            Response::Exists(exists) => Ok(exists),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// See [`crate::Cbm::dir`]
+    pub fn dir(&self, device: u8, drive_num: Option<u8>) -> Result<CbmDirListing, Error> {
+        match self.call(Request::Dir { device, drive_num })? {
+            Response::Dir(listing) => Ok(listing),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// See [`crate::Cbm::read_file`]
+    pub fn read_file(&self, device: u8, filename: &AsciiString) -> Result<Vec<u8>, Error> {
+        self.data(Request::ReadFile {
+            device,
+            filename: filename.to_string(),
+        })
+    }
+
+    /// See [`crate::Cbm::write_file`]
+    pub fn write_file(&self, device: u8, filename: &AsciiString, data: &[u8]) -> Result<(), Error> {
+        self.done(Request::WriteFile {
+            device,
+            filename: filename.to_string(),
+            data: data.to_vec(),
+        })
+    }
+
+    /// See [`crate::Cbm::delete_file`]
+    pub fn delete_file(&self, device: u8, filename: &AsciiString) -> Result<(), Error> {
+        self.done(Request::DeleteFile {
+            device,
+            filename: filename.to_string(),
+        })
+    }
+
+    /// See [`crate::Cbm::load_file_petscii`]
+    pub fn load_file_petscii(
+        &self,
+        device: u8,
+        filename: &PetsciiString,
+    ) -> Result<Vec<u8>, Error> {
+        self.data(Request::LoadFilePetscii {
+            device,
+            filename: filename.as_bytes().to_vec(),
+        })
+    }
+
+    /// See [`crate::Cbm::read_file_petscii`]
+    pub fn read_file_petscii(
+        &self,
+        device: u8,
+        filename: &PetsciiString,
+        file_type: CbmFileType,
+    ) -> Result<Vec<u8>, Error> {
+        self.data(Request::ReadFilePetscii {
+            device,
+            filename: filename.as_bytes().to_vec(),
+            file_type,
+        })
+    }
+
+    /// See [`crate::Cbm::save_file_petscii`]
+    pub fn save_file_petscii(
+        &self,
+        device: u8,
+        filename: &PetsciiString,
+        file_type: CbmFileType,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        self.done(Request::SaveFilePetscii {
+            device,
+            filename: filename.as_bytes().to_vec(),
+            file_type,
+            data: data.to_vec(),
+        })
+    }
+
+    /// See [`crate::Cbm::send_string_command_ascii`]
+    pub fn send_string_command_ascii(&self, device: u8, command: &str) -> Result<(), Error> {
+        self.done(Request::SendStringCommandAscii {
+            device,
+            command: command.to_string(),
+        })
+    }
+
+    /// See [`crate::Cbm::send_command_petscii`]
+    pub fn send_command_petscii(&self, device: u8, cmd: &PetsciiString) -> Result<(), Error> {
+        self.done(Request::SendCommandPetscii {
+            device,
+            command: cmd.as_bytes().to_vec(),
+        })
+    }
+
+    /// See [`crate::Cbm::reset_bus`]
+    pub fn reset_bus(&self) -> Result<(), Error> {
+        self.done(Request::ResetBus)
+    }
+
+    fn data(&self, request: Request) -> Result<Vec<u8>, Error> {
+        match self.call(request)? {
+            Response::Data(data) => Ok(data),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn done(&self, request: Request) -> Result<(), Error> {
+        match self.call(request)? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> Error {
+    Error::Parse {
+        message: format!("Unexpected response from daemon: {response:?}"),
+    }
+}
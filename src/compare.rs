@@ -0,0 +1,291 @@
+//! Contains functions to compare disks and disk images sector by sector
+//!
+//! [`compare_disks`] works with any pair of [`BlockDevice`]s, so can verify
+//! a physical disk against the image it was written from, or against a
+//! disk in another drive it was copied from.  Differences are reported for
+//! each sector, and for each file using those sectors.
+
+use crate::block::{dir_slots, BlockDevice};
+use crate::diskcheck::SectorUsage;
+use crate::error::Error;
+use crate::image::DiskImageFormat;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// How a sector differs, returned by [`compare_disks`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SectorDifference {
+    /// Both sectors were read, but their contents differ.  `offset` is the
+    /// first byte which differs.
+    Contents { offset: usize },
+    /// The sector couldn't be read from the disk being checked
+    Unreadable { message: String },
+    /// The sector couldn't be read from the reference disk
+    ReferenceUnreadable { message: String },
+}
+
+impl fmt::Display for SectorDifference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SectorDifference::Contents { offset } => {
+                write!(f, "contents differ from byte {offset}")
+            }
+            SectorDifference::Unreadable { message } => write!(f, "unreadable: {message}"),
+            SectorDifference::ReferenceUnreadable { message } => {
+                write!(f, "unreadable on reference: {message}")
+            }
+        }
+    }
+}
+
+/// A file with sectors which differ
+///
+/// # Fields
+///
+/// * `filename` - The file's name, or `(directory)` for the header, BAM and
+///   directory sectors
+/// * `blocks` - Number of sectors the file uses
+/// * `sectors` - The sectors which differ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDifference {
+    pub filename: String,
+    pub blocks: usize,
+    pub sectors: Vec<(u8, u8)>,
+}
+
+/// The result of [`compare_disks`]
+///
+/// # Fields
+///
+/// * `format` - The layout of both disks
+/// * `sectors` - Each sector which differs
+/// * `files` - Each file with sectors which differ, using the reference
+///   disk's directory.  Sectors not used by any file are only included in
+///   `sectors`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskComparison {
+    pub format: DiskImageFormat,
+    pub sectors: BTreeMap<(u8, u8), SectorDifference>,
+    pub files: Vec<FileDifference>,
+}
+
+impl DiskComparison {
+    /// Whether the disks are identical
+    pub fn is_same(&self) -> bool {
+        self.sectors.is_empty()
+    }
+
+    /// Number of differing sectors which aren't used by any file
+    pub fn unused_sectors(&self) -> usize {
+        self.sectors
+            .keys()
+            .filter(|ts| !self.files.iter().any(|file| file.sectors.contains(ts)))
+            .count()
+    }
+}
+
+impl fmt::Display for DiskComparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_same() {
+            return writeln!(f, "Disks are identical");
+        }
+        writeln!(f, "{} sectors differ", self.sectors.len())?;
+        for ((track, sector), difference) in self.sectors.iter() {
+            writeln!(f, "  {track}/{sector}: {difference}")?;
+        }
+        for file in self.files.iter() {
+            writeln!(
+                f,
+                "{}: {} of {} blocks differ",
+                file.filename,
+                file.sectors.len(),
+                file.blocks
+            )?;
+        }
+        let unused = self.unused_sectors();
+        if unused > 0 {
+            writeln!(f, "{unused} differing sectors are not used by any file")?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares a disk with a reference, sector by sector
+///
+/// Sector read errors on either disk are reported as differences.  Other
+/// errors, such as a drive not being ready, are returned.
+///
+/// Differences are mapped to files using the reference's directory, or the
+/// disk's directory if the reference's can't be read.
+///
+/// # Example
+/// ```ignore
+/// let image = DiskImage::load("game.d64")?;
+/// let drive = DriveBlocks::new(&cbm, 8, image.format());
+/// let result = compare_disks(&drive, &image)?;
+/// print!("{result}");
+///
+/// // Or against a disk in another drive
+/// let original = DriveBlocks::new(&cbm, 9, DiskImageFormat::D64);
+/// let result = compare_disks(&drive, &original)?;
+/// ```
+pub fn compare_disks<D, R>(disk: &D, reference: &R) -> Result<DiskComparison, Error>
+where
+    D: BlockDevice + ?Sized,
+    R: BlockDevice + ?Sized,
+{
+    let format = reference.format();
+    if disk.format() != format {
+        return Err(Error::Validation {
+            message: format!("Can't compare {} disk with {format}", disk.format()),
+        });
+    }
+
+    let mut sectors = BTreeMap::new();
+    for track in 1..=format.num_tracks() {
+        for sector in 0..format.sectors_per_track(track) {
+            let difference = match (
+                read_sector(disk, track, sector)?,
+                read_sector(reference, track, sector)?,
+            ) {
+                (Err(message), _) => Some(SectorDifference::Unreadable { message }),
+                (_, Err(message)) => Some(SectorDifference::ReferenceUnreadable { message }),
+                (Ok(block), Ok(expected)) => block
+                    .iter()
+                    .zip(expected.iter())
+                    .position(|(a, b)| a != b)
+                    .map(|offset| SectorDifference::Contents { offset }),
+            };
+            if let Some(difference) = difference {
+                debug!("Sector {track}/{sector} differs: {difference}");
+                sectors.insert((track, sector), difference);
+            }
+        }
+    }
+
+    let files = if sectors.is_empty() {
+        Vec::new()
+    } else {
+        match file_differences(reference, &sectors) {
+            Ok(files) => files,
+            Err(e) => {
+                warn!("Failed to read reference directory, using disk's: {e}");
+                file_differences(disk, &sectors).unwrap_or_else(|e| {
+                    warn!("Failed to read disk directory: {e}");
+                    Vec::new()
+                })
+            }
+        }
+    };
+
+    Ok(DiskComparison {
+        format,
+        sectors,
+        files,
+    })
+}
+
+// Reads a sector, returning the inner error as a string if it's a read error
+// which should be reported as a difference
+fn read_sector<D: BlockDevice + ?Sized>(
+    disk: &D,
+    track: u8,
+    sector: u8,
+) -> Result<Result<Vec<u8>, String>, Error> {
+    match disk.read_block(track, sector) {
+        Ok(block) => Ok(Ok(block)),
+        Err(e @ Error::Status { .. }) => Ok(Err(e.to_string())),
+        Err(e) => Err(e),
+    }
+}
+
+// Maps the differing sectors to the files using them
+fn file_differences<D: BlockDevice + ?Sized>(
+    disk: &D,
+    sectors: &BTreeMap<(u8, u8), SectorDifference>,
+) -> Result<Vec<FileDifference>, Error> {
+    let mut usage = SectorUsage::new(disk.format());
+    usage.add_system(disk)?;
+    let mut problems = Vec::new();
+    for entry in dir_slots(disk)?.iter().filter(|entry| !entry.is_empty()) {
+        usage.add_file(disk, entry, &entry.filename_ascii(), &mut problems);
+    }
+
+    let mut files: Vec<FileDifference> = Vec::new();
+    for &(track, sector) in sectors.keys() {
+        for owner in usage.owners(track, sector) {
+            match files.iter_mut().find(|file| &file.filename == owner) {
+                Some(file) => file.sectors.push((track, sector)),
+                None => files.push(FileDifference {
+                    filename: owner.clone(),
+                    blocks: usage.owned_by(owner),
+                    sectors: vec![(track, sector)],
+                }),
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::CbmFileType;
+    use crate::image::DiskImage;
+    use crate::string::PetsciiString;
+
+    #[test]
+    fn test_compare() {
+        let mut image = DiskImage::new(
+            DiskImageFormat::D64,
+            &PetsciiString::from_ascii_str("compare"),
+            &PetsciiString::from_ascii_str("01"),
+        );
+        let entry = image
+            .write_file(
+                &PetsciiString::from_ascii_str("file"),
+                CbmFileType::PRG,
+                &vec![0x01; 1000],
+            )
+            .unwrap();
+        let reference = image.clone();
+        assert!(compare_disks(&image, &reference).unwrap().is_same());
+
+        let mut block = image
+            .read_sector(entry.track, entry.sector)
+            .unwrap()
+            .to_vec();
+        block[100] = 0x02;
+        image
+            .write_sector(entry.track, entry.sector, &block)
+            .unwrap();
+        image.write_sector(1, 0, &[0xff; 256]).unwrap();
+
+        let result = compare_disks(&image, &reference).unwrap();
+        assert!(!result.is_same());
+        assert_eq!(result.sectors.len(), 2);
+        assert_eq!(
+            result.sectors[&(entry.track, entry.sector)],
+            SectorDifference::Contents { offset: 100 }
+        );
+        assert_eq!(
+            result.files,
+            vec![FileDifference {
+                filename: "file".to_string(),
+                blocks: 4,
+                sectors: vec![(entry.track, entry.sector)],
+            }]
+        );
+        assert_eq!(result.unused_sectors(), 1);
+
+        let d71 = DiskImage::new(
+            DiskImageFormat::D71,
+            &PetsciiString::from_ascii_str("compare"),
+            &PetsciiString::from_ascii_str("01"),
+        );
+        assert!(compare_disks(&d71, &reference).is_err());
+    }
+}
@@ -0,0 +1,256 @@
+//! Contains [`OpContext`], which reports the progress of long running
+//! operations and allows them to be cancelled
+//!
+//! Operations which take an [`OpContext`] have a `_ctx` suffix, for example
+//! [`Cbm::load_file_petscii_ctx`].  They call the context's progress
+//! function as data is transferred, and check whether the operation has
+//! been cancelled between transfers.  A cancelled operation cleans up the
+//! bus and closes any open file, before returning [`Error::Cancelled`].
+//!
+//! An [`OpContext`] is cheap to clone, and clones share the cancellation
+//! state, so one clone can be passed to the operation and another used to
+//! cancel it from a different thread.
+//!
+//! # Example
+//! ```ignore
+//! let entry = ...; // CbmFileEntry from a directory listing
+//! let ctx = OpContext::new()
+//!     .with_total(entry.max_size().unwrap_or(0))
+//!     .with_progress(|progress| println!("{progress}"));
+//! let canceller = ctx.clone();
+//! ctrlc::set_handler(move || canceller.cancel())?;
+//! let data = cbm.load_file_petscii_ctx(8, &filename, &ctx)?;
+//! ```
+//!
+//! [`Cbm::load_file_petscii_ctx`]: crate::Cbm::load_file_petscii_ctx
+
+use crate::error::Error;
+use crate::CbmStatus;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use xum1541::DeviceChannel;
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Units [`Progress`] is measured in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressUnit {
+    Bytes,
+    Blocks,
+}
+
+impl fmt::Display for ProgressUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let output = match self {
+            ProgressUnit::Bytes => "bytes",
+            ProgressUnit::Blocks => "blocks",
+        };
+        write!(f, "{}", output)
+    }
+}
+
+/// Progress of an operation, passed to an [`OpContext`]'s progress function
+///
+/// * `done` - Amount transferred so far
+/// * `total` - Expected total, if known.  This may be an estimate, for
+///   example from [`crate::CbmFileEntry::max_size`], so `done` can exceed it
+/// * `unit` - What `done` and `total` count
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub done: u64,
+    pub total: Option<u64>,
+    pub unit: ProgressUnit,
+}
+
+impl Progress {
+    /// Returns the fraction of the operation completed, from 0.0 to 1.0, if
+    /// the total is known
+    pub fn fraction(&self) -> Option<f64> {
+        match self.total {
+            Some(0) | None => None,
+            Some(total) => Some((self.done as f64 / total as f64).min(1.0)),
+        }
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.total {
+            Some(total) => write!(f, "{}/{} {}", self.done, total, self.unit),
+            None => write!(f, "{} {}", self.done, self.unit),
+        }
+    }
+}
+
+type ProgressFn = dyn Fn(&Progress) + Send + Sync;
+
+/// Context for a long running operation, used to report progress and to
+/// cancel it.  See the [module documentation](self).
+#[derive(Clone, Default)]
+pub struct OpContext {
+    cancelled: Arc<AtomicBool>,
+    progress: Option<Arc<ProgressFn>>,
+    total: Option<u64>,
+}
+
+impl fmt::Debug for OpContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpContext")
+            .field("cancelled", &self.is_cancelled())
+            .field("progress", &self.progress.is_some())
+            .field("total", &self.total)
+            .finish()
+    }
+}
+
+impl OpContext {
+    /// Creates a context with no progress function
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the function called as the operation progresses
+    pub fn with_progress<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Progress) + Send + Sync + 'static,
+    {
+        self.progress = Some(Arc::new(f));
+        self
+    }
+
+    /// Sets the expected total, for operations which can't determine it
+    /// themselves, such as reading a file
+    pub fn with_total(mut self, total: u64) -> Self {
+        self.total = Some(total);
+        self
+    }
+
+    /// Cancels the operation using this context, or any clone of it.  The
+    /// operation stops at the next point it checks for cancellation.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether [`OpContext::cancel`] has been called
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    // Returns Error::Cancelled if the operation has been cancelled
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        if self.is_cancelled() {
+            Err(Error::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Reports progress, using the total set by the caller if the operation
+    // doesn't know it
+    pub(crate) fn report(&self, done: u64, total: Option<u64>, unit: ProgressUnit) {
+        if let Some(progress) = &self.progress {
+            progress(&Progress {
+                done,
+                total: total.or(self.total),
+                unit,
+            });
+        }
+    }
+}
+
+// The bus calls needed to abandon a file open on a channel.  Implemented by
+// the bus used by Cbm, and by a recorder in the tests.
+pub(crate) trait FileCleanup {
+    fn untalk(&mut self) -> Result<(), Error>;
+    fn unlisten(&mut self) -> Result<(), Error>;
+    fn close(&mut self, dc: DeviceChannel) -> Result<(), Error>;
+    fn read_status(&mut self, device: u8) -> Result<CbmStatus, Error>;
+}
+
+// Cleans up after an operation on a file open on this channel is cancelled:
+// releases the bus, closes the file and reads the status, which clears the
+// drive's error channel.  Closing a file being written leaves the data
+// written so far on the disk, rather than an unclosed (splat) file.
+pub(crate) fn abandon_file<B: FileCleanup>(bus: &mut B, dc: DeviceChannel, writing: bool) {
+    let _ = if writing {
+        bus.unlisten()
+    } else {
+        bus.untalk()
+    };
+    if let Err(e) = bus.close(dc) {
+        debug!("Failed to close {dc} after cancel: {e}");
+    }
+    match bus.read_status(dc.device()) {
+        Ok(status) => debug!("Status after cancel: {status}"),
+        Err(e) => debug!("Failed to read status after cancel: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // Records the cleanup calls made
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+
+    impl FileCleanup for Recorder {
+        fn untalk(&mut self) -> Result<(), Error> {
+            self.0.push("untalk".to_string());
+            Ok(())
+        }
+
+        fn unlisten(&mut self) -> Result<(), Error> {
+            self.0.push("unlisten".to_string());
+            Ok(())
+        }
+
+        fn close(&mut self, dc: DeviceChannel) -> Result<(), Error> {
+            self.0.push(format!("close {}", dc.channel()));
+            Ok(())
+        }
+
+        fn read_status(&mut self, device: u8) -> Result<CbmStatus, Error> {
+            self.0.push("status".to_string());
+            CbmStatus::new("00, OK,00,00\r", device)
+        }
+    }
+
+    #[test]
+    fn test_context() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_clone = Arc::clone(&seen);
+        let ctx = OpContext::new()
+            .with_total(508)
+            .with_progress(move |progress| seen_clone.lock().unwrap().push(*progress));
+
+        ctx.report(254, None, ProgressUnit::Bytes);
+        ctx.report(3, Some(683), ProgressUnit::Blocks);
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen[0].to_string(), "254/508 bytes");
+        assert_eq!(seen[0].fraction(), Some(0.5));
+        assert_eq!(seen[1].total, Some(683));
+
+        let canceller = ctx.clone();
+        assert!(ctx.check().is_ok());
+        canceller.cancel();
+        assert_eq!(ctx.check(), Err(Error::Cancelled));
+    }
+
+    #[test]
+    fn test_abandon_file() {
+        let dc = DeviceChannel::new(8, 2).unwrap();
+
+        let mut bus = Recorder::default();
+        abandon_file(&mut bus, dc, false);
+        assert_eq!(bus.0, ["untalk", "close 2", "status"]);
+
+        let mut bus = Recorder::default();
+        abandon_file(&mut bus, dc, true);
+        assert_eq!(bus.0, ["unlisten", "close 2", "status"]);
+    }
+}
@@ -0,0 +1,472 @@
+//! Contains functions to copy files between drives
+//!
+//! Files can be copied without passing through the host, as on the IEC bus
+//! one device can talk while another listens.  Within a single unit, such
+//! as a dual drive, the DOS copy command is used instead, so the data never
+//! leaves the unit.  Either way [`Cbm::copy_file`] can fall back to reading
+//! the file to the host and writing it back out.
+
+use crate::cbm::Cbm;
+use crate::disk::CbmFileType;
+use crate::error::Error;
+use crate::string::PetsciiString;
+use crate::validate::{validate_device, DeviceValidation};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use xum1541::DeviceChannel;
+
+use std::fmt;
+
+// Channels used for the source and destination files
+const SOURCE_CHANNEL: u8 = 2;
+const DEST_CHANNEL: u8 = 3;
+
+/// How a file is copied by [`Cbm::copy_file`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyMethod {
+    /// Uses [`CopyMethod::DriveCommand`] within a unit, otherwise
+    /// [`CopyMethod::Direct`], falling back to [`CopyMethod::HostBuffered`]
+    /// if the direct copy fails
+    Auto,
+
+    /// The source device talks and the destination listens, so the data
+    /// goes straight between them.  Not supported for REL files.
+    Direct,
+
+    /// The DOS copy command, for copies within a single unit
+    DriveCommand,
+
+    /// The file is read to the host and then written out.  Not supported
+    /// for REL files.
+    HostBuffered,
+}
+
+impl fmt::Display for CopyMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let output = match self {
+            CopyMethod::Auto => "auto",
+            CopyMethod::Direct => "direct",
+            CopyMethod::DriveCommand => "drive command",
+            CopyMethod::HostBuffered => "host buffered",
+        };
+        write!(f, "{}", output)
+    }
+}
+
+/// The source or destination of a copy
+///
+/// * `device` - Device number
+/// * `drive` - Drive number within the unit, 0 except for the second drive
+///   of a dual drive
+/// * `filename` - Filename in PETSCII.  Does not include the drive number,
+///   suffix or file type
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopyEndpoint {
+    pub device: u8,
+    pub drive: u8,
+    pub filename: PetsciiString,
+}
+
+impl CopyEndpoint {
+    /// Creates an endpoint for a file on drive 0 of a device
+    pub fn new(device: u8, filename: &PetsciiString) -> Self {
+        Self {
+            device,
+            drive: 0,
+            filename: filename.clone(),
+        }
+    }
+
+    /// Sets the drive number within the unit
+    pub fn with_drive(mut self, drive: u8) -> Self {
+        self.drive = drive;
+        self
+    }
+
+    // Returns the filename prefixed with the drive number
+    fn path(&self) -> Vec<u8> {
+        let mut path = format!("{}:", self.drive).into_bytes();
+        path.extend_from_slice(self.filename.as_bytes());
+        path
+    }
+
+    // Returns the name used to open the file, of the form "0:name,P,R"
+    fn open_name(&self, file_type: CbmFileType, mode: &[u8]) -> PetsciiString {
+        let mut open_name = self.path();
+        open_name.extend_from_slice(file_type._to_suffix().as_bytes());
+        open_name.extend_from_slice(mode);
+        PetsciiString::from_petscii_bytes(&open_name)
+    }
+}
+
+impl fmt::Display for CopyEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}:{}", self.device, self.drive, self.filename)
+    }
+}
+
+// Returns the DOS copy command, of the form "C1:dest=0:source"
+fn copy_command(from: &CopyEndpoint, to: &CopyEndpoint) -> PetsciiString {
+    let mut cmd = b"C".to_vec();
+    cmd.extend(to.path());
+    cmd.push(b'=');
+    cmd.extend(from.path());
+    PetsciiString::from_petscii_bytes(&cmd)
+}
+
+// Bus operations used by a direct copy, implemented by the bus in cbm.rs
+pub(crate) trait DirectCopyBus {
+    fn open_file(&mut self, dc: DeviceChannel, name: &PetsciiString) -> Result<(), Error>;
+    fn close_file(&mut self, dc: DeviceChannel) -> Result<(), Error>;
+    fn transfer(
+        &mut self,
+        source: DeviceChannel,
+        dest: DeviceChannel,
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<usize, Error>;
+    fn check_status(&mut self, device: u8) -> Result<(), Error>;
+}
+
+// Why a direct copy failed, and whether it had created the destination file
+// by then.  If not, any file with that name was already there, so mustn't
+// be scratched.
+#[derive(Debug)]
+pub(crate) struct DirectCopyError {
+    pub error: Error,
+    pub dest_created: bool,
+}
+
+// Copies a file directly from one device to another.  The destination is
+// told to listen and the source to talk on the bus, so the bytes flow
+// between them, with the xum1541 also listening in order to report
+// progress.
+pub(crate) fn copy_direct<B: DirectCopyBus>(
+    bus: &mut B,
+    source: DeviceChannel,
+    source_name: &PetsciiString,
+    dest: DeviceChannel,
+    dest_name: &PetsciiString,
+    progress: &mut dyn FnMut(usize),
+) -> Result<usize, DirectCopyError> {
+    let not_created = |error| DirectCopyError {
+        error,
+        dest_created: false,
+    };
+    bus.open_file(source, source_name).map_err(not_created)?;
+    bus.open_file(dest, dest_name)
+        .inspect_err(|_| {
+            // Clean up
+            let _ = bus.close_file(source);
+        })
+        .map_err(not_created)?;
+
+    let copy_result = bus.transfer(source, dest, progress);
+    let source_close = bus.close_file(source);
+    let dest_close = bus.close_file(dest);
+
+    // As when saving, errors such as the disk being full are reported
+    // once the file has been closed
+    copy_result
+        .and_then(|copied| source_close.and(dest_close).map(|_| copied))
+        .and_then(|copied| bus.check_status(dest.device()).map(|_| copied))
+        .map_err(|error| DirectCopyError {
+            error,
+            dest_created: true,
+        })
+}
+
+// Resolves Auto to the first method to try, and checks the method can be
+// used for this copy
+fn choose_method(
+    from: &CopyEndpoint,
+    to: &CopyEndpoint,
+    file_type: CbmFileType,
+    method: CopyMethod,
+) -> Result<CopyMethod, Error> {
+    let same_unit = from.device == to.device;
+    let method = match method {
+        CopyMethod::Auto if same_unit => CopyMethod::DriveCommand,
+        CopyMethod::Auto => CopyMethod::Direct,
+        method => method,
+    };
+    let message = match method {
+        CopyMethod::DriveCommand if !same_unit => {
+            "The drive copy command only works within a unit".to_string()
+        }
+        CopyMethod::Direct if same_unit => {
+            "A device can't talk and listen at the same time".to_string()
+        }
+        CopyMethod::Direct | CopyMethod::HostBuffered
+            if matches!(file_type, CbmFileType::REL | CbmFileType::Unknown) =>
+        {
+            format!("Can't copy file of type {file_type:?} using {method} copy")
+        }
+        _ => return Ok(method),
+    };
+    Err(Error::Validation { message })
+}
+
+/// Drive to drive copy functions
+impl Cbm {
+    /// Copies a file, returning the [`CopyMethod`] used
+    ///
+    /// `progress` is called with the number of bytes copied so far, as the
+    /// copy proceeds.  It isn't called for [`CopyMethod::DriveCommand`]
+    /// copies, as these happen entirely within the drive, and only once the
+    /// file has been written for [`CopyMethod::HostBuffered`] copies.
+    ///
+    /// If a direct copy fails when using [`CopyMethod::Auto`] after the
+    /// destination file has been created, the partly written file is
+    /// scratched and the copy retried via the host.  If it fails before
+    /// then - for example because the source doesn't exist, or a file with
+    /// the destination name already does - the error is returned, leaving
+    /// any existing destination file alone.
+    ///
+    /// # Example
+    /// ```ignore
+    /// let from = CopyEndpoint::new(8, &PetsciiString::from_ascii_str("game"));
+    /// let to = CopyEndpoint::new(9, &PetsciiString::from_ascii_str("game"));
+    /// let method = cbm.copy_file(&from, &to, CbmFileType::PRG, CopyMethod::Auto, |bytes| {
+    ///     println!("Copied {bytes} bytes");
+    /// })?;
+    /// ```
+    pub fn copy_file<F>(
+        &self,
+        from: &CopyEndpoint,
+        to: &CopyEndpoint,
+        file_type: CbmFileType,
+        method: CopyMethod,
+        mut progress: F,
+    ) -> Result<CopyMethod, Error>
+    where
+        F: FnMut(usize),
+    {
+        trace!("Cbm::copy_file from: {from} to: {to} type: {file_type:?} method: {method}");
+        validate_device(Some(from.device), DeviceValidation::Required)?;
+        validate_device(Some(to.device), DeviceValidation::Required)?;
+
+        match choose_method(from, to, file_type, method)? {
+            CopyMethod::DriveCommand => {
+                self.send_command_petscii(from.device, &copy_command(from, to))?;
+                let status: Result<(), Error> = self.get_status(from.device)?.into();
+                status.map(|_| CopyMethod::DriveCommand)
+            }
+            CopyMethod::Direct => {
+                let source = DeviceChannel::new(from.device, SOURCE_CHANNEL)?;
+                let dest = DeviceChannel::new(to.device, DEST_CHANNEL)?;
+                let result = self.copy_file_direct(
+                    source,
+                    &from.open_name(file_type, b",R"),
+                    dest,
+                    &to.open_name(file_type, b",W"),
+                    &mut progress,
+                );
+                match result {
+                    Ok(copied) => {
+                        debug!("Copied {copied} bytes directly from {from} to {to}");
+                        Ok(CopyMethod::Direct)
+                    }
+                    Err(failed) if method == CopyMethod::Auto && failed.dest_created => {
+                        let e = failed.error;
+                        warn!("Direct copy from {from} to {to} failed, copying via host: {e}");
+                        self.scratch_partial_copy(to);
+                        self.copy_file_buffered(from, to, file_type, &mut progress)
+                    }
+                    Err(failed) => Err(failed.error),
+                }
+            }
+            _ => self.copy_file_buffered(from, to, file_type, &mut progress),
+        }
+    }
+
+    // Copies a file by reading it to the host and writing it out
+    fn copy_file_buffered(
+        &self,
+        from: &CopyEndpoint,
+        to: &CopyEndpoint,
+        file_type: CbmFileType,
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<CopyMethod, Error> {
+        let source_path = PetsciiString::from_petscii_bytes(&from.path());
+        let dest_path = PetsciiString::from_petscii_bytes(&to.path());
+        let data = self.read_file_petscii(from.device, &source_path, file_type)?;
+        self.save_file_petscii(to.device, &dest_path, file_type, &data)?;
+        progress(data.len());
+        Ok(CopyMethod::HostBuffered)
+    }
+
+    // Removes whatever a failed direct copy left behind, so the file can be
+    // written again
+    fn scratch_partial_copy(&self, to: &CopyEndpoint) {
+        let mut cmd = b"S".to_vec();
+        cmd.extend(to.path());
+        let result = self
+            .send_command_petscii(to.device, &PetsciiString::from_petscii_bytes(&cmd))
+            .and_then(|_| self.get_status(to.device));
+        if let Err(e) = result {
+            warn!("Failed to scratch partial copy {to}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cbmtype::CbmStatus;
+
+    // Records the bus operations made by a direct copy, failing those
+    // given
+    #[derive(Default)]
+    struct Recorder {
+        calls: Vec<String>,
+        fail_open: Option<(u8, &'static str)>,
+        fail_transfer: bool,
+    }
+
+    fn status_error(status: &str, device: u8) -> Error {
+        Error::Status {
+            status: CbmStatus::new(status, device).unwrap(),
+        }
+    }
+
+    impl DirectCopyBus for Recorder {
+        fn open_file(&mut self, dc: DeviceChannel, name: &PetsciiString) -> Result<(), Error> {
+            let name = String::from_utf8_lossy(name.as_bytes());
+            self.calls.push(format!("open {} {name}", dc.device()));
+            match self.fail_open {
+                Some((device, status)) if device == dc.device() => {
+                    Err(status_error(status, device))
+                }
+                _ => Ok(()),
+            }
+        }
+
+        fn close_file(&mut self, dc: DeviceChannel) -> Result<(), Error> {
+            self.calls.push(format!("close {}", dc.device()));
+            Ok(())
+        }
+
+        fn transfer(
+            &mut self,
+            source: DeviceChannel,
+            _dest: DeviceChannel,
+            progress: &mut dyn FnMut(usize),
+        ) -> Result<usize, Error> {
+            self.calls.push("transfer".to_string());
+            progress(254);
+            if self.fail_transfer {
+                Err(status_error("20,READ ERROR,18,01", source.device()))
+            } else {
+                Ok(254)
+            }
+        }
+
+        fn check_status(&mut self, device: u8) -> Result<(), Error> {
+            self.calls.push(format!("status {device}"));
+            Ok(())
+        }
+    }
+
+    fn copy(bus: &mut Recorder) -> Result<usize, DirectCopyError> {
+        let from = endpoint(8, 0, "game");
+        let to = endpoint(9, 0, "game");
+        copy_direct(
+            bus,
+            DeviceChannel::new(8, SOURCE_CHANNEL).unwrap(),
+            &from.open_name(CbmFileType::PRG, b",R"),
+            DeviceChannel::new(9, DEST_CHANNEL).unwrap(),
+            &to.open_name(CbmFileType::PRG, b",W"),
+            &mut |_| (),
+        )
+    }
+
+    #[test]
+    fn test_copy_direct() {
+        let mut bus = Recorder::default();
+        assert_eq!(copy(&mut bus).unwrap(), 254);
+        assert_eq!(
+            bus.calls,
+            [
+                "open 8 0:GAME,P,R",
+                "open 9 0:GAME,P,W",
+                "transfer",
+                "close 8",
+                "close 9",
+                "status 9"
+            ]
+        );
+
+        // The destination had been created, so the partial copy needs
+        // scratching
+        let mut bus = Recorder {
+            fail_transfer: true,
+            ..Default::default()
+        };
+        assert!(copy(&mut bus).unwrap_err().dest_created);
+        assert!(bus.calls.contains(&"close 9".to_string()));
+    }
+
+    #[test]
+    fn test_copy_direct_dest_exists() {
+        // An existing destination file mustn't be reported as created, so
+        // it isn't scratched
+        let mut bus = Recorder {
+            fail_open: Some((9, "63,FILE EXISTS,00,00")),
+            ..Default::default()
+        };
+        let failed = copy(&mut bus).unwrap_err();
+        assert!(!failed.dest_created);
+        assert!(failed.error.to_string().contains("FILE EXISTS"));
+        assert_eq!(
+            bus.calls,
+            ["open 8 0:GAME,P,R", "open 9 0:GAME,P,W", "close 8"]
+        );
+
+        let mut bus = Recorder {
+            fail_open: Some((8, "62,FILE NOT FOUND,00,00")),
+            ..Default::default()
+        };
+        assert!(!copy(&mut bus).unwrap_err().dest_created);
+        assert_eq!(bus.calls, ["open 8 0:GAME,P,R"]);
+    }
+
+    fn endpoint(device: u8, drive: u8, name: &str) -> CopyEndpoint {
+        CopyEndpoint::new(device, &PetsciiString::from_ascii_str(name)).with_drive(drive)
+    }
+
+    #[test]
+    fn test_names() {
+        let from = endpoint(8, 0, "game");
+        let to = endpoint(8, 1, "copy");
+        assert_eq!(
+            from.open_name(CbmFileType::SEQ, b",R").as_bytes(),
+            b"0:GAME,S,R"
+        );
+        assert_eq!(copy_command(&from, &to).as_bytes(), b"C1:COPY=0:GAME");
+    }
+
+    #[test]
+    fn test_choose_method() {
+        let a = endpoint(8, 0, "game");
+        let b = endpoint(8, 1, "game");
+        let c = endpoint(9, 0, "game");
+        let rel = CbmFileType::REL;
+        let prg = CbmFileType::PRG;
+        assert_eq!(
+            choose_method(&a, &b, rel, CopyMethod::Auto).unwrap(),
+            CopyMethod::DriveCommand
+        );
+        assert_eq!(
+            choose_method(&a, &c, prg, CopyMethod::Auto).unwrap(),
+            CopyMethod::Direct
+        );
+        assert!(choose_method(&a, &c, rel, CopyMethod::Auto).is_err());
+        assert!(choose_method(&a, &b, prg, CopyMethod::Direct).is_err());
+        assert!(choose_method(&a, &c, prg, CopyMethod::DriveCommand).is_err());
+        assert_eq!(
+            choose_method(&a, &b, prg, CopyMethod::HostBuffered).unwrap(),
+            CopyMethod::HostBuffered
+        );
+    }
+}
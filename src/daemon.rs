@@ -0,0 +1,399 @@
+//! Contains [`Daemon`], which shares a [`Cbm`] with other processes over a
+//! Unix socket, and the protocol it uses
+//!
+//! Only one process can own a xum1541.  The daemon owns it instead, and
+//! serves any number of clients, such as [`crate::CbmClient`] and the
+//! rs1541d binary.  Requests from all clients are queued and run one at a
+//! time, in the order they arrive.
+//!
+//! # Protocol
+//!
+//! Each message is a single line of JSON.  The client sends a
+//! [`RequestMessage`] and the daemon replies with a [`ResponseMessage`]
+//! carrying the same `id`.  Both contain the [`PROTOCOL_VERSION`], and the
+//! daemon rejects requests for a different version with
+//! [`Error::Validation`].  Data and PETSCII filenames are sent as arrays of
+//! bytes.
+//!
+//! ```text
+//! {"version":1,"id":1,"request":{"GetStatus":{"device":8}}}
+//! {"version":1,"id":1,"result":{"Ok":{"Status":{...}}}}
+//! ```
+//!
+//! # Example
+//! ```ignore
+//! let cbm = Cbm::new(None, None)?;
+//! Daemon::new(cbm, DEFAULT_SOCKET_PATH).run()?;
+//! ```
+
+use crate::cbm::Cbm;
+use crate::disk::{CbmDirListing, CbmFileType};
+use crate::error::Error;
+use crate::string::{AsciiString, PetsciiString};
+use crate::{CbmDeviceInfo, CbmStatus};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+/// Version of the protocol spoken by this [`Daemon`] and
+/// [`crate::CbmClient`]
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Socket used by the rs1541d binary and [`crate::CbmClient::connect_default`]
+pub const DEFAULT_SOCKET_PATH: &str = "/tmp/rs1541.sock";
+
+/// An operation requested of the daemon.  Each mirrors the [`Cbm`] function
+/// of the same name.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Request {
+    Identify {
+        device: u8,
+    },
+    GetStatus {
+        device: u8,
+    },
+    ScanBus,
+    ScanBusRange {
+        start: u8,
+        end: u8,
+    },
+    DriveExists {
+        device: u8,
+    },
+    Dir {
+        device: u8,
+        drive_num: Option<u8>,
+    },
+    ReadFile {
+        device: u8,
+        filename: String,
+    },
+    WriteFile {
+        device: u8,
+        filename: String,
+        data: Vec<u8>,
+    },
+    DeleteFile {
+        device: u8,
+        filename: String,
+    },
+    LoadFilePetscii {
+        device: u8,
+        filename: Vec<u8>,
+    },
+    ReadFilePetscii {
+        device: u8,
+        filename: Vec<u8>,
+        file_type: CbmFileType,
+    },
+    SaveFilePetscii {
+        device: u8,
+        filename: Vec<u8>,
+        file_type: CbmFileType,
+        data: Vec<u8>,
+    },
+    SendStringCommandAscii {
+        device: u8,
+        command: String,
+    },
+    SendCommandPetscii {
+        device: u8,
+        command: Vec<u8>,
+    },
+    ResetBus,
+}
+
+/// The result of a successful [`Request`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Response {
+    DeviceInfo(CbmDeviceInfo),
+    Status(CbmStatus),
+    Devices(HashMap<u8, CbmDeviceInfo>),
+    Exists(bool),
+    Dir(CbmDirListing),
+    Data(Vec<u8>),
+    Done,
+}
+
+/// A [`Request`] as sent to the daemon
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestMessage {
+    pub version: u32,
+    pub id: u64,
+    pub request: Request,
+}
+
+/// The daemon's reply to a [`RequestMessage`]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseMessage {
+    pub version: u32,
+    pub id: u64,
+    pub result: Result<Response, Error>,
+}
+
+// Runs a request against the Cbm
+fn handle_request(cbm: &Cbm, request: Request) -> Result<Response, Error> {
+    match request {
+        Request::Identify { device } => cbm.identify(device).map(Response::DeviceInfo),
+        Request::GetStatus { device } => cbm.get_status(device).map(Response::Status),
+        Request::ScanBus => cbm.scan_bus().map(Response::Devices),
+        Request::ScanBusRange { start, end } => {
+            cbm.scan_bus_range(start..=end).map(Response::Devices)
+        }
+        Request::DriveExists { device } => cbm.drive_exists(device).map(Response::Exists),
+        Request::Dir { device, drive_num } => cbm.dir(device, drive_num).map(Response::Dir),
+        Request::ReadFile { device, filename } => cbm
+            .read_file(device, &ascii(&filename)?)
+            .map(Response::Data),
+        Request::WriteFile {
+            device,
+            filename,
+            data,
+        } => cbm
+            .write_file(device, &ascii(&filename)?, &data)
+            .map(|_| Response::Done),
+        Request::DeleteFile { device, filename } => cbm
+            .delete_file(device, &ascii(&filename)?)
+            .map(|_| Response::Done),
+        Request::LoadFilePetscii { device, filename } => cbm
+            .load_file_petscii(device, &PetsciiString::from_petscii_bytes(&filename))
+            .map(Response::Data),
+        Request::ReadFilePetscii {
+            device,
+            filename,
+            file_type,
+        } => cbm
+            .read_file_petscii(
+                device,
+                &PetsciiString::from_petscii_bytes(&filename),
+                file_type,
+            )
+            .map(Response::Data),
+        Request::SaveFilePetscii {
+            device,
+            filename,
+            file_type,
+            data,
+        } => cbm
+            .save_file_petscii(
+                device,
+                &PetsciiString::from_petscii_bytes(&filename),
+                file_type,
+                &data,
+            )
+            .map(|_| Response::Done),
+        Request::SendStringCommandAscii { device, command } => cbm
+            .send_string_command_ascii(device, &command)
+            .map(|_| Response::Done),
+        Request::SendCommandPetscii { device, command } => cbm
+            .send_command_petscii(device, &PetsciiString::from_petscii_bytes(&command))
+            .map(|_| Response::Done),
+        Request::ResetBus => cbm.reset_bus().map(|_| Response::Done),
+    }
+}
+
+fn ascii(filename: &str) -> Result<AsciiString, Error> {
+    AsciiString::try_from(filename).map_err(|e| Error::Validation {
+        message: format!("Filename {filename} is not ASCII: {e}"),
+    })
+}
+
+// A request queued to the worker, with where to send the result
+type Job = (Request, mpsc::Sender<Result<Response, Error>>);
+
+/// Serves a [`Cbm`] to clients on a Unix socket.  See the
+/// [module documentation](self).
+#[derive(Debug)]
+pub struct Daemon {
+    cbm: Cbm,
+    path: PathBuf,
+}
+
+impl Daemon {
+    /// Creates a daemon which will serve this [`Cbm`] on the socket at `path`
+    pub fn new<P: AsRef<Path>>(cbm: Cbm, path: P) -> Self {
+        Self {
+            cbm,
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Returns the socket path
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Listens on the socket and serves clients.  Only returns if the socket
+    /// can't be set up.
+    ///
+    /// A socket left behind by a daemon which has exited is replaced, but if
+    /// another daemon is still listening this fails.
+    pub fn run(self) -> Result<(), Error> {
+        let listener = bind(&self.path)?;
+        info!("Listening on {}", self.path.display());
+        let cbm = self.cbm;
+        serve(listener, move |request| handle_request(&cbm, request))
+    }
+}
+
+fn bind(path: &Path) -> Result<UnixListener, Error> {
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(Error::Io {
+                    message: format!("Another daemon is listening on {}", path.display()),
+                });
+            }
+            debug!("Removing stale socket {}", path.display());
+            std::fs::remove_file(path)?;
+            Ok(UnixListener::bind(path)?)
+        }
+        result => Ok(result?),
+    }
+}
+
+// Accepts connections, passing their requests to a single worker thread
+// which runs them in turn using the handler
+fn serve<H>(listener: UnixListener, mut handler: H) -> Result<(), Error>
+where
+    H: FnMut(Request) -> Result<Response, Error> + Send + 'static,
+{
+    let (jobs, queue) = mpsc::channel::<Job>();
+    thread::Builder::new()
+        .name("rs1541d-bus".to_string())
+        .spawn(move || {
+            for (request, reply) in queue {
+                trace!("Running request {request:?}");
+                let _ = reply.send(handler(request));
+            }
+        })?;
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let jobs = jobs.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve_client(stream, jobs) {
+                        debug!("Client disconnected: {e}");
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept connection: {e}"),
+        }
+    }
+    Ok(())
+}
+
+// Reads requests from a client until it disconnects, replying to each
+fn serve_client(stream: UnixStream, jobs: mpsc::Sender<Job>) -> Result<(), Error> {
+    debug!("Client connected");
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let (id, result) = match serde_json::from_str::<RequestMessage>(&line) {
+            Ok(message) if message.version != PROTOCOL_VERSION => (
+                message.id,
+                Err(Error::Validation {
+                    message: format!(
+                        "Unsupported protocol version {}, expected {PROTOCOL_VERSION}",
+                        message.version
+                    ),
+                }),
+            ),
+            Ok(message) => {
+                let (reply, result) = mpsc::channel();
+                jobs.send((message.request, reply))
+                    .map_err(|_| worker_stopped())?;
+                (message.id, result.recv().map_err(|_| worker_stopped())?)
+            }
+            Err(e) => (
+                0,
+                Err(Error::Parse {
+                    message: format!("Invalid request: {e}"),
+                }),
+            ),
+        };
+
+        let response = ResponseMessage {
+            version: PROTOCOL_VERSION,
+            id,
+            result,
+        };
+        let mut json = serde_json::to_string(&response).map_err(|e| Error::Parse {
+            message: format!("Failed to encode response: {e}"),
+        })?;
+        json.push('\n');
+        writer.write_all(json.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn worker_stopped() -> Error {
+    Error::Io {
+        message: "Daemon worker has stopped".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::CbmClient;
+    use crate::CbmErrorNumber;
+
+    #[test]
+    fn test_request_json() {
+        let message = RequestMessage {
+            version: PROTOCOL_VERSION,
+            id: 1,
+            request: Request::GetStatus { device: 8 },
+        };
+        let json = serde_json::to_string(&message).unwrap();
+        assert_eq!(
+            json,
+            r#"{"version":1,"id":1,"request":{"GetStatus":{"device":8}}}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<RequestMessage>(&json).unwrap(),
+            message
+        );
+    }
+
+    #[test]
+    fn test_client_and_daemon() {
+        let path = std::env::temp_dir().join(format!("rs1541-test-{}.sock", std::process::id()));
+        let listener = bind(&path).unwrap();
+        thread::spawn(move || {
+            serve(listener, |request| match request {
+                Request::GetStatus { device } => {
+                    CbmStatus::new("00, OK,00,00\r", device).map(Response::Status)
+                }
+                Request::LoadFilePetscii { filename, .. } => Ok(Response::Data(filename)),
+                _ => Err(Error::Validation {
+                    message: "Unsupported".to_string(),
+                }),
+            })
+        });
+
+        let client = CbmClient::connect(&path).unwrap();
+        let status = client.get_status(9).unwrap();
+        assert_eq!(status.error_number, CbmErrorNumber::Ok);
+        assert_eq!(status.device, 9);
+        let data = client
+            .load_file_petscii(8, &PetsciiString::from_petscii_bytes(b"GAME"))
+            .unwrap();
+        assert_eq!(data, b"GAME");
+        assert!(matches!(client.reset_bus(), Err(Error::Validation { .. })));
+
+        // A daemon which is still listening isn't replaced
+        assert!(bind(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -4,11 +4,12 @@
 use crate::error::Error;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
 pub(crate) const BYTES_PER_BLOCK: usize = 254;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CbmFileType {
     PRG,
     SEQ,
@@ -27,6 +28,30 @@ impl CbmFileType {
             CbmFileType::Unknown => "",
         }
     }
+
+    /// Converts the file type bits of a directory entry's type byte.  DEL
+    /// and invalid types are returned as Unknown.
+    pub fn from_dir_type(type_byte: u8) -> Self {
+        match type_byte & 0x07 {
+            1 => CbmFileType::SEQ,
+            2 => CbmFileType::PRG,
+            3 => CbmFileType::USR,
+            4 => CbmFileType::REL,
+            _ => CbmFileType::Unknown,
+        }
+    }
+
+    /// Returns the file type bits used in a directory entry's type byte.
+    /// Unknown is returned as DEL (0).
+    pub fn to_dir_type(&self) -> u8 {
+        match self {
+            CbmFileType::SEQ => 1,
+            CbmFileType::PRG => 2,
+            CbmFileType::USR => 3,
+            CbmFileType::REL => 4,
+            CbmFileType::Unknown => 0,
+        }
+    }
 }
 
 impl fmt::Display for CbmFileType {
@@ -97,7 +122,7 @@ impl CbmFileMode {
 ///     }
 /// }
 /// ```
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CbmFileEntry {
     /// Represents a successfully parsed directory entry.
     ///
@@ -212,7 +237,7 @@ impl fmt::Display for CbmFileEntry {
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CbmDiskHeader {
     pub drive_number: u8,
     pub name: String,
@@ -286,7 +311,7 @@ impl fmt::Display for CbmDiskHeader {
 /// - The ID is always exactly 2 characters
 /// - Special characters in the name are stored in PETSCII but converted to ASCII for display
 #[allow(dead_code)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CbmDirListing {
     /// The drive number (0 or 1) where this disk is mounted
     pub header: CbmDiskHeader,
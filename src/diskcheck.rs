@@ -0,0 +1,493 @@
+//! Contains a read-only, host-side, disk validator
+//!
+//! [`check_disk`] performs the same walk as the DOS `V` (validate) command -
+//! following the sector chain of every directory entry and comparing the
+//! sectors used with the BAM - but only reports what it finds, without
+//! writing anything.  Unlike `V`, it doesn't delete unclosed (splat) files.
+//! This makes it safe to check a disk before deciding whether to run
+//! [`crate::Cbm::validate_disk`].
+//!
+//! It works on any [`BlockDevice`], so on both disk images and physical
+//! disks.
+
+use crate::bam::{Bam, BamProblem};
+use crate::block::{dir_sectors, dir_slots, BlockDevice};
+use crate::disk::CbmFileType;
+use crate::error::Error;
+use crate::image::{CbmDirEntry, DiskImageFormat};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+// Owner used for the header, BAM and directory sectors
+const SYSTEM_OWNER: &str = "(directory)";
+
+/// A problem found by [`check_disk`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiskProblem {
+    /// A sector is used by more than one file (or a file and the directory)
+    CrossLinked {
+        track: u8,
+        sector: u8,
+        files: Vec<String>,
+    },
+    /// A file's chain links back to a sector already in the chain
+    ChainLoop { file: String, track: u8, sector: u8 },
+    /// A file's chain links to a track and sector which doesn't exist
+    ChainOffDisk { file: String, track: u8, sector: u8 },
+    /// A sector in a file's chain couldn't be read
+    ReadError {
+        file: String,
+        track: u8,
+        sector: u8,
+        message: String,
+    },
+    /// The file wasn't closed properly.  `V` would delete it.
+    SplatFile { file: String },
+    /// The directory entry's block count doesn't match the sectors used
+    BlockCountMismatch {
+        file: String,
+        dir_blocks: u16,
+        used_blocks: u16,
+    },
+    /// A sector is allocated in the BAM, but not used.  `V` would free it.
+    AllocatedUnused { track: u8, sector: u8 },
+    /// A sector is used, but is free in the BAM, so could be overwritten
+    UsedNotAllocated { track: u8, sector: u8, file: String },
+    /// A problem with the BAM itself
+    Bam(BamProblem),
+}
+
+impl fmt::Display for DiskProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiskProblem::CrossLinked {
+                track,
+                sector,
+                files,
+            } => write!(
+                f,
+                "Sector {track}/{sector} is cross-linked between {}",
+                files.join(", ")
+            ),
+            DiskProblem::ChainLoop {
+                file,
+                track,
+                sector,
+            } => write!(f, "{file}: chain loops back to {track}/{sector}"),
+            DiskProblem::ChainOffDisk {
+                file,
+                track,
+                sector,
+            } => write!(f, "{file}: chain links to invalid sector {track}/{sector}"),
+            DiskProblem::ReadError {
+                file,
+                track,
+                sector,
+                message,
+            } => write!(f, "{file}: failed to read {track}/{sector}: {message}"),
+            DiskProblem::SplatFile { file } => write!(f, "{file}: file is not closed"),
+            DiskProblem::BlockCountMismatch {
+                file,
+                dir_blocks,
+                used_blocks,
+            } => write!(
+                f,
+                "{file}: directory shows {dir_blocks} blocks, {used_blocks} used"
+            ),
+            DiskProblem::AllocatedUnused { track, sector } => {
+                write!(f, "Sector {track}/{sector} is allocated but not used")
+            }
+            DiskProblem::UsedNotAllocated {
+                track,
+                sector,
+                file,
+            } => write!(
+                f,
+                "{file}: sector {track}/{sector} is used but not allocated"
+            ),
+            DiskProblem::Bam(problem) => write!(f, "BAM: {problem}"),
+        }
+    }
+}
+
+/// The results of [`check_disk`]
+///
+/// # Fields
+///
+/// * `problems` - Problems found, if any
+/// * `files` - Number of directory entries checked
+/// * `blocks_used` - Number of sectors used by files and the directory
+/// * `blocks_free` - Number of free blocks in the BAM, as DOS reports
+/// * `splat_blocks` - Number of sectors used by splat files.  These are
+///   allocated in the BAM, so `V` would free them as well.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskCheckReport {
+    pub problems: Vec<DiskProblem>,
+    pub files: usize,
+    pub blocks_used: usize,
+    pub blocks_free: u16,
+    pub splat_blocks: u16,
+}
+
+impl DiskCheckReport {
+    /// Whether no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for DiskCheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} files, {} blocks used, {} blocks free, {} blocks in splat files",
+            self.files, self.blocks_used, self.blocks_free, self.splat_blocks
+        )?;
+        if self.is_ok() {
+            writeln!(f, "No problems found")
+        } else {
+            for problem in &self.problems {
+                writeln!(f, "{problem}")?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// Tracks which sectors are used, and by what
+pub(crate) struct SectorUsage {
+    format: DiskImageFormat,
+    owners: BTreeMap<(u8, u8), Vec<String>>,
+}
+
+impl SectorUsage {
+    pub(crate) fn new(format: DiskImageFormat) -> Self {
+        SectorUsage {
+            format,
+            owners: BTreeMap::new(),
+        }
+    }
+
+    // The sectors recorded, in track and sector order
+    pub(crate) fn sectors(&self) -> Vec<(u8, u8)> {
+        self.owners.keys().copied().collect()
+    }
+
+    // The files using a sector
+    pub(crate) fn owners(&self, track: u8, sector: u8) -> &[String] {
+        self.owners
+            .get(&(track, sector))
+            .map(|owners| owners.as_slice())
+            .unwrap_or_default()
+    }
+
+    // Number of sectors used by a file
+    pub(crate) fn owned_by(&self, owner: &str) -> usize {
+        self.owners
+            .values()
+            .filter(|owners| owners.iter().any(|o| o == owner))
+            .count()
+    }
+
+    // Records the header, BAM and directory sectors
+    pub(crate) fn add_system<D: BlockDevice + ?Sized>(&mut self, disk: &D) -> Result<(), Error> {
+        let dir_track = self.format.dir_track();
+        self.add(dir_track, 0, SYSTEM_OWNER);
+        for (track, sector) in self.format.bam_sectors() {
+            if (track, sector) != (dir_track, 0) {
+                self.add(track, sector, SYSTEM_OWNER);
+            }
+        }
+        for (track, sector) in dir_sectors(disk)? {
+            self.add(track, sector, SYSTEM_OWNER);
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, track: u8, sector: u8, owner: &str) {
+        self.owners
+            .entry((track, sector))
+            .or_default()
+            .push(owner.to_string());
+    }
+
+    // Follows a sector chain, recording the sectors used.  Returns the
+    // number of sectors in the chain.
+    fn walk_chain<D: BlockDevice + ?Sized>(
+        &mut self,
+        disk: &D,
+        start: (u8, u8),
+        owner: &str,
+        problems: &mut Vec<DiskProblem>,
+    ) -> u16 {
+        let mut visited = HashSet::new();
+        let (mut track, mut sector) = start;
+        loop {
+            if sector >= self.format.sectors_per_track(track) {
+                problems.push(DiskProblem::ChainOffDisk {
+                    file: owner.to_string(),
+                    track,
+                    sector,
+                });
+                break;
+            }
+            if !visited.insert((track, sector)) {
+                problems.push(DiskProblem::ChainLoop {
+                    file: owner.to_string(),
+                    track,
+                    sector,
+                });
+                break;
+            }
+            self.add(track, sector, owner);
+
+            match disk.read_block(track, sector) {
+                Ok(block) if block[0] == 0 => break,
+                Ok(block) => (track, sector) = (block[0], block[1]),
+                Err(e) => {
+                    problems.push(DiskProblem::ReadError {
+                        file: owner.to_string(),
+                        track,
+                        sector,
+                        message: e.to_string(),
+                    });
+                    break;
+                }
+            }
+        }
+        visited.len() as u16
+    }
+
+    // Records a single sector, such as a GEOS info block.  Returns the number
+    // of sectors recorded.
+    fn add_single(&mut self, start: (u8, u8), owner: &str, problems: &mut Vec<DiskProblem>) -> u16 {
+        let (track, sector) = start;
+        if sector >= self.format.sectors_per_track(track) {
+            problems.push(DiskProblem::ChainOffDisk {
+                file: owner.to_string(),
+                track,
+                sector,
+            });
+            return 0;
+        }
+        self.add(track, sector, owner);
+        1
+    }
+
+    // Records all the sectors used by a file.  Returns the number of
+    // sectors used.
+    pub(crate) fn add_file<D: BlockDevice + ?Sized>(
+        &mut self,
+        disk: &D,
+        entry: &CbmDirEntry,
+        owner: &str,
+        problems: &mut Vec<DiskProblem>,
+    ) -> u16 {
+        let mut used = 0;
+        if let Some(geos) = entry.geos() {
+            if geos.info_track != 0 {
+                used += self.add_single((geos.info_track, geos.info_sector), owner, problems);
+            }
+            if geos.structure == crate::geos::GeosStructure::Vlir {
+                used += self.add_single((entry.track, entry.sector), owner, problems);
+                if let Ok(index) = disk.read_block(entry.track, entry.sector) {
+                    for pointer in index[2..].chunks(2) {
+                        match (pointer[0], pointer[1]) {
+                            (0, 0) => break,
+                            (0, _) => (),
+                            start => used += self.walk_chain(disk, start, owner, problems),
+                        }
+                    }
+                }
+                return used;
+            }
+        }
+
+        if entry.track != 0 {
+            used += self.walk_chain(disk, (entry.track, entry.sector), owner, problems);
+        }
+        if entry.file_type == CbmFileType::REL && entry.side_track != 0 {
+            let side_sectors = (entry.side_track, entry.side_sector);
+            used += self.walk_chain(disk, side_sectors, owner, problems);
+        }
+        used
+    }
+}
+
+/// Checks a disk or disk image without changing it, returning any problems
+/// found.
+///
+/// Errors reading individual file sectors are reported as problems.  An
+/// `Err` is only returned if the BAM or directory can't be read.
+///
+/// # Example
+/// ```ignore
+/// let report = check_disk(&DriveBlocks::new(&cbm, 8, DiskImageFormat::D64))?;
+/// if report.is_ok() {
+///     cbm.validate_disk(8)?;
+/// } else {
+///     println!("{report}");
+/// }
+/// ```
+pub fn check_disk<D: BlockDevice + ?Sized>(disk: &D) -> Result<DiskCheckReport, Error> {
+    let format = disk.format();
+    let bam = Bam::read(disk)?;
+    let mut problems: Vec<DiskProblem> = bam.check().into_iter().map(DiskProblem::Bam).collect();
+    let mut usage = SectorUsage::new(format);
+    usage.add_system(disk)?;
+
+    let entries: Vec<CbmDirEntry> = dir_slots(disk)?
+        .into_iter()
+        .filter(|entry| !entry.is_empty())
+        .collect();
+    let mut splat_blocks = 0;
+    for entry in entries.iter() {
+        let owner = entry.filename_ascii();
+        let used_blocks = usage.add_file(disk, entry, &owner, &mut problems);
+        if !entry.is_closed() {
+            problems.push(DiskProblem::SplatFile {
+                file: owner.clone(),
+            });
+            splat_blocks += used_blocks;
+        }
+        if used_blocks != entry.blocks {
+            problems.push(DiskProblem::BlockCountMismatch {
+                file: owner,
+                dir_blocks: entry.blocks,
+                used_blocks,
+            });
+        }
+    }
+
+    for track in 1..=format.num_tracks() {
+        for sector in 0..format.sectors_per_track(track) {
+            let owners = usage.owners.get(&(track, sector));
+            let system_track = format.is_system_track(track);
+            match owners {
+                Some(owners) if owners.len() > 1 => problems.push(DiskProblem::CrossLinked {
+                    track,
+                    sector,
+                    files: owners.clone(),
+                }),
+                Some(owners) if bam.is_free(track, sector) => {
+                    problems.push(DiskProblem::UsedNotAllocated {
+                        track,
+                        sector,
+                        file: owners[0].clone(),
+                    })
+                }
+                None if !bam.is_free(track, sector) && !system_track => {
+                    problems.push(DiskProblem::AllocatedUnused { track, sector })
+                }
+                _ => (),
+            }
+        }
+    }
+
+    Ok(DiskCheckReport {
+        problems,
+        files: entries.len(),
+        blocks_used: usage.owners.len(),
+        blocks_free: bam.blocks_free(),
+        splat_blocks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::DiskImage;
+    use crate::string::PetsciiString;
+
+    fn new_image() -> DiskImage {
+        let mut image = DiskImage::new(
+            DiskImageFormat::D64,
+            &PetsciiString::from_ascii_str("check"),
+            &PetsciiString::from_ascii_str("01"),
+        );
+        for (name, len) in [("one", 300), ("two", 1000)] {
+            image
+                .write_file(
+                    &PetsciiString::from_ascii_str(name),
+                    CbmFileType::PRG,
+                    &vec![0x01; len],
+                )
+                .unwrap();
+        }
+        image
+    }
+
+    #[test]
+    fn test_clean_disk() {
+        let image = new_image();
+        let report = check_disk(&image).unwrap();
+        assert!(report.is_ok(), "{report}");
+        assert_eq!(report.files, 2);
+        assert_eq!(report.blocks_used, 2 + 4 + 2);
+        assert_eq!(report.blocks_free, image.blocks_free());
+    }
+
+    #[test]
+    fn test_problems() {
+        let mut image = new_image();
+        let entries = image.dir_entries().unwrap();
+        let one = &entries[0];
+        let two = &entries[1];
+
+        // Link the end of one into two
+        let mut block = image.read_sector(one.track, one.sector).unwrap().to_vec();
+        let next = (block[0], block[1]);
+        let mut last = image.read_sector(next.0, next.1).unwrap().to_vec();
+        last[0] = two.track;
+        last[1] = two.sector;
+        image.write_sector(next.0, next.1, &last).unwrap();
+
+        // Allocate an unused sector, and mark two as unclosed
+        image.allocate_sector(1, 0).unwrap();
+        let (dir_track, dir_sector) = (two.dir_track, two.dir_sector);
+        block.copy_from_slice(image.read_sector(dir_track, dir_sector).unwrap());
+        block[two.dir_index as usize * 32 + 2] &= 0x7f;
+        image.write_sector(dir_track, dir_sector, &block).unwrap();
+
+        let report = check_disk(&image).unwrap();
+        assert_eq!(report.blocks_free, image.blocks_free());
+        assert_eq!(report.splat_blocks, 4);
+        let problems = &report.problems;
+        assert!(problems.contains(&DiskProblem::SplatFile {
+            file: "two".to_string()
+        }));
+        assert!(problems.contains(&DiskProblem::AllocatedUnused {
+            track: 1,
+            sector: 0
+        }));
+        assert!(problems.contains(&DiskProblem::BlockCountMismatch {
+            file: "one".to_string(),
+            dir_blocks: 2,
+            used_blocks: 6
+        }));
+        assert!(problems.contains(&DiskProblem::CrossLinked {
+            track: two.track,
+            sector: two.sector,
+            files: vec!["one".to_string(), "two".to_string()]
+        }));
+    }
+
+    #[test]
+    fn test_chain_off_disk() {
+        let mut image = new_image();
+        let one = image.dir_entries().unwrap()[0].clone();
+        let mut block = image.read_sector(one.track, one.sector).unwrap().to_vec();
+        block[0] = 36;
+        image.write_sector(one.track, one.sector, &block).unwrap();
+
+        let report = check_disk(&image).unwrap();
+        assert!(report.problems.contains(&DiskProblem::ChainOffDisk {
+            file: "one".to_string(),
+            track: 36,
+            sector: block[1]
+        }));
+    }
+}
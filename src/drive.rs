@@ -23,6 +23,7 @@ pub struct CbmDriveUnit {
     pub device_info: CbmDeviceInfo,
     channel_manager: Arc<Mutex<CbmChannelManager>>,
     busy: bool,
+    generation: Option<u64>,
 }
 
 impl fmt::Display for CbmDriveUnit {
@@ -70,8 +71,12 @@ impl CbmDriveUnit {
     /// a CbmDriveUnit object for it.
     pub fn try_from_bus(cbm: &Cbm, device: u8) -> Result<Self, Error> {
         if cbm.drive_exists(device)? {
+            let generation = cbm.connection_generation();
             let info = cbm.identify(device)?;
-            Ok(Self::new(device, info))
+            Ok(Self {
+                generation: Some(generation),
+                ..Self::new(device, info)
+            })
         } else {
             Err(Error::Device {
                 device,
@@ -101,9 +106,32 @@ impl CbmDriveUnit {
             device_info,
             channel_manager: Arc::new(Mutex::new(CbmChannelManager::new())),
             busy: false,
+            generation: None,
+        }
+    }
+
+    /// Whether [`CbmDriveUnit::device_info`] is still valid.  It is
+    /// invalidated when the [`Cbm`] reconnects to the xum1541, or its USB
+    /// device is reset, as a different drive may now be attached with this
+    /// device number.  Call [`CbmDriveUnit::reidentify`] to update it.
+    ///
+    /// Drive units created with [`CbmDriveUnit::new`] are always considered
+    /// current, as they weren't identified using the bus.
+    pub fn is_identity_current(&self, cbm: &Cbm) -> bool {
+        match self.generation {
+            Some(generation) => generation == cbm.connection_generation(),
+            None => true,
         }
     }
 
+    /// Identifies the drive again, updating [`CbmDriveUnit::device_info`]
+    pub fn reidentify(&mut self, cbm: &Cbm) -> Result<(), Error> {
+        let generation = cbm.connection_generation();
+        self.device_info = cbm.identify(self.device_number)?;
+        self.generation = Some(generation);
+        Ok(())
+    }
+
     /// Gets the current status of the drive unit.
     ///
     /// Retrieves the status message from the drive, which may include error conditions,
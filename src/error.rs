@@ -1,5 +1,7 @@
+use crate::lock::in_use_message;
+use crate::timeout::{recovery_summary, BusOp, RecoveryAttempt};
 use crate::CbmStatus;
-use libc::{EINVAL, EIO, ENODEV, ETIMEDOUT};
+use libc::{EBUSY, ECANCELED, EINVAL, EIO, ENODEV, ETIMEDOUT};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use xum1541::DeviceChannel;
@@ -28,6 +30,15 @@ pub enum Error {
     #[error("Timeout error, duration: {dur:?}")]
     Timeout { dur: std::time::Duration },
 
+    /// A bus operation exceeded one of the [`crate::Timeouts`].  `recovery`
+    /// lists the steps taken to recover the bus afterwards.
+    #[error("Bus {operation} timed out after {dur:?}{}", recovery_summary(.recovery))]
+    BusTimeout {
+        operation: BusOp,
+        dur: std::time::Duration,
+        recovery: Vec<RecoveryAttempt>,
+    },
+
     /// Argument validation failed
     #[error("Validation error: {message}")]
     Validation { message: String },
@@ -35,6 +46,22 @@ pub enum Error {
     /// Parsing error, most likely on data received from the device
     #[error("Parse error: {message}")]
     Parse { message: String },
+
+    /// Error reading or writing a file on the host
+    #[error("I/O error: {message}")]
+    Io { message: String },
+
+    /// The operation was cancelled using its [`crate::OpContext`]
+    #[error("Operation cancelled")]
+    Cancelled,
+
+    /// Another process holds the [`crate::AdapterLock`] for this xum1541.
+    /// `pid` is its process ID, if known.
+    #[error("{}", in_use_message(.serial, .pid))]
+    AdapterInUse {
+        serial: Option<u8>,
+        pid: Option<u32>,
+    },
 }
 
 /// (CBM) Device errors
@@ -69,17 +96,29 @@ impl From<CbmStatus> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io {
+            message: error.to_string(),
+        }
+    }
+}
+
 impl Error {
     /// Convert the error to a an errno
     pub fn to_errno(&self) -> i32 {
         match self {
-            xum @ Error::Xum1541(_) => xum.to_errno(),
-            e @ Error::Device { .. } => e.to_errno(),
+            Error::Xum1541(xum) => xum.to_errno(),
+            Error::Device { error, .. } => error.to_errno(),
             Error::File { .. } => EIO,
             Error::Timeout { .. } => ETIMEDOUT,
+            Error::BusTimeout { .. } => ETIMEDOUT,
             Error::Validation { .. } => EINVAL,
             Error::Status { .. } => EIO,
             Error::Parse { message: _ } => EINVAL,
+            Error::Io { .. } => EIO,
+            Error::Cancelled => ECANCELED,
+            Error::AdapterInUse { .. } => EBUSY,
         }
     }
 }
@@ -141,5 +180,6 @@ mod tests {
             message: "Test error".to_string(),
         };
         assert_eq!(error.to_errno(), EINVAL);
+        assert_eq!(DeviceError::no_device(8).to_errno(), ENODEV);
     }
 }
@@ -0,0 +1,852 @@
+//! Contains types and functions for working with GEOS files
+//!
+//! GEOS files appear as ordinary USR (or PRG/SEQ) files in a directory
+//! listing, but reuse the REL file fields of the directory entry to store:
+//!
+//! - the location of the file's info block, which holds its icon, class
+//!   name, author and description
+//! - the file's structure - sequential, with the data in a single sector
+//!   chain, or VLIR, where the entry points to an index sector listing up to
+//!   127 records, each its own sector chain
+//! - the GEOS file type and a timestamp.
+//!
+//! GEOS files can be read from and written to disk images and physical
+//! disks, and converted to and from the CVT format used to store GEOS files
+//! on the host.
+//!
+//! A CVT file contains:
+//!
+//! - a 254 byte block holding the directory entry (without the link bytes)
+//!   followed by a signature - "PRG formatted GEOS file V1.0" for VLIR
+//!   files, or "SEQ formatted GEOS file V1.0" for sequential files
+//! - the info block, without its link bytes
+//! - for VLIR files, the index sector (without link bytes) with each
+//!   record's track and sector replaced by its length in blocks and the
+//!   number of bytes used in its last block, plus 1
+//! - the data.  For VLIR files each record is padded to a whole number of
+//!   blocks, apart from the last.
+
+use crate::block::{dir_slots, read_chain, BlockDevice, DriveBlocks};
+use crate::cbm::Cbm;
+use crate::disk::{CbmFileType, BYTES_PER_BLOCK};
+use crate::error::Error;
+use crate::image::{
+    write_padded, CbmDirEntry, DiskImage, DiskImageFormat, DIR_BLOCKS, DIR_ENTRY_SIZE,
+    DIR_FILENAME, DIR_TYPE, MAX_FILENAME_LEN, SECTOR_SIZE,
+};
+use crate::string::PetsciiString;
+use crate::util::petscii_str_to_ascii;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::path::Path;
+
+// GEOS fields within a directory entry
+const GEOS_INFO_TRACK: usize = 0x15;
+const GEOS_INFO_SECTOR: usize = 0x16;
+const GEOS_STRUCTURE: usize = 0x17;
+const GEOS_TYPE: usize = 0x18;
+const GEOS_TIMESTAMP: usize = 0x19;
+const GEOS_TIMESTAMP_LEN: usize = 5;
+
+// Layout of the info block
+const INFO_ICON_WIDTH: usize = 0x02;
+const INFO_ICON_HEIGHT: usize = 0x03;
+const INFO_ICON_TYPE: usize = 0x04;
+const INFO_ICON: usize = 0x05;
+const INFO_DOS_TYPE: usize = 0x44;
+const INFO_GEOS_TYPE: usize = 0x45;
+const INFO_STRUCTURE: usize = 0x46;
+const INFO_LOAD_ADDR: usize = 0x47;
+const INFO_END_ADDR: usize = 0x49;
+const INFO_START_ADDR: usize = 0x4b;
+const INFO_CLASS_NAME: usize = 0x4d;
+const INFO_AUTHOR: usize = 0x61;
+const INFO_PARENT: usize = 0x75;
+const INFO_APP_DATA: usize = 0x89;
+const INFO_DESCRIPTION: usize = 0xa0;
+const INFO_STRING_LEN: usize = 20;
+
+/// Size of a GEOS icon bitmap - 24x21 pixels
+pub const GEOS_ICON_SIZE: usize = 63;
+
+// Icon dimensions and bitmap type stored in the info block
+const ICON_WIDTH_BYTES: u8 = 3;
+const ICON_HEIGHT: u8 = 21;
+const ICON_TYPE: u8 = 0xbf;
+
+/// Maximum number of records in a VLIR file
+pub const VLIR_MAX_RECORDS: usize = 127;
+
+// Index sector entry for a record which exists but is empty
+const VLIR_EMPTY_RECORD: (u8, u8) = (0, 0xff);
+
+// CVT file layout
+const CVT_DIR_ENTRY_LEN: usize = DIR_ENTRY_SIZE - 2;
+const CVT_SIGNATURE_VLIR: &[u8] = b"PRG formatted GEOS file V1.0";
+const CVT_SIGNATURE_SEQUENTIAL: &[u8] = b"SEQ formatted GEOS file V1.0";
+const CVT_SIGNATURE_CHECK: &[u8] = b"formatted GEOS file";
+
+/// Structure of a GEOS file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeosStructure {
+    /// Data is stored in a single sector chain
+    Sequential,
+    /// Variable Length Indexed Record - the data is stored in a number of
+    /// records, each a sector chain, listed in an index sector
+    Vlir,
+}
+
+impl GeosStructure {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(GeosStructure::Sequential),
+            1 => Some(GeosStructure::Vlir),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            GeosStructure::Sequential => 0,
+            GeosStructure::Vlir => 1,
+        }
+    }
+}
+
+/// GEOS file type, as stored in the directory entry and info block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeosFileType {
+    NonGeos,
+    Basic,
+    Assembler,
+    Data,
+    System,
+    DeskAccessory,
+    Application,
+    ApplicationData,
+    Font,
+    PrinterDriver,
+    InputDriver,
+    DiskDriver,
+    SystemBoot,
+    Temporary,
+    AutoExecute,
+    Input128,
+    Unknown(u8),
+}
+
+impl GeosFileType {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0 => GeosFileType::NonGeos,
+            1 => GeosFileType::Basic,
+            2 => GeosFileType::Assembler,
+            3 => GeosFileType::Data,
+            4 => GeosFileType::System,
+            5 => GeosFileType::DeskAccessory,
+            6 => GeosFileType::Application,
+            7 => GeosFileType::ApplicationData,
+            8 => GeosFileType::Font,
+            9 => GeosFileType::PrinterDriver,
+            10 => GeosFileType::InputDriver,
+            11 => GeosFileType::DiskDriver,
+            12 => GeosFileType::SystemBoot,
+            13 => GeosFileType::Temporary,
+            14 => GeosFileType::AutoExecute,
+            15 => GeosFileType::Input128,
+            _ => GeosFileType::Unknown(value),
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            GeosFileType::NonGeos => 0,
+            GeosFileType::Basic => 1,
+            GeosFileType::Assembler => 2,
+            GeosFileType::Data => 3,
+            GeosFileType::System => 4,
+            GeosFileType::DeskAccessory => 5,
+            GeosFileType::Application => 6,
+            GeosFileType::ApplicationData => 7,
+            GeosFileType::Font => 8,
+            GeosFileType::PrinterDriver => 9,
+            GeosFileType::InputDriver => 10,
+            GeosFileType::DiskDriver => 11,
+            GeosFileType::SystemBoot => 12,
+            GeosFileType::Temporary => 13,
+            GeosFileType::AutoExecute => 14,
+            GeosFileType::Input128 => 15,
+            GeosFileType::Unknown(value) => *value,
+        }
+    }
+}
+
+/// Timestamp stored in a GEOS directory entry.  The year is stored as 2
+/// digits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GeosTimestamp {
+    pub year: u8,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+}
+
+impl GeosTimestamp {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        GeosTimestamp {
+            year: bytes[0],
+            month: bytes[1],
+            day: bytes[2],
+            hour: bytes[3],
+            minute: bytes[4],
+        }
+    }
+
+    fn to_bytes(self) -> [u8; GEOS_TIMESTAMP_LEN] {
+        [self.year, self.month, self.day, self.hour, self.minute]
+    }
+}
+
+/// The GEOS fields of a directory entry - see [`CbmDirEntry::geos`]
+///
+/// # Fields
+///
+/// * `info_track`/`info_sector` - Location of the info block, or 0/0 if
+///   the file has none
+/// * `structure` - Whether the file is sequential or VLIR
+/// * `geos_type` - GEOS file type
+/// * `timestamp` - When the file was last modified
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GeosDirInfo {
+    pub info_track: u8,
+    pub info_sector: u8,
+    pub structure: GeosStructure,
+    pub geos_type: GeosFileType,
+    pub timestamp: GeosTimestamp,
+}
+
+impl GeosDirInfo {
+    /// Decodes the GEOS fields from a directory entry.  Returns None if the
+    /// entry isn't a GEOS file.
+    pub fn from_dir_entry(entry: &CbmDirEntry) -> Option<Self> {
+        let raw = &entry.raw;
+        if matches!(entry.file_type, CbmFileType::REL | CbmFileType::Unknown) || raw[GEOS_TYPE] == 0
+        {
+            return None;
+        }
+        Some(GeosDirInfo {
+            info_track: raw[GEOS_INFO_TRACK],
+            info_sector: raw[GEOS_INFO_SECTOR],
+            structure: GeosStructure::from_u8(raw[GEOS_STRUCTURE])?,
+            geos_type: GeosFileType::from_u8(raw[GEOS_TYPE]),
+            timestamp: GeosTimestamp::from_bytes(&raw[GEOS_TIMESTAMP..]),
+        })
+    }
+
+    // Writes the fields into a raw directory entry
+    fn write_to_raw(&self, raw: &mut [u8]) {
+        raw[GEOS_INFO_TRACK] = self.info_track;
+        raw[GEOS_INFO_SECTOR] = self.info_sector;
+        raw[GEOS_STRUCTURE] = self.structure.to_u8();
+        raw[GEOS_TYPE] = self.geos_type.to_u8();
+        raw[GEOS_TIMESTAMP..GEOS_TIMESTAMP + GEOS_TIMESTAMP_LEN]
+            .copy_from_slice(&self.timestamp.to_bytes());
+    }
+}
+
+/// A GEOS info block
+///
+/// # Fields
+///
+/// * `icon` - The icon bitmap, 24x21 pixels
+/// * `dos_type` - The directory entry type byte
+/// * `geos_type` - GEOS file type
+/// * `structure` - Whether the file is sequential or VLIR
+/// * `load_addr`/`end_addr`/`start_addr` - Where the file is loaded and
+///   run, for applications
+/// * `class_name` - Class name, including version, e.g. "geoWrite    V2.1"
+/// * `author` - Author, for applications
+/// * `parent` - Class name of the application which created this file, for
+///   data files
+/// * `app_data` - Reserved for use by the application
+/// * `description` - Description shown by the desktop
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeosInfoBlock {
+    pub icon: [u8; GEOS_ICON_SIZE],
+    pub dos_type: u8,
+    pub geos_type: GeosFileType,
+    pub structure: GeosStructure,
+    pub load_addr: u16,
+    pub end_addr: u16,
+    pub start_addr: u16,
+    pub class_name: PetsciiString,
+    pub author: PetsciiString,
+    pub parent: PetsciiString,
+    pub app_data: Vec<u8>,
+    pub description: PetsciiString,
+}
+
+impl GeosInfoBlock {
+    /// Parses an info block from a sector
+    pub fn parse(block: &[u8]) -> Result<Self, Error> {
+        if block.len() != SECTOR_SIZE {
+            return Err(Error::Parse {
+                message: format!("GEOS info block must be {SECTOR_SIZE} bytes"),
+            });
+        }
+        let structure =
+            GeosStructure::from_u8(block[INFO_STRUCTURE]).ok_or_else(|| Error::Parse {
+                message: format!("Invalid GEOS file structure {}", block[INFO_STRUCTURE]),
+            })?;
+
+        let mut icon = [0u8; GEOS_ICON_SIZE];
+        icon.copy_from_slice(&block[INFO_ICON..INFO_ICON + GEOS_ICON_SIZE]);
+        let addr = |offset: usize| u16::from_le_bytes([block[offset], block[offset + 1]]);
+
+        Ok(GeosInfoBlock {
+            icon,
+            dos_type: block[INFO_DOS_TYPE],
+            geos_type: GeosFileType::from_u8(block[INFO_GEOS_TYPE]),
+            structure,
+            load_addr: addr(INFO_LOAD_ADDR),
+            end_addr: addr(INFO_END_ADDR),
+            start_addr: addr(INFO_START_ADDR),
+            class_name: info_string(&block[INFO_CLASS_NAME..INFO_CLASS_NAME + INFO_STRING_LEN]),
+            author: info_string(&block[INFO_AUTHOR..INFO_AUTHOR + INFO_STRING_LEN]),
+            parent: info_string(&block[INFO_PARENT..INFO_PARENT + INFO_STRING_LEN]),
+            app_data: block[INFO_APP_DATA..INFO_DESCRIPTION].to_vec(),
+            description: info_string(&block[INFO_DESCRIPTION..]),
+        })
+    }
+
+    /// Returns the info block as a sector
+    pub fn to_block(&self) -> [u8; SECTOR_SIZE] {
+        let mut block = [0u8; SECTOR_SIZE];
+        block[1] = 0xff;
+        block[INFO_ICON_WIDTH] = ICON_WIDTH_BYTES;
+        block[INFO_ICON_HEIGHT] = ICON_HEIGHT;
+        block[INFO_ICON_TYPE] = ICON_TYPE;
+        block[INFO_ICON..INFO_ICON + GEOS_ICON_SIZE].copy_from_slice(&self.icon);
+        block[INFO_DOS_TYPE] = self.dos_type;
+        block[INFO_GEOS_TYPE] = self.geos_type.to_u8();
+        block[INFO_STRUCTURE] = self.structure.to_u8();
+        block[INFO_LOAD_ADDR..INFO_LOAD_ADDR + 2].copy_from_slice(&self.load_addr.to_le_bytes());
+        block[INFO_END_ADDR..INFO_END_ADDR + 2].copy_from_slice(&self.end_addr.to_le_bytes());
+        block[INFO_START_ADDR..INFO_START_ADDR + 2].copy_from_slice(&self.start_addr.to_le_bytes());
+        write_info_string(
+            &mut block[INFO_CLASS_NAME..INFO_CLASS_NAME + INFO_STRING_LEN],
+            &self.class_name,
+        );
+        write_info_string(
+            &mut block[INFO_AUTHOR..INFO_AUTHOR + INFO_STRING_LEN],
+            &self.author,
+        );
+        write_info_string(
+            &mut block[INFO_PARENT..INFO_PARENT + INFO_STRING_LEN],
+            &self.parent,
+        );
+        let app_data_len = self.app_data.len().min(INFO_DESCRIPTION - INFO_APP_DATA);
+        block[INFO_APP_DATA..INFO_APP_DATA + app_data_len]
+            .copy_from_slice(&self.app_data[..app_data_len]);
+        write_info_string(&mut block[INFO_DESCRIPTION..], &self.description);
+        block
+    }
+}
+
+// Reads a NUL terminated string from the info block
+fn info_string(field: &[u8]) -> PetsciiString {
+    let len = field.iter().position(|&c| c == 0).unwrap_or(field.len());
+    PetsciiString::from_petscii_bytes(&field[..len])
+}
+
+// Writes a string to the info block, truncating it if necessary to leave
+// room for the NUL terminator
+fn write_info_string(field: &mut [u8], string: &PetsciiString) {
+    let len = string.as_bytes().len().min(field.len() - 1);
+    field[..len].copy_from_slice(&string.as_bytes()[..len]);
+    field[len..].fill(0);
+}
+
+/// The contents of a GEOS file
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeosData {
+    /// The contents of a sequential file
+    Sequential(Vec<u8>),
+    /// The records of a VLIR file.  None indicates a record which is
+    /// present in the index, but empty.
+    Vlir(Vec<Option<Vec<u8>>>),
+}
+
+impl GeosData {
+    pub fn structure(&self) -> GeosStructure {
+        match self {
+            GeosData::Sequential(_) => GeosStructure::Sequential,
+            GeosData::Vlir(_) => GeosStructure::Vlir,
+        }
+    }
+}
+
+/// A GEOS file
+///
+/// # Fields
+///
+/// * `filename` - Filename in PETSCII
+/// * `file_type` - The type shown in the directory listing, usually USR
+/// * `geos_type` - GEOS file type
+/// * `timestamp` - When the file was last modified
+/// * `info` - The info block, if the file has one
+/// * `data` - Contents of the file
+///
+/// # Example
+/// ```ignore
+/// let image = DiskImage::load("geos.d64")?;
+/// let file = GeosFile::find(&image, &PetsciiString::from_ascii_str("GEOWRITE"))?
+///     .ok_or("Not found")?;
+/// file.save_cvt("geowrite.cvt")?;
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeosFile {
+    pub filename: PetsciiString,
+    pub file_type: CbmFileType,
+    pub geos_type: GeosFileType,
+    pub timestamp: GeosTimestamp,
+    pub info: Option<GeosInfoBlock>,
+    pub data: GeosData,
+}
+
+/// Functions to read and write GEOS files on disks and images
+impl GeosFile {
+    /// Reads the GEOS file with the given directory entry from a disk or
+    /// image
+    ///
+    /// # Errors
+    /// Returns `Error::Validation` if the entry isn't a GEOS file
+    pub fn read<D: BlockDevice + ?Sized>(disk: &D, entry: &CbmDirEntry) -> Result<Self, Error> {
+        let dir_info = entry.geos().ok_or_else(|| Error::Validation {
+            message: format!("{} is not a GEOS file", entry.filename_ascii()),
+        })?;
+
+        let info = match dir_info.info_track {
+            0 => None,
+            track => Some(GeosInfoBlock::parse(
+                &disk.read_block(track, dir_info.info_sector)?,
+            )?),
+        };
+
+        let data = match dir_info.structure {
+            GeosStructure::Sequential => {
+                GeosData::Sequential(read_chain(disk, entry.track, entry.sector)?)
+            }
+            GeosStructure::Vlir => {
+                let index = disk.read_block(entry.track, entry.sector)?;
+                let mut records = Vec::new();
+                for pointer in index[2..].chunks(2) {
+                    match (pointer[0], pointer[1]) {
+                        (0, 0) => break,
+                        (0, _) => records.push(None),
+                        (track, sector) => records.push(Some(read_chain(disk, track, sector)?)),
+                    }
+                }
+                GeosData::Vlir(records)
+            }
+        };
+
+        Ok(GeosFile {
+            filename: entry.filename.clone(),
+            file_type: entry.file_type,
+            geos_type: dir_info.geos_type,
+            timestamp: dir_info.timestamp,
+            info,
+            data,
+        })
+    }
+
+    /// Finds a GEOS file by its PETSCII name on a disk or image, and reads
+    /// it
+    pub fn find<D: BlockDevice + ?Sized>(
+        disk: &D,
+        filename: &PetsciiString,
+    ) -> Result<Option<Self>, Error> {
+        dir_slots(disk)?
+            .iter()
+            .find(|entry| entry.is_closed() && entry.filename == *filename)
+            .map(|entry| Self::read(disk, entry))
+            .transpose()
+    }
+
+    /// Reads a GEOS file from a drive holding a disk with the given layout
+    pub fn read_from_drive(
+        cbm: &Cbm,
+        device: u8,
+        format: DiskImageFormat,
+        filename: &PetsciiString,
+    ) -> Result<Option<Self>, Error> {
+        Self::find(&DriveBlocks::new(cbm, device, format), filename)
+    }
+
+    /// Writes the file to a disk image, returning its new directory entry
+    pub fn write_to_image(&self, image: &mut DiskImage) -> Result<CbmDirEntry, Error> {
+        if matches!(self.file_type, CbmFileType::REL | CbmFileType::Unknown) {
+            return Err(Error::Validation {
+                message: format!("Invalid GEOS file type {:?}", self.file_type),
+            });
+        }
+        if let GeosData::Vlir(records) = &self.data {
+            if records.len() > VLIR_MAX_RECORDS {
+                return Err(Error::Validation {
+                    message: format!("Too many VLIR records {}", records.len()),
+                });
+            }
+        }
+
+        let blocks = self.blocks();
+        image.check_new_file(&self.filename, blocks)?;
+        let slot = image.free_dir_slot()?;
+        let sectors = image.allocate_sectors(blocks)?;
+        let mut sectors = sectors.as_slice();
+
+        let (info_track, info_sector) = match &self.info {
+            Some(info) => {
+                let (track, sector) = sectors[0];
+                sectors = &sectors[1..];
+                image.write_sector(track, sector, &info.to_block())?;
+                (track, sector)
+            }
+            None => (0, 0),
+        };
+
+        let first = match &self.data {
+            GeosData::Sequential(data) => {
+                image.write_chain(sectors, data)?;
+                sectors[0]
+            }
+            GeosData::Vlir(records) => {
+                let (index_track, index_sector) = sectors[0];
+                sectors = &sectors[1..];
+                let mut index = [0u8; SECTOR_SIZE];
+                index[1] = 0xff;
+                for (ii, record) in records.iter().enumerate() {
+                    let pointer = match record {
+                        Some(data) => {
+                            let (chain, rest) = sectors.split_at(record_blocks(data));
+                            sectors = rest;
+                            image.write_chain(chain, data)?;
+                            chain[0]
+                        }
+                        None => VLIR_EMPTY_RECORD,
+                    };
+                    index[2 + ii * 2] = pointer.0;
+                    index[3 + ii * 2] = pointer.1;
+                }
+                image.write_sector(index_track, index_sector, &index)?;
+                (index_track, index_sector)
+            }
+        };
+
+        let dir_info = self.dir_info(info_track, info_sector);
+        let raw = image.write_dir_entry(slot, self.file_type, &self.filename, first, blocks);
+        dir_info.write_to_raw(raw);
+        Ok(CbmDirEntry::from_raw(raw, slot.0, slot.1, slot.2))
+    }
+
+    /// Writes the file to the disk in a drive, which must have the given
+    /// layout.  See [`DriveBlocks::update_filesystem`] for how the disk is
+    /// updated.
+    pub fn write_to_drive(
+        &self,
+        cbm: &Cbm,
+        device: u8,
+        format: DiskImageFormat,
+    ) -> Result<(), Error> {
+        DriveBlocks::new(cbm, device, format)
+            .update_filesystem(|image| self.write_to_image(image))
+            .map(|_| ())
+    }
+
+    /// Size of the file on disk in blocks, including the info block and
+    /// VLIR index sector
+    pub fn blocks(&self) -> usize {
+        let info_blocks = self.info.is_some() as usize;
+        let data_blocks = match &self.data {
+            GeosData::Sequential(data) => record_blocks(data),
+            GeosData::Vlir(records) => {
+                1 + records
+                    .iter()
+                    .flatten()
+                    .map(|data| record_blocks(data))
+                    .sum::<usize>()
+            }
+        };
+        info_blocks + data_blocks
+    }
+
+    /// The filename, converted to ASCII
+    pub fn filename_ascii(&self) -> String {
+        petscii_str_to_ascii(self.filename.as_bytes())
+    }
+
+    fn dir_info(&self, info_track: u8, info_sector: u8) -> GeosDirInfo {
+        GeosDirInfo {
+            info_track,
+            info_sector,
+            structure: self.data.structure(),
+            geos_type: self.geos_type,
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+/// Functions to convert to and from CVT files
+impl GeosFile {
+    /// Parses a CVT file
+    pub fn from_cvt(bytes: &[u8]) -> Result<Self, Error> {
+        let signature = &bytes[CVT_DIR_ENTRY_LEN.min(bytes.len())..];
+        if bytes.len() < 2 * BYTES_PER_BLOCK || !signature[4..].starts_with(CVT_SIGNATURE_CHECK) {
+            return Err(Error::Parse {
+                message: "Not a CVT file".to_string(),
+            });
+        }
+
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        raw[2..].copy_from_slice(&bytes[..CVT_DIR_ENTRY_LEN]);
+        let entry = CbmDirEntry::from_raw(&raw, 0, 0, 0);
+        let dir_info = entry.geos().ok_or_else(|| Error::Parse {
+            message: "CVT file does not contain a GEOS directory entry".to_string(),
+        })?;
+
+        let mut info_block = vec![0, 0xff];
+        info_block.extend_from_slice(&bytes[BYTES_PER_BLOCK..2 * BYTES_PER_BLOCK]);
+        let info = GeosInfoBlock::parse(&info_block)?;
+
+        let contents = &bytes[2 * BYTES_PER_BLOCK..];
+        let data = match dir_info.structure {
+            GeosStructure::Sequential => GeosData::Sequential(contents.to_vec()),
+            GeosStructure::Vlir => {
+                if contents.len() < BYTES_PER_BLOCK {
+                    return Err(Error::Parse {
+                        message: "CVT file is missing its VLIR index".to_string(),
+                    });
+                }
+                let (index, mut contents) = contents.split_at(BYTES_PER_BLOCK);
+                let mut records = Vec::new();
+                for pointer in index.chunks(2) {
+                    match (pointer[0], pointer[1]) {
+                        (0, 0) => break,
+                        (0, _) => records.push(None),
+                        (blocks, lsu) => {
+                            let blocks = blocks as usize;
+                            let len = (blocks - 1) * BYTES_PER_BLOCK
+                                + (lsu as usize).saturating_sub(1).min(BYTES_PER_BLOCK);
+                            records.push(Some(contents[..len.min(contents.len())].to_vec()));
+                            contents = &contents[(blocks * BYTES_PER_BLOCK).min(contents.len())..];
+                        }
+                    }
+                }
+                GeosData::Vlir(records)
+            }
+        };
+
+        Ok(GeosFile {
+            filename: entry.filename,
+            file_type: entry.file_type,
+            geos_type: dir_info.geos_type,
+            timestamp: dir_info.timestamp,
+            info: Some(info),
+            data,
+        })
+    }
+
+    /// Returns the file in CVT format
+    pub fn to_cvt(&self) -> Vec<u8> {
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        raw[DIR_TYPE] = 0x80 | self.file_type.to_dir_type();
+        write_padded(
+            &mut raw[DIR_FILENAME..DIR_FILENAME + MAX_FILENAME_LEN],
+            self.filename.as_bytes(),
+        );
+        raw[DIR_BLOCKS..DIR_BLOCKS + 2].copy_from_slice(&(self.blocks() as u16).to_le_bytes());
+        self.dir_info(0, 0).write_to_raw(&mut raw);
+
+        let mut output = Vec::new();
+        output.extend_from_slice(&raw[2..]);
+        output.extend_from_slice(match self.data {
+            GeosData::Sequential(_) => CVT_SIGNATURE_SEQUENTIAL,
+            GeosData::Vlir(_) => CVT_SIGNATURE_VLIR,
+        });
+        output.resize(BYTES_PER_BLOCK, 0);
+
+        match &self.info {
+            Some(info) => output.extend_from_slice(&info.to_block()[2..]),
+            None => output.resize(2 * BYTES_PER_BLOCK, 0),
+        }
+
+        match &self.data {
+            GeosData::Sequential(data) => output.extend_from_slice(data),
+            GeosData::Vlir(records) => {
+                let mut index = [0u8; BYTES_PER_BLOCK];
+                for (ii, record) in records.iter().enumerate() {
+                    let (blocks, lsu) = match record {
+                        Some(data) => {
+                            let blocks = record_blocks(data);
+                            let last = data.len() - (blocks - 1) * BYTES_PER_BLOCK;
+                            (blocks as u8, (last + 1) as u8)
+                        }
+                        None => VLIR_EMPTY_RECORD,
+                    };
+                    index[ii * 2] = blocks;
+                    index[ii * 2 + 1] = lsu;
+                }
+                output.extend_from_slice(&index);
+
+                let mut records = records.iter().flatten().peekable();
+                while let Some(data) = records.next() {
+                    output.extend_from_slice(data);
+                    if records.peek().is_some() {
+                        let padded = record_blocks(data) * BYTES_PER_BLOCK;
+                        output.resize(output.len() + padded - data.len(), 0);
+                    }
+                }
+            }
+        }
+        output
+    }
+
+    /// Loads a CVT file from the host
+    pub fn load_cvt<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::from_cvt(&std::fs::read(path)?)
+    }
+
+    /// Writes the file to the host in CVT format
+    pub fn save_cvt<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        std::fs::write(path, self.to_cvt()).map_err(|e| e.into())
+    }
+}
+
+// Number of blocks used to store a record, or the contents of a sequential
+// file.  Empty data still uses a block.
+fn record_blocks(data: &[u8]) -> usize {
+    data.len().div_ceil(BYTES_PER_BLOCK).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_image() -> DiskImage {
+        DiskImage::new(
+            DiskImageFormat::D64,
+            &PetsciiString::from_ascii_str("geosdisk"),
+            &PetsciiString::from_ascii_str("01"),
+        )
+    }
+
+    fn test_file(data: GeosData) -> GeosFile {
+        let mut icon = [0u8; GEOS_ICON_SIZE];
+        icon[0] = 0xff;
+        GeosFile {
+            filename: PetsciiString::from_petscii_bytes(b"TEST APP"),
+            file_type: CbmFileType::USR,
+            geos_type: GeosFileType::Application,
+            timestamp: GeosTimestamp {
+                year: 88,
+                month: 7,
+                day: 4,
+                hour: 12,
+                minute: 30,
+            },
+            info: Some(GeosInfoBlock {
+                icon,
+                dos_type: 0x83,
+                geos_type: GeosFileType::Application,
+                structure: data.structure(),
+                load_addr: 0x0400,
+                end_addr: 0x5000,
+                start_addr: 0x0400,
+                class_name: PetsciiString::from_petscii_bytes(b"TEST APP    V1.0"),
+                author: PetsciiString::from_petscii_bytes(b"SOMEONE"),
+                parent: PetsciiString::from_petscii_bytes(b""),
+                app_data: vec![0u8; INFO_DESCRIPTION - INFO_APP_DATA],
+                description: PetsciiString::from_petscii_bytes(b"A TEST APPLICATION"),
+            }),
+            data,
+        }
+    }
+
+    fn vlir_file() -> GeosFile {
+        test_file(GeosData::Vlir(vec![
+            Some((0..600).map(|ii| ii as u8).collect()),
+            None,
+            Some(vec![0x42; 10]),
+            Some(vec![]),
+        ]))
+    }
+
+    #[test]
+    fn test_vlir_image_roundtrip() {
+        let mut image = new_image();
+        let file = vlir_file();
+        assert_eq!(file.blocks(), 7);
+
+        let entry = file.write_to_image(&mut image).unwrap();
+        assert_eq!(entry.blocks, 7);
+        assert_eq!(image.blocks_free(), 664 - 7);
+
+        let dir_info = entry.geos().unwrap();
+        assert_eq!(dir_info.structure, GeosStructure::Vlir);
+        assert_eq!(dir_info.geos_type, GeosFileType::Application);
+        assert_ne!(dir_info.info_track, 0);
+
+        let read = GeosFile::find(&image, &file.filename).unwrap().unwrap();
+        assert_eq!(read, file);
+    }
+
+    #[test]
+    fn test_sequential_image_roundtrip() {
+        let mut image = new_image();
+        let file = test_file(GeosData::Sequential(vec![0x55; 300]));
+        let entry = file.write_to_image(&mut image).unwrap();
+        assert_eq!(entry.blocks, 3);
+        assert_eq!(GeosFile::read(&image, &entry).unwrap(), file);
+    }
+
+    #[test]
+    fn test_non_geos_entry() {
+        let mut image = new_image();
+        let entry = image
+            .write_file(
+                &PetsciiString::from_ascii_str("plain"),
+                CbmFileType::USR,
+                &[1, 2, 3],
+            )
+            .unwrap();
+        assert!(entry.geos().is_none());
+        assert!(GeosFile::read(&image, &entry).is_err());
+    }
+
+    #[test]
+    fn test_cvt_roundtrip() {
+        let file = vlir_file();
+        let cvt = file.to_cvt();
+        assert_eq!(
+            &cvt[CVT_DIR_ENTRY_LEN..][..CVT_SIGNATURE_VLIR.len()],
+            CVT_SIGNATURE_VLIR
+        );
+        // Directory block, info block and index, then 3 blocks for the first
+        // record, a padded block for the second and nothing for the empty
+        // last record
+        assert_eq!(cvt.len(), 7 * BYTES_PER_BLOCK);
+        assert_eq!(GeosFile::from_cvt(&cvt).unwrap(), file);
+
+        let file = test_file(GeosData::Sequential(vec![0x55; 300]));
+        let cvt = file.to_cvt();
+        assert_eq!(
+            &cvt[CVT_DIR_ENTRY_LEN..][..CVT_SIGNATURE_SEQUENTIAL.len()],
+            CVT_SIGNATURE_SEQUENTIAL
+        );
+        assert_eq!(GeosFile::from_cvt(&cvt).unwrap(), file);
+
+        assert!(GeosFile::from_cvt(&[0u8; 1000]).is_err());
+    }
+}
@@ -0,0 +1,1053 @@
+//! Contains types and functions for working with disk images
+//!
+//! Supports D64 images, in both standard 35 track and extended 40 track
+//! forms, D71 and D81 images, optionally with error information appended.
+//! Images are held in memory and written back with [`DiskImage::save`].
+//!
+//! See [`crate::bam`] for the layout of the BAM in each format.
+
+use crate::bam::BamLocation;
+use crate::block;
+use crate::disk::{CbmDirListing, CbmDiskHeader, CbmFileEntry, CbmFileType, BYTES_PER_BLOCK};
+use crate::error::Error;
+use crate::geos::GeosDirInfo;
+use crate::string::PetsciiString;
+use crate::util::petscii_str_to_ascii;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+
+/// Size of a sector, including the 2 byte link to the next sector
+pub const SECTOR_SIZE: usize = 256;
+
+/// Size of a directory entry
+pub const DIR_ENTRY_SIZE: usize = 32;
+
+/// Number of directory entries in each directory sector
+pub const DIR_ENTRIES_PER_SECTOR: usize = SECTOR_SIZE / DIR_ENTRY_SIZE;
+
+/// Padding character used in filenames and disk names (shifted space)
+pub const PETSCII_PADDING: u8 = 0xa0;
+
+/// Maximum length of a filename
+pub const MAX_FILENAME_LEN: usize = 16;
+
+// The header sector, on the directory track
+const HEADER_SECTOR: u8 = 0;
+
+// Layout of the D64 and D71 BAM/header sector
+const D64_DISK_NAME: usize = 0x90;
+const D64_DISK_ID: usize = 0xa2;
+const D64_DOS_TYPE: usize = 0xa5;
+const D71_DOUBLE_SIDED: usize = 0x03;
+
+// Layout of the D81 header and BAM sectors
+const D81_DISK_NAME: usize = 0x04;
+const D81_DISK_ID: usize = 0x16;
+const D81_DOS_TYPE: usize = 0x19;
+const D81_BAM_VERSION: usize = 0x02;
+const D81_BAM_ID: usize = 0x04;
+const D81_BAM_IO_BYTE: usize = 0x06;
+
+// Offsets within a directory entry
+pub(crate) const DIR_TYPE: usize = 2;
+pub(crate) const DIR_TRACK: usize = 3;
+pub(crate) const DIR_SECTOR: usize = 4;
+pub(crate) const DIR_FILENAME: usize = 5;
+const DIR_SIDE_TRACK: usize = 21;
+const DIR_SIDE_SECTOR: usize = 22;
+const DIR_RECORD_LEN: usize = 23;
+pub(crate) const DIR_BLOCKS: usize = 30;
+
+// Layout of a REL file side sector
+const SS_NUMBER: usize = 2;
+const SS_RECORD_LEN: usize = 3;
+const SS_SIDE_SECTORS: usize = 4;
+const SS_DATA_BLOCKS: usize = 16;
+const SIDE_SECTOR_POINTERS: usize = 120;
+const MAX_SIDE_SECTORS: usize = 6;
+
+// Bits of the directory entry type byte
+pub(crate) const DIR_TYPE_CLOSED: u8 = 0x80;
+const DIR_TYPE_LOCKED: u8 = 0x40;
+
+/// Supported disk image formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiskImageFormat {
+    /// Standard 35 track 1541 image
+    D64,
+    /// Extended 40 track 1541 image
+    D64Ext,
+    /// Double sided 1571 image
+    D71,
+    /// 3.5" 1581 image
+    D81,
+}
+
+impl fmt::Display for DiskImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let output = match self {
+            DiskImageFormat::D64 => "D64",
+            DiskImageFormat::D64Ext => "D64 (40 track)",
+            DiskImageFormat::D71 => "D71",
+            DiskImageFormat::D81 => "D81",
+        };
+        write!(f, "{}", output)
+    }
+}
+
+impl DiskImageFormat {
+    /// Number of tracks in this format
+    pub fn num_tracks(&self) -> u8 {
+        match self {
+            DiskImageFormat::D64 => 35,
+            DiskImageFormat::D64Ext => 40,
+            DiskImageFormat::D71 => 70,
+            DiskImageFormat::D81 => 80,
+        }
+    }
+
+    /// Number of sectors on the given track.  Returns 0 if the track doesn't
+    /// exist in this format.
+    pub fn sectors_per_track(&self, track: u8) -> u8 {
+        match (self, track) {
+            _ if track == 0 || track > self.num_tracks() => 0,
+            (DiskImageFormat::D81, _) => 40,
+            (DiskImageFormat::D71, 36..) => DiskImageFormat::D64.sectors_per_track(track - 35),
+            (_, 1..=17) => 21,
+            (_, 18..=24) => 19,
+            (_, 25..=30) => 18,
+            _ => 17,
+        }
+    }
+
+    /// Total number of sectors in this format
+    pub fn total_sectors(&self) -> usize {
+        (1..=self.num_tracks())
+            .map(|track| self.sectors_per_track(track) as usize)
+            .sum()
+    }
+
+    /// Size of the image in bytes, excluding any error information
+    pub fn image_size(&self) -> usize {
+        self.total_sectors() * SECTOR_SIZE
+    }
+
+    /// The track holding the header, BAM and directory
+    pub fn dir_track(&self) -> u8 {
+        match self {
+            DiskImageFormat::D81 => 40,
+            _ => 18,
+        }
+    }
+
+    /// The first sector of the directory, on the directory track
+    pub fn first_dir_sector(&self) -> u8 {
+        match self {
+            DiskImageFormat::D81 => 3,
+            _ => 1,
+        }
+    }
+
+    /// The sectors holding the BAM
+    pub fn bam_sectors(&self) -> Vec<(u8, u8)> {
+        let dir_track = self.dir_track();
+        match self {
+            DiskImageFormat::D71 => vec![(dir_track, HEADER_SECTOR), (53, 0)],
+            DiskImageFormat::D81 => vec![(dir_track, 1), (dir_track, 2)],
+            _ => vec![(dir_track, HEADER_SECTOR)],
+        }
+    }
+
+    /// Whether the track is reserved for the header, BAM and directory, so
+    /// isn't used for files or included in the blocks free count
+    pub fn is_system_track(&self, track: u8) -> bool {
+        track == self.dir_track() || (*self == DiskImageFormat::D71 && track == 53)
+    }
+
+    // Sector interleave used by DOS when writing files
+    fn file_interleave(&self) -> u8 {
+        match self {
+            DiskImageFormat::D71 => 6,
+            DiskImageFormat::D81 => 1,
+            _ => 10,
+        }
+    }
+
+    // Sector interleave used by DOS when adding directory sectors
+    fn dir_interleave(&self) -> u8 {
+        match self {
+            DiskImageFormat::D81 => 1,
+            _ => 3,
+        }
+    }
+
+    /// Figures out the format of an image from its size, returning the
+    /// format and whether error information is included
+    pub fn from_image_size(size: usize) -> Option<(Self, bool)> {
+        [
+            DiskImageFormat::D64,
+            DiskImageFormat::D64Ext,
+            DiskImageFormat::D71,
+            DiskImageFormat::D81,
+        ]
+        .into_iter()
+        .find_map(|format| {
+            if size == format.image_size() {
+                Some((format, false))
+            } else if size == format.image_size() + format.total_sectors() {
+                Some((format, true))
+            } else {
+                None
+            }
+        })
+    }
+
+    // Index of the given sector within the image
+    fn sector_index(&self, track: u8, sector: u8) -> Option<usize> {
+        if sector >= self.sectors_per_track(track) {
+            return None;
+        }
+        let index: usize = (1..track)
+            .map(|t| self.sectors_per_track(t) as usize)
+            .sum::<usize>()
+            + sector as usize;
+        Some(index)
+    }
+}
+
+/// A raw directory entry, as stored on disk
+///
+/// # Fields
+///
+/// * `file_type` - Type of the file, Unknown for DEL files
+/// * `type_byte` - Raw type byte, including the closed and locked flags
+/// * `track`/`sector` - Location of the first block of the file
+/// * `filename` - Filename in PETSCII, with padding removed
+/// * `side_track`/`side_sector` - First side sector (REL files)
+/// * `record_len` - Record length (REL files)
+/// * `blocks` - Size of the file in blocks, as stored in the directory
+/// * `dir_track`/`dir_sector`/`dir_index` - Location of this entry in the
+///   directory
+/// * `raw` - The raw directory entry.  The first 2 bytes are only valid for
+///   the first entry in a directory sector
+#[derive(Debug, Clone, PartialEq)]
+pub struct CbmDirEntry {
+    pub file_type: CbmFileType,
+    pub type_byte: u8,
+    pub track: u8,
+    pub sector: u8,
+    pub filename: PetsciiString,
+    pub side_track: u8,
+    pub side_sector: u8,
+    pub record_len: u8,
+    pub blocks: u16,
+    pub dir_track: u8,
+    pub dir_sector: u8,
+    pub dir_index: u8,
+    pub raw: [u8; DIR_ENTRY_SIZE],
+}
+
+impl CbmDirEntry {
+    /// Parses a directory entry from the raw bytes
+    pub fn from_raw(raw: &[u8], dir_track: u8, dir_sector: u8, dir_index: u8) -> Self {
+        let mut entry = [0u8; DIR_ENTRY_SIZE];
+        entry.copy_from_slice(&raw[..DIR_ENTRY_SIZE]);
+        let type_byte = entry[DIR_TYPE];
+        CbmDirEntry {
+            file_type: CbmFileType::from_dir_type(type_byte),
+            type_byte,
+            track: entry[DIR_TRACK],
+            sector: entry[DIR_SECTOR],
+            filename: PetsciiString::from_petscii_bytes(strip_padding(
+                &entry[DIR_FILENAME..DIR_FILENAME + MAX_FILENAME_LEN],
+            )),
+            side_track: entry[DIR_SIDE_TRACK],
+            side_sector: entry[DIR_SIDE_SECTOR],
+            record_len: entry[DIR_RECORD_LEN],
+            blocks: u16::from_le_bytes([entry[DIR_BLOCKS], entry[DIR_BLOCKS + 1]]),
+            dir_track,
+            dir_sector,
+            dir_index,
+            raw: entry,
+        }
+    }
+
+    /// Whether this slot is unused (the type byte is 0)
+    pub fn is_empty(&self) -> bool {
+        self.type_byte == 0
+    }
+
+    /// Whether the file was closed properly.  Unclosed files are shown with
+    /// a `*` in directory listings.
+    pub fn is_closed(&self) -> bool {
+        self.type_byte & DIR_TYPE_CLOSED != 0
+    }
+
+    /// Whether the file is locked (shown with a `<` in directory listings)
+    pub fn is_locked(&self) -> bool {
+        self.type_byte & DIR_TYPE_LOCKED != 0
+    }
+
+    /// The filename, converted to ASCII
+    pub fn filename_ascii(&self) -> String {
+        petscii_str_to_ascii(self.filename.as_bytes())
+    }
+
+    /// Converts to the entry type returned by directory listings
+    pub fn to_file_entry(&self) -> CbmFileEntry {
+        CbmFileEntry::ValidFile {
+            blocks: self.blocks,
+            filename: self.filename_ascii(),
+            file_type: self.file_type,
+        }
+    }
+
+    /// Returns the GEOS fields of the entry, or None if this isn't a GEOS
+    /// file
+    pub fn geos(&self) -> Option<GeosDirInfo> {
+        GeosDirInfo::from_dir_entry(self)
+    }
+}
+
+// Removes trailing padding characters from a name
+pub(crate) fn strip_padding(name: &[u8]) -> &[u8] {
+    let len = name
+        .iter()
+        .rposition(|&c| c != PETSCII_PADDING)
+        .map(|pos| pos + 1)
+        .unwrap_or(0);
+    &name[..len]
+}
+
+// Copies a name into a field, padding with shifted spaces
+pub(crate) fn write_padded(field: &mut [u8], name: &[u8]) {
+    field.fill(PETSCII_PADDING);
+    let len = name.len().min(field.len());
+    field[..len].copy_from_slice(&name[..len]);
+}
+
+/// An in-memory disk image
+#[derive(Debug, Clone)]
+pub struct DiskImage {
+    format: DiskImageFormat,
+    data: Vec<u8>,
+    error_info: Option<Vec<u8>>,
+}
+
+/// Functions to create, load and save images
+impl DiskImage {
+    /// Creates a new, formatted, image
+    ///
+    /// # Arguments
+    /// * `format` - Format of the image
+    /// * `name` - Disk name in PETSCII, up to 16 characters
+    /// * `id` - Disk ID in PETSCII, 2 characters
+    pub fn new(format: DiskImageFormat, name: &PetsciiString, id: &PetsciiString) -> Self {
+        let mut image = DiskImage {
+            format,
+            data: vec![0u8; format.image_size()],
+            error_info: None,
+        };
+
+        let dir_track = format.dir_track();
+        let first_dir_sector = format.first_dir_sector();
+        for track in 1..=format.num_tracks() {
+            for sector in 0..format.sectors_per_track(track) {
+                image.set_sector_free(track, sector, true);
+            }
+        }
+
+        let name = &name.as_bytes()[..name.as_bytes().len().min(MAX_FILENAME_LEN)];
+        let mut disk_id = [PETSCII_PADDING; 2];
+        let id_len = id.as_bytes().len().min(2);
+        disk_id[..id_len].copy_from_slice(&id.as_bytes()[..id_len]);
+
+        let header = image.sector_mut(dir_track, HEADER_SECTOR);
+        header[0] = dir_track;
+        header[1] = first_dir_sector;
+        if format == DiskImageFormat::D81 {
+            header[2] = b'D';
+            write_padded(&mut header[D81_DISK_NAME..D81_DISK_ID], name);
+            write_padded(&mut header[D81_DISK_ID..D81_DOS_TYPE + 4], &disk_id);
+            header[D81_DOS_TYPE] = b'3';
+            header[D81_DOS_TYPE + 1] = b'D';
+
+            // The BAM sectors are chained together, and each holds a copy of
+            // the ID
+            let bam_sectors = format.bam_sectors();
+            for (ii, (track, sector)) in bam_sectors.iter().enumerate() {
+                let bam = image.sector_mut(*track, *sector);
+                match bam_sectors.get(ii + 1) {
+                    Some((next_track, next_sector)) => {
+                        bam[0] = *next_track;
+                        bam[1] = *next_sector;
+                    }
+                    None => bam[1] = 0xff,
+                }
+                bam[D81_BAM_VERSION] = b'D';
+                bam[D81_BAM_VERSION + 1] = !b'D';
+                bam[D81_BAM_ID..D81_BAM_ID + 2].copy_from_slice(&disk_id);
+                bam[D81_BAM_IO_BYTE] = 0xc0;
+            }
+        } else {
+            header[2] = b'A';
+            write_padded(&mut header[D64_DISK_NAME..D64_DISK_ID], name);
+            write_padded(&mut header[D64_DISK_ID..D64_DOS_TYPE + 6], &disk_id);
+            header[D64_DOS_TYPE] = b'2';
+            header[D64_DOS_TYPE + 1] = b'A';
+            if format == DiskImageFormat::D71 {
+                header[D71_DOUBLE_SIDED] = 0x80;
+            }
+        }
+
+        let dir = image.sector_mut(dir_track, first_dir_sector);
+        dir[1] = 0xff;
+
+        image.set_sector_free(dir_track, HEADER_SECTOR, false);
+        image.set_sector_free(dir_track, first_dir_sector, false);
+        for track in 1..=format.num_tracks() {
+            for sector in 0..format.sectors_per_track(track) {
+                if format.bam_sectors().contains(&(track, sector))
+                    || (format.is_system_track(track) && track != dir_track)
+                {
+                    image.set_sector_free(track, sector, false);
+                }
+            }
+        }
+        image
+    }
+
+    /// Creates an image from its raw contents.  The format is determined
+    /// from the size.
+    pub fn from_bytes(mut data: Vec<u8>) -> Result<Self, Error> {
+        let (format, has_errors) =
+            DiskImageFormat::from_image_size(data.len()).ok_or_else(|| Error::Validation {
+                message: format!("Unsupported disk image size {}", data.len()),
+            })?;
+        let error_info = if has_errors {
+            Some(data.split_off(format.image_size()))
+        } else {
+            None
+        };
+        Ok(DiskImage {
+            format,
+            data,
+            error_info,
+        })
+    }
+
+    /// Loads an image from a file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::from_bytes(std::fs::read(path)?)
+    }
+
+    /// Writes the image, including any error information, to a file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        std::fs::write(path, self.to_bytes()).map_err(|e| e.into())
+    }
+
+    /// Returns the raw contents of the image, including any error
+    /// information
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut output = self.data.clone();
+        if let Some(error_info) = &self.error_info {
+            output.extend_from_slice(error_info);
+        }
+        output
+    }
+
+    /// Returns the format of this image
+    pub fn format(&self) -> DiskImageFormat {
+        self.format
+    }
+}
+
+/// Sector level functions
+impl DiskImage {
+    /// Reads a sector
+    pub fn read_sector(&self, track: u8, sector: u8) -> Result<&[u8], Error> {
+        let offset = self.offset_or_err(track, sector)?;
+        Ok(&self.data[offset..offset + SECTOR_SIZE])
+    }
+
+    /// Writes a sector.  `data` must be [`SECTOR_SIZE`] bytes.
+    pub fn write_sector(&mut self, track: u8, sector: u8, data: &[u8]) -> Result<(), Error> {
+        if data.len() != SECTOR_SIZE {
+            return Err(Error::Validation {
+                message: format!(
+                    "Sector data must be {SECTOR_SIZE} bytes, got {}",
+                    data.len()
+                ),
+            });
+        }
+        let offset = self.offset_or_err(track, sector)?;
+        self.data[offset..offset + SECTOR_SIZE].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Returns the error code stored for a sector, if the image includes
+    /// error information.  1 indicates no error, other values are the DOS
+    /// error number.
+    pub fn sector_error(&self, track: u8, sector: u8) -> Option<u8> {
+        let index = self.format.sector_index(track, sector)?;
+        self.error_info.as_ref().map(|info| info[index])
+    }
+
+    fn offset_or_err(&self, track: u8, sector: u8) -> Result<usize, Error> {
+        self.format
+            .sector_index(track, sector)
+            .map(|index| index * SECTOR_SIZE)
+            .ok_or_else(|| Error::Validation {
+                message: format!("Invalid track/sector {track}/{sector} for {}", self.format),
+            })
+    }
+
+    // Only for use with known good track and sector values
+    fn sector_mut(&mut self, track: u8, sector: u8) -> &mut [u8] {
+        let offset = self.format.sector_index(track, sector).unwrap() * SECTOR_SIZE;
+        &mut self.data[offset..offset + SECTOR_SIZE]
+    }
+
+    /// Follows a chain of sectors from the given track and sector, returning
+    /// the data they contain
+    pub fn read_chain(&self, track: u8, sector: u8) -> Result<Vec<u8>, Error> {
+        block::read_chain(self, track, sector)
+    }
+}
+
+/// Block Availability Map functions
+impl DiskImage {
+    // Returns the track's bitmap from the BAM
+    fn bam_bitmap(&self, track: u8) -> &[u8] {
+        let location = BamLocation::new(self.format, track);
+        let (bam_track, bam_sector, offset) = location.bitmap;
+        let bam = self.read_sector(bam_track, bam_sector).unwrap();
+        &bam[offset..offset + location.bitmap_len]
+    }
+
+    /// Whether the given sector is marked as free in the BAM
+    pub fn is_sector_free(&self, track: u8, sector: u8) -> bool {
+        if sector >= self.format.sectors_per_track(track) {
+            return false;
+        }
+        let bitmap = self.bam_bitmap(track);
+        bitmap[sector as usize / 8] & (1 << (sector % 8)) != 0
+    }
+
+    /// Marks a sector as allocated in the BAM
+    pub fn allocate_sector(&mut self, track: u8, sector: u8) -> Result<(), Error> {
+        self.offset_or_err(track, sector)?;
+        self.set_sector_free(track, sector, false);
+        Ok(())
+    }
+
+    /// Marks a sector as free in the BAM
+    pub fn free_sector(&mut self, track: u8, sector: u8) -> Result<(), Error> {
+        self.offset_or_err(track, sector)?;
+        self.set_sector_free(track, sector, true);
+        Ok(())
+    }
+
+    // Updates the bitmap and the free count for the track
+    fn set_sector_free(&mut self, track: u8, sector: u8, free: bool) {
+        let location = BamLocation::new(self.format, track);
+        let (bam_track, bam_sector, offset) = location.bitmap;
+        let bam = self.sector_mut(bam_track, bam_sector);
+        let bitmap = &mut bam[offset..offset + location.bitmap_len];
+        let mask = 1 << (sector % 8);
+        if free {
+            bitmap[sector as usize / 8] |= mask;
+        } else {
+            bitmap[sector as usize / 8] &= !mask;
+        }
+        let count = bitmap.iter().map(|b| b.count_ones() as u8).sum();
+        let (bam_track, bam_sector, offset) = location.count;
+        self.sector_mut(bam_track, bam_sector)[offset] = count;
+    }
+
+    /// Number of free sectors on a track, according to the BAM bitmap
+    pub fn track_blocks_free(&self, track: u8) -> u8 {
+        (0..self.format.sectors_per_track(track))
+            .filter(|&sector| self.is_sector_free(track, sector))
+            .count() as u8
+    }
+
+    /// Number of free blocks on the disk, excluding the directory track(s)
+    /// (as reported by DOS)
+    pub fn blocks_free(&self) -> u16 {
+        (1..=self.format.num_tracks())
+            .filter(|&track| !self.format.is_system_track(track))
+            .map(|track| self.track_blocks_free(track) as u16)
+            .sum()
+    }
+
+    // Finds the next free sector for a file, following the DOS strategy of
+    // starting close to the directory track and working outwards
+    fn next_free_sector(&self, prev: Option<(u8, u8)>) -> Option<(u8, u8)> {
+        let num_tracks = self.format.num_tracks();
+        let dir_track = self.format.dir_track();
+
+        let mut tracks = Vec::new();
+        if let Some((track, _)) = prev {
+            // Continue on this track then further away from the directory
+            tracks.push(track);
+            if track < dir_track {
+                tracks.extend((1..track).rev());
+            } else {
+                tracks.extend(track + 1..=num_tracks);
+            }
+        }
+        for distance in 1..num_tracks {
+            if distance < dir_track {
+                tracks.push(dir_track - distance);
+            }
+            if dir_track + distance <= num_tracks {
+                tracks.push(dir_track + distance);
+            }
+        }
+
+        tracks
+            .into_iter()
+            .filter(|&track| !self.format.is_system_track(track))
+            .find_map(|track| {
+                let sectors = self.format.sectors_per_track(track);
+                let start = match prev {
+                    Some((prev_track, prev_sector)) if prev_track == track => {
+                        (prev_sector + self.format.file_interleave()) % sectors
+                    }
+                    _ => 0,
+                };
+                (0..sectors)
+                    .map(|ii| (start + ii) % sectors)
+                    .find(|&sector| self.is_sector_free(track, sector))
+                    .map(|sector| (track, sector))
+            })
+    }
+}
+
+/// Directory and file functions
+impl DiskImage {
+    /// Returns the disk header
+    pub fn header(&self) -> CbmDiskHeader {
+        let header = self
+            .read_sector(self.format.dir_track(), HEADER_SECTOR)
+            .unwrap();
+        let (name, id) = match self.format {
+            DiskImageFormat::D81 => (D81_DISK_NAME, D81_DISK_ID),
+            _ => (D64_DISK_NAME, D64_DISK_ID),
+        };
+        CbmDiskHeader {
+            drive_number: 0,
+            name: petscii_str_to_ascii(strip_padding(&header[name..name + MAX_FILENAME_LEN])),
+            id: petscii_str_to_ascii(&header[id..id + 2]),
+        }
+    }
+
+    /// Returns all directory slots, including empty ones and deleted files
+    pub fn dir_slots(&self) -> Result<Vec<CbmDirEntry>, Error> {
+        block::dir_slots(self)
+    }
+
+    /// Returns the directory entries which would appear in a directory
+    /// listing
+    pub fn dir_entries(&self) -> Result<Vec<CbmDirEntry>, Error> {
+        Ok(self
+            .dir_slots()?
+            .into_iter()
+            .filter(|entry| !entry.is_empty())
+            .collect())
+    }
+
+    /// Returns the directory listing, in the same form as [`crate::Cbm::dir`]
+    pub fn dir(&self) -> Result<CbmDirListing, Error> {
+        Ok(CbmDirListing {
+            header: self.header(),
+            files: self
+                .dir_entries()?
+                .iter()
+                .map(|entry| entry.to_file_entry())
+                .collect(),
+            blocks_free: self.blocks_free(),
+        })
+    }
+
+    /// Finds a file by its PETSCII name
+    pub fn find_file(&self, filename: &PetsciiString) -> Result<Option<CbmDirEntry>, Error> {
+        Ok(self
+            .dir_entries()?
+            .into_iter()
+            .find(|entry| entry.is_closed() && entry.filename == *filename))
+    }
+
+    /// Reads the contents of a file.  For PRG files this includes the load
+    /// address.
+    pub fn read_file(&self, entry: &CbmDirEntry) -> Result<Vec<u8>, Error> {
+        self.read_chain(entry.track, entry.sector)
+    }
+
+    /// Writes a new file to the image
+    ///
+    /// # Arguments
+    /// * `filename` - Filename in PETSCII, up to 16 characters
+    /// * `file_type` - Type of the file.  Use [`DiskImage::write_rel_file`]
+    ///   for REL files.
+    /// * `data` - Contents of the file.  For PRG files this must start with
+    ///   the load address.
+    ///
+    /// # Errors
+    /// Returns `Error::Validation` if the file already exists, the name or
+    /// type are invalid, or there is not enough space on the disk.
+    pub fn write_file(
+        &mut self,
+        filename: &PetsciiString,
+        file_type: CbmFileType,
+        data: &[u8],
+    ) -> Result<CbmDirEntry, Error> {
+        if matches!(file_type, CbmFileType::REL | CbmFileType::Unknown) {
+            return Err(Error::Validation {
+                message: format!("Can't write file of type {file_type:?} to image with write_file"),
+            });
+        }
+
+        let blocks = data.len().div_ceil(BYTES_PER_BLOCK).max(1);
+        self.check_new_file(filename, blocks)?;
+
+        // Find a directory slot first, as this may need a new directory
+        // sector
+        let slot = self.free_dir_slot()?;
+        let sectors = self.allocate_sectors(blocks)?;
+        self.write_chain(&sectors, data)?;
+
+        let raw = self.write_dir_entry(slot, file_type, filename, sectors[0], blocks);
+        Ok(CbmDirEntry::from_raw(raw, slot.0, slot.1, slot.2))
+    }
+
+    /// Writes a new REL file to the image, creating its side sectors
+    ///
+    /// # Arguments
+    /// * `filename` - Filename in PETSCII, up to 16 characters
+    /// * `record_len` - Length of each record, 1-254
+    /// * `data` - The records, stored contiguously
+    pub fn write_rel_file(
+        &mut self,
+        filename: &PetsciiString,
+        record_len: u8,
+        data: &[u8],
+    ) -> Result<CbmDirEntry, Error> {
+        if record_len == 0 || record_len as usize > BYTES_PER_BLOCK {
+            return Err(Error::Validation {
+                message: format!("Invalid REL record length {record_len}"),
+            });
+        }
+
+        let data_blocks = data.len().div_ceil(BYTES_PER_BLOCK).max(1);
+        let side_sectors = data_blocks.div_ceil(SIDE_SECTOR_POINTERS);
+        if side_sectors > MAX_SIDE_SECTORS {
+            return Err(Error::Validation {
+                message: format!("REL file too large ({data_blocks} data blocks)"),
+            });
+        }
+        self.check_new_file(filename, data_blocks + side_sectors)?;
+
+        let slot = self.free_dir_slot()?;
+        let sectors = self.allocate_sectors(data_blocks + side_sectors)?;
+        let (data_sectors, ss_sectors) = sectors.split_at(data_blocks);
+        self.write_chain(data_sectors, data)?;
+
+        // Each side sector lists all of the side sectors, then the data
+        // blocks it covers
+        for (ii, pointers) in data_sectors.chunks(SIDE_SECTOR_POINTERS).enumerate() {
+            let mut block = [0u8; SECTOR_SIZE];
+            match ss_sectors.get(ii + 1) {
+                Some((track, sector)) => {
+                    block[0] = *track;
+                    block[1] = *sector;
+                }
+                None => block[1] = (SS_DATA_BLOCKS + pointers.len() * 2 - 1) as u8,
+            }
+            block[SS_NUMBER] = ii as u8;
+            block[SS_RECORD_LEN] = record_len;
+            for (jj, (track, sector)) in ss_sectors.iter().enumerate() {
+                block[SS_SIDE_SECTORS + jj * 2] = *track;
+                block[SS_SIDE_SECTORS + jj * 2 + 1] = *sector;
+            }
+            for (jj, (track, sector)) in pointers.iter().enumerate() {
+                block[SS_DATA_BLOCKS + jj * 2] = *track;
+                block[SS_DATA_BLOCKS + jj * 2 + 1] = *sector;
+            }
+            let (track, sector) = ss_sectors[ii];
+            self.write_sector(track, sector, &block)?;
+        }
+
+        let raw = self.write_dir_entry(
+            slot,
+            CbmFileType::REL,
+            filename,
+            data_sectors[0],
+            sectors.len(),
+        );
+        raw[DIR_SIDE_TRACK] = ss_sectors[0].0;
+        raw[DIR_SIDE_SECTOR] = ss_sectors[0].1;
+        raw[DIR_RECORD_LEN] = record_len;
+        Ok(CbmDirEntry::from_raw(raw, slot.0, slot.1, slot.2))
+    }
+
+    // Checks a new file can be written, with the given number of blocks
+    pub(crate) fn check_new_file(
+        &self,
+        filename: &PetsciiString,
+        blocks: usize,
+    ) -> Result<(), Error> {
+        let name = filename.as_bytes();
+        if name.is_empty() || name.len() > MAX_FILENAME_LEN {
+            return Err(Error::Validation {
+                message: format!("Invalid filename length {}", name.len()),
+            });
+        }
+        if self.find_file(filename)?.is_some() {
+            return Err(Error::Validation {
+                message: format!(
+                    "File {} already exists",
+                    petscii_str_to_ascii(filename.as_bytes())
+                ),
+            });
+        }
+        if blocks > self.blocks_free() as usize {
+            return Err(Error::Validation {
+                message: format!(
+                    "Not enough space for {blocks} blocks, {} free",
+                    self.blocks_free()
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    // Allocates sectors for a new file
+    pub(crate) fn allocate_sectors(&mut self, count: usize) -> Result<Vec<(u8, u8)>, Error> {
+        let mut sectors = Vec::with_capacity(count);
+        let mut prev = None;
+        for _ in 0..count {
+            let (track, sector) = self
+                .next_free_sector(prev)
+                .ok_or_else(|| Error::Validation {
+                    message: "Disk full".to_string(),
+                })?;
+            self.set_sector_free(track, sector, false);
+            sectors.push((track, sector));
+            prev = Some((track, sector));
+        }
+        Ok(sectors)
+    }
+
+    // Writes the data to the sectors, linking each sector to the next
+    pub(crate) fn write_chain(&mut self, sectors: &[(u8, u8)], data: &[u8]) -> Result<(), Error> {
+        let mut chunks: Vec<&[u8]> = data.chunks(BYTES_PER_BLOCK).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+        for (ii, chunk) in chunks.iter().enumerate() {
+            let mut block = [0u8; SECTOR_SIZE];
+            match sectors.get(ii + 1) {
+                Some((track, sector)) => {
+                    block[0] = *track;
+                    block[1] = *sector;
+                }
+                None => block[1] = (chunk.len() + 1) as u8,
+            }
+            block[2..2 + chunk.len()].copy_from_slice(chunk);
+            let (track, sector) = sectors[ii];
+            self.write_sector(track, sector, &block)?;
+        }
+        Ok(())
+    }
+
+    // Fills in the directory entry for a new file, returning the raw entry
+    // so type specific fields can be added
+    pub(crate) fn write_dir_entry(
+        &mut self,
+        slot: (u8, u8, u8),
+        file_type: CbmFileType,
+        filename: &PetsciiString,
+        first: (u8, u8),
+        blocks: usize,
+    ) -> &mut [u8] {
+        let (dir_track, dir_sector, dir_index) = slot;
+        let dir_block = self.sector_mut(dir_track, dir_sector);
+        let raw = &mut dir_block[dir_index as usize * DIR_ENTRY_SIZE..][..DIR_ENTRY_SIZE];
+        raw[DIR_TYPE..].fill(0);
+        raw[DIR_TYPE] = DIR_TYPE_CLOSED | file_type.to_dir_type();
+        raw[DIR_TRACK] = first.0;
+        raw[DIR_SECTOR] = first.1;
+        write_padded(
+            &mut raw[DIR_FILENAME..DIR_FILENAME + MAX_FILENAME_LEN],
+            filename.as_bytes(),
+        );
+        raw[DIR_BLOCKS..DIR_BLOCKS + 2].copy_from_slice(&(blocks as u16).to_le_bytes());
+        raw
+    }
+
+    // Finds an unused directory slot, adding a new directory sector if
+    // required
+    pub(crate) fn free_dir_slot(&mut self) -> Result<(u8, u8, u8), Error> {
+        if let Some(entry) = self.dir_slots()?.into_iter().find(|e| e.is_empty()) {
+            return Ok((entry.dir_track, entry.dir_sector, entry.dir_index));
+        }
+
+        let dir_track = self.format.dir_track();
+        let (last_track, last_sector) = *block::dir_sectors(self)?.last().unwrap();
+        let sectors = self.format.sectors_per_track(dir_track);
+        let new_sector = (0..sectors)
+            .map(|ii| (last_sector + self.format.dir_interleave() + ii) % sectors)
+            .find(|&sector| self.is_sector_free(dir_track, sector))
+            .ok_or_else(|| Error::Validation {
+                message: "Directory full".to_string(),
+            })?;
+
+        self.set_sector_free(dir_track, new_sector, false);
+        let mut block = [0u8; SECTOR_SIZE];
+        block[1] = 0xff;
+        self.write_sector(dir_track, new_sector, &block)?;
+        let last = self.sector_mut(last_track, last_sector);
+        last[0] = dir_track;
+        last[1] = new_sector;
+
+        Ok((dir_track, new_sector, 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_image() -> DiskImage {
+        DiskImage::new(
+            DiskImageFormat::D64,
+            &PetsciiString::from_ascii_str("testdisk"),
+            &PetsciiString::from_ascii_str("01"),
+        )
+    }
+
+    #[test]
+    fn test_format_geometry() {
+        assert_eq!(DiskImageFormat::D64.total_sectors(), 683);
+        assert_eq!(DiskImageFormat::D64.image_size(), 174848);
+        assert_eq!(DiskImageFormat::D64Ext.image_size(), 196608);
+        assert_eq!(
+            DiskImageFormat::from_image_size(175531),
+            Some((DiskImageFormat::D64, true))
+        );
+        assert_eq!(DiskImageFormat::from_image_size(1000), None);
+        assert_eq!(DiskImageFormat::D64.sectors_per_track(36), 0);
+        assert_eq!(DiskImageFormat::D71.sectors_per_track(36), 21);
+        assert_eq!(DiskImageFormat::D71.image_size(), 349696);
+        assert_eq!(DiskImageFormat::D81.image_size(), 819200);
+        assert_eq!(
+            DiskImageFormat::from_image_size(822400),
+            Some((DiskImageFormat::D81, true))
+        );
+    }
+
+    #[test]
+    fn test_new_image() {
+        let image = new_image();
+        assert_eq!(image.blocks_free(), 664);
+        assert_eq!(image.header().name, "testdisk");
+        assert_eq!(image.header().id, "01");
+        assert!(image.dir_entries().unwrap().is_empty());
+        assert!(!image.is_sector_free(18, 0));
+        assert!(image.is_sector_free(18, 2));
+    }
+
+    #[test]
+    fn test_new_d81_image() {
+        let mut image = DiskImage::new(
+            DiskImageFormat::D81,
+            &PetsciiString::from_ascii_str("disk81"),
+            &PetsciiString::from_ascii_str("81"),
+        );
+        assert_eq!(image.header().name, "disk81");
+        assert_eq!(image.header().id, "81");
+        assert_eq!(image.blocks_free(), 3160);
+        assert!(!image.is_sector_free(40, 3));
+
+        let name = PetsciiString::from_ascii_str("test");
+        let entry = image.write_file(&name, CbmFileType::PRG, &[1, 2]).unwrap();
+        assert_eq!(entry.dir_track, 40);
+        assert_eq!(entry.dir_sector, 3);
+        assert_eq!(image.read_file(&entry).unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_write_read_file() {
+        let mut image = new_image();
+        let data: Vec<u8> = (0..1000).map(|ii| ii as u8).collect();
+        let name = PetsciiString::from_ascii_str("test");
+        let entry = image.write_file(&name, CbmFileType::PRG, &data).unwrap();
+        assert_eq!(entry.blocks, 4);
+        assert_eq!(entry.track, 17);
+        assert_eq!(image.blocks_free(), 660);
+
+        let found = image.find_file(&name).unwrap().unwrap();
+        assert_eq!(found, entry);
+        assert_eq!(image.read_file(&found).unwrap(), data);
+
+        // Can't write the same file twice
+        assert!(image.write_file(&name, CbmFileType::PRG, &data).is_err());
+    }
+
+    #[test]
+    fn test_empty_file() {
+        let mut image = new_image();
+        let name = PetsciiString::from_ascii_str("empty");
+        let entry = image.write_file(&name, CbmFileType::SEQ, &[]).unwrap();
+        assert_eq!(entry.blocks, 1);
+        assert!(image.read_file(&entry).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_write_rel_file() {
+        let mut image = new_image();
+        let name = PetsciiString::from_ascii_str("records");
+        let data: Vec<u8> = (0..50 * 254).map(|ii| (ii % 251) as u8).collect();
+        let entry = image.write_rel_file(&name, 100, &data).unwrap();
+        assert_eq!(entry.file_type, CbmFileType::REL);
+        assert_eq!(entry.record_len, 100);
+        assert_eq!(entry.blocks, 51);
+        assert_eq!(image.read_file(&entry).unwrap(), data);
+
+        let ss = image
+            .read_sector(entry.side_track, entry.side_sector)
+            .unwrap();
+        assert_eq!(ss[0], 0);
+        assert_eq!(ss[1] as usize, SS_DATA_BLOCKS + 50 * 2 - 1);
+        assert_eq!(ss[SS_RECORD_LEN], 100);
+        assert_eq!(
+            (ss[SS_SIDE_SECTORS], ss[SS_SIDE_SECTORS + 1]),
+            (entry.side_track, entry.side_sector)
+        );
+        assert_eq!(
+            (ss[SS_DATA_BLOCKS], ss[SS_DATA_BLOCKS + 1]),
+            (entry.track, entry.sector)
+        );
+    }
+
+    #[test]
+    fn test_directory_grows() {
+        let mut image = new_image();
+        for ii in 0..20 {
+            let name = PetsciiString::from_ascii_str(&format!("file{ii}"));
+            image.write_file(&name, CbmFileType::SEQ, &[ii]).unwrap();
+        }
+        assert_eq!(image.dir_entries().unwrap().len(), 20);
+        assert_eq!(
+            block::dir_sectors(&image).unwrap(),
+            vec![(18, 1), (18, 4), (18, 7)]
+        );
+        assert_eq!(image.dir().unwrap().files.len(), 20);
+    }
+}
@@ -53,17 +53,49 @@
 //! XUM1541 errors and drive-specific error codes.
 
 // Define rs1541 modules
+pub mod archive;
+#[cfg(feature = "tokio")]
+pub mod asynccbm;
+pub mod bam;
+pub mod block;
 pub mod cbm;
 pub mod cbmtype;
 pub mod channel;
+pub mod checksum;
+#[cfg(unix)]
+pub mod client;
+pub mod compare;
+pub mod context;
+pub mod copy;
+#[cfg(unix)]
+pub mod daemon;
 pub mod disk;
+pub mod diskcheck;
 pub mod drive;
 pub mod error;
+pub mod geos;
+pub mod image;
+pub mod lock;
+pub mod mount;
+pub mod nibbler;
+pub mod pc64;
+pub mod pool;
+pub mod reconnect;
+pub mod recover;
 pub mod string;
+pub mod surface;
+pub mod t64;
+pub mod timeout;
+pub mod transfer;
 pub mod util;
 pub mod validate;
 
 /// Export the public API
+pub use archive::{Archive, ArchiveEntry, ArchiveFormat};
+#[cfg(feature = "tokio")]
+pub use asynccbm::AsyncCbm;
+pub use bam::{Bam, BamProblem, BamTrack};
+pub use block::{BlockDevice, DriveBlocks};
 pub use cbm::Cbm;
 pub use cbmtype::{
     CbmDeviceInfo, CbmDeviceType, CbmErrorNumber, CbmErrorNumberOk, CbmOperation, CbmOperationType,
@@ -71,10 +103,36 @@ pub use cbmtype::{
 };
 pub use channel::{CbmChannel, CbmChannelManager, CbmChannelPurpose};
 pub use channel::{CBM_CHANNEL_CTRL, CBM_CHANNEL_LOAD};
+pub use checksum::crc16;
+#[cfg(unix)]
+pub use client::CbmClient;
+pub use compare::{compare_disks, DiskComparison, FileDifference, SectorDifference};
+pub use context::{OpContext, Progress, ProgressUnit};
+pub use copy::{CopyEndpoint, CopyMethod};
+#[cfg(unix)]
+pub use daemon::{Daemon, DEFAULT_SOCKET_PATH, PROTOCOL_VERSION};
 pub use disk::{CbmDirListing, CbmDiskHeader, CbmFileEntry, CbmFileType};
+pub use diskcheck::{check_disk, DiskCheckReport, DiskProblem};
 pub use drive::CbmDriveUnit;
 pub use error::{DeviceError, Error};
+pub use geos::{
+    GeosData, GeosDirInfo, GeosFile, GeosFileType, GeosInfoBlock, GeosStructure, GeosTimestamp,
+};
+pub use image::{CbmDirEntry, DiskImage, DiskImageFormat};
+pub use lock::AdapterLock;
+pub use mount::{
+    fs_file_name, open_name, parse_fs_file_name, DirCache, FileMode, MountedDir, MountedFile,
+};
+pub use nibbler::{NibblerConfig, RawDisk, RawTrack};
+pub use pc64::Pc64File;
+pub use pool::{adapter_info, list_adapters, CbmPool, DriveAddress, Xum1541Adapter};
+pub use reconnect::{ReconnectEvent, ReconnectPolicy};
+pub use recover::{carve_files, scratched_files, CarvedFile, ChainStatus, ScratchedFile};
 pub use string::{AsciiString, CbmString, PetsciiString};
+pub use surface::{scan_surface, ScanOptions, SectorScan, SurfaceScan};
+pub use t64::{T64Archive, T64Entry, T64EntryType};
+pub use timeout::{BusOp, RecoveryAttempt, RecoveryStep, Timeouts};
+pub use transfer::{TransferMode, BATCH_SECTORS};
 pub use util::{ascii_str_to_petscii, ascii_to_petscii, petscii_str_to_ascii, petscii_to_ascii};
 pub use validate::{validate_device, DeviceValidation};
 
@@ -89,28 +147,22 @@ pub use xum1541::{Device, RemoteUsbDevice, UsbDevice};
 pub use xum1541::device::remoteusb::DEFAULT_ADDR as DEFAULT_REMOTE_ADDR;
 pub use xum1541::device::remoteusb::DEFAULT_PORT as DEFAULT_REMOTE_PORT;
 
-/// A trait to allow us to get the Bus as a reference from a MutexGuard and
-/// automatically convert the None case to a Error
-trait BusGuardRef {
-    fn bus_ref_or_err(&self) -> Result<&xum1541::Bus, Error>;
+/// A trait to allow us to get the Bus as a mutable reference from a
+/// MutexGuard and automatically convert the None case to a Error
+trait BusGuardMut {
+    fn bus_mut_or_err(&mut self) -> Result<&mut xum1541::Bus, Error>;
 }
 
-impl BusGuardRef for parking_lot::MutexGuard<'_, Option<xum1541::Bus>> {
-    fn bus_ref_or_err(&self) -> Result<&xum1541::Bus, Error> {
-        self.as_ref()
+impl<'a> BusGuardMut for parking_lot::MutexGuard<'_, Option<xum1541::Bus>> {
+    fn bus_mut_or_err(&mut self) -> Result<&mut xum1541::Bus, Error> {
+        self.as_mut()
             .ok_or(Error::Xum1541(xum1541::Error::DeviceAccess {
                 kind: xum1541::DeviceAccessError::NoDevice,
             }))
     }
 }
 
-/// A trait to allow us to get the Bus as a mutable reference from a
-/// MutexGuard and automatically convert the None case to a Error
-trait BusGuardMut {
-    fn bus_mut_or_err(&mut self) -> Result<&mut xum1541::Bus, Error>;
-}
-
-impl<'a> BusGuardMut for parking_lot::MutexGuard<'_, Option<xum1541::Bus>> {
+impl BusGuardMut for Option<xum1541::Bus> {
     fn bus_mut_or_err(&mut self) -> Result<&mut xum1541::Bus, Error> {
         self.as_mut()
             .ok_or(Error::Xum1541(xum1541::Error::DeviceAccess {
@@ -0,0 +1,250 @@
+//! Contains [`AdapterLock`], an advisory lock which stops several processes
+//! using the same xum1541 at once
+//!
+//! Only one process can have the USB device open.  [`Cbm::new`] takes the
+//! lock for the adapter before opening it, and holds it until the [`Cbm`]
+//! and all of its clones are dropped, so a second process gets
+//! [`Error::AdapterInUse`] with the PID of the process using the adapter,
+//! rather than a USB error.  Locks aren't taken for remote adapters, as the
+//! remote server owns the device.
+//!
+//! Lock files are created in `$XDG_RUNTIME_DIR`, or the temporary directory
+//! if that isn't set, and are named after the adapter's serial number.
+//! When [`Cbm::new`] is given no serial number, it can't know which adapter
+//! it will find, so it opens the adapter first and then takes the lock for
+//! the serial number the adapter reports.  If the open fails because
+//! another process has the adapter, it checks the existing locks using
+//! [`AdapterLock::locked_adapters`], so still returns
+//! [`Error::AdapterInUse`].
+//!
+//! Locking is only supported on Unix - elsewhere [`AdapterLock::acquire`]
+//! always succeeds.
+//!
+//! [`Cbm`]: crate::Cbm
+//! [`Cbm::new`]: crate::Cbm::new
+
+use crate::error::Error;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// An advisory lock on a xum1541, released when dropped.  See the
+/// [module documentation](self).
+#[derive(Debug)]
+pub struct AdapterLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl AdapterLock {
+    /// Locks the adapter with this serial number, returning
+    /// [`Error::AdapterInUse`] if another process holds the lock
+    pub fn acquire(serial: u8) -> Result<Self, Error> {
+        Self::acquire_in(&Self::lock_dir(), serial)
+    }
+
+    /// As [`AdapterLock::acquire`], but with the lock file in the given
+    /// directory
+    pub fn acquire_in(dir: &Path, serial: u8) -> Result<Self, Error> {
+        let path = dir.join(Self::lock_file_name(serial));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| Error::Io {
+                message: format!("Failed to open lock file {}: {e}", path.display()),
+            })?;
+
+        if !try_lock(&file)? {
+            return Err(Error::AdapterInUse {
+                serial: Some(serial),
+                pid: read_pid(&mut file),
+            });
+        }
+
+        // We hold the lock, so record our PID for anyone else who tries
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        debug!("Locked xum1541 using {}", path.display());
+
+        Ok(Self { _file: file, path })
+    }
+
+    /// Returns the serial number of each locked adapter, and the PID of the
+    /// process holding the lock if known, in serial number order
+    ///
+    /// Each lock file is checked by briefly taking a shared lock on it, so
+    /// a process acquiring that adapter at the same moment could be told
+    /// it's in use.
+    pub fn locked_adapters() -> Vec<(u8, Option<u32>)> {
+        Self::locked_adapters_in(&Self::lock_dir())
+    }
+
+    /// As [`AdapterLock::locked_adapters`], but with the lock files in the
+    /// given directory
+    pub fn locked_adapters_in(dir: &Path) -> Vec<(u8, Option<u32>)> {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        let mut locked: Vec<_> = entries
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name();
+                let serial = Self::lock_file_serial(name.to_str()?)?;
+                let mut file = File::open(dir.join(&name)).ok()?;
+                match try_lock_shared(&file) {
+                    Ok(false) => Some((serial, read_pid(&mut file))),
+                    _ => None,
+                }
+            })
+            .collect();
+        locked.sort();
+        locked
+    }
+
+    /// Returns the directory lock files are created in
+    pub fn lock_dir() -> PathBuf {
+        std::env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir)
+    }
+
+    /// Returns the path of the lock file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn lock_file_name(serial: u8) -> String {
+        format!("rs1541-xum1541-{serial}.lock")
+    }
+
+    // Returns the serial number from a lock file name
+    fn lock_file_serial(name: &str) -> Option<u8> {
+        name.strip_prefix("rs1541-xum1541-")?
+            .strip_suffix(".lock")?
+            .parse()
+            .ok()
+    }
+}
+
+impl Drop for AdapterLock {
+    fn drop(&mut self) {
+        // Closing the file releases the lock.  The file is left in place, as
+        // removing it could let two processes lock different files.
+        trace!("Unlocking xum1541 using {}", self.path.display());
+    }
+}
+
+// Describes Error::AdapterInUse
+pub(crate) fn in_use_message(serial: &Option<u8>, pid: &Option<u32>) -> String {
+    let adapter = match serial {
+        Some(serial) => format!("xum1541 serial {serial}"),
+        None => "xum1541".to_string(),
+    };
+    match pid {
+        Some(pid) => format!("{adapter} in use by PID {pid}"),
+        None => format!("{adapter} in use by another process"),
+    }
+}
+
+// Reads the PID written by the process holding the lock, if it has got that
+// far
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+// Takes an exclusive lock on the file without blocking, returning false if
+// another process holds it
+#[cfg(unix)]
+fn try_lock(file: &File) -> Result<bool, Error> {
+    flock(file, libc::LOCK_EX)
+}
+
+// Takes a shared lock on the file without blocking, returning false if
+// another process holds an exclusive lock.  The lock is released when the
+// file is closed.
+#[cfg(unix)]
+fn try_lock_shared(file: &File) -> Result<bool, Error> {
+    flock(file, libc::LOCK_SH)
+}
+
+#[cfg(unix)]
+fn flock(file: &File, operation: libc::c_int) -> Result<bool, Error> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: flock only operates on the file descriptor, which is valid
+    // for the lifetime of file
+    let rc = unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) };
+    if rc == 0 {
+        return Ok(true);
+    }
+    let error = std::io::Error::last_os_error();
+    if error.kind() == std::io::ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(Error::Io {
+            message: format!("Failed to lock file: {error}"),
+        })
+    }
+}
+
+#[cfg(not(unix))]
+fn try_lock(_file: &File) -> Result<bool, Error> {
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+fn try_lock_shared(_file: &File) -> Result<bool, Error> {
+    Ok(true)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock() {
+        let dir = std::env::temp_dir().join(format!("rs1541-lock-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let lock = AdapterLock::acquire_in(&dir, 3).unwrap();
+        assert_eq!(
+            AdapterLock::acquire_in(&dir, 3).unwrap_err(),
+            Error::AdapterInUse {
+                serial: Some(3),
+                pid: Some(std::process::id()),
+            }
+        );
+
+        // Other adapters are unaffected
+        let other = AdapterLock::acquire_in(&dir, 12).unwrap();
+        std::fs::write(dir.join("unrelated.lock"), "").unwrap();
+        assert_eq!(
+            AdapterLock::locked_adapters_in(&dir),
+            [
+                (3, Some(std::process::id())),
+                (12, Some(std::process::id()))
+            ]
+        );
+
+        // And the lock is released on drop
+        drop(lock);
+        drop(other);
+        assert!(AdapterLock::locked_adapters_in(&dir).is_empty());
+        assert!(AdapterLock::acquire_in(&dir, 3).is_ok());
+
+        assert_eq!(
+            in_use_message(&Some(3), &Some(1234)),
+            "xum1541 serial 3 in use by PID 1234"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
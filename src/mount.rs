@@ -0,0 +1,459 @@
+//! Contains the mapping between a drive's directory and host files used by
+//! bin/rs1541fs, which mounts a drive as a directory using FUSE
+//!
+//! Each file in a [`CbmDirListing`] appears as a host file named after the
+//! CBM filename with its [`CbmFileType`] as a suffix, for example
+//! `hello world.prg` - see [`fs_file_name`].  New files are created with
+//! the type given by their suffix - see [`parse_fs_file_name`].  Dual drive
+//! units have a sub-directory for each drive, `0` and `1`.
+//!
+//! CBM DOS finds files by name alone, so if several files on a disk share a
+//! name, a command naming one may act on another - scratching `hello` deletes
+//! every file called `hello`, whatever its type.  Such files are marked
+//! [`MountedFile::shared_name`], and the mount refuses to open or delete
+//! them.  Likewise a name containing a character DOS treats specially, such
+//! as the `*` and `?` wildcards or the `,` before a file type, can't be
+//! passed to the drive safely, so such files are marked
+//! [`MountedFile::reserved_name`] and also refused.
+//!
+//! Reading a directory from a drive is slow, so listings are held in a
+//! [`DirCache`] and re-read when they are older than its TTL, or after the
+//! mount changes the disk.  If a re-read finds a different disk name or ID,
+//! or no disk, the disk has been changed, and the drive's generation is
+//! incremented, so files opened on the old disk can be failed.
+//!
+//! # Example
+//! ```ignore
+//! let mut cache = DirCache::new(Duration::from_secs(2));
+//! let dir = cache.get(0, || cbm.dir(8, None))?;
+//! if let Some(file) = dir.get("hello world.prg") {
+//!     let name = open_name(0, &file.name, file.file_type, FileMode::Read);
+//!     cbm.open_file(dc, &AsciiString::try_from(name.as_str())?)?;
+//! }
+//! ```
+
+use crate::disk::{CbmDirListing, CbmDiskHeader, CbmFileEntry, CbmFileType, BYTES_PER_BLOCK};
+use crate::error::Error;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// Longest filename supported by CBM DOS
+pub const MAX_FILENAME_LEN: usize = 16;
+
+/// Host character used for `/` in CBM filenames, as `/` can't appear in a
+/// host filename
+pub const SLASH_REPLACEMENT: char = '\u{2215}';
+
+// Characters which have special meaning in a CBM DOS open or scratch
+// command, so can't be in a filename created through the mount
+const RESERVED_CHARS: &[char] = &[',', ':', '*', '?', '=', '"'];
+
+/// How a file is opened on the drive, see [`open_name`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileMode {
+    /// Read an existing file
+    Read,
+    /// Create a new file
+    Write,
+    /// Create a file, replacing any existing file of that name
+    Replace,
+    /// Add to the end of an existing file
+    Append,
+}
+
+/// Returns the host filename for a CBM file, in the spirit of
+/// [`crate::CbmDeviceType::to_fs_name`]
+///
+/// The file type is added as a suffix, with DEL and unknown types using
+/// `del`, and any `/` is replaced with [`SLASH_REPLACEMENT`].
+pub fn fs_file_name(filename: &str, file_type: CbmFileType) -> String {
+    let name: String = filename
+        .chars()
+        .map(|c| if c == '/' { SLASH_REPLACEMENT } else { c })
+        .collect();
+    match file_type {
+        CbmFileType::Unknown => format!("{name}.del"),
+        _ => format!("{name}.{file_type}"),
+    }
+}
+
+/// Returns the CBM filename and type for a new host file, reversing
+/// [`fs_file_name`]
+///
+/// # Errors
+/// Returns `Error::Validation` if the name doesn't end in `.prg`, `.seq` or
+/// `.usr`, or isn't a valid CBM filename.  REL files can't be created, as
+/// they have to be written a record at a time.
+pub fn parse_fs_file_name(name: &str) -> Result<(String, CbmFileType), Error> {
+    let (filename, file_type) = match name.rsplit_once('.') {
+        Some((filename, suffix)) => (filename, CbmFileType::from(suffix)),
+        None => (name, CbmFileType::Unknown),
+    };
+    if matches!(file_type, CbmFileType::REL | CbmFileType::Unknown) {
+        return Err(Error::Validation {
+            message: format!("Filename {name} must end in .prg, .seq or .usr"),
+        });
+    }
+
+    let filename: String = filename
+        .chars()
+        .map(|c| if c == SLASH_REPLACEMENT { '/' } else { c })
+        .collect();
+    if filename.is_empty() || filename.len() > MAX_FILENAME_LEN {
+        return Err(Error::Validation {
+            message: format!("Filename {filename} must be 1-{MAX_FILENAME_LEN} characters"),
+        });
+    }
+    if !filename.is_ascii() || filename.contains(RESERVED_CHARS) {
+        return Err(Error::Validation {
+            message: format!("Filename {filename} contains an invalid character"),
+        });
+    }
+
+    Ok((filename, file_type))
+}
+
+/// Returns the name to pass to [`crate::Cbm::open_file`] to open a file on
+/// the given drive, for example `0:hello,P,R`
+pub fn open_name(drive: u8, filename: &str, file_type: CbmFileType, mode: FileMode) -> String {
+    let suffix = file_type._to_suffix();
+    match mode {
+        FileMode::Read => format!("{drive}:{filename}{suffix},R"),
+        FileMode::Write => format!("{drive}:{filename}{suffix},W"),
+        FileMode::Replace => format!("@{drive}:{filename}{suffix},W"),
+        FileMode::Append => format!("{drive}:{filename}{suffix},A"),
+    }
+}
+
+/// A file in a [`MountedDir`]
+///
+/// * `name` - The CBM filename, in ASCII
+/// * `file_type` - Type of the file
+/// * `blocks` - Size of the file in blocks, as listed in the directory
+/// * `shared_name` - Whether another file on the disk has the same CBM
+///   filename, so opening or scratching this one by name isn't safe
+/// * `reserved_name` - Whether the CBM filename contains a character DOS
+///   treats specially in commands, so it can't be opened or scratched by
+///   name either
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountedFile {
+    pub name: String,
+    pub file_type: CbmFileType,
+    pub blocks: u16,
+    pub shared_name: bool,
+    pub reserved_name: bool,
+}
+
+impl MountedFile {
+    /// Returns whether the file can be safely opened or scratched by name -
+    /// see [`MountedFile::shared_name`] and [`MountedFile::reserved_name`]
+    pub fn addressable(&self) -> bool {
+        !self.shared_name && !self.reserved_name
+    }
+
+    /// Returns the largest the file can be, as the directory only gives the
+    /// number of blocks.  Reads stop at the real end of the file.
+    pub fn max_size(&self) -> u64 {
+        self.blocks as u64 * BYTES_PER_BLOCK as u64
+    }
+}
+
+/// A drive's directory, keyed by host filename
+#[derive(Debug, Clone)]
+pub struct MountedDir {
+    pub header: CbmDiskHeader,
+    pub blocks_free: u16,
+    files: BTreeMap<String, MountedFile>,
+}
+
+impl MountedDir {
+    /// Maps a directory listing to host filenames.  Entries which couldn't
+    /// be parsed are skipped, and if several files have the same host
+    /// filename, `~2`, `~3` etc are added to the later ones.  Files whose
+    /// CBM filename is used more than once, of any type, are marked
+    /// [`MountedFile::shared_name`], and those containing characters which
+    /// are reserved in DOS commands [`MountedFile::reserved_name`].
+    pub fn from_listing(listing: &CbmDirListing) -> Self {
+        let mut name_counts: HashMap<&str, usize> = HashMap::new();
+        for entry in &listing.files {
+            if let CbmFileEntry::ValidFile { filename, .. } = entry {
+                *name_counts.entry(filename).or_default() += 1;
+            }
+        }
+
+        let mut files = BTreeMap::new();
+        for entry in &listing.files {
+            let CbmFileEntry::ValidFile {
+                blocks,
+                filename,
+                file_type,
+            } = entry
+            else {
+                debug!("Skipping directory entry {entry}");
+                continue;
+            };
+
+            let mut fs_name = fs_file_name(filename, *file_type);
+            let mut copy = 1;
+            while files.contains_key(&fs_name) {
+                copy += 1;
+                fs_name = fs_file_name(&format!("{filename}~{copy}"), *file_type);
+            }
+            files.insert(
+                fs_name,
+                MountedFile {
+                    name: filename.clone(),
+                    file_type: *file_type,
+                    blocks: *blocks,
+                    shared_name: name_counts[filename.as_str()] > 1,
+                    reserved_name: filename.contains(RESERVED_CHARS),
+                },
+            );
+        }
+
+        Self {
+            header: listing.header.clone(),
+            blocks_free: listing.blocks_free,
+            files,
+        }
+    }
+
+    /// Returns the file with this host filename
+    pub fn get(&self, fs_name: &str) -> Option<&MountedFile> {
+        self.files.get(fs_name)
+    }
+
+    /// Returns the host filename and details of each file, in filename
+    /// order
+    pub fn files(&self) -> impl Iterator<Item = (&str, &MountedFile)> {
+        self.files.iter().map(|(name, file)| (name.as_str(), file))
+    }
+
+    // Whether this is the same disk, judged by the header
+    fn is_same_disk(&self, other: &MountedDir) -> bool {
+        self.header.name == other.header.name && self.header.id == other.header.id
+    }
+}
+
+#[derive(Debug)]
+struct CachedDir {
+    dir: Option<MountedDir>,
+    fetched: Option<Instant>,
+    generation: u64,
+}
+
+/// Caches each drive's [`MountedDir`].  See the
+/// [module documentation](self).
+#[derive(Debug)]
+pub struct DirCache {
+    ttl: Duration,
+    drives: HashMap<u8, CachedDir>,
+}
+
+impl DirCache {
+    /// Creates an empty cache, whose listings are re-read once they are
+    /// older than `ttl`
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            drives: HashMap::new(),
+        }
+    }
+
+    /// Returns the directory of the given drive, calling `fetch` to read it
+    /// if it isn't cached or is out of date
+    ///
+    /// If `fetch` fails, the error is returned and the disk is treated as
+    /// changed.
+    pub fn get<F>(&mut self, drive: u8, fetch: F) -> Result<&MountedDir, Error>
+    where
+        F: FnOnce() -> Result<CbmDirListing, Error>,
+    {
+        let ttl = self.ttl;
+        let cached = self.drives.entry(drive).or_insert(CachedDir {
+            dir: None,
+            fetched: None,
+            generation: 0,
+        });
+
+        let fresh = cached
+            .fetched
+            .is_some_and(|fetched| fetched.elapsed() < ttl);
+        if !fresh {
+            trace!("Reading directory of drive {drive}");
+            match fetch() {
+                Ok(listing) => {
+                    let dir = MountedDir::from_listing(&listing);
+                    if let Some(old) = &cached.dir {
+                        if !old.is_same_disk(&dir) {
+                            info!("Disk in drive {drive} changed to {}", dir.header);
+                            cached.generation += 1;
+                        }
+                    }
+                    cached.dir = Some(dir);
+                    cached.fetched = Some(Instant::now());
+                }
+                Err(e) => {
+                    if cached.dir.take().is_some() {
+                        info!("Disk in drive {drive} removed: {e}");
+                        cached.generation += 1;
+                    }
+                    cached.fetched = None;
+                    return Err(e);
+                }
+            }
+        }
+
+        // The directory is always set here, as it was either fresh or has
+        // just been read
+        cached.dir.as_ref().ok_or_else(|| Error::Validation {
+            message: format!("No directory for drive {drive}"),
+        })
+    }
+
+    /// Marks the drive's directory as out of date, so it's re-read on the
+    /// next [`DirCache::get`].  Used after the mount changes the disk.
+    pub fn invalidate(&mut self, drive: u8) {
+        if let Some(cached) = self.drives.get_mut(&drive) {
+            cached.fetched = None;
+        }
+    }
+
+    /// Returns the number of times the disk in this drive has been found to
+    /// have changed
+    pub fn generation(&self, drive: u8) -> u64 {
+        self.drives
+            .get(&drive)
+            .map(|cached| cached.generation)
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(id: &str, files: &[(&str, CbmFileType)]) -> CbmDirListing {
+        CbmDirListing {
+            header: CbmDiskHeader {
+                drive_number: 0,
+                name: "TEST DISK".to_string(),
+                id: id.to_string(),
+            },
+            files: files
+                .iter()
+                .map(|(filename, file_type)| CbmFileEntry::ValidFile {
+                    blocks: 2,
+                    filename: filename.to_string(),
+                    file_type: *file_type,
+                })
+                .collect(),
+            blocks_free: 660,
+        }
+    }
+
+    #[test]
+    fn test_file_names() {
+        assert_eq!(fs_file_name("hello", CbmFileType::PRG), "hello.prg");
+        assert_eq!(fs_file_name("a/b", CbmFileType::SEQ), "a\u{2215}b.seq");
+        assert_eq!(fs_file_name("old", CbmFileType::Unknown), "old.del");
+
+        assert_eq!(
+            parse_fs_file_name("a\u{2215}b.SEQ").unwrap(),
+            ("a/b".to_string(), CbmFileType::SEQ)
+        );
+        for name in [
+            "hello",
+            "hello.rel",
+            "a,b.prg",
+            ".prg",
+            "seventeen chars!!.prg",
+        ] {
+            assert!(parse_fs_file_name(name).is_err(), "{name}");
+        }
+
+        assert_eq!(
+            open_name(1, "hello", CbmFileType::USR, FileMode::Replace),
+            "@1:hello,U,W"
+        );
+        assert_eq!(
+            open_name(0, "hello", CbmFileType::SEQ, FileMode::Append),
+            "0:hello,S,A"
+        );
+    }
+
+    #[test]
+    fn test_mounted_dir() {
+        let dir = MountedDir::from_listing(&listing(
+            "01",
+            &[
+                ("hello", CbmFileType::PRG),
+                ("hello", CbmFileType::SEQ),
+                ("hello", CbmFileType::PRG),
+                ("world", CbmFileType::PRG),
+            ],
+        ));
+        let names: Vec<_> = dir.files().map(|(name, _)| name).collect();
+        assert_eq!(
+            names,
+            ["hello.prg", "hello.seq", "hello~2.prg", "world.prg"]
+        );
+        assert_eq!(dir.get("hello~2.prg").unwrap().name, "hello");
+        assert_eq!(dir.get("hello.seq").unwrap().max_size(), 508);
+
+        // Every hello shares its CBM filename, whatever its type or host
+        // filename, but world doesn't
+        for name in ["hello.prg", "hello.seq", "hello~2.prg"] {
+            assert!(dir.get(name).unwrap().shared_name, "{name}");
+        }
+        assert!(!dir.get("world.prg").unwrap().shared_name);
+        assert!(dir.get("world.prg").unwrap().addressable());
+    }
+
+    #[test]
+    fn test_reserved_names() {
+        // Each of these would be taken as a wildcard, or as part of the
+        // command, if passed to the drive
+        let names = ["a*", "a?c", "a,s", "a:b", "a=b", "a\"b"];
+        let files: Vec<_> = names
+            .iter()
+            .map(|name| (*name, CbmFileType::PRG))
+            .chain([("abc", CbmFileType::PRG)])
+            .collect();
+        let dir = MountedDir::from_listing(&listing("01", &files));
+        for name in names {
+            let file = dir.get(&fs_file_name(name, CbmFileType::PRG)).unwrap();
+            assert!(file.reserved_name, "{name}");
+            assert!(!file.shared_name, "{name}");
+            assert!(!file.addressable(), "{name}");
+        }
+        assert!(dir.get("abc.prg").unwrap().addressable());
+    }
+
+    #[test]
+    fn test_dir_cache() {
+        let mut cache = DirCache::new(Duration::from_secs(3600));
+        let files = [("hello", CbmFileType::PRG)];
+        assert!(cache.get(0, || Ok(listing("01", &files))).is_ok());
+
+        // Cached, so not read again until invalidated
+        assert!(cache.get(0, || panic!("read again")).is_ok());
+        cache.invalidate(0);
+        let dir = cache.get(0, || Ok(listing("01", &[]))).unwrap();
+        assert!(dir.get("hello.prg").is_none());
+        assert_eq!(cache.generation(0), 0);
+
+        // A different ID, or no disk, is a disk change
+        cache.invalidate(0);
+        assert!(cache.get(0, || Ok(listing("02", &files))).is_ok());
+        assert_eq!(cache.generation(0), 1);
+        cache.invalidate(0);
+        assert!(cache.get(0, || Err(Error::Cancelled)).is_err());
+        assert_eq!(cache.generation(0), 2);
+        assert_eq!(cache.generation(1), 0);
+    }
+}
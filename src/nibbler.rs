@@ -0,0 +1,823 @@
+//! Contains types and functions for capturing raw GCR data from 1541
+//! drives ("nibbling"), and storing it as G64 or NIB images.
+//!
+//! Reading a disk through DOS only returns the decoded contents of
+//! standard sectors, which loses the information needed to preserve copy
+//! protected disks.  The nibbler instead uploads a routine to the drive
+//! which reads the raw GCR bitstream from the read head, including
+//! half-tracks and tracks beyond 35.
+//!
+//! The 1541 only has 2KB of RAM, so a track is captured in windows of
+//! [`CAPTURE_WINDOW`] bytes.  Each window is aligned to the same sync mark
+//! on the track, using the bytes following the first header block seen as
+//! a signature, and then skips the bytes already captured.  The windows are
+//! then stitched together on the host.
+//!
+//! The 1541 doesn't signal any bytes while a sync mark is passing under the
+//! head, so the drive routine records each sync mark as a single `0xFF`
+//! byte.  These are expanded back into full length sync marks when writing
+//! a G64 image.
+//!
+//! Transfer uses standard M-R commands, so capturing a disk is slow - expect
+//! in the region of 10-20 seconds per track.
+
+use crate::cbm::Cbm;
+use crate::cbmtype::{CbmDeviceType, DosVersion};
+use crate::channel::CBM_CHANNEL_CTRL;
+use crate::error::{DeviceError, Error};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use xum1541::DeviceChannel;
+
+use std::path::Path;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Lowest track number which can be nibbled
+pub const NIB_MIN_TRACK: u8 = 1;
+
+/// Highest track number which can be nibbled
+pub const NIB_MAX_TRACK: u8 = 42;
+
+/// Maximum number of revolutions which can be captured per track
+pub const NIB_MAX_REVOLUTIONS: u8 = 8;
+
+/// Number of bytes captured by each run of the drive routine
+pub const CAPTURE_WINDOW: usize = 768;
+
+/// Size of the track data stored for each track in a NIB image
+pub const NIB_TRACK_SIZE: usize = 0x2000;
+
+/// Standard maximum track size in a G64 image
+pub const G64_MAX_TRACK_SIZE: usize = 7928;
+
+/// Number of half-tracks in a G64 image (tracks 1 to 42.5)
+pub const G64_NUM_HALFTRACKS: usize = 84;
+
+/// Byte used to represent a sync mark in captured data
+pub const SYNC_MARKER: u8 = 0xff;
+
+/// Number of sync bytes written for each sync mark in a G64 image
+const G64_SYNC_LEN: usize = 5;
+
+const G64_SIGNATURE: &[u8] = b"GCR-1541";
+const NIB_SIGNATURE: &[u8] = b"MNIB-1541-RAW";
+const NIB_VERSION: u8 = 3;
+const NIB_HEADER_SIZE: usize = 0x100;
+
+// Highest track the drive's DOS will seek to
+const DOS_MAX_TRACK: u8 = 35;
+
+// Drive memory layout used by the drive routine - see drivecode/nibread.s
+const DRIVE_CODE_ADDR: u16 = 0x0300;
+const PARAM_ADDR: u16 = 0x04e0;
+const RESULT_ADDR: u16 = 0x04e6;
+const CAPTURE_ADDR: u16 = 0x0500;
+
+// Drive routine result codes
+const RESULT_OK: u8 = 0;
+const RESULT_NO_SYNC: u8 = 1;
+const RESULT_NO_SIGNATURE: u8 = 2;
+
+// Density value telling the drive routine to leave the density alone
+const DENSITY_UNCHANGED: u8 = 0xff;
+
+// Number of bytes used to align windows after the first
+const SIGNATURE_LEN: usize = 8;
+
+// Number of syncs the drive routine tries before giving up on finding the
+// signature - around 2 revolutions of a standard track
+const SIGNATURE_TRIES: u8 = 100;
+
+// First GCR byte of a header block (0x08)
+const HEADER_GCR_START: u8 = 0x52;
+
+// Minimum length of a revolution - used when looking for the repeat of the
+// start of the track in the captured data
+const MIN_REVOLUTION_LEN: usize = 5000;
+
+// Number of bytes compared when looking for the repeat of the start of the
+// track
+const REVOLUTION_MATCH_LEN: usize = 16;
+
+// The 1541 job queue, used to seek to the track before capturing.  We use
+// buffer 2's job slot and header.
+const JOB_ADDR: u16 = 0x0002;
+const JOB_HEADER_ADDR: u16 = 0x000a;
+const JOB_SEEK: u8 = 0xb0;
+const JOB_POLL_INTERVAL: Duration = Duration::from_millis(20);
+const JOB_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Time to allow for the drive routine to run, in addition to the time taken
+// to skip and capture bytes.  Covers motor spin up and finding the signature.
+const CAPTURE_BASE_TIME: Duration = Duration::from_millis(900);
+
+// Time taken by the drive routine to step the head one half-track
+const STEP_TIME: Duration = Duration::from_millis(45);
+
+/// 1541 drive routine which captures raw GCR data.  Assembled from
+/// drivecode/nibread.s, to run at [`DRIVE_CODE_ADDR`].
+const NIBREAD_CODE: [u8; 383] = [
+    0x78, 0xa9, 0x00, 0x8d, 0xe6, 0x04, 0xad, 0xe0, 0x04, 0xc9, 0xff, 0xf0, 0x13, 0x0a, 0x0a, 0x0a,
+    0x0a, 0x0a, 0x8d, 0xe7, 0x04, 0xad, 0x00, 0x1c, 0x29, 0x9f, 0x0d, 0xe7, 0x04, 0x8d, 0x00, 0x1c,
+    0xad, 0x00, 0x1c, 0x29, 0x04, 0x48, 0xad, 0x00, 0x1c, 0x09, 0x0c, 0x8d, 0x00, 0x1c, 0x68, 0xd0,
+    0x0a, 0xa2, 0x00, 0x20, 0x76, 0x04, 0xa2, 0x00, 0x20, 0x76, 0x04, 0xa9, 0xee, 0x8d, 0x0c, 0x1c,
+    0xa9, 0x00, 0x8d, 0x03, 0x1c, 0xad, 0xe1, 0x04, 0x8d, 0xf3, 0x04, 0xad, 0xf3, 0x04, 0xf0, 0x09,
+    0x20, 0x55, 0x04, 0xce, 0xf3, 0x04, 0x4c, 0x4b, 0x03, 0xad, 0xe5, 0x04, 0x8d, 0xf2, 0x04, 0xa9,
+    0x00, 0x8d, 0xf0, 0x04, 0x8d, 0xf1, 0x04, 0x2c, 0x00, 0x1c, 0x10, 0x0d, 0xce, 0xf0, 0x04, 0xd0,
+    0xf6, 0xce, 0xf1, 0x04, 0xd0, 0xf1, 0x4c, 0x88, 0x03, 0x2c, 0x00, 0x1c, 0x30, 0x12, 0xce, 0xf0,
+    0x04, 0xd0, 0xf6, 0xce, 0xf1, 0x04, 0xd0, 0xf1, 0xa9, 0x01, 0x8d, 0xe6, 0x04, 0x4c, 0x3f, 0x04,
+    0xb8, 0xa2, 0x00, 0xad, 0xe4, 0x04, 0xf0, 0x21, 0x50, 0xfe, 0xb8, 0xad, 0x01, 0x1c, 0xdd, 0xe8,
+    0x04, 0xd0, 0x09, 0xe8, 0xec, 0xe4, 0x04, 0xd0, 0xef, 0x4c, 0xb9, 0x03, 0xce, 0xf2, 0x04, 0xd0,
+    0xae, 0xa9, 0x02, 0x8d, 0xe6, 0x04, 0x4c, 0x3f, 0x04, 0xac, 0xe2, 0x04, 0xae, 0xe3, 0x04, 0x98,
+    0xd0, 0x04, 0x8a, 0xf0, 0x15, 0xca, 0x88, 0x70, 0x0d, 0x2c, 0x00, 0x1c, 0x30, 0xf9, 0x2c, 0x00,
+    0x1c, 0x10, 0xfb, 0x4c, 0xbf, 0x03, 0xb8, 0x4c, 0xbf, 0x03, 0xa0, 0x00, 0x70, 0x15, 0x2c, 0x00,
+    0x1c, 0x30, 0xf9, 0xa9, 0xff, 0x99, 0x00, 0x05, 0xc8, 0xf0, 0x21, 0x2c, 0x00, 0x1c, 0x10, 0xfb,
+    0x4c, 0xdc, 0x03, 0xb8, 0xad, 0x01, 0x1c, 0x99, 0x00, 0x05, 0xc8, 0xd0, 0xdf, 0x70, 0x15, 0x2c,
+    0x00, 0x1c, 0x30, 0xf9, 0xa9, 0xff, 0x99, 0x00, 0x06, 0xc8, 0xf0, 0x21, 0x2c, 0x00, 0x1c, 0x10,
+    0xfb, 0x4c, 0xfd, 0x03, 0xb8, 0xad, 0x01, 0x1c, 0x99, 0x00, 0x06, 0xc8, 0xd0, 0xdf, 0x70, 0x15,
+    0x2c, 0x00, 0x1c, 0x30, 0xf9, 0xa9, 0xff, 0x99, 0x00, 0x07, 0xc8, 0xf0, 0x12, 0x2c, 0x00, 0x1c,
+    0x10, 0xfb, 0x4c, 0x1e, 0x04, 0xb8, 0xad, 0x01, 0x1c, 0x99, 0x00, 0x07, 0xc8, 0xd0, 0xdf, 0xad,
+    0xe1, 0x04, 0x8d, 0xf3, 0x04, 0xad, 0xf3, 0x04, 0xf0, 0x09, 0x20, 0x5f, 0x04, 0xce, 0xf3, 0x04,
+    0x4c, 0x45, 0x04, 0x58, 0x60, 0xad, 0x00, 0x1c, 0xaa, 0x18, 0x69, 0x01, 0x4c, 0x66, 0x04, 0xad,
+    0x00, 0x1c, 0xaa, 0x38, 0xe9, 0x01, 0x29, 0x03, 0x8d, 0xe7, 0x04, 0x8a, 0x29, 0xfc, 0x0d, 0xe7,
+    0x04, 0x8d, 0x00, 0x1c, 0xa2, 0x20, 0xa0, 0x00, 0x88, 0xd0, 0xfd, 0xca, 0xd0, 0xfa, 0x60,
+];
+
+/// Returns the standard density (speed zone) for a track
+///
+/// Tracks 1-17 use density 3, 18-24 density 2, 25-30 density 1 and 31
+/// onwards density 0.
+pub fn speed_zone(track: u8) -> u8 {
+    match track {
+        0..=17 => 3,
+        18..=24 => 2,
+        25..=30 => 1,
+        _ => 0,
+    }
+}
+
+/// Returns the approximate number of bytes read in one revolution of the
+/// disk, at the given density
+pub fn bytes_per_revolution(density: u8) -> usize {
+    // Bit rates for each density, at 300 rpm (5 revolutions per second)
+    let bits_per_sec = match density {
+        3 => 307_692,
+        2 => 285_714,
+        1 => 266_667,
+        _ => 250_000,
+    };
+    bits_per_sec / 8 / 5
+}
+
+// Time taken for the given number of bytes to pass under the head
+fn byte_time(density: u8, bytes: usize) -> Duration {
+    let bytes_per_sec = bytes_per_revolution(density) as u64 * 5;
+    Duration::from_micros(bytes as u64 * 1_000_000 / bytes_per_sec)
+}
+
+/// Configuration for a nibbler capture of a whole disk
+///
+/// # Fields
+///
+/// * `start_track` - First track to capture, 1-42
+/// * `end_track` - Last track to capture, 1-42
+/// * `half_tracks` - Whether to capture half-tracks as well as whole tracks
+/// * `density` - Density (0-3) to read all tracks at.  None uses the
+///   standard density for each track
+/// * `revolutions` - Number of disk revolutions to capture for each track
+#[derive(Debug, Clone)]
+pub struct NibblerConfig {
+    pub start_track: u8,
+    pub end_track: u8,
+    pub half_tracks: bool,
+    pub density: Option<u8>,
+    pub revolutions: u8,
+}
+
+impl Default for NibblerConfig {
+    fn default() -> Self {
+        NibblerConfig {
+            start_track: NIB_MIN_TRACK,
+            end_track: DOS_MAX_TRACK,
+            half_tracks: false,
+            density: None,
+            revolutions: 1,
+        }
+    }
+}
+
+impl NibblerConfig {
+    /// Checks the configuration is valid
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.start_track < NIB_MIN_TRACK
+            || self.end_track > NIB_MAX_TRACK
+            || self.start_track > self.end_track
+        {
+            return Err(Error::Validation {
+                message: format!(
+                    "Invalid track range {}-{}, must be within {}-{}",
+                    self.start_track, self.end_track, NIB_MIN_TRACK, NIB_MAX_TRACK
+                ),
+            });
+        }
+        validate_density(self.density)?;
+        validate_revolutions(self.revolutions)
+    }
+
+    /// Returns an iterator over the half-tracks to be captured.  Half-track
+    /// numbers are 2 for track 1, 3 for track 1.5, and so on.
+    pub fn halftracks(&self) -> impl Iterator<Item = u8> {
+        let step = if self.half_tracks { 1 } else { 2 };
+        (self.start_track * 2..=self.end_track * 2).step_by(step)
+    }
+}
+
+fn validate_halftrack(halftrack: u8) -> Result<(), Error> {
+    if !(NIB_MIN_TRACK * 2..=NIB_MAX_TRACK * 2 + 1).contains(&halftrack) {
+        Err(Error::Validation {
+            message: format!("Invalid half-track {halftrack}"),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_density(density: Option<u8>) -> Result<(), Error> {
+    match density {
+        Some(d) if d > 3 => Err(Error::Validation {
+            message: format!("Invalid density {d}, must be 0-3"),
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn validate_revolutions(revolutions: u8) -> Result<(), Error> {
+    if revolutions == 0 || revolutions > NIB_MAX_REVOLUTIONS {
+        Err(Error::Validation {
+            message: format!(
+                "Invalid number of revolutions {revolutions}, must be 1-{NIB_MAX_REVOLUTIONS}"
+            ),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Raw GCR data captured from a single track or half-track
+///
+/// # Fields
+///
+/// * `halftrack` - Half-track number, 2 for track 1, 3 for track 1.5, up to
+///   85 for track 42.5
+/// * `density` - Density (0-3) the track was read at
+/// * `data` - Raw GCR data, starting with the sync mark before the first
+///   header block found.  Each sync mark is represented by a single
+///   [`SYNC_MARKER`] byte.  Empty if no sync marks were found on the track.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawTrack {
+    pub halftrack: u8,
+    pub density: u8,
+    pub data: Vec<u8>,
+}
+
+impl RawTrack {
+    /// The whole track number
+    pub fn track(&self) -> u8 {
+        self.halftrack / 2
+    }
+
+    /// Whether this is a half-track
+    pub fn is_half_track(&self) -> bool {
+        self.halftrack % 2 == 1
+    }
+
+    /// Returns the length of a single revolution of the track, if it can be
+    /// determined.
+    ///
+    /// This is found by looking for the start of the captured data repeating
+    /// later in the capture, so requires more than one revolution to have
+    /// been captured.
+    pub fn revolution_len(&self) -> Option<usize> {
+        if self.data.len() < MIN_REVOLUTION_LEN + REVOLUTION_MATCH_LEN {
+            return None;
+        }
+        let needle = &self.data[..REVOLUTION_MATCH_LEN];
+        self.data[MIN_REVOLUTION_LEN..]
+            .windows(REVOLUTION_MATCH_LEN)
+            .position(|window| window == needle)
+            .map(|pos| pos + MIN_REVOLUTION_LEN)
+    }
+
+    /// Returns a single revolution of the track, if it can be determined
+    pub fn revolution(&self) -> Option<&[u8]> {
+        self.revolution_len().map(|len| &self.data[..len])
+    }
+
+    /// Returns the track as it should be stored in a G64 image - a single
+    /// revolution (or as much of the capture as fits if the revolution
+    /// couldn't be determined), with sync marks expanded to full length.
+    pub fn g64_data(&self) -> Vec<u8> {
+        let data = match self.revolution() {
+            Some(rev) => rev,
+            None => {
+                let len = self.data.len().min(G64_MAX_TRACK_SIZE);
+                &self.data[..len]
+            }
+        };
+
+        let mut output = Vec::with_capacity(data.len() + 256);
+        for &byte in data {
+            if byte == SYNC_MARKER {
+                output.extend_from_slice(&[SYNC_MARKER; G64_SYNC_LEN]);
+            } else {
+                output.push(byte);
+            }
+        }
+        output
+    }
+}
+
+/// Raw GCR data captured from a disk
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RawDisk {
+    pub tracks: Vec<RawTrack>,
+}
+
+impl RawDisk {
+    /// Creates a G64 image from the captured tracks
+    ///
+    /// Tracks with no data (for example unformatted tracks) are stored as
+    /// not present.
+    pub fn to_g64(&self) -> Vec<u8> {
+        let mut tracks: Vec<Option<(Vec<u8>, u8)>> = vec![None; G64_NUM_HALFTRACKS];
+        for track in self.tracks.iter().filter(|t| !t.data.is_empty()) {
+            let index = (track.halftrack - 2) as usize;
+            if index < G64_NUM_HALFTRACKS {
+                tracks[index] = Some((track.g64_data(), track.density));
+            }
+        }
+
+        let max_track_size = tracks
+            .iter()
+            .flatten()
+            .map(|(data, _)| data.len())
+            .max()
+            .unwrap_or(0)
+            .max(G64_MAX_TRACK_SIZE);
+
+        // Header, then track offset table, then speed zone table
+        let mut output = Vec::new();
+        output.extend_from_slice(G64_SIGNATURE);
+        output.push(0); // Version
+        output.push(G64_NUM_HALFTRACKS as u8);
+        output.extend_from_slice(&(max_track_size as u16).to_le_bytes());
+
+        let data_start = output.len() + G64_NUM_HALFTRACKS * 8;
+        let mut offset = data_start;
+        for track in tracks.iter() {
+            match track {
+                Some(_) => {
+                    output.extend_from_slice(&(offset as u32).to_le_bytes());
+                    offset += 2 + max_track_size;
+                }
+                None => output.extend_from_slice(&0u32.to_le_bytes()),
+            }
+        }
+        for track in tracks.iter() {
+            let speed = track.as_ref().map(|(_, density)| *density).unwrap_or(0);
+            output.extend_from_slice(&(speed as u32).to_le_bytes());
+        }
+
+        // Track data, each preceded by its length and padded to the maximum
+        // track size
+        for (data, _) in tracks.iter().flatten() {
+            output.extend_from_slice(&(data.len() as u16).to_le_bytes());
+            output.extend_from_slice(data);
+            output.resize(output.len() + max_track_size - data.len(), 0);
+        }
+
+        output
+    }
+
+    /// Creates a NIB image from the captured tracks
+    ///
+    /// Each track stores the first [`NIB_TRACK_SIZE`] bytes of the capture
+    /// (padded with zeros if fewer were captured), with sync marks
+    /// represented by single `0xFF` bytes.
+    pub fn to_nib(&self) -> Vec<u8> {
+        let mut output = vec![0u8; NIB_HEADER_SIZE];
+        output[..NIB_SIGNATURE.len()].copy_from_slice(NIB_SIGNATURE);
+        output[NIB_SIGNATURE.len()] = NIB_VERSION;
+
+        let max_tracks = (NIB_HEADER_SIZE - 0x10) / 2;
+        for (ii, track) in self.tracks.iter().take(max_tracks).enumerate() {
+            output[0x10 + ii * 2] = track.halftrack;
+            output[0x11 + ii * 2] = track.density;
+        }
+
+        for track in self.tracks.iter().take(max_tracks) {
+            let len = track.data.len().min(NIB_TRACK_SIZE);
+            output.extend_from_slice(&track.data[..len]);
+            output.resize(output.len() + NIB_TRACK_SIZE - len, 0);
+        }
+
+        output
+    }
+
+    /// Writes a G64 image to the given path
+    pub fn save_g64<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        std::fs::write(path, self.to_g64()).map_err(|e| e.into())
+    }
+
+    /// Writes a NIB image to the given path
+    pub fn save_nib<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        std::fs::write(path, self.to_nib()).map_err(|e| e.into())
+    }
+}
+
+// Outcome of a single run of the drive routine
+enum CaptureResult {
+    Data(Vec<u8>),
+    NoSync,
+    NoSignature,
+}
+
+/// Nibbler functions
+impl Cbm {
+    /// Captures the raw GCR data from a range of tracks on a disk.
+    ///
+    /// The drive must be a 1541 (or compatible).  The drive is reinitialized
+    /// afterwards, as capturing overwrites the drive's buffers.
+    ///
+    /// # Arguments
+    /// * `device` - Device number
+    /// * `config` - The tracks, density and revolutions to capture
+    ///
+    /// # Example
+    /// ```ignore
+    /// let config = NibblerConfig {
+    ///     half_tracks: true,
+    ///     ..Default::default()
+    /// };
+    /// let disk = cbm.nib_read_disk(8, &config)?;
+    /// disk.save_g64("disk.g64")?;
+    /// ```
+    pub fn nib_read_disk(&self, device: u8, config: &NibblerConfig) -> Result<RawDisk, Error> {
+        config.validate()?;
+        self.nib_prepare(device)?;
+
+        let mut disk = RawDisk::default();
+        let result = (|| {
+            for halftrack in config.halftracks() {
+                info!("Nibbling device {device} half-track {halftrack}");
+                let track = self.nib_read_track_prepared(
+                    device,
+                    halftrack,
+                    config.density,
+                    config.revolutions,
+                )?;
+                disk.tracks.push(track);
+            }
+            Ok(())
+        })();
+
+        self.nib_cleanup(device);
+        result.map(|_| disk)
+    }
+
+    /// Captures the raw GCR data from a single track or half-track.
+    ///
+    /// # Arguments
+    /// * `device` - Device number
+    /// * `halftrack` - The half-track to capture, 2 for track 1, 3 for track
+    ///   1.5, and so on
+    /// * `density` - Density (0-3) to read the track at.  None uses the
+    ///   standard density for the track
+    /// * `revolutions` - Number of disk revolutions to capture
+    pub fn nib_read_track(
+        &self,
+        device: u8,
+        halftrack: u8,
+        density: Option<u8>,
+        revolutions: u8,
+    ) -> Result<RawTrack, Error> {
+        validate_halftrack(halftrack)?;
+        validate_density(density)?;
+        validate_revolutions(revolutions)?;
+        self.nib_prepare(device)?;
+
+        let result = self.nib_read_track_prepared(device, halftrack, density, revolutions);
+
+        self.nib_cleanup(device);
+        result
+    }
+
+    // Checks the drive is supported and uploads the drive routine
+    fn nib_prepare(&self, device: u8) -> Result<(), Error> {
+        let info = self.identify(device)?;
+        match info.device_type {
+            CbmDeviceType::Cbm1540
+            | CbmDeviceType::Cbm1541
+            | CbmDeviceType::Cbm1570
+            | CbmDeviceType::Cbm1571 => (),
+            _ => {
+                return Err(Error::Validation {
+                    message: format!(
+                        "Device {device} is a {}, nibbler requires a 1541 compatible drive",
+                        info.device_type
+                    ),
+                })
+            }
+        }
+
+        debug!("Uploading nibbler drive code to device {device}");
+        self.write_drive_memory(device, DRIVE_CODE_ADDR, &NIBREAD_CODE)
+    }
+
+    // Reinitializes the drive, as its buffers (including the BAM) have been
+    // overwritten
+    fn nib_cleanup(&self, device: u8) {
+        let _ = self
+            .send_string_command_ascii(device, "i")
+            .and_then(|_| self.get_status(device))
+            .inspect(|status| debug!("Status after nibbler reinitialize: {status}"))
+            .inspect_err(|e| warn!("Failed to reinitialize device {device} after nibbling: {e}"));
+    }
+
+    fn nib_read_track_prepared(
+        &self,
+        device: u8,
+        halftrack: u8,
+        density: Option<u8>,
+        revolutions: u8,
+    ) -> Result<RawTrack, Error> {
+        let track = halftrack / 2;
+        let density = density.unwrap_or(speed_zone(track));
+
+        // Have DOS seek to the nearest track it can, and have the drive
+        // routine step the rest of the way
+        let dos_track = track.min(DOS_MAX_TRACK);
+        let steps = halftrack - dos_track * 2;
+        self.nib_seek(device, dos_track)?;
+
+        let capture_len = revolutions as usize * bytes_per_revolution(density) + CAPTURE_WINDOW;
+        let windows = capture_len.div_ceil(CAPTURE_WINDOW);
+
+        // Capture the first window, starting after the first header block's
+        // sync mark if we can find one, otherwise after any sync mark
+        let mut prefix = vec![HEADER_GCR_START];
+        let first = match self.nib_capture_window(device, density, steps, &prefix, 0)? {
+            CaptureResult::Data(data) => data,
+            CaptureResult::NoSignature => {
+                debug!("No header block found on half-track {halftrack}, aligning to any sync");
+                prefix.clear();
+                match self.nib_capture_window(device, density, steps, &prefix, 0)? {
+                    CaptureResult::Data(data) => data,
+                    _ => Vec::new(),
+                }
+            }
+            CaptureResult::NoSync => Vec::new(),
+        };
+        if first.is_empty() {
+            info!("No sync found on device {device} half-track {halftrack}");
+            return Ok(RawTrack {
+                halftrack,
+                density,
+                data: Vec::new(),
+            });
+        }
+
+        // The stream of bytes after the sync mark we've aligned to
+        let mut stream = prefix;
+        stream.extend_from_slice(&first);
+        let prefix_len = stream.len() - first.len();
+        let signature = stream[..SIGNATURE_LEN].to_vec();
+
+        // Capture the remaining windows, each aligned to the same sync mark
+        for window in 1..windows {
+            let skip = prefix_len + window * CAPTURE_WINDOW - SIGNATURE_LEN;
+            match self.nib_capture_window(device, density, steps, &signature, skip as u16)? {
+                CaptureResult::Data(data) => stream.extend_from_slice(&data),
+                _ => {
+                    let dc = DeviceChannel::new(device, CBM_CHANNEL_CTRL)?;
+                    return Err(DeviceError::read_error(
+                        dc,
+                        format!("Lost alignment reading half-track {halftrack} window {window}"),
+                    ));
+                }
+            }
+        }
+
+        // Include the sync mark we aligned to
+        let mut data = vec![SYNC_MARKER];
+        data.extend_from_slice(&stream);
+
+        Ok(RawTrack {
+            halftrack,
+            density,
+            data,
+        })
+    }
+
+    // Uses the drive's job queue to seek to a track
+    fn nib_seek(&self, device: u8, track: u8) -> Result<(), Error> {
+        trace!("Seek device {device} to track {track}");
+        self.write_drive_memory(device, JOB_HEADER_ADDR, &[track, 0])?;
+        self.write_drive_memory(device, JOB_ADDR, &[JOB_SEEK])?;
+
+        let mut waited = Duration::ZERO;
+        loop {
+            sleep(JOB_POLL_INTERVAL);
+            waited += JOB_POLL_INTERVAL;
+
+            let mut job = [0u8; 1];
+            self.read_drive_memory_dos(device, JOB_ADDR, &mut job, &DosVersion::Dos2)?;
+            if job[0] < 0x80 {
+                // Non-OK results are expected for unformatted tracks, but the
+                // head will still have moved
+                if job[0] != 1 {
+                    debug!("Seek to track {track} returned job result {}", job[0]);
+                }
+                break Ok(());
+            }
+            if waited >= JOB_TIMEOUT {
+                break Err(Error::Timeout { dur: waited });
+            }
+        }
+    }
+
+    // Runs the drive routine once, returning the captured window
+    fn nib_capture_window(
+        &self,
+        device: u8,
+        density: u8,
+        steps: u8,
+        signature: &[u8],
+        skip: u16,
+    ) -> Result<CaptureResult, Error> {
+        trace!(
+            "Capture window: device {device} density {density} steps {steps} skip {skip} signature {signature:02x?}"
+        );
+        let mut params = [0u8; 16];
+        params[0] = if density > 3 {
+            DENSITY_UNCHANGED
+        } else {
+            density
+        };
+        params[1] = steps;
+        params[2..4].copy_from_slice(&skip.to_le_bytes());
+        params[4] = signature.len() as u8;
+        params[5] = SIGNATURE_TRIES;
+        params[8..8 + signature.len()].copy_from_slice(signature);
+        self.write_drive_memory(device, PARAM_ADDR, &params)?;
+        let expected = CAPTURE_BASE_TIME
+            + STEP_TIME * (steps as u32 * 2)
+            + byte_time(density, skip as usize + CAPTURE_WINDOW);
+        let mut result = [0u8; 1];
+        self.run_drive_routine(device, DRIVE_CODE_ADDR, expected, RESULT_ADDR, &mut result)?;
+
+        match result[0] {
+            RESULT_OK => {
+                let mut data = vec![0u8; CAPTURE_WINDOW];
+                self.read_drive_memory_dos(device, CAPTURE_ADDR, &mut data, &DosVersion::Dos2)?;
+                Ok(CaptureResult::Data(data))
+            }
+            RESULT_NO_SYNC => Ok(CaptureResult::NoSync),
+            RESULT_NO_SIGNATURE => Ok(CaptureResult::NoSignature),
+            other => Err(Error::Parse {
+                message: format!("Unexpected nibbler result {other} from device {device}"),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_track(halftrack: u8, rev_len: usize, revs: usize) -> RawTrack {
+        // A revolution starting with a sync then a header block, followed by
+        // incrementing bytes that don't repeat within the revolution
+        let mut rev = vec![SYNC_MARKER, HEADER_GCR_START];
+        while rev.len() < rev_len {
+            let n = rev.len();
+            rev.push(((n * 7) % 251) as u8 ^ (n >> 8) as u8);
+        }
+        let mut data = Vec::new();
+        for _ in 0..revs {
+            data.extend_from_slice(&rev);
+        }
+        RawTrack {
+            halftrack,
+            density: speed_zone(halftrack / 2),
+            data,
+        }
+    }
+
+    #[test]
+    fn test_speed_zone() {
+        assert_eq!(speed_zone(1), 3);
+        assert_eq!(speed_zone(17), 3);
+        assert_eq!(speed_zone(18), 2);
+        assert_eq!(speed_zone(25), 1);
+        assert_eq!(speed_zone(31), 0);
+        assert_eq!(speed_zone(42), 0);
+    }
+
+    #[test]
+    fn test_config_halftracks() {
+        let config = NibblerConfig {
+            start_track: 1,
+            end_track: 3,
+            ..Default::default()
+        };
+        assert_eq!(config.halftracks().collect::<Vec<_>>(), vec![2, 4, 6]);
+
+        let config = NibblerConfig {
+            start_track: 1,
+            end_track: 2,
+            half_tracks: true,
+            ..Default::default()
+        };
+        assert_eq!(config.halftracks().collect::<Vec<_>>(), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn test_config_validate() {
+        assert!(NibblerConfig::default().validate().is_ok());
+        let config = NibblerConfig {
+            end_track: 43,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        let config = NibblerConfig {
+            density: Some(4),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        let config = NibblerConfig {
+            revolutions: 0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_revolution_len() {
+        let track = test_track(2, 7000, 2);
+        assert_eq!(track.revolution_len(), Some(7000));
+        assert_eq!(track.revolution().unwrap().len(), 7000);
+
+        let track = test_track(2, 7000, 1);
+        assert_eq!(track.revolution_len(), None);
+    }
+
+    #[test]
+    fn test_g64_data_expands_syncs() {
+        let track = test_track(2, 7000, 2);
+        let g64 = track.g64_data();
+        assert_eq!(&g64[..G64_SYNC_LEN], &[SYNC_MARKER; G64_SYNC_LEN]);
+        assert_eq!(g64[G64_SYNC_LEN], HEADER_GCR_START);
+    }
+
+    #[test]
+    fn test_to_g64() {
+        let disk = RawDisk {
+            tracks: vec![test_track(2, 7000, 2), test_track(4, 7000, 2)],
+        };
+        let g64 = disk.to_g64();
+        assert_eq!(&g64[..8], G64_SIGNATURE);
+        assert_eq!(g64[9] as usize, G64_NUM_HALFTRACKS);
+        let max = u16::from_le_bytes([g64[10], g64[11]]) as usize;
+        assert_eq!(max, G64_MAX_TRACK_SIZE);
+
+        // First track's offset points at its length
+        let offset = u32::from_le_bytes([g64[12], g64[13], g64[14], g64[15]]) as usize;
+        assert_eq!(offset, 12 + G64_NUM_HALFTRACKS * 8);
+        let len = u16::from_le_bytes([g64[offset], g64[offset + 1]]) as usize;
+        assert_eq!(len, disk.tracks[0].g64_data().len());
+
+        // Half-track 3 isn't present
+        assert_eq!(&g64[16..20], &[0, 0, 0, 0]);
+
+        assert_eq!(g64.len(), 12 + G64_NUM_HALFTRACKS * 8 + 2 * (2 + max));
+    }
+
+    #[test]
+    fn test_to_nib() {
+        let disk = RawDisk {
+            tracks: vec![test_track(2, 7000, 2), test_track(36, 6250, 2)],
+        };
+        let nib = disk.to_nib();
+        assert_eq!(&nib[..13], NIB_SIGNATURE);
+        assert_eq!(nib[13], NIB_VERSION);
+        assert_eq!(&nib[0x10..0x14], &[2, 3, 36, 2]);
+        assert_eq!(nib.len(), NIB_HEADER_SIZE + 2 * NIB_TRACK_SIZE);
+        assert_eq!(
+            &nib[NIB_HEADER_SIZE..NIB_HEADER_SIZE + 2],
+            &[SYNC_MARKER, HEADER_GCR_START]
+        );
+    }
+}
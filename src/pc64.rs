@@ -0,0 +1,333 @@
+//! Contains types and functions for working with PC64 (P00, S00, U00, R00)
+//! files
+//!
+//! PC64 files store a single Commodore file on the host, together with its
+//! original PETSCII filename and, for REL files, its record length.  The
+//! file type is stored in the first character of the host file's extension,
+//! and the remaining two characters are a number used to keep host
+//! filenames unique.
+//!
+//! Host filenames are generated using the PC64 8.3 rules - see
+//! [`pc64_base_name`].
+
+use crate::cbm::Cbm;
+use crate::disk::CbmFileType;
+use crate::error::Error;
+use crate::image::{DiskImage, MAX_FILENAME_LEN};
+use crate::string::PetsciiString;
+use crate::util::petscii_str_to_ascii;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::path::{Path, PathBuf};
+
+/// Signature at the start of every PC64 file
+pub const PC64_SIGNATURE: &[u8] = b"C64File\0";
+
+/// Size of the PC64 header
+pub const PC64_HEADER_SIZE: usize = 26;
+
+// Header layout
+const PC64_FILENAME: usize = 8;
+const PC64_RECORD_LEN: usize = 25;
+
+// Maximum length of the host filename, excluding the extension
+const PC64_BASE_NAME_LEN: usize = 8;
+
+// Highest number used in the host file extension
+const PC64_MAX_NUM: u8 = 99;
+
+/// A file stored in PC64 format
+///
+/// # Fields
+///
+/// * `filename` - The file's name on the Commodore disk, in PETSCII
+/// * `file_type` - Type of the file
+/// * `record_len` - Record length for REL files, 0 for other types
+/// * `data` - Contents of the file.  For PRG files this includes the load
+///   address
+///
+/// # Example
+/// ```ignore
+/// let filename = PetsciiString::from_ascii_str("game");
+/// let data = cbm.load_file_petscii(8, &filename)?;
+/// let file = Pc64File::new(filename, CbmFileType::PRG, 0, data);
+/// let path = file.save_to_dir("/tmp")?; // Creates /tmp/GAME.P00
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pc64File {
+    pub filename: PetsciiString,
+    pub file_type: CbmFileType,
+    pub record_len: u8,
+    pub data: Vec<u8>,
+}
+
+impl Pc64File {
+    /// Creates a new PC64 file
+    pub fn new(
+        filename: PetsciiString,
+        file_type: CbmFileType,
+        record_len: u8,
+        data: Vec<u8>,
+    ) -> Self {
+        Pc64File {
+            filename,
+            file_type,
+            record_len,
+            data,
+        }
+    }
+
+    /// Parses the contents of a PC64 file.  The file type isn't stored in
+    /// the contents, so must be provided (see [`pc64_file_type`]).
+    pub fn parse(bytes: &[u8], file_type: CbmFileType) -> Result<Self, Error> {
+        if bytes.len() < PC64_HEADER_SIZE || !bytes.starts_with(PC64_SIGNATURE) {
+            return Err(Error::Parse {
+                message: "Not a PC64 file".to_string(),
+            });
+        }
+
+        let name = &bytes[PC64_FILENAME..PC64_FILENAME + MAX_FILENAME_LEN];
+        let name_len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+
+        Ok(Pc64File {
+            filename: PetsciiString::from_petscii_bytes(&name[..name_len]),
+            file_type,
+            record_len: bytes[PC64_RECORD_LEN],
+            data: bytes[PC64_HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    /// Loads a PC64 file from the host.  The file type is determined from
+    /// the extension.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let file_type = pc64_file_type(path).ok_or_else(|| Error::Validation {
+            message: format!("{} does not have a PC64 extension", path.display()),
+        })?;
+        Self::parse(&std::fs::read(path)?, file_type)
+    }
+
+    /// Returns the contents of the PC64 file, including the header
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut output = Vec::with_capacity(PC64_HEADER_SIZE + self.data.len());
+        output.extend_from_slice(PC64_SIGNATURE);
+
+        let mut name = [0u8; MAX_FILENAME_LEN];
+        let name_len = self.filename.as_bytes().len().min(MAX_FILENAME_LEN);
+        name[..name_len].copy_from_slice(&self.filename.as_bytes()[..name_len]);
+        output.extend_from_slice(&name);
+
+        output.push(0);
+        output.push(self.record_len);
+        output.extend_from_slice(&self.data);
+        output
+    }
+
+    /// Writes the file to the given host directory, choosing a name which
+    /// doesn't clash with any existing file.  Returns the path of the file
+    /// written.
+    pub fn save_to_dir<P: AsRef<Path>>(&self, dir: P) -> Result<PathBuf, Error> {
+        let base = pc64_base_name(&self.filename);
+        let type_char = pc64_type_char(self.file_type);
+
+        for num in 0..=PC64_MAX_NUM {
+            let path = dir.as_ref().join(format!("{base}.{type_char}{num:02}"));
+            if !path.exists() {
+                debug!("Saving {} as {}", self.filename_ascii(), path.display());
+                std::fs::write(&path, self.to_bytes())?;
+                return Ok(path);
+            }
+        }
+
+        Err(Error::Validation {
+            message: format!(
+                "No free PC64 filename for {base} in {}",
+                dir.as_ref().display()
+            ),
+        })
+    }
+
+    /// The filename, converted to ASCII
+    pub fn filename_ascii(&self) -> String {
+        petscii_str_to_ascii(self.filename.as_bytes())
+    }
+
+    /// Reads a file from a drive
+    ///
+    /// # Arguments
+    /// * `cbm` - The Cbm object to use
+    /// * `device` - Device number
+    /// * `filename` - Filename in PETSCII
+    /// * `file_type` - Type of the file.  REL files are not supported
+    pub fn read_from_drive(
+        cbm: &Cbm,
+        device: u8,
+        filename: &PetsciiString,
+        file_type: CbmFileType,
+    ) -> Result<Self, Error> {
+        let data = cbm.read_file_petscii(device, filename, file_type)?;
+        Ok(Self::new(filename.clone(), file_type, 0, data))
+    }
+
+    /// Writes the file to a drive, using the PETSCII filename and file type
+    /// stored in the PC64 file
+    pub fn write_to_drive(&self, cbm: &Cbm, device: u8) -> Result<(), Error> {
+        match self.file_type {
+            CbmFileType::REL => {
+                cbm.save_rel_file_petscii(device, &self.filename, self.record_len, &self.data)
+            }
+            _ => cbm.save_file_petscii(device, &self.filename, self.file_type, &self.data),
+        }
+    }
+
+    /// Writes the file to a disk image
+    pub fn write_to_image(&self, image: &mut DiskImage) -> Result<(), Error> {
+        match self.file_type {
+            CbmFileType::REL => image.write_rel_file(&self.filename, self.record_len, &self.data),
+            _ => image.write_file(&self.filename, self.file_type, &self.data),
+        }
+        .map(|_| ())
+    }
+}
+
+/// Returns the file type of a PC64 file from its extension, or None if the
+/// extension isn't a PC64 one.  DEL files are returned as Unknown.
+pub fn pc64_file_type<P: AsRef<Path>>(path: P) -> Option<CbmFileType> {
+    let ext = path.as_ref().extension()?.to_str()?.as_bytes();
+    if ext.len() != 3 || !ext[1].is_ascii_digit() || !ext[2].is_ascii_digit() {
+        return None;
+    }
+    match ext[0].to_ascii_uppercase() {
+        b'P' => Some(CbmFileType::PRG),
+        b'S' => Some(CbmFileType::SEQ),
+        b'U' => Some(CbmFileType::USR),
+        b'R' => Some(CbmFileType::REL),
+        b'D' => Some(CbmFileType::Unknown),
+        _ => None,
+    }
+}
+
+// The character used in the extension for each file type
+fn pc64_type_char(file_type: CbmFileType) -> char {
+    match file_type {
+        CbmFileType::PRG => 'P',
+        CbmFileType::SEQ => 'S',
+        CbmFileType::USR => 'U',
+        CbmFileType::REL => 'R',
+        CbmFileType::Unknown => 'D',
+    }
+}
+
+/// Converts a PETSCII filename to the base (pre-extension) part of a PC64
+/// host filename, following the PC64 rules:
+///
+/// - Letters are converted to upper case, and digits kept
+/// - Spaces and `-` become `_`
+/// - All other characters are removed
+/// - While the name is longer than 8 characters, remove `_` characters, then
+///   vowels, then other letters, starting from the right.  The first
+///   character is never removed
+/// - Truncate to 8 characters
+/// - An empty name becomes `_`
+pub fn pc64_base_name(filename: &PetsciiString) -> String {
+    let mut name: Vec<u8> = filename
+        .as_bytes()
+        .iter()
+        .filter_map(|&c| match c {
+            b' ' | b'-' => Some(b'_'),
+            b'0'..=b'9' => Some(c),
+            0x41..=0x5a => Some(c),
+            0x61..=0x7a | 0xc1..=0xda => Some((c & 0x1f) | 0x40),
+            _ => None,
+        })
+        .collect();
+
+    remove_from_right(&mut name, |c| c == b'_');
+    remove_from_right(&mut name, |c| b"AEIOU".contains(&c));
+    remove_from_right(&mut name, |c| c.is_ascii_alphabetic());
+    name.truncate(PC64_BASE_NAME_LEN);
+
+    if name.is_empty() {
+        name.push(b'_');
+    }
+    String::from_utf8_lossy(&name).to_string()
+}
+
+// Removes matching characters, from the right, until the name is short
+// enough.  Never removes the first character.
+fn remove_from_right<F: Fn(u8) -> bool>(name: &mut Vec<u8>, matches: F) {
+    let mut ii = name.len();
+    while name.len() > PC64_BASE_NAME_LEN && ii > 1 {
+        ii -= 1;
+        if matches(name[ii]) {
+            name.remove(ii);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base_name(name: &str) -> String {
+        pc64_base_name(&PetsciiString::from_ascii_str(name))
+    }
+
+    #[test]
+    fn test_base_name() {
+        assert_eq!(base_name("hello"), "HELLO");
+        assert_eq!(base_name("my game"), "MY_GAME");
+        assert_eq!(base_name("a-b c"), "A_B_C");
+        assert_eq!(base_name("!*?"), "_");
+        assert_eq!(base_name("the last ninja"), "THLSTNNJ");
+        assert_eq!(base_name("game part 1"), "GAMEPRT1");
+        assert_eq!(base_name("1234567890"), "12345678");
+    }
+
+    #[test]
+    fn test_file_type_from_extension() {
+        assert_eq!(pc64_file_type("GAME.P00"), Some(CbmFileType::PRG));
+        assert_eq!(pc64_file_type("data.s01"), Some(CbmFileType::SEQ));
+        assert_eq!(pc64_file_type("REL.R12"), Some(CbmFileType::REL));
+        assert_eq!(pc64_file_type("GAME.PRG"), None);
+        assert_eq!(pc64_file_type("GAME"), None);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let file = Pc64File::new(
+            PetsciiString::from_ascii_str("my file"),
+            CbmFileType::REL,
+            64,
+            vec![1, 2, 3],
+        );
+        let bytes = file.to_bytes();
+        assert_eq!(bytes.len(), PC64_HEADER_SIZE + 3);
+        assert_eq!(&bytes[..8], PC64_SIGNATURE);
+        assert_eq!(bytes[25], 64);
+
+        let parsed = Pc64File::parse(&bytes, CbmFileType::REL).unwrap();
+        assert_eq!(parsed, file);
+    }
+
+    #[test]
+    fn test_save_to_dir() {
+        let dir = std::env::temp_dir().join(format!("rs1541-pc64-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let file = Pc64File::new(
+            PetsciiString::from_ascii_str("test"),
+            CbmFileType::PRG,
+            0,
+            vec![0x01, 0x08],
+        );
+        let first = file.save_to_dir(&dir).unwrap();
+        let second = file.save_to_dir(&dir).unwrap();
+        assert_eq!(first.file_name().unwrap(), "TEST.P00");
+        assert_eq!(second.file_name().unwrap(), "TEST.P01");
+        assert_eq!(Pc64File::load(&second).unwrap(), file);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,395 @@
+//! Contains functions to find the attached xum1541 adapters, and
+//! [`CbmPool`] to use several of them at once
+//!
+//! Each xum1541 has its own IEC bus, so a drive is addressed by the
+//! adapter's serial number as well as its device number - see
+//! [`DriveAddress`].
+//!
+//! # Example
+//! ```ignore
+//! for adapter in list_adapters()? {
+//!     println!("{adapter}");
+//! }
+//! let pool = CbmPool::open_all()?;
+//! for (serial, result) in pool.scan_all() {
+//!     println!("Adapter {serial}: {:?}", result?);
+//! }
+//! let status = pool.get_status(DriveAddress::new(2, 8))?;
+//! ```
+
+use crate::cbm::Cbm;
+use crate::error::Error;
+use crate::lock::AdapterLock;
+use crate::{CbmDeviceInfo, CbmDirListing, CbmStatus, Xum1541DeviceInfo};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rusb::{Device, DeviceDescriptor, DeviceHandle, GlobalContext};
+use xum1541::BusBuilder;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::thread;
+
+/// USB vendor ID of the xum1541
+pub const XUM1541_VENDOR_ID: u16 = 0x16d0;
+
+/// USB product ID of the xum1541
+pub const XUM1541_PRODUCT_ID: u16 = 0x0504;
+
+/// An attached xum1541 adapter, as returned by [`list_adapters`]
+///
+/// * `serial` - Serial number, as passed to [`Cbm::new`]
+/// * `product` - The USB product string, if it could be read
+/// * `bus_number`, `address` - Where the adapter is on the USB
+/// * `in_use` - Whether an rs1541 process has the adapter open, according
+///   to its [`AdapterLock`]
+/// * `info` - The adapter's details, including firmware version and
+///   capabilities, as returned by [`Cbm::xum1541_info`].  None if the
+///   adapter is in use, or couldn't be opened - see [`adapter_info`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Xum1541Adapter {
+    pub serial: u8,
+    pub product: Option<String>,
+    pub bus_number: u8,
+    pub address: u8,
+    pub in_use: bool,
+    pub info: Option<Xum1541DeviceInfo>,
+}
+
+impl fmt::Display for Xum1541Adapter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Serial {}: {} (USB bus {} address {})",
+            self.serial,
+            self.product.as_deref().unwrap_or("xum1541"),
+            self.bus_number,
+            self.address
+        )?;
+        if let Some(info) = &self.info {
+            write!(f, ", firmware {}", info.firmware_version)?;
+        }
+        if self.in_use {
+            write!(f, ", in use")?;
+        }
+        Ok(())
+    }
+}
+
+/// Lists the local xum1541 adapters attached over USB, in serial number
+/// order
+///
+/// Adapters are found from their USB descriptors.  Those not in use are
+/// then opened briefly to read their details, while holding their
+/// [`AdapterLock`], and adapters in use by other processes aren't claimed.
+/// Adapters whose serial number can't be read, for example due to
+/// permissions, are skipped.
+pub fn list_adapters() -> Result<Vec<Xum1541Adapter>, Error> {
+    let locked = AdapterLock::locked_adapters();
+
+    let mut adapters = Vec::new();
+    for (device, descriptor) in xum1541_devices()? {
+        // Opening the device only allows its descriptors to be read - it
+        // isn't claimed
+        let (bus_number, address) = (device.bus_number(), device.address());
+        let handle = match device.open() {
+            Ok(handle) => handle,
+            Err(e) => {
+                info!("Skipping xum1541 at USB bus {bus_number} address {address}: {e}");
+                continue;
+            }
+        };
+        let Some(serial) = read_serial(&handle, &descriptor) else {
+            info!("Skipping xum1541 at USB bus {bus_number} address {address}, as its serial number couldn't be read");
+            continue;
+        };
+
+        debug!("Found xum1541 serial {serial}");
+        let product = handle.read_product_string_ascii(&descriptor).ok();
+        let in_use = locked.iter().any(|(locked, _)| *locked == serial);
+        let info = if in_use {
+            None
+        } else {
+            adapter_info(serial).unwrap_or_else(|e| {
+                info!("Couldn't read details of xum1541 serial {serial}: {e}");
+                None
+            })
+        };
+        adapters.push(Xum1541Adapter {
+            serial,
+            product,
+            bus_number,
+            address,
+            in_use,
+            info,
+        });
+    }
+    adapters.sort_by_key(|adapter| adapter.serial);
+    Ok(adapters)
+}
+
+/// Returns the details of the local xum1541 with this serial number,
+/// including its firmware version and capabilities, without keeping it open
+///
+/// The adapter is opened briefly, holding its [`AdapterLock`], so this fails
+/// with [`Error::AdapterInUse`] if an rs1541 process, including this one,
+/// has it open.  Use [`Cbm::xum1541_info`] for an adapter already open.  The
+/// IEC bus isn't initialized, so drives on it aren't disturbed.
+pub fn adapter_info(serial: u8) -> Result<Option<Xum1541DeviceInfo>, Error> {
+    let _lock = AdapterLock::acquire(serial)?;
+    let bus = BusBuilder::new().serial(serial).build()?;
+    Ok(bus.device_info())
+}
+
+// Resets the xum1541 with this serial number, or the only one attached if
+// None, at the USB level.  Used to abort a transfer the xum1541 isn't
+// completing, which fails once the device is reset.
+pub(crate) fn usb_reset_adapter(serial: Option<u8>) -> Result<(), Error> {
+    let mut handles = Vec::new();
+    for (device, descriptor) in xum1541_devices()? {
+        let Ok(handle) = device.open() else {
+            continue;
+        };
+        if serial.is_none() || read_serial(&handle, &descriptor) == serial {
+            handles.push(handle);
+        }
+    }
+
+    let mut handle = match (handles.len(), serial) {
+        (1, _) => handles.remove(0),
+        (0, Some(serial)) => {
+            return Err(Error::Validation {
+                message: format!("No xum1541 with serial {serial} to reset"),
+            })
+        }
+        (_, _) => {
+            return Err(Error::Validation {
+                message: "Can't tell which xum1541 to reset".to_string(),
+            })
+        }
+    };
+    info!("Resetting xum1541 at the USB level");
+    handle.reset().map_err(|e| Error::Io {
+        message: format!("Failed to reset xum1541: {e}"),
+    })
+}
+
+// Returns each attached xum1541 with its USB device descriptor
+fn xum1541_devices() -> Result<Vec<(Device<GlobalContext>, DeviceDescriptor)>, Error> {
+    let devices = rusb::devices().map_err(|e| Error::Io {
+        message: format!("Failed to list USB devices: {e}"),
+    })?;
+    Ok(devices
+        .iter()
+        .filter_map(|device| {
+            let descriptor = device.device_descriptor().ok()?;
+            (descriptor.vendor_id() == XUM1541_VENDOR_ID
+                && descriptor.product_id() == XUM1541_PRODUCT_ID)
+                .then_some((device, descriptor))
+        })
+        .collect())
+}
+
+// Reads the serial number from a xum1541's USB descriptor
+fn read_serial(handle: &DeviceHandle<GlobalContext>, descriptor: &DeviceDescriptor) -> Option<u8> {
+    handle
+        .read_serial_number_string_ascii(descriptor)
+        .ok()
+        .and_then(|serial| serial.trim().parse().ok())
+}
+
+/// A drive on one of the buses in a [`CbmPool`]
+///
+/// * `adapter` - Serial number of the xum1541 the drive is attached to
+/// * `device` - The drive's device number
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DriveAddress {
+    pub adapter: u8,
+    pub device: u8,
+}
+
+impl DriveAddress {
+    pub fn new(adapter: u8, device: u8) -> Self {
+        Self { adapter, device }
+    }
+}
+
+impl fmt::Display for DriveAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.adapter, self.device)
+    }
+}
+
+/// A set of open [`Cbm`] instances, one per xum1541, keyed by the adapter's
+/// serial number.  See the [module documentation](self).
+#[derive(Debug, Clone, Default)]
+pub struct CbmPool {
+    cbms: BTreeMap<u8, Cbm>,
+}
+
+impl CbmPool {
+    /// Creates an empty pool
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens every adapter found by [`list_adapters`].  Adapters which are
+    /// in use, or can't be opened, are skipped.
+    pub fn open_all() -> Result<Self, Error> {
+        let mut pool = Self::new();
+        for adapter in list_adapters()? {
+            if adapter.in_use {
+                info!(
+                    "Skipping xum1541 serial {}, as it is in use",
+                    adapter.serial
+                );
+                continue;
+            }
+            match Cbm::new(Some(adapter.serial), None) {
+                Ok(cbm) => {
+                    pool.insert(adapter.serial, cbm);
+                }
+                Err(e) => warn!("Skipping xum1541 serial {}: {e}", adapter.serial),
+            }
+        }
+        Ok(pool)
+    }
+
+    /// Opens the local adapter with this serial number and adds it to the
+    /// pool
+    pub fn open(&mut self, serial: u8) -> Result<&Cbm, Error> {
+        let cbm = Cbm::new(Some(serial), None)?;
+        self.cbms.insert(serial, cbm);
+        self.get(serial)
+    }
+
+    /// Adds an already open [`Cbm`], such as one using a remote adapter, under
+    /// the given serial number.  Returns any [`Cbm`] it replaces.
+    pub fn insert(&mut self, serial: u8, cbm: Cbm) -> Option<Cbm> {
+        self.cbms.insert(serial, cbm)
+    }
+
+    /// Removes an adapter from the pool, returning its [`Cbm`]
+    pub fn remove(&mut self, serial: u8) -> Option<Cbm> {
+        self.cbms.remove(&serial)
+    }
+
+    /// Returns the serial numbers of the adapters in the pool, in order
+    pub fn serials(&self) -> impl Iterator<Item = u8> + '_ {
+        self.cbms.keys().copied()
+    }
+
+    /// Returns the [`Cbm`] for this adapter
+    pub fn get(&self, serial: u8) -> Result<&Cbm, Error> {
+        self.cbms.get(&serial).ok_or_else(|| Error::Validation {
+            message: format!("No xum1541 with serial {serial} in pool"),
+        })
+    }
+
+    /// Runs a function against the drive at this address, passing it the
+    /// adapter's [`Cbm`] and the device number
+    ///
+    /// # Example
+    /// ```ignore
+    /// let data = pool.with_drive(addr, |cbm, device| cbm.read_sector(device, 18, 0))?;
+    /// ```
+    pub fn with_drive<T, F>(&self, addr: DriveAddress, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Cbm, u8) -> Result<T, Error>,
+    {
+        f(self.get(addr.adapter)?, addr.device)
+    }
+
+    /// See [`Cbm::identify`]
+    pub fn identify(&self, addr: DriveAddress) -> Result<CbmDeviceInfo, Error> {
+        self.with_drive(addr, |cbm, device| cbm.identify(device))
+    }
+
+    /// See [`Cbm::get_status`]
+    pub fn get_status(&self, addr: DriveAddress) -> Result<CbmStatus, Error> {
+        self.with_drive(addr, |cbm, device| cbm.get_status(device))
+    }
+
+    /// See [`Cbm::dir`]
+    pub fn dir(&self, addr: DriveAddress, drive_num: Option<u8>) -> Result<CbmDirListing, Error> {
+        self.with_drive(addr, |cbm, device| cbm.dir(device, drive_num))
+    }
+
+    /// Scans every adapter's bus at the same time, using [`Cbm::scan_bus`],
+    /// returning the result for each adapter
+    pub fn scan_all(&self) -> BTreeMap<u8, Result<HashMap<u8, CbmDeviceInfo>, Error>> {
+        thread::scope(|scope| {
+            let scans: Vec<_> = self
+                .cbms
+                .iter()
+                .map(|(serial, cbm)| (*serial, scope.spawn(|| cbm.scan_bus())))
+                .collect();
+            scans
+                .into_iter()
+                .map(|(serial, scan)| {
+                    let result = scan.join().unwrap_or_else(|_| {
+                        Err(Error::Io {
+                            message: format!("Scan of xum1541 serial {serial} panicked"),
+                        })
+                    });
+                    (serial, result)
+                })
+                .collect()
+        })
+    }
+
+    /// Returns the address of every drive found by [`CbmPool::scan_all`].
+    /// Adapters whose scan failed are skipped.
+    pub fn drives(&self) -> Vec<(DriveAddress, CbmDeviceInfo)> {
+        let mut drives: Vec<_> = self
+            .scan_all()
+            .into_iter()
+            .filter_map(|(serial, result)| match result {
+                Ok(devices) => Some((serial, devices)),
+                Err(e) => {
+                    warn!("Failed to scan xum1541 serial {serial}: {e}");
+                    None
+                }
+            })
+            .flat_map(|(serial, devices)| {
+                devices
+                    .into_iter()
+                    .map(move |(device, info)| (DriveAddress::new(serial, device), info))
+            })
+            .collect();
+        drives.sort_by_key(|(addr, _)| *addr);
+        drives
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routing() {
+        let pool = CbmPool::new();
+        let addr = DriveAddress::new(2, 8);
+        assert_eq!(addr.to_string(), "2/8");
+        assert!(matches!(
+            pool.get_status(addr),
+            Err(Error::Validation { .. })
+        ));
+        assert!(pool.scan_all().is_empty());
+        assert!(pool.drives().is_empty());
+
+        let adapter = Xum1541Adapter {
+            serial: 2,
+            product: None,
+            bus_number: 1,
+            address: 7,
+            in_use: true,
+            info: None,
+        };
+        assert_eq!(
+            adapter.to_string(),
+            "Serial 2: xum1541 (USB bus 1 address 7), in use"
+        );
+    }
+}
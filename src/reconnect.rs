@@ -0,0 +1,192 @@
+//! Contains [`ReconnectPolicy`], which lets a [`Cbm`] reconnect to its
+//! xum1541 after the USB device is unplugged, or the remote device server
+//! restarts
+//!
+//! Reconnection is off by default, in which case every call fails with
+//! [`DeviceAccessError::NoDevice`] once the device has gone, until a new
+//! [`Cbm`] is created.  When enabled with [`Cbm::set_reconnect`]:
+//! * The call which finds the device has gone still fails, as it may have
+//!   been part way through talking to a drive
+//! * The next call reopens the device using the [`Cbm`]'s original
+//!   configuration, retrying with backoff as set by the [`ReconnectPolicy`],
+//!   before carrying on
+//! * The handler set by [`Cbm::on_reconnect`] is called with a
+//!   [`ReconnectEvent`]
+//! * [`Cbm::connection_generation`] is incremented, so a
+//!   [`crate::CbmDriveUnit`] identified before the reconnect reports that its
+//!   identity is no longer current, as the drives may have changed
+//!
+//! # Example
+//! ```ignore
+//! let mut cbm = Cbm::new(None, None)?;
+//! cbm.set_reconnect(Some(ReconnectPolicy::default()));
+//! cbm.on_reconnect(|event| println!("{event}"));
+//! ```
+//!
+//! [`Cbm`]: crate::Cbm
+//! [`Cbm::set_reconnect`]: crate::Cbm::set_reconnect
+//! [`Cbm::on_reconnect`]: crate::Cbm::on_reconnect
+//! [`Cbm::connection_generation`]: crate::Cbm::connection_generation
+//! [`DeviceAccessError::NoDevice`]: crate::DeviceAccessError::NoDevice
+
+use xum1541::Error as Xum1541Error;
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How a [`crate::Cbm`] retries reopening a lost device.  See the
+/// [module documentation](self).
+///
+/// * `attempts` - How many times to try to reopen the device before the
+///   call fails.  Later calls will try again.
+/// * `initial_delay` - Delay after the first failed attempt, which doubles
+///   after each subsequent one
+/// * `max_delay` - Limit on the delay between attempts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    pub attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    /// 6 attempts over around 15 seconds
+    fn default() -> Self {
+        Self {
+            attempts: 6,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(8),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Returns the delay to wait after each failed attempt, except the last
+    pub fn delays(&self) -> impl Iterator<Item = Duration> + '_ {
+        let mut delay = self.initial_delay;
+        (1..self.attempts).map(move |_| {
+            let this_delay = delay.min(self.max_delay);
+            delay = delay.saturating_mul(2);
+            this_delay
+        })
+    }
+}
+
+/// Passed to the handler set by [`crate::Cbm::on_reconnect`] when the device
+/// has been reopened
+///
+/// * `attempts` - Number of attempts it took
+/// * `elapsed` - Time taken to reconnect, including the delays
+/// * `generation` - The new [`crate::Cbm::connection_generation`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectEvent {
+    pub attempts: u32,
+    pub elapsed: Duration,
+    pub generation: u64,
+}
+
+impl fmt::Display for ReconnectEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Reconnected to xum1541 after {} attempt(s) in {:?}",
+            self.attempts, self.elapsed
+        )
+    }
+}
+
+type ReconnectFn = dyn Fn(&ReconnectEvent) + Send + Sync;
+
+// The handler set by Cbm::on_reconnect, wrapped so CbmConfig can be Debug
+#[derive(Clone)]
+pub(crate) struct ReconnectHandler(Arc<ReconnectFn>);
+
+impl ReconnectHandler {
+    pub(crate) fn new<F>(f: F) -> Self
+    where
+        F: Fn(&ReconnectEvent) + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    pub(crate) fn call(&self, event: &ReconnectEvent) {
+        (self.0)(event)
+    }
+}
+
+impl fmt::Debug for ReconnectHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ReconnectHandler")
+    }
+}
+
+// Whether this error means the xum1541 itself has gone, as opposed to a
+// problem with a drive
+pub(crate) fn is_device_lost(error: &Xum1541Error) -> bool {
+    matches!(
+        error,
+        Xum1541Error::Usb(_) | Xum1541Error::DeviceAccess { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cbm::Cbm;
+    use crate::string::AsciiString;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Instant;
+
+    #[test]
+    fn test_delays() {
+        let policy = ReconnectPolicy {
+            attempts: 6,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+        };
+        let delays: Vec<u64> = policy.delays().map(|delay| delay.as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+
+        let once = ReconnectPolicy {
+            attempts: 1,
+            ..Default::default()
+        };
+        assert_eq!(once.delays().count(), 0);
+    }
+
+    #[test]
+    fn test_reconnect_on_next_call() {
+        // Each call must try to reconnect, rather than failing straight away
+        // because the device has gone.  A closed local port is used, so
+        // without hardware the attempts fail quickly, after the delay.
+        let delay = Duration::from_millis(50);
+        type Call = fn(&Cbm) -> Result<(), crate::Error>;
+        let calls: [(&str, Call); 3] = [
+            ("read_file", |cbm| {
+                cbm.read_file(30, &AsciiString::from_ascii_str("test"))
+                    .map(|_| ())
+            }),
+            ("reset_bus", |cbm| cbm.reset_bus()),
+            ("xum1541_info", |cbm| cbm.xum1541_info().map(|_| ())),
+        ];
+        for (name, call) in calls {
+            let mut cbm = Cbm::disconnected(None, Some("127.0.0.1:1".parse().unwrap()));
+            cbm.set_reconnect(Some(ReconnectPolicy {
+                attempts: 2,
+                initial_delay: delay,
+                max_delay: delay,
+            }));
+            let reconnected = Arc::new(AtomicBool::new(false));
+            let flag = Arc::clone(&reconnected);
+            cbm.on_reconnect(move |_| flag.store(true, Ordering::SeqCst));
+
+            let start = Instant::now();
+            let _ = call(&cbm);
+            assert!(
+                reconnected.load(Ordering::SeqCst) || start.elapsed() >= delay,
+                "{name} didn't try to reconnect"
+            );
+        }
+    }
+}
@@ -0,0 +1,603 @@
+//! Contains functions to recover deleted files from disk images and
+//! physical disks
+//!
+//! Scratching a file only zeroes the type byte of its directory entry and
+//! frees its sectors in the BAM - the rest of the entry, and the sector
+//! chain, are left alone until they're reused.  [`scratched_files`] lists
+//! such entries, and whether their chains look intact, and
+//! [`ScratchedFile::restore_to_image`] and
+//! [`ScratchedFile::restore_to_drive`] bring them back.
+//!
+//! If the directory itself is damaged, [`carve_files`] reads every sector
+//! and reassembles files from the sector chains alone.
+
+use crate::bam::Bam;
+use crate::block::{dir_slots, BlockDevice, DriveBlocks};
+use crate::disk::CbmFileType;
+use crate::diskcheck::{DiskProblem, SectorUsage};
+use crate::error::Error;
+use crate::image::{
+    CbmDirEntry, DiskImage, DIR_ENTRY_SIZE, DIR_TYPE, DIR_TYPE_CLOSED, SECTOR_SIZE,
+};
+use crate::pc64::Pc64File;
+use crate::string::PetsciiString;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+// Load addresses commonly used by PRG files - BASIC on the PET, C64, C16/
+// Plus4, VIC-20 (unexpanded and expanded) and C128
+const COMMON_LOAD_ADDRESSES: [u16; 6] = [0x0401, 0x0801, 0x1001, 0x1201, 0x1c01, 0x4001];
+
+/// The state of a scratched file's sector chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainStatus {
+    /// The chain can be followed, and none of its sectors have been reused
+    Intact,
+    /// The chain can be followed, but a sector has since been allocated,
+    /// so has probably been overwritten
+    Allocated { track: u8, sector: u8 },
+    /// The chain can't be followed
+    Broken(DiskProblem),
+}
+
+impl fmt::Display for ChainStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainStatus::Intact => write!(f, "intact"),
+            ChainStatus::Allocated { track, sector } => {
+                write!(f, "sector {track}/{sector} reused")
+            }
+            ChainStatus::Broken(problem) => write!(f, "broken: {problem}"),
+        }
+    }
+}
+
+/// A scratched directory entry, returned by [`scratched_files`]
+///
+/// # Fields
+///
+/// * `entry` - The directory entry, as it is on disk
+/// * `file_type` - The file type it most likely had.  The type isn't kept
+///   when a file is scratched, so this is REL if the entry has side sectors,
+///   USR if it has GEOS fields, and PRG otherwise.
+/// * `sectors` - The sectors used by the file, including any side sectors
+///   or GEOS info block
+/// * `status` - Whether the file can be restored
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScratchedFile {
+    pub entry: CbmDirEntry,
+    pub file_type: CbmFileType,
+    pub sectors: Vec<(u8, u8)>,
+    pub status: ChainStatus,
+}
+
+/// Lists scratched files on a disk image or physical disk
+///
+/// # Example
+/// ```ignore
+/// let mut drive = DriveBlocks::new(&cbm, 8, DiskImageFormat::D64);
+/// for file in scratched_files(&drive)? {
+///     println!("{} {}/{} {}", file.entry.filename_ascii(), file.entry.track, file.entry.sector, file.status);
+///     if file.is_intact() {
+///         file.restore_to_drive(&mut drive, file.file_type)?;
+///     }
+/// }
+/// ```
+pub fn scratched_files<D: BlockDevice + ?Sized>(disk: &D) -> Result<Vec<ScratchedFile>, Error> {
+    let bam = Bam::read(disk)?;
+    let mut files = Vec::new();
+    for entry in dir_slots(disk)? {
+        if !entry.is_empty() || entry.track == 0 || entry.filename.as_bytes().is_empty() {
+            continue;
+        }
+        let file_type = guess_file_type(&entry);
+        files.push(scratched_file(disk, &bam, entry, file_type));
+    }
+    Ok(files)
+}
+
+impl ScratchedFile {
+    /// Whether the file can be restored without losing data
+    pub fn is_intact(&self) -> bool {
+        self.status == ChainStatus::Intact
+    }
+
+    /// Restores the file on a disk image, setting its type byte and
+    /// allocating its sectors.  Returns the restored directory entry.
+    ///
+    /// `file_type` is normally [`ScratchedFile::file_type`], but may be any
+    /// type other than REL for a non-REL file.
+    pub fn restore_to_image(
+        &self,
+        image: &mut DiskImage,
+        file_type: CbmFileType,
+    ) -> Result<CbmDirEntry, Error> {
+        self.restore(image, file_type)
+    }
+
+    /// Restores the file on the disk in a drive, setting its type byte and
+    /// allocating its sectors.  Returns the restored directory entry.
+    ///
+    /// The directory sector and BAM are written directly, then the drive is
+    /// initialized so DOS picks up the changes.
+    pub fn restore_to_drive(
+        &self,
+        drive: &mut DriveBlocks,
+        file_type: CbmFileType,
+    ) -> Result<CbmDirEntry, Error> {
+        drive.initialize()?;
+        let entry = self.restore(drive, file_type)?;
+        drive.initialize()?;
+        Ok(entry)
+    }
+
+    fn restore<D: BlockDevice + ?Sized>(
+        &self,
+        disk: &mut D,
+        file_type: CbmFileType,
+    ) -> Result<CbmDirEntry, Error> {
+        let name = self.entry.filename_ascii();
+        if (file_type == CbmFileType::REL) != (self.file_type == CbmFileType::REL)
+            || file_type == CbmFileType::Unknown
+        {
+            return Err(Error::Validation {
+                message: format!("Can't restore {name} as {file_type}"),
+            });
+        }
+        if let ChainStatus::Broken(problem) = &self.status {
+            return Err(Error::Validation {
+                message: format!("Can't restore {name}, chain is broken: {problem}"),
+            });
+        }
+
+        // Check nothing has changed since the file was found
+        let (dir_track, dir_sector) = (self.entry.dir_track, self.entry.dir_sector);
+        let mut block = disk.read_block(dir_track, dir_sector)?;
+        let offset = self.entry.dir_index as usize * DIR_ENTRY_SIZE;
+        let raw = &mut block[offset..offset + DIR_ENTRY_SIZE];
+        if raw[DIR_TYPE..] != self.entry.raw[DIR_TYPE..] {
+            return Err(Error::Validation {
+                message: format!("Directory entry for {name} has changed"),
+            });
+        }
+        let mut bam = Bam::read(disk)?;
+        if let Some((track, sector)) = self
+            .sectors
+            .iter()
+            .copied()
+            .find(|&(track, sector)| !bam.is_free(track, sector))
+        {
+            return Err(Error::Validation {
+                message: format!("Can't restore {name}, sector {track}/{sector} is in use"),
+            });
+        }
+
+        debug!("Restoring {name} as {file_type}");
+        for &(track, sector) in self.sectors.iter() {
+            bam.set_free(track, sector, false)?;
+        }
+        raw[DIR_TYPE] = DIR_TYPE_CLOSED | file_type.to_dir_type();
+        let entry = CbmDirEntry::from_raw(raw, dir_track, dir_sector, self.entry.dir_index);
+        disk.write_block(dir_track, dir_sector, &block)?;
+        bam.write(disk)?;
+
+        Ok(entry)
+    }
+}
+
+// The type byte isn't kept, so guess the type from the other fields
+fn guess_file_type(entry: &CbmDirEntry) -> CbmFileType {
+    if entry.side_track != 0 && entry.record_len != 0 {
+        CbmFileType::REL
+    } else if with_file_type(entry, CbmFileType::PRG).geos().is_some() {
+        CbmFileType::USR
+    } else {
+        CbmFileType::PRG
+    }
+}
+
+// Returns a copy of a scratched entry with the type byte set
+fn with_file_type(entry: &CbmDirEntry, file_type: CbmFileType) -> CbmDirEntry {
+    let mut raw = entry.raw;
+    raw[DIR_TYPE] = DIR_TYPE_CLOSED | file_type.to_dir_type();
+    CbmDirEntry::from_raw(&raw, entry.dir_track, entry.dir_sector, entry.dir_index)
+}
+
+fn scratched_file<D: BlockDevice + ?Sized>(
+    disk: &D,
+    bam: &Bam,
+    entry: CbmDirEntry,
+    file_type: CbmFileType,
+) -> ScratchedFile {
+    let name = entry.filename_ascii();
+    let mut usage = SectorUsage::new(disk.format());
+    let mut problems = Vec::new();
+    usage.add_file(
+        disk,
+        &with_file_type(&entry, file_type),
+        &name,
+        &mut problems,
+    );
+    let sectors = usage.sectors();
+
+    let status = if let Some(problem) = problems.into_iter().next() {
+        ChainStatus::Broken(problem)
+    } else if let Some((track, sector)) = sectors
+        .iter()
+        .copied()
+        .find(|&(track, sector)| !bam.is_free(track, sector))
+    {
+        ChainStatus::Allocated { track, sector }
+    } else {
+        ChainStatus::Intact
+    };
+    trace!("Found scratched file {name}: {status}");
+
+    ScratchedFile {
+        entry,
+        file_type,
+        sectors,
+        status,
+    }
+}
+
+/// A file reassembled from a sector chain by [`carve_files`]
+///
+/// # Fields
+///
+/// * `track`, `sector` - The first sector of the chain
+/// * `sectors` - The sectors in the chain
+/// * `complete` - Whether the chain ends with a valid last sector.  If not,
+///   `data` is everything up to where the chain breaks.
+/// * `file_type` - The guessed file type: PRG if the data starts with a
+///   plausible load address, SEQ if it looks like PETSCII text, otherwise
+///   USR
+/// * `confidence` - How likely this is to be a real file, from 0 to 100
+/// * `data` - The contents of the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CarvedFile {
+    pub track: u8,
+    pub sector: u8,
+    pub sectors: Vec<(u8, u8)>,
+    pub complete: bool,
+    pub file_type: CbmFileType,
+    pub confidence: u8,
+    pub data: Vec<u8>,
+}
+
+impl CarvedFile {
+    /// The name used when writing the file, based on its first sector, such
+    /// as `t17s00`
+    pub fn filename(&self) -> PetsciiString {
+        PetsciiString::from_ascii_str(&format!("t{:02}s{:02}", self.track, self.sector))
+    }
+
+    /// The load address, if this is a PRG file
+    pub fn load_address(&self) -> Option<u16> {
+        match self.file_type {
+            CbmFileType::PRG => Some(u16::from_le_bytes([self.data[0], self.data[1]])),
+            _ => None,
+        }
+    }
+
+    /// Writes the file to a host directory as a PC64 file, returning the
+    /// path of the file written
+    pub fn save_to_dir<P: AsRef<Path>>(&self, dir: P) -> Result<PathBuf, Error> {
+        Pc64File::new(self.filename(), self.file_type, 0, self.data.clone()).save_to_dir(dir)
+    }
+
+    /// Writes the file to a disk image, normally a freshly created one
+    pub fn write_to_image(&self, image: &mut DiskImage) -> Result<CbmDirEntry, Error> {
+        image.write_file(&self.filename(), self.file_type, &self.data)
+    }
+}
+
+// The link from a sector, where it is a plausible part of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Link {
+    Next(u8, u8),
+    Last(u8),
+}
+
+/// Recovers files from a disk image or physical disk without using the
+/// directory, by reading every sector and following the links between
+/// them
+///
+/// Every sector outside the directory track is read, and those whose first
+/// two bytes are a valid link to another sector, or mark the last sector of
+/// a file, are joined into chains.  Chains are followed from each sector
+/// which no other sector links to.  Unreadable sectors are skipped, which
+/// may break chains.
+///
+/// This also finds scratched files, and fragments of files whose sectors
+/// have since been reused, so the candidates are returned most confident
+/// first.
+///
+/// # Example
+/// ```ignore
+/// let drive = DriveBlocks::new(&cbm, 8, DiskImageFormat::D64);
+/// let mut image = DiskImage::new(DiskImageFormat::D64, &name, &id);
+/// for file in carve_files(&drive)?.iter().filter(|f| f.confidence >= 50) {
+///     file.write_to_image(&mut image)?;
+/// }
+/// ```
+pub fn carve_files<D: BlockDevice + ?Sized>(disk: &D) -> Result<Vec<CarvedFile>, Error> {
+    let format = disk.format();
+    let mut sectors = HashMap::new();
+    for track in (1..=format.num_tracks()).filter(|&t| !format.is_system_track(t)) {
+        for sector in 0..format.sectors_per_track(track) {
+            match disk.read_block(track, sector) {
+                Ok(block) => {
+                    let link = match (block[0], block[1]) {
+                        (0, last) if last >= 2 => Link::Last(last),
+                        (0, _) => continue,
+                        (t, s) if s < format.sectors_per_track(t) => Link::Next(t, s),
+                        _ => continue,
+                    };
+                    sectors.insert((track, sector), (link, block));
+                }
+                Err(e) => warn!("Skipping unreadable sector {track}/{sector}: {e}"),
+            }
+        }
+    }
+
+    let linked_to: HashSet<(u8, u8)> = sectors
+        .values()
+        .filter_map(|(link, _)| match link {
+            Link::Next(t, s) => Some((*t, *s)),
+            Link::Last(_) => None,
+        })
+        .collect();
+    let mut heads: Vec<(u8, u8)> = sectors
+        .keys()
+        .copied()
+        .filter(|ts| !linked_to.contains(ts))
+        .collect();
+    heads.sort();
+    debug!(
+        "Found {} chain sectors, {} chain heads",
+        sectors.len(),
+        heads.len()
+    );
+
+    let mut files: Vec<CarvedFile> = heads
+        .into_iter()
+        .map(|head| carve_chain(&sectors, head))
+        .collect();
+    files.sort_by_key(|file| Reverse(file.confidence));
+    Ok(files)
+}
+
+// Follows a chain from its head
+fn carve_chain(sectors: &HashMap<(u8, u8), (Link, Vec<u8>)>, head: (u8, u8)) -> CarvedFile {
+    let mut chain = Vec::new();
+    let mut data = Vec::new();
+    let mut complete = false;
+    let mut next = head;
+    while let Some((link, block)) = sectors.get(&next) {
+        if chain.contains(&next) {
+            break;
+        }
+        chain.push(next);
+        match *link {
+            Link::Next(track, sector) => {
+                data.extend_from_slice(&block[2..SECTOR_SIZE]);
+                next = (track, sector);
+            }
+            Link::Last(last) => {
+                data.extend_from_slice(&block[2..=last as usize]);
+                complete = true;
+                break;
+            }
+        }
+    }
+
+    let (file_type, type_confidence) = guess_carved_type(&data);
+    let mut confidence = if complete { 50 } else { 10 } + type_confidence;
+    if data.iter().all(|&byte| byte == data[0]) {
+        confidence = confidence.min(5);
+    }
+
+    CarvedFile {
+        track: head.0,
+        sector: head.1,
+        sectors: chain,
+        complete,
+        file_type,
+        confidence,
+        data,
+    }
+}
+
+// Guesses the type of a file from its contents, returning the type and how
+// much to add to the confidence score
+fn guess_carved_type(data: &[u8]) -> (CbmFileType, u8) {
+    let load = match data {
+        [low, high, _, ..] => Some(u16::from_le_bytes([*low, *high])),
+        _ => None,
+    };
+    let fits = |load: u16| load as usize + data.len() - 2 <= 0x10000;
+    if load.is_some_and(|load| COMMON_LOAD_ADDRESSES.contains(&load) && fits(load)) {
+        return (CbmFileType::PRG, 50);
+    }
+
+    let text = data
+        .iter()
+        .filter(|&&c| matches!(c, 0x0d | 0x20..=0x5f | 0xc1..=0xda))
+        .count();
+    if text * 10 >= data.len() * 9 {
+        (CbmFileType::SEQ, 30)
+    } else if load.is_some_and(|load| load >= 0x0200 && fits(load)) {
+        (CbmFileType::PRG, 20)
+    } else {
+        (CbmFileType::USR, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::chain_sectors;
+    use crate::image::DiskImageFormat;
+    use crate::string::PetsciiString;
+
+    // Scratches a file as DOS does
+    fn scratch(image: &mut DiskImage, entry: &CbmDirEntry) {
+        for (track, sector) in chain_sectors(image, entry.track, entry.sector).unwrap() {
+            image.free_sector(track, sector).unwrap();
+        }
+        let mut block = image
+            .read_sector(entry.dir_track, entry.dir_sector)
+            .unwrap()
+            .to_vec();
+        block[entry.dir_index as usize * DIR_ENTRY_SIZE + DIR_TYPE] = 0;
+        image
+            .write_sector(entry.dir_track, entry.dir_sector, &block)
+            .unwrap();
+    }
+
+    fn new_image() -> (DiskImage, CbmDirEntry) {
+        let mut image = DiskImage::new(
+            DiskImageFormat::D64,
+            &PetsciiString::from_ascii_str("recover"),
+            &PetsciiString::from_ascii_str("01"),
+        );
+        let entry = image
+            .write_file(
+                &PetsciiString::from_ascii_str("deleted"),
+                CbmFileType::SEQ,
+                &vec![0x55; 700],
+            )
+            .unwrap();
+        scratch(&mut image, &entry);
+        (image, entry)
+    }
+
+    #[test]
+    fn test_restore() {
+        let (mut image, original) = new_image();
+        let blocks_free = image.blocks_free();
+        assert!(image.dir_entries().unwrap().is_empty());
+
+        let files = scratched_files(&image).unwrap();
+        assert_eq!(files.len(), 1);
+        let file = &files[0];
+        assert_eq!(file.entry.filename_ascii(), "deleted");
+        assert_eq!(file.file_type, CbmFileType::PRG);
+        assert_eq!(file.sectors.len(), 3);
+        assert!(file.is_intact());
+
+        let entry = file.restore_to_image(&mut image, CbmFileType::SEQ).unwrap();
+        assert_eq!(entry, original);
+        assert_eq!(image.blocks_free(), blocks_free - 3);
+        assert_eq!(image.dir_entries().unwrap(), vec![original.clone()]);
+        assert_eq!(image.read_file(&entry).unwrap(), vec![0x55; 700]);
+        assert!(scratched_files(&image).unwrap().is_empty());
+
+        // Can't restore twice
+        assert!(file.restore_to_image(&mut image, CbmFileType::SEQ).is_err());
+    }
+
+    #[test]
+    fn test_carve() {
+        let mut image = DiskImage::new(
+            DiskImageFormat::D64,
+            &PetsciiString::from_ascii_str("carve"),
+            &PetsciiString::from_ascii_str("01"),
+        );
+        let mut prg = vec![0x01, 0x08];
+        prg.extend((0..600).map(|ii| (ii % 251) as u8));
+        let text = b"HELLO\rWORLD\r".repeat(30);
+        let prg_entry = image
+            .write_file(
+                &PetsciiString::from_ascii_str("prog"),
+                CbmFileType::PRG,
+                &prg,
+            )
+            .unwrap();
+        let seq_entry = image
+            .write_file(
+                &PetsciiString::from_ascii_str("text"),
+                CbmFileType::SEQ,
+                &text,
+            )
+            .unwrap();
+
+        // Destroy the directory track
+        let dir_track = image.format().dir_track();
+        for sector in 0..image.format().sectors_per_track(dir_track) {
+            image.write_sector(dir_track, sector, &[0; 256]).unwrap();
+        }
+
+        let files = carve_files(&image).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(
+            (files[0].track, files[0].sector),
+            (prg_entry.track, prg_entry.sector)
+        );
+        assert_eq!(files[0].file_type, CbmFileType::PRG);
+        assert_eq!(files[0].load_address(), Some(0x0801));
+        assert_eq!(files[0].data, prg);
+        assert_eq!(files[0].confidence, 100);
+        assert!(files[0].complete);
+        assert_eq!(files[1].file_type, CbmFileType::SEQ);
+        assert_eq!(files[1].data, text);
+        assert_eq!(files[1].sectors.len(), seq_entry.blocks as usize);
+
+        let mut fresh = DiskImage::new(
+            DiskImageFormat::D64,
+            &PetsciiString::from_ascii_str("recovered"),
+            &PetsciiString::from_ascii_str("01"),
+        );
+        let entry = files[0].write_to_image(&mut fresh).unwrap();
+        assert_eq!(fresh.read_file(&entry).unwrap(), prg);
+        assert_eq!(entry.filename, files[0].filename());
+    }
+
+    #[test]
+    fn test_overwritten() {
+        let (mut image, original) = new_image();
+        image
+            .write_file(
+                &PetsciiString::from_ascii_str("new"),
+                CbmFileType::PRG,
+                &[0x01, 0x08],
+            )
+            .unwrap();
+
+        // The new file reuses the first directory slot, so the scratched
+        // entry is gone
+        assert!(scratched_files(&image).unwrap().is_empty());
+
+        // Recreate the scratched entry in another slot
+        let mut block = image
+            .read_sector(original.dir_track, original.dir_sector)
+            .unwrap()
+            .to_vec();
+        let mut raw = original.raw;
+        raw[DIR_TYPE] = 0;
+        raw[..DIR_TYPE].fill(0);
+        block[DIR_ENTRY_SIZE..2 * DIR_ENTRY_SIZE].copy_from_slice(&raw);
+        image
+            .write_sector(original.dir_track, original.dir_sector, &block)
+            .unwrap();
+
+        let files = scratched_files(&image).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            files[0].status,
+            ChainStatus::Allocated {
+                track: original.track,
+                sector: original.sector
+            }
+        );
+        assert!(files[0]
+            .restore_to_image(&mut image, CbmFileType::SEQ)
+            .is_err());
+    }
+}
@@ -0,0 +1,357 @@
+//! Contains a surface scan, to check the health of a physical disk
+//!
+//! [`scan_surface`] reads every sector of a disk, retrying those which fail
+//! and periodically bumping the head to recalibrate it, and records the
+//! resulting DOS error for each sector in a [`SurfaceScan`].  This can be
+//! serialized to JSON, and drawn as a grid with [`SurfaceScan::grid`].
+
+use crate::block::{BlockDevice, DriveBlocks};
+use crate::cbm::Cbm;
+use crate::cbmtype::CbmErrorNumber;
+use crate::error::Error;
+use crate::image::DiskImageFormat;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+// 1541/1571 job queue entry and track/sector for buffer 0, and the job code
+// to bump the head against the track 1 stop
+const JOB_QUEUE: u16 = 0x0000;
+const JOB_TRACK_SECTOR: u16 = 0x0006;
+const JOB_BUMP: u8 = 0xc0;
+const JOB_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Options for [`scan_surface`]
+///
+/// # Fields
+///
+/// * `retries` - How many times to retry a sector which fails to read
+/// * `bump_every` - Bump the head after this many failed attempts at a
+///   sector.  0 disables bumping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanOptions {
+    pub retries: u8,
+    pub bump_every: u8,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            retries: 4,
+            bump_every: 2,
+        }
+    }
+}
+
+/// The result of scanning a single sector
+///
+/// # Fields
+///
+/// * `error` - The DOS error from the last attempt, [`CbmErrorNumber::Ok`]
+///   if the sector was read
+/// * `attempts` - How many reads were attempted.  More than 1 with `error`
+///   Ok means the sector was read after retrying.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SectorScan {
+    pub error: CbmErrorNumber,
+    pub attempts: u16,
+}
+
+impl SectorScan {
+    /// Whether the sector was read successfully
+    pub fn is_ok(&self) -> bool {
+        self.error == CbmErrorNumber::Ok
+    }
+}
+
+/// A track by sector map of the result of a surface scan
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SurfaceScan {
+    format: DiskImageFormat,
+    tracks: Vec<Vec<SectorScan>>,
+}
+
+impl SurfaceScan {
+    /// The layout of the disk scanned
+    pub fn format(&self) -> DiskImageFormat {
+        self.format
+    }
+
+    /// Returns the result for a sector, or None if it doesn't exist
+    pub fn sector(&self, track: u8, sector: u8) -> Option<&SectorScan> {
+        self.tracks
+            .get((track as usize).checked_sub(1)?)?
+            .get(sector as usize)
+    }
+
+    /// Returns the track, sector and error of each sector which couldn't be
+    /// read
+    pub fn bad_sectors(&self) -> Vec<(u8, u8, CbmErrorNumber)> {
+        self.sectors()
+            .filter(|(_, _, scan)| !scan.is_ok())
+            .map(|(track, sector, scan)| (track, sector, scan.error.clone()))
+            .collect()
+    }
+
+    /// Number of sectors which were only read after retrying
+    pub fn retried_sectors(&self) -> usize {
+        self.sectors()
+            .filter(|(_, _, scan)| scan.is_ok() && scan.attempts > 1)
+            .count()
+    }
+
+    /// Whether every sector was read
+    pub fn is_ok(&self) -> bool {
+        self.sectors().all(|(_, _, scan)| scan.is_ok())
+    }
+
+    /// Serializes the scan as JSON
+    pub fn to_json(&self) -> Result<String, Error> {
+        serde_json::to_string_pretty(self).map_err(|e| Error::Parse {
+            message: format!("Failed to serialize surface scan: {e}"),
+        })
+    }
+
+    /// Deserializes a scan previously written by [`SurfaceScan::to_json`]
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        serde_json::from_str(json).map_err(|e| Error::Parse {
+            message: format!("Invalid surface scan: {e}"),
+        })
+    }
+
+    /// Draws the scan as a grid, one row per track.  Each sector is shown as
+    /// `.` if it was read first time, `r` if it was read after retrying, or
+    /// the last digit of the DOS error number (for example `3` for 23, a
+    /// data block checksum error).
+    pub fn grid(&self) -> String {
+        let max_sectors = self.tracks.iter().map(|t| t.len()).max().unwrap_or(0);
+        let mut output = String::from("    ");
+        for sector in 0..max_sectors {
+            let _ = write!(output, "{}", sector % 10);
+        }
+        output.push('\n');
+
+        for (ii, track) in self.tracks.iter().enumerate() {
+            let _ = write!(output, "{:>3} ", ii + 1);
+            for scan in track {
+                output.push(match scan {
+                    scan if scan.is_ok() && scan.attempts <= 1 => '.',
+                    scan if scan.is_ok() => 'r',
+                    scan => char::from_digit(scan.error.clone() as u32 % 10, 10).unwrap(),
+                });
+            }
+            output.push('\n');
+        }
+
+        let bad = self.bad_sectors().len();
+        let _ = writeln!(
+            output,
+            "{bad} bad sectors, {} read after retrying",
+            self.retried_sectors()
+        );
+        output
+    }
+
+    fn sectors(&self) -> impl Iterator<Item = (u8, u8, &SectorScan)> {
+        self.tracks.iter().enumerate().flat_map(|(track, sectors)| {
+            sectors
+                .iter()
+                .enumerate()
+                .map(move |(sector, scan)| (track as u8 + 1, sector as u8, scan))
+        })
+    }
+}
+
+/// Reads every sector of the disk in a drive, returning the DOS error for
+/// each one
+///
+/// Read errors (20-29) are recorded against the sector.  Any other error,
+/// such as 74 (drive not ready), aborts the scan.
+///
+/// Head bumps use the drive's job queue on 1541 and 1571 drives.  On a 1581
+/// the head is instead moved to track 1 and back.
+///
+/// # Example
+/// ```ignore
+/// let scan = scan_surface(&cbm, 8, DiskImageFormat::D64, &ScanOptions::default())?;
+/// println!("{}", scan.grid());
+/// std::fs::write("scan.json", scan.to_json()?)?;
+/// ```
+pub fn scan_surface(
+    cbm: &Cbm,
+    device: u8,
+    format: DiskImageFormat,
+    options: &ScanOptions,
+) -> Result<SurfaceScan, Error> {
+    let drive = DriveBlocks::new(cbm, device, format);
+    scan_blocks(&drive, options, || match format {
+        DiskImageFormat::D81 => drive
+            .read_block(1, 0)
+            .map(|_| ())
+            .or_else(|e| read_error(&e).map(|_| ()).ok_or(e)),
+        _ => bump_head(cbm, device),
+    })
+}
+
+// Scans every sector of a block device, calling bump to bump the head
+fn scan_blocks<D, F>(disk: &D, options: &ScanOptions, mut bump: F) -> Result<SurfaceScan, Error>
+where
+    D: BlockDevice + ?Sized,
+    F: FnMut() -> Result<(), Error>,
+{
+    let format = disk.format();
+    let max_attempts = options.retries as u16 + 1;
+    let bump_every = options.bump_every as u16;
+    let mut tracks = Vec::new();
+    for track in 1..=format.num_tracks() {
+        let mut sectors = Vec::new();
+        for sector in 0..format.sectors_per_track(track) {
+            let mut attempts = 0;
+            let error = loop {
+                attempts += 1;
+                let error = match disk.read_block(track, sector) {
+                    Ok(_) => break CbmErrorNumber::Ok,
+                    Err(e) => read_error(&e).ok_or(e)?,
+                };
+                trace!("Sector {track}/{sector} attempt {attempts}: {error}");
+                if attempts >= max_attempts {
+                    break error;
+                }
+                if bump_every != 0 && attempts % bump_every == 0 {
+                    debug!("Bumping head after {attempts} attempts at {track}/{sector}");
+                    bump()?;
+                }
+            };
+            if error != CbmErrorNumber::Ok {
+                info!("Sector {track}/{sector} failed: {error}");
+            }
+            sectors.push(SectorScan { error, attempts });
+        }
+        tracks.push(sectors);
+    }
+    Ok(SurfaceScan { format, tracks })
+}
+
+// Returns the DOS error if this is a sector read error
+fn read_error(error: &Error) -> Option<CbmErrorNumber> {
+    match error {
+        Error::Status { status } if (20..=29).contains(&status.number) => {
+            Some(status.error_number.clone())
+        }
+        _ => None,
+    }
+}
+
+// Bumps the head using the job queue, waiting for the job to complete
+fn bump_head(cbm: &Cbm, device: u8) -> Result<(), Error> {
+    cbm.write_drive_memory(device, JOB_TRACK_SECTOR, &[1, 0])?;
+    cbm.write_drive_memory(device, JOB_QUEUE, &[JOB_BUMP])?;
+    let start = Instant::now();
+    let mut job = [JOB_BUMP];
+    while job[0] & 0x80 != 0 {
+        if start.elapsed() > JOB_TIMEOUT {
+            return Err(Error::Timeout { dur: JOB_TIMEOUT });
+        }
+        cbm.read_drive_memory(device, JOB_QUEUE, &mut job)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cbmtype::CbmStatus;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    // A disk which fails to read some sectors a number of times
+    struct FlakyDisk {
+        failures: RefCell<HashMap<(u8, u8), u16>>,
+    }
+
+    impl BlockDevice for FlakyDisk {
+        fn format(&self) -> DiskImageFormat {
+            DiskImageFormat::D64
+        }
+
+        fn read_block(&self, track: u8, sector: u8) -> Result<Vec<u8>, Error> {
+            match self.failures.borrow_mut().get_mut(&(track, sector)) {
+                Some(count) if *count > 0 => {
+                    *count -= 1;
+                    Err(Error::Status {
+                        status: CbmStatus {
+                            number: 23,
+                            error_number: CbmErrorNumber::ReadErrorChecksumErrorInDataBlock,
+                            message: "read error".to_string(),
+                            track,
+                            sector,
+                            device: 8,
+                        },
+                    })
+                }
+                _ => Ok(vec![0; 256]),
+            }
+        }
+
+        fn write_block(&mut self, _: u8, _: u8, _: &[u8]) -> Result<(), Error> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn test_scan() {
+        let disk = FlakyDisk {
+            failures: RefCell::new(HashMap::from([((1, 0), 2), ((18, 3), 255)])),
+        };
+        let mut bumps = 0;
+        let options = ScanOptions {
+            retries: 3,
+            bump_every: 2,
+        };
+        let scan = scan_blocks(&disk, &options, || {
+            bumps += 1;
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(bumps, 2);
+        assert!(!scan.is_ok());
+        assert_eq!(scan.sector(1, 0).unwrap().attempts, 3);
+        assert!(scan.sector(1, 0).unwrap().is_ok());
+        assert_eq!(
+            scan.bad_sectors(),
+            vec![(18, 3, CbmErrorNumber::ReadErrorChecksumErrorInDataBlock)]
+        );
+        assert_eq!(scan.sector(18, 3).unwrap().attempts, 4);
+        assert_eq!(scan.retried_sectors(), 1);
+        assert!(scan.sector(36, 0).is_none());
+
+        let grid = scan.grid();
+        assert!(grid.contains("\n  1 r....................\n"));
+        assert!(grid.contains("\n 18 ...3...............\n"));
+
+        let json = scan.to_json().unwrap();
+        assert_eq!(SurfaceScan::from_json(&json).unwrap(), scan);
+    }
+
+    #[test]
+    fn test_scan_max_retries() {
+        let disk = FlakyDisk {
+            failures: RefCell::new(HashMap::from([((1, 0), 255), ((1, 1), 1000)])),
+        };
+        let options = ScanOptions {
+            retries: u8::MAX,
+            bump_every: 0,
+        };
+        let scan = scan_blocks(&disk, &options, || Ok(())).unwrap();
+
+        // Read on the last attempt, and failing every attempt
+        assert!(scan.sector(1, 0).unwrap().is_ok());
+        assert_eq!(scan.sector(1, 0).unwrap().attempts, 256);
+        assert!(!scan.sector(1, 1).unwrap().is_ok());
+        assert_eq!(scan.sector(1, 1).unwrap().attempts, 256);
+    }
+}
@@ -0,0 +1,389 @@
+//! Contains types and functions for working with T64 tape archives
+//!
+//! T64 files hold the contents of a C64 tape as a set of entries, each with
+//! a load address and a filename.  Many T64 files in the wild have incorrect
+//! end addresses (a bug in an early creation tool set them all to 0xC3C6),
+//! so the length of each entry is checked against the position of the next
+//! entry's data and the size of the archive.
+//!
+//! Entries can be extracted to a [`DiskImage`], or to a physical drive via
+//! [`Cbm`].
+
+use crate::cbm::Cbm;
+use crate::disk::{CbmFileEntry, CbmFileType, BYTES_PER_BLOCK};
+use crate::error::Error;
+use crate::image::DiskImage;
+use crate::string::PetsciiString;
+use crate::util::petscii_str_to_ascii;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::path::Path;
+
+// Header layout
+const T64_SIGNATURE: &[u8] = b"C64";
+const T64_VERSION: usize = 0x20;
+const T64_MAX_ENTRIES: usize = 0x22;
+const T64_USED_ENTRIES: usize = 0x24;
+const T64_TAPE_NAME: usize = 0x28;
+const T64_TAPE_NAME_LEN: usize = 24;
+const T64_HEADER_SIZE: usize = 0x40;
+
+// Directory entry layout
+const T64_ENTRY_SIZE: usize = 32;
+const T64_ENTRY_TYPE: usize = 0;
+const T64_FILE_TYPE: usize = 1;
+const T64_START_ADDR: usize = 2;
+const T64_END_ADDR: usize = 4;
+const T64_OFFSET: usize = 8;
+const T64_FILENAME: usize = 16;
+const T64_FILENAME_LEN: usize = 16;
+
+// Names in T64 files are padded with spaces rather than shifted spaces
+const T64_PADDING: u8 = 0x20;
+
+/// Type of a T64 directory entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum T64EntryType {
+    Free,
+    Normal,
+    Snapshot,
+    Other(u8),
+}
+
+impl From<u8> for T64EntryType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => T64EntryType::Free,
+            1 => T64EntryType::Normal,
+            3 => T64EntryType::Snapshot,
+            other => T64EntryType::Other(other),
+        }
+    }
+}
+
+/// An entry in a T64 archive
+///
+/// # Fields
+///
+/// * `index` - Index of the entry in the archive's directory
+/// * `entry_type` - Type of the entry
+/// * `file_type` - Type of the file, PRG if the archive doesn't specify a
+///   valid type
+/// * `start_addr` - Load address of the file
+/// * `end_addr` - End address as stored in the archive (may be incorrect)
+/// * `offset` - Offset of the file's data within the archive
+/// * `len` - Length of the file's data, after correcting for bad end
+///   addresses
+/// * `filename` - Filename in PETSCII, with padding removed
+#[derive(Debug, Clone, PartialEq)]
+pub struct T64Entry {
+    pub index: usize,
+    pub entry_type: T64EntryType,
+    pub file_type: CbmFileType,
+    pub start_addr: u16,
+    pub end_addr: u16,
+    pub offset: usize,
+    pub len: usize,
+    pub filename: PetsciiString,
+}
+
+impl T64Entry {
+    /// Size of the file in blocks, if it were written to disk
+    pub fn blocks(&self) -> u16 {
+        self.file_len().div_ceil(BYTES_PER_BLOCK).max(1) as u16
+    }
+
+    /// Length of the file when written to disk, including the load address
+    /// for PRG files
+    pub fn file_len(&self) -> usize {
+        match self.file_type {
+            CbmFileType::PRG => self.len + 2,
+            _ => self.len,
+        }
+    }
+
+    /// The filename, converted to ASCII
+    pub fn filename_ascii(&self) -> String {
+        petscii_str_to_ascii(self.filename.as_bytes())
+    }
+
+    /// Converts to the entry type returned by directory listings
+    pub fn to_file_entry(&self) -> CbmFileEntry {
+        CbmFileEntry::ValidFile {
+            blocks: self.blocks(),
+            filename: self.filename_ascii(),
+            file_type: self.file_type,
+        }
+    }
+}
+
+/// A parsed T64 archive
+///
+/// # Example
+/// ```ignore
+/// let archive = T64Archive::load("games.t64")?;
+/// for entry in archive.entries.iter() {
+///     println!("{}", entry.to_file_entry());
+/// }
+/// archive.extract_to_drive(&cbm, 8, &archive.entries)?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct T64Archive {
+    pub version: u16,
+    pub name: PetsciiString,
+    pub entries: Vec<T64Entry>,
+    data: Vec<u8>,
+}
+
+impl T64Archive {
+    /// Parses a T64 archive
+    pub fn parse(data: Vec<u8>) -> Result<Self, Error> {
+        if data.len() < T64_HEADER_SIZE || !data.starts_with(T64_SIGNATURE) {
+            return Err(Error::Parse {
+                message: "Not a T64 archive".to_string(),
+            });
+        }
+
+        let read_u16 = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let version = read_u16(T64_VERSION);
+        let max_entries = read_u16(T64_MAX_ENTRIES) as usize;
+        let used_entries = read_u16(T64_USED_ENTRIES) as usize;
+        let name = PetsciiString::from_petscii_bytes(strip_t64_padding(
+            &data[T64_TAPE_NAME..T64_TAPE_NAME + T64_TAPE_NAME_LEN],
+        ));
+        trace!("T64 version {version:#06x} max entries {max_entries} used entries {used_entries}");
+
+        // Some archives claim 0 max entries - assume at least the used count
+        // and never read beyond the archive
+        let num_entries = max_entries
+            .max(used_entries)
+            .max(1)
+            .min((data.len() - T64_HEADER_SIZE) / T64_ENTRY_SIZE);
+
+        let mut entries = Vec::new();
+        for index in 0..num_entries {
+            let raw = &data[T64_HEADER_SIZE + index * T64_ENTRY_SIZE..][..T64_ENTRY_SIZE];
+            let entry_type = T64EntryType::from(raw[T64_ENTRY_TYPE]);
+            if entry_type == T64EntryType::Free {
+                continue;
+            }
+
+            let type_byte = raw[T64_FILE_TYPE];
+            let file_type = match CbmFileType::from_dir_type(type_byte) {
+                CbmFileType::Unknown => CbmFileType::PRG,
+                _ if type_byte & 0x80 == 0 => CbmFileType::PRG,
+                file_type => file_type,
+            };
+            let start_addr = u16::from_le_bytes([raw[T64_START_ADDR], raw[T64_START_ADDR + 1]]);
+            let end_addr = u16::from_le_bytes([raw[T64_END_ADDR], raw[T64_END_ADDR + 1]]);
+            let offset =
+                u32::from_le_bytes(raw[T64_OFFSET..T64_OFFSET + 4].try_into().unwrap()) as usize;
+
+            if offset >= data.len() {
+                warn!("T64 entry {index} data offset {offset} is beyond the end of the archive");
+                continue;
+            }
+
+            entries.push(T64Entry {
+                index,
+                entry_type,
+                file_type,
+                start_addr,
+                end_addr,
+                offset,
+                len: end_addr.wrapping_sub(start_addr) as usize,
+                filename: PetsciiString::from_petscii_bytes(strip_t64_padding(
+                    &raw[T64_FILENAME..T64_FILENAME + T64_FILENAME_LEN],
+                )),
+            });
+        }
+
+        // Correct any lengths which would overlap the next entry's data or
+        // run off the end of the archive
+        let mut offsets: Vec<usize> = entries.iter().map(|e| e.offset).collect();
+        offsets.push(data.len());
+        offsets.sort_unstable();
+        for entry in entries.iter_mut() {
+            let next = offsets
+                .iter()
+                .copied()
+                .find(|&offset| offset > entry.offset)
+                .unwrap_or(data.len());
+            let max_len = next - entry.offset;
+            if entry.len == 0 || entry.len > max_len {
+                debug!(
+                    "Correcting length of T64 entry {} from {} to {}",
+                    entry.index, entry.len, max_len
+                );
+                entry.len = max_len;
+            }
+        }
+
+        Ok(T64Archive {
+            version,
+            name,
+            entries,
+            data,
+        })
+    }
+
+    /// Loads and parses a T64 archive from a file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::parse(std::fs::read(path)?)
+    }
+
+    /// Returns the archive's entries in the form used by directory listings
+    pub fn files(&self) -> Vec<CbmFileEntry> {
+        self.entries.iter().map(|e| e.to_file_entry()).collect()
+    }
+
+    /// Returns the contents of an entry as it would be stored on disk.  For
+    /// PRG files this includes the load address.
+    pub fn file_data(&self, entry: &T64Entry) -> Vec<u8> {
+        let mut output = Vec::with_capacity(entry.file_len());
+        if entry.file_type == CbmFileType::PRG {
+            output.extend_from_slice(&entry.start_addr.to_le_bytes());
+        }
+        output.extend_from_slice(&self.data[entry.offset..entry.offset + entry.len]);
+        output
+    }
+
+    /// Writes the given entries to a disk image
+    pub fn extract_to_image(
+        &self,
+        image: &mut DiskImage,
+        entries: &[T64Entry],
+    ) -> Result<(), Error> {
+        for entry in entries {
+            debug!("Extracting {} to image", entry.filename_ascii());
+            image.write_file(&entry.filename, entry.file_type, &self.file_data(entry))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the given entries to a physical drive
+    pub fn extract_to_drive(
+        &self,
+        cbm: &Cbm,
+        device: u8,
+        entries: &[T64Entry],
+    ) -> Result<(), Error> {
+        for entry in entries {
+            debug!("Extracting {} to device {device}", entry.filename_ascii());
+            cbm.save_file_petscii(
+                device,
+                &entry.filename,
+                entry.file_type,
+                &self.file_data(entry),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Removes trailing padding from a T64 name.  Some tools pad with shifted
+// spaces, so remove those too.
+fn strip_t64_padding(name: &[u8]) -> &[u8] {
+    let len = name
+        .iter()
+        .rposition(|&c| c != T64_PADDING && c != 0xa0 && c != 0)
+        .map(|pos| pos + 1)
+        .unwrap_or(0);
+    &name[..len]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::DiskImageFormat;
+
+    // Builds a T64 with the given (name, start, end, data) entries
+    fn build_t64(files: &[(&str, u16, u16, &[u8])]) -> Vec<u8> {
+        let mut output = vec![0u8; T64_HEADER_SIZE + files.len() * T64_ENTRY_SIZE];
+        output[..19].copy_from_slice(b"C64 tape image file");
+        output[T64_VERSION..T64_VERSION + 2].copy_from_slice(&0x0101u16.to_le_bytes());
+        output[T64_MAX_ENTRIES] = files.len() as u8;
+        output[T64_USED_ENTRIES] = files.len() as u8;
+        output[T64_TAPE_NAME..T64_TAPE_NAME + T64_TAPE_NAME_LEN].fill(T64_PADDING);
+        output[T64_TAPE_NAME..T64_TAPE_NAME + 4].copy_from_slice(b"TAPE");
+
+        for (ii, (name, start, end, data)) in files.iter().enumerate() {
+            let offset = output.len();
+            let raw = &mut output[T64_HEADER_SIZE + ii * T64_ENTRY_SIZE..][..T64_ENTRY_SIZE];
+            raw[T64_ENTRY_TYPE] = 1;
+            raw[T64_FILE_TYPE] = 0x82;
+            raw[T64_START_ADDR..T64_START_ADDR + 2].copy_from_slice(&start.to_le_bytes());
+            raw[T64_END_ADDR..T64_END_ADDR + 2].copy_from_slice(&end.to_le_bytes());
+            raw[T64_OFFSET..T64_OFFSET + 4].copy_from_slice(&(offset as u32).to_le_bytes());
+            raw[T64_FILENAME..T64_FILENAME + T64_FILENAME_LEN].fill(T64_PADDING);
+            raw[T64_FILENAME..T64_FILENAME + name.len()].copy_from_slice(name.as_bytes());
+            output.extend_from_slice(data);
+        }
+        output
+    }
+
+    #[test]
+    fn test_parse() {
+        let t64 = build_t64(&[
+            ("GAME", 0x0801, 0x0805, &[1, 2, 3, 4]),
+            ("DATA", 0x2000, 0x2002, &[5, 6]),
+        ]);
+        let archive = T64Archive::parse(t64).unwrap();
+        assert_eq!(archive.version, 0x0101);
+        assert_eq!(archive.name.as_bytes(), b"TAPE");
+        assert_eq!(archive.entries.len(), 2);
+
+        let entry = &archive.entries[0];
+        assert_eq!(entry.filename.as_bytes(), b"GAME");
+        assert_eq!(entry.file_type, CbmFileType::PRG);
+        assert_eq!(entry.start_addr, 0x0801);
+        assert_eq!(entry.len, 4);
+        assert_eq!(entry.blocks(), 1);
+        assert_eq!(archive.file_data(entry), vec![0x01, 0x08, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_bad_end_address() {
+        let t64 = build_t64(&[
+            ("ONE", 0x0801, 0xc3c6, &[1, 2, 3]),
+            ("TWO", 0x0801, 0xc3c6, &[4, 5]),
+        ]);
+        let archive = T64Archive::parse(t64).unwrap();
+        assert_eq!(archive.entries[0].len, 3);
+        assert_eq!(archive.entries[1].len, 2);
+        assert_eq!(
+            archive.file_data(&archive.entries[1]),
+            vec![0x01, 0x08, 4, 5]
+        );
+    }
+
+    #[test]
+    fn test_not_t64() {
+        assert!(T64Archive::parse(vec![0u8; 100]).is_err());
+    }
+
+    #[test]
+    fn test_extract_to_image() {
+        let t64 = build_t64(&[("GAME", 0x0801, 0x0805, &[1, 2, 3, 4])]);
+        let archive = T64Archive::parse(t64).unwrap();
+        let mut image = DiskImage::new(
+            DiskImageFormat::D64,
+            &PetsciiString::from_ascii_str("disk"),
+            &PetsciiString::from_ascii_str("01"),
+        );
+        archive
+            .extract_to_image(&mut image, &archive.entries)
+            .unwrap();
+
+        let entry = image
+            .find_file(&PetsciiString::from_petscii_bytes(b"GAME"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.file_type, CbmFileType::PRG);
+        assert_eq!(
+            image.read_file(&entry).unwrap(),
+            vec![0x01, 0x08, 1, 2, 3, 4]
+        );
+    }
+}
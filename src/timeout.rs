@@ -0,0 +1,637 @@
+//! Contains [`Timeouts`], which limit how long bus operations can take, and
+//! the recovery attempted when one times out
+//!
+//! Limits can be set for each bus primitive - talk, listen, read and write -
+//! and for a whole operation, meaning a single [`Cbm`] call such as
+//! [`Cbm::read_file`].  They are set using [`Cbm::set_timeouts`], and none
+//! are set by default.
+//!
+//! When a primitive has a limit, or is part of an operation with one, it is
+//! run on a watchdog thread, so the call returns once the limit passes even
+//! if the xum1541 doesn't.  A primitive is given the shorter of its own
+//! limit and the time left for the operation.  Primitives without a limit
+//! are called directly, as before.
+//!
+//! When a limit is exceeded the bus is recovered by escalating through each
+//! [`RecoveryStep`], up to [`Timeouts::recovery`], until the device responds
+//! to a status request, which is itself subject to the talk and read limits:
+//! 1. Untalk and unlisten
+//! 2. Reset the IEC bus
+//! 3. Reset the USB device, as [`Cbm::usb_device_reset`] does
+//!
+//! If the xum1541 is still stuck in the primitive, the first two steps
+//! can't be attempted.  The USB reset resets a local xum1541 at the USB
+//! level, which aborts the stuck transfer, before reopening it.
+//!
+//! The operation still fails, returning [`Error::BusTimeout`], which lists
+//! each step attempted and its outcome.  If recovery didn't free a stuck
+//! xum1541, calls fail until it is reopened, by [`Cbm::usb_device_reset`]
+//! or by reconnecting - see [`crate::reconnect`].
+//!
+//! # Example
+//! ```ignore
+//! let mut cbm = Cbm::new(None, None)?;
+//! cbm.set_timeouts(Timeouts {
+//!     read: Some(Duration::from_secs(2)),
+//!     operation: Some(Duration::from_secs(60)),
+//!     ..Default::default()
+//! });
+//! ```
+//!
+//! [`Cbm`]: crate::Cbm
+//! [`Cbm::read_file`]: crate::Cbm::read_file
+//! [`Cbm::set_timeouts`]: crate::Cbm::set_timeouts
+//! [`Cbm::usb_device_reset`]: crate::Cbm::usb_device_reset
+
+use crate::cbm::{Cbm, CbmConfig};
+use crate::channel::CBM_CHANNEL_CTRL;
+use crate::error::{DeviceError, Error};
+use crate::pool::usb_reset_adapter;
+use crate::reconnect::is_device_lost;
+use crate::{BusGuardMut, Xum1541DeviceInfo};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use xum1541::{Bus, BusRecoveryType, DeviceChannel};
+use xum1541::{DeviceAccessError, Error as Xum1541Error};
+
+use std::fmt;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long to give drives to come back up after an IEC bus reset, before
+// checking whether they respond
+const BUS_RESET_DELAY: Duration = Duration::from_secs(2);
+
+// How long to wait for a stuck primitive to return once the xum1541 has
+// been reset at the USB level
+const USB_RESET_RELEASE_WAIT: Duration = Duration::from_secs(2);
+
+// A primitive's result, returned with the Bus by the watchdog thread
+type Returned<T> = (Bus, Result<T, Xum1541Error>);
+
+// Waits up to the given time for a stalled primitive to return its Bus
+type Stalled = dyn FnMut(Duration) -> Option<Bus>;
+
+/// A bus primitive, or a whole operation, which can time out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BusOp {
+    Talk,
+    Listen,
+    Read,
+    Write,
+    Operation,
+}
+
+impl fmt::Display for BusOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let output = match self {
+            BusOp::Talk => "talk",
+            BusOp::Listen => "listen",
+            BusOp::Read => "read",
+            BusOp::Write => "write",
+            BusOp::Operation => "operation",
+        };
+        write!(f, "{}", output)
+    }
+}
+
+/// A step taken to recover the bus after a timeout, in the order they are
+/// tried
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum RecoveryStep {
+    /// Untalk and unlisten, releasing whichever device was addressed
+    UntalkUnlisten,
+
+    /// Reset the IEC bus, which resets every device on it
+    BusReset,
+
+    /// Reopen the xum1541, which resets the USB device
+    UsbReset,
+}
+
+impl RecoveryStep {
+    const ALL: [RecoveryStep; 3] = [
+        RecoveryStep::UntalkUnlisten,
+        RecoveryStep::BusReset,
+        RecoveryStep::UsbReset,
+    ];
+}
+
+impl fmt::Display for RecoveryStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let output = match self {
+            RecoveryStep::UntalkUnlisten => "untalk/unlisten",
+            RecoveryStep::BusReset => "bus reset",
+            RecoveryStep::UsbReset => "USB reset",
+        };
+        write!(f, "{}", output)
+    }
+}
+
+/// A [`RecoveryStep`] which was attempted, and whether the device responded
+/// afterwards
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecoveryAttempt {
+    pub step: RecoveryStep,
+    pub result: Result<(), String>,
+}
+
+impl fmt::Display for RecoveryAttempt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.result {
+            Ok(()) => write!(f, "{} succeeded", self.step),
+            Err(e) => write!(f, "{} failed: {}", self.step, e),
+        }
+    }
+}
+
+/// Limits on how long bus operations can take.  See the
+/// [module documentation](self).
+///
+/// * `talk`, `listen`, `read`, `write` - Limits for each bus primitive
+/// * `operation` - Limit for a whole [`crate::Cbm`] call
+/// * `recovery` - The last [`RecoveryStep`] to try when a limit is
+///   exceeded, or None to not attempt recovery
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub talk: Option<Duration>,
+    pub listen: Option<Duration>,
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+    pub operation: Option<Duration>,
+    pub recovery: Option<RecoveryStep>,
+}
+
+impl Default for Timeouts {
+    /// No limits, and full recovery should any be set
+    fn default() -> Self {
+        Self {
+            talk: None,
+            listen: None,
+            read: None,
+            write: None,
+            operation: None,
+            recovery: Some(RecoveryStep::UsbReset),
+        }
+    }
+}
+
+impl Timeouts {
+    /// Returns the limit for this [`BusOp`], if any
+    pub fn limit(&self, op: BusOp) -> Option<Duration> {
+        match op {
+            BusOp::Talk => self.talk,
+            BusOp::Listen => self.listen,
+            BusOp::Read => self.read,
+            BusOp::Write => self.write,
+            BusOp::Operation => self.operation,
+        }
+    }
+
+    // Returns how long a primitive can take, given the time left for the
+    // operation, and which limit that is, so it can be reported if hit
+    fn primitive_limit(&self, op: BusOp, remaining: Option<Duration>) -> (BusOp, Option<Duration>) {
+        match (self.limit(op), remaining) {
+            (Some(limit), Some(remaining)) if remaining < limit => {
+                (BusOp::Operation, Some(remaining))
+            }
+            (Some(limit), _) => (op, Some(limit)),
+            (None, remaining) => (BusOp::Operation, remaining),
+        }
+    }
+}
+
+// Summarises the recovery steps for Error::BusTimeout
+pub(crate) fn recovery_summary(recovery: &[RecoveryAttempt]) -> String {
+    if recovery.is_empty() {
+        return String::new();
+    }
+    let steps: Vec<String> = recovery.iter().map(|attempt| attempt.to_string()).collect();
+    format!(", recovery: {}", steps.join(", "))
+}
+
+// Wraps the Bus for the duration of a Cbm call, applying the configured
+// Timeouts to each primitive and recovering the bus if one is exceeded.
+//
+// Holds the Option<Bus> from the guard, rather than the Bus, as a USB reset
+// replaces it, and if reconnection is enabled a lost device is dropped so
+// the next call reconnects.  While a primitive runs on the watchdog thread
+// the Bus is moved there, leaving None, and if the primitive doesn't return
+// in time `stalled` receives it if it ever does.  Untalk, unlisten, open and
+// close aren't timed, as they are used to clean up after errors, including
+// timeouts.
+pub(crate) struct TimedBus<'a> {
+    handle: &'a mut Option<Bus>,
+    config: &'a CbmConfig,
+    deadline: Option<Instant>,
+    device: Option<u8>,
+    recovered: bool,
+    stalled: Option<Box<Stalled>>,
+}
+
+impl<'a> TimedBus<'a> {
+    // Returns an error if there is no Bus, as bus_mut_or_err() does, unless
+    // it can be reconnected
+    pub(crate) fn new(handle: &'a mut Option<Bus>, config: &'a CbmConfig) -> Result<Self, Error> {
+        if let (None, Some(policy)) = (&handle, &config.reconnect) {
+            Cbm::reconnect_bus(handle, config, policy)?;
+        }
+        handle.bus_mut_or_err()?;
+        Ok(Self {
+            handle,
+            config,
+            deadline: config.timeouts.operation.map(|dur| Instant::now() + dur),
+            device: None,
+            recovered: false,
+            stalled: None,
+        })
+    }
+
+    fn bus(&mut self) -> Result<&mut Bus, Error> {
+        self.handle.bus_mut_or_err()
+    }
+
+    pub(crate) fn talk(&mut self, dc: DeviceChannel) -> Result<(), Error> {
+        self.device = Some(dc.device());
+        self.timed(BusOp::Talk, move |bus| bus.talk(dc))
+    }
+
+    pub(crate) fn listen(&mut self, dc: DeviceChannel) -> Result<(), Error> {
+        self.device = Some(dc.device());
+        self.timed(BusOp::Listen, move |bus| bus.listen(dc))
+    }
+
+    // The primitives which take buffers are given their own, as the
+    // watchdog thread may outlive the call
+    pub(crate) fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut owned = vec![0u8; buf.len()];
+        let (count, owned) = self.timed(BusOp::Read, move |bus| {
+            bus.read(&mut owned).map(|count| (count, owned))
+        })?;
+        let count = count.min(buf.len());
+        buf[..count].copy_from_slice(&owned[..count]);
+        Ok(count)
+    }
+
+    pub(crate) fn read_until(&mut self, buf: &mut Vec<u8>, pattern: &[u8]) -> Result<usize, Error> {
+        let mut owned = std::mem::take(buf);
+        let pattern = pattern.to_vec();
+        let (count, owned) = self.timed(BusOp::Read, move |bus| {
+            bus.read_until(&mut owned, &pattern)
+                .map(|count| (count, owned))
+        })?;
+        *buf = owned;
+        Ok(count)
+    }
+
+    pub(crate) fn read_until_any(
+        &mut self,
+        buf: &mut Vec<u8>,
+        pattern: &[u8],
+    ) -> Result<usize, Error> {
+        let mut owned = std::mem::take(buf);
+        let pattern = pattern.to_vec();
+        let (count, owned) = self.timed(BusOp::Read, move |bus| {
+            bus.read_until_any(&mut owned, &pattern)
+                .map(|count| (count, owned))
+        })?;
+        *buf = owned;
+        Ok(count)
+    }
+
+    pub(crate) fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let data = data.to_vec();
+        self.timed(BusOp::Write, move |bus| bus.write(&data))
+    }
+
+    pub(crate) fn untalk(&mut self) -> Result<(), Error> {
+        let result = self.bus()?.untalk();
+        self.check_lost(result)
+    }
+
+    pub(crate) fn unlisten(&mut self) -> Result<(), Error> {
+        let result = self.bus()?.unlisten();
+        self.check_lost(result)
+    }
+
+    pub(crate) fn open(&mut self, dc: DeviceChannel) -> Result<(), Error> {
+        let result = self.bus()?.open(dc);
+        self.check_lost(result)
+    }
+
+    pub(crate) fn close(&mut self, dc: DeviceChannel) -> Result<(), Error> {
+        let result = self.bus()?.close(dc);
+        self.check_lost(result)
+    }
+
+    pub(crate) fn reset(&mut self) -> Result<(), Error> {
+        let result = self.bus()?.reset();
+        self.check_lost(result)
+    }
+
+    pub(crate) fn set_recovery_type(
+        &mut self,
+        recovery_type: BusRecoveryType,
+    ) -> Result<(), Error> {
+        self.bus()?.set_recovery_type(recovery_type);
+        Ok(())
+    }
+
+    pub(crate) fn device_info(&mut self) -> Result<Option<Xum1541DeviceInfo>, Error> {
+        Ok(self.bus()?.device_info())
+    }
+
+    pub(crate) fn is_talking(&self) -> Option<DeviceChannel> {
+        self.handle.as_ref().and_then(|bus| bus.is_talking())
+    }
+
+    fn timed<T, F>(&mut self, op: BusOp, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Bus) -> Result<T, Xum1541Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let timeouts = self.config.timeouts;
+        let remaining = match (self.deadline, timeouts.operation) {
+            (Some(deadline), Some(dur)) => match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => Some(remaining),
+                _ => return Err(self.timed_out(BusOp::Operation, dur)),
+            },
+            _ => None,
+        };
+
+        let (limit_op, limit) = timeouts.primitive_limit(op, remaining);
+
+        let start = Instant::now();
+        match self.run(limit, f)? {
+            None => {
+                let dur = match limit_op {
+                    BusOp::Operation => timeouts.operation.unwrap_or_default(),
+                    _ => start.elapsed(),
+                };
+                Err(self.timed_out(limit_op, dur))
+            }
+            Some(Err(Xum1541Error::Timeout { .. })) if limit.is_some() => {
+                Err(self.timed_out(op, start.elapsed()))
+            }
+            Some(result) => self.check_lost(result),
+        }
+    }
+
+    // Runs a primitive.  With a limit it runs on a watchdog thread, and None
+    // is returned if it doesn't finish in time, leaving the Bus with the
+    // thread.
+    fn run<T, F>(
+        &mut self,
+        limit: Option<Duration>,
+        f: F,
+    ) -> Result<Option<Result<T, Xum1541Error>>, Error>
+    where
+        F: FnOnce(&mut Bus) -> Result<T, Xum1541Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let Some(limit) = limit else {
+            return Ok(Some(f(self.bus()?)));
+        };
+        let Some(mut bus) = self.handle.take() else {
+            return Err(Error::Xum1541(Xum1541Error::DeviceAccess {
+                kind: DeviceAccessError::NoDevice,
+            }));
+        };
+
+        let (tx, rx) = mpsc::channel::<Returned<T>>();
+        thread::Builder::new()
+            .name("rs1541-bus".to_string())
+            .spawn(move || {
+                let result = f(&mut bus);
+                // If nobody is waiting the Bus is dropped, closing the device
+                let _ = tx.send((bus, result));
+            })
+            .map_err(|e| Error::Io {
+                message: format!("Failed to start bus watchdog: {e}"),
+            })?;
+
+        match rx.recv_timeout(limit) {
+            Ok((bus, result)) => {
+                *self.handle = Some(bus);
+                Ok(Some(result))
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                warn!("xum1541 didn't return within {limit:?}");
+                self.stalled = Some(Box::new(move |wait| {
+                    rx.recv_timeout(wait).ok().map(|(bus, _)| bus)
+                }));
+                Ok(None)
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(Error::Io {
+                message: "Bus watchdog thread failed".to_string(),
+            }),
+        }
+    }
+
+    // Gets the Bus back from a stalled primitive, waiting up to `wait` for
+    // it to return.  Returns true if there's a Bus.
+    fn reclaim(&mut self, wait: Duration) -> bool {
+        if let Some(stalled) = &mut self.stalled {
+            if let Some(bus) = stalled(wait) {
+                debug!("Stalled xum1541 primitive returned");
+                *self.handle = Some(bus);
+                self.stalled = None;
+            }
+        }
+        self.handle.is_some()
+    }
+
+    // Drops the Bus if the xum1541 has gone and reconnection is enabled, so
+    // the next call reconnects
+    fn check_lost<T>(&mut self, result: Result<T, Xum1541Error>) -> Result<T, Error> {
+        result.map_err(|e| {
+            if self.config.reconnect.is_some() && is_device_lost(&e) && self.handle.is_some() {
+                warn!("Lost xum1541, will reconnect on next call: {e}");
+                *self.handle = None;
+            }
+            e.into()
+        })
+    }
+
+    // Builds the error for a timeout, recovering the bus the first time
+    fn timed_out(&mut self, operation: BusOp, dur: Duration) -> Error {
+        warn!("Bus {operation} timed out after {dur:?}");
+        let recovery = if self.recovered {
+            Vec::new()
+        } else {
+            self.recovered = true;
+            self.recover()
+        };
+        Error::BusTimeout {
+            operation,
+            dur,
+            recovery,
+        }
+    }
+
+    // Escalates through the recovery steps until the device responds
+    fn recover(&mut self) -> Vec<RecoveryAttempt> {
+        let mut attempts = Vec::new();
+        let Some(last) = self.config.timeouts.recovery else {
+            return attempts;
+        };
+
+        for step in RecoveryStep::ALL.into_iter().filter(|step| *step <= last) {
+            info!("Attempting bus recovery: {step}");
+            let result = self.recovery_step(step).and_then(|_| self.probe());
+            let recovered = result.is_ok();
+            if let Err(e) = &result {
+                warn!("Bus recovery {step} failed: {e}");
+            }
+            attempts.push(RecoveryAttempt {
+                step,
+                result: result.map_err(|e| e.to_string()),
+            });
+            if recovered {
+                break;
+            }
+        }
+        attempts
+    }
+
+    fn recovery_step(&mut self, step: RecoveryStep) -> Result<(), Error> {
+        if step != RecoveryStep::UsbReset && !self.reclaim(Duration::ZERO) {
+            return Err(Error::Validation {
+                message: "xum1541 isn't responding".to_string(),
+            });
+        }
+        match step {
+            RecoveryStep::UntalkUnlisten => {
+                let bus = self.bus()?;
+                let untalk = bus.untalk();
+                bus.unlisten()?;
+                untalk?;
+            }
+            RecoveryStep::BusReset => {
+                self.bus()?.reset()?;
+                thread::sleep(BUS_RESET_DELAY);
+            }
+            RecoveryStep::UsbReset => {
+                if self.stalled.is_some() {
+                    self.release_stalled()?;
+                }
+                Cbm::reopen_bus(self.handle, self.config)?
+            }
+        }
+        Ok(())
+    }
+
+    // Frees a xum1541 stuck in a primitive, by resetting it at the USB level
+    // so the transfer fails, and waiting for the primitive to return
+    fn release_stalled(&mut self) -> Result<(), Error> {
+        if self.config.remote.is_none() {
+            usb_reset_adapter(self.config.serial)?;
+        }
+        if self.reclaim(USB_RESET_RELEASE_WAIT) {
+            Ok(())
+        } else {
+            Err(Error::Validation {
+                message: "xum1541 still isn't responding".to_string(),
+            })
+        }
+    }
+
+    // Checks the device we were talking to responds to a status request.
+    // If no device was addressed there's nothing to check.  Talk and read
+    // are limited as for any primitive, or by the operation limit if they
+    // have none, as the operation's deadline may have passed.
+    fn probe(&mut self) -> Result<(), Error> {
+        let Some(device) = self.device else {
+            return Ok(());
+        };
+        let dc = DeviceChannel::new(device, CBM_CHANNEL_CTRL)?;
+        let timeouts = self.config.timeouts;
+        let limit = |op| timeouts.limit(op).or(timeouts.operation);
+
+        self.probe_step(limit(BusOp::Talk), move |bus| bus.talk(dc))?;
+        let result = self.probe_step(limit(BusOp::Read), |bus| {
+            let mut buf = vec![0u8; 64];
+            bus.read_until(&mut buf, b"\r")
+        });
+        self.bus()?.untalk()?;
+        match result? {
+            0 => Err(DeviceError::no_device(device)),
+            _ => Ok(()),
+        }
+    }
+
+    fn probe_step<T, F>(&mut self, limit: Option<Duration>, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Bus) -> Result<T, Xum1541Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        match self.run(limit, f)? {
+            Some(result) => result.map_err(Into::into),
+            None => Err(Error::Timeout {
+                dur: limit.unwrap_or_default(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeouts() {
+        let timeouts = Timeouts {
+            read: Some(Duration::from_secs(2)),
+            ..Default::default()
+        };
+        assert_eq!(timeouts.limit(BusOp::Read), Some(Duration::from_secs(2)));
+        assert_eq!(timeouts.limit(BusOp::Operation), None);
+
+        // A primitive gets the shorter of its own limit and the time left
+        // for the operation
+        let secs = Duration::from_secs;
+        assert_eq!(
+            timeouts.primitive_limit(BusOp::Read, None),
+            (BusOp::Read, Some(secs(2)))
+        );
+        assert_eq!(
+            timeouts.primitive_limit(BusOp::Read, Some(secs(1))),
+            (BusOp::Operation, Some(secs(1)))
+        );
+        assert_eq!(
+            timeouts.primitive_limit(BusOp::Talk, Some(secs(5))),
+            (BusOp::Operation, Some(secs(5)))
+        );
+        assert_eq!(timeouts.primitive_limit(BusOp::Talk, None).1, None);
+
+        assert!(RecoveryStep::UntalkUnlisten < RecoveryStep::BusReset);
+        assert!(RecoveryStep::BusReset < RecoveryStep::UsbReset);
+    }
+
+    #[test]
+    fn test_timeout_error() {
+        let error = Error::BusTimeout {
+            operation: BusOp::Read,
+            dur: Duration::from_secs(2),
+            recovery: vec![
+                RecoveryAttempt {
+                    step: RecoveryStep::UntalkUnlisten,
+                    result: Err("no device".to_string()),
+                },
+                RecoveryAttempt {
+                    step: RecoveryStep::BusReset,
+                    result: Ok(()),
+                },
+            ],
+        };
+        assert_eq!(
+            error.to_string(),
+            "Bus read timed out after 2s, recovery: untalk/unlisten failed: no device, \
+             bus reset succeeded"
+        );
+        assert_eq!(error.to_errno(), libc::ETIMEDOUT);
+    }
+}
@@ -0,0 +1,413 @@
+//! Contains the transfer modes used to read files and disk images
+//!
+//! With [`TransferMode::Standard`] every sector is read with its own
+//! channel open, U1 command and close, and the drive can only start reading
+//! the next sector once the host has asked for it, so reading a whole disk
+//! takes several minutes.
+//!
+//! [`TransferMode::Batched`] instead uploads a routine to 1541 and 1571
+//! drives which reads up to [`BATCH_SECTORS`] sectors per run using the
+//! drive's job queue, either following a file's sector chain or queuing a
+//! list of sectors so they're read in whichever order they pass under the
+//! head.  The host then collects them with bulk memory reads.  This saves
+//! the per-sector channel and command round trips, and the disk revolution
+//! missed when the drive waits for the host between sectors.  Other drives
+//! fall back to standard transfers.
+//!
+//! This is not a fast loader.  The bytes themselves are still sent using
+//! the standard serial protocol, at around 400 bytes/s, so the batched mode
+//! can only save the command overhead between sectors.  A 2-bit, nibbler
+//! or parallel transfer protocol isn't implemented, as the xum1541 crate
+//! doesn't provide the adapter's commands for them, and no speedup has
+//! been measured for the batched mode yet.
+//!
+//! The mode can be set for a [`Cbm`] using [`Cbm::set_transfer_mode`], or
+//! passed to the `_with` form of each operation, so the modes can be
+//! compared side by side - examples/transfer.rs times both.
+
+use crate::block::{dir_slots, DriveBlocks};
+use crate::cbm::Cbm;
+use crate::cbmtype::CbmDeviceType;
+use crate::channel::CBM_CHANNEL_CTRL;
+use crate::context::{OpContext, ProgressUnit};
+use crate::disk::{CbmFileType, BYTES_PER_BLOCK};
+use crate::error::{DeviceError, Error};
+use crate::image::{DiskImage, DiskImageFormat, SECTOR_SIZE};
+use crate::string::PetsciiString;
+use crate::validate::{validate_device, DeviceValidation};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use xum1541::DeviceChannel;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::time::Duration;
+
+/// Number of sectors read by each run of the batch read drive routine
+pub const BATCH_SECTORS: usize = 3;
+
+// Drive memory layout used by the drive routine - see drivecode/batchread.s
+const DRIVE_CODE_ADDR: u16 = 0x0300;
+const PARAM_ADDR: u16 = 0x03f0;
+const RESULT_ADDR: u16 = 0x03f8;
+
+// Number of result bytes before the buffers, which immediately follow them
+const RESULT_LEN: usize = 8;
+const RESULT_COUNT: usize = 3;
+
+// Drive routine modes
+const MODE_LIST: u8 = 0;
+const MODE_CHAIN: u8 = 1;
+
+// Job queue result for a successful read.  Other values are errors,
+// numbered from 2, corresponding to DOS errors from 20.
+const RESULT_OK: u8 = 1;
+const JOB_ERROR_OFFSET: u8 = 18;
+
+// Time to allow for the drive routine to run, per sector and in total
+const ROUTINE_BASE_TIME: Duration = Duration::from_millis(50);
+const SECTOR_TIME: Duration = Duration::from_millis(100);
+
+/// 1541/1571 drive routine which reads up to [`BATCH_SECTORS`] sectors into
+/// drive buffers 1-3.  Assembled from drivecode/batchread.s, to run at
+/// [`DRIVE_CODE_ADDR`].
+const BATCHREAD_CODE: [u8; 142] = [
+    0xa9, 0x00, 0x8d, 0xfb, 0x03, 0x58, 0xad, 0xf0, 0x03, 0xd0, 0x2f, 0xa2, 0x00, 0xa0, 0x00, 0xbd,
+    0xf2, 0x03, 0x95, 0x08, 0xbd, 0xf3, 0x03, 0x95, 0x09, 0xa9, 0x80, 0x99, 0x01, 0x00, 0xe8, 0xe8,
+    0xc8, 0xcc, 0xf1, 0x03, 0xd0, 0xe9, 0xa0, 0x00, 0xb9, 0x01, 0x00, 0x30, 0xf9, 0x99, 0xf8, 0x03,
+    0xc8, 0xcc, 0xf1, 0x03, 0xd0, 0xf2, 0x8c, 0xfb, 0x03, 0x60, 0xad, 0xf2, 0x03, 0x8d, 0xfc, 0x03,
+    0xad, 0xf3, 0x03, 0x8d, 0xfd, 0x03, 0xa0, 0x00, 0x98, 0x0a, 0xaa, 0xad, 0xfc, 0x03, 0x95, 0x08,
+    0xad, 0xfd, 0x03, 0x95, 0x09, 0xa9, 0x80, 0x99, 0x01, 0x00, 0xb9, 0x01, 0x00, 0x30, 0xfb, 0x99,
+    0xf8, 0x03, 0xc8, 0x8c, 0xfb, 0x03, 0xc9, 0x01, 0xd0, 0x23, 0xcc, 0xf1, 0x03, 0xf0, 0x1e, 0xc0,
+    0x01, 0xd0, 0x09, 0xae, 0x01, 0x04, 0xad, 0x00, 0x04, 0x4c, 0x82, 0x03, 0xae, 0x01, 0x05, 0xad,
+    0x00, 0x05, 0x8e, 0xfd, 0x03, 0x8d, 0xfc, 0x03, 0xf0, 0x03, 0x4c, 0x48, 0x03, 0x60,
+];
+
+/// How data is transferred from the drive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferMode {
+    /// Standard DOS commands and serial bus transfers, supported by all
+    /// drives
+    #[default]
+    Standard,
+
+    /// Sectors are read by a drive routine and collected in bulk.  Only
+    /// supported by 1541 and 1571 drives - others use standard transfers.
+    Batched,
+}
+
+impl fmt::Display for TransferMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let output = match self {
+            TransferMode::Standard => "standard",
+            TransferMode::Batched => "batched",
+        };
+        write!(f, "{}", output)
+    }
+}
+
+// Sectors read by the drive routine, with their track and sector
+type SectorsRead = Vec<((u8, u8), Vec<u8>)>;
+
+// Returns whether the batch read drive routine can run on this type of drive
+fn supports_batched(device_type: CbmDeviceType) -> bool {
+    matches!(
+        device_type,
+        CbmDeviceType::Cbm1540
+            | CbmDeviceType::Cbm1541
+            | CbmDeviceType::Cbm1570
+            | CbmDeviceType::Cbm1571
+    )
+}
+
+// Splits the data read back after a run of the drive routine into the
+// sectors read, returning an error for the first which couldn't be read.
+// `sectors` are the locations passed to the routine - in chain mode only the
+// first is used, and the rest are found from the link bytes.
+fn batch_results(
+    device: u8,
+    mode: u8,
+    sectors: &[(u8, u8)],
+    buf: &[u8],
+) -> Result<SectorsRead, Error> {
+    let read = (buf[RESULT_COUNT] as usize).min(BATCH_SECTORS);
+    let mut blocks: SectorsRead = Vec::with_capacity(read);
+    for (index, &result) in buf[..read].iter().enumerate() {
+        let location = match (mode, blocks.last()) {
+            (MODE_CHAIN, Some((_, previous))) => (previous[0], previous[1]),
+            _ => sectors[index.min(sectors.len() - 1)],
+        };
+        if result != RESULT_OK {
+            let dc = DeviceChannel::new(device, CBM_CHANNEL_CTRL)?;
+            return Err(DeviceError::read_error(
+                dc,
+                format!(
+                    "Error {} reading track {} sector {}",
+                    result.wrapping_add(JOB_ERROR_OFFSET),
+                    location.0,
+                    location.1
+                ),
+            ));
+        }
+        let start = RESULT_LEN + index * SECTOR_SIZE;
+        let end = start + SECTOR_SIZE;
+        let block = buf.get(start..end).ok_or_else(|| Error::Parse {
+            message: format!("Short read of sector {}/{}", location.0, location.1),
+        })?;
+        blocks.push((location, block.to_vec()));
+    }
+    Ok(blocks)
+}
+
+/// Transfer mode dependent file and disk reads
+impl Cbm {
+    /// Loads a file as [`Cbm::load_file_petscii`] does, using the given
+    /// [`TransferMode`] rather than the one set for this [`Cbm`].
+    ///
+    /// In [`TransferMode::Batched`] the file is found in the directory and
+    /// its sector chain read directly, so filenames must match exactly - names
+    /// containing wildcards or a drive number are loaded using standard
+    /// transfers, as are REL files.
+    ///
+    /// # Example
+    /// ```ignore
+    /// let filename = PetsciiString::from_ascii_str("game");
+    /// let start = std::time::Instant::now();
+    /// let data = cbm.load_file_with(8, &filename, TransferMode::Batched)?;
+    /// println!("Loaded {} bytes in {:?}", data.len(), start.elapsed());
+    /// ```
+    pub fn load_file_with(
+        &self,
+        device: u8,
+        filename: &PetsciiString,
+        mode: TransferMode,
+    ) -> Result<Vec<u8>, Error> {
+        self.load_file_mode(device, filename, mode, &OpContext::new())
+    }
+
+    // Loads a file using the given transfer mode, reporting progress to the
+    // context.  In batched mode the total is known from the directory entry.
+    pub(crate) fn load_file_mode(
+        &self,
+        device: u8,
+        filename: &PetsciiString,
+        mode: TransferMode,
+        ctx: &OpContext,
+    ) -> Result<Vec<u8>, Error> {
+        trace!("Cbm::load_file_mode device: {device} filename: {filename} mode: {mode}");
+        validate_device(Some(device), DeviceValidation::Required)?;
+
+        let pattern = filename
+            .as_bytes()
+            .iter()
+            .any(|&b| matches!(b, b'*' | b'?' | b':' | b'$'));
+        if mode == TransferMode::Standard || pattern {
+            return self.load_file_standard(device, filename, ctx);
+        }
+
+        let device_type = self.identify(device)?.device_type;
+        if !supports_batched(device_type) {
+            debug!("Device {device} can't run batch read routine, using standard transfers");
+            return self.load_file_standard(device, filename, ctx);
+        }
+
+        let drive = DriveBlocks::new(self, device, DiskImageFormat::D64);
+        let entry = dir_slots(&drive)?
+            .into_iter()
+            .find(|entry| entry.is_closed() && &entry.filename == filename)
+            .ok_or_else(|| Error::File {
+                device,
+                message: format!("File {filename} not found"),
+            })?;
+        if matches!(entry.file_type, CbmFileType::REL | CbmFileType::Unknown) {
+            return self.load_file_standard(device, filename, ctx);
+        }
+
+        self.write_drive_memory(device, DRIVE_CODE_ADDR, &BATCHREAD_CODE)?;
+        let total = entry.blocks as u64 * BYTES_PER_BLOCK as u64;
+        let mut data = Vec::with_capacity(total as usize);
+        let mut visited = HashSet::new();
+        let mut next = Some((entry.track, entry.sector));
+        while let Some(start) = next.take() {
+            ctx.check()?;
+            for ((track, sector), block) in self.run_batch_routine(device, MODE_CHAIN, &[start])? {
+                if !visited.insert((track, sector)) {
+                    return Err(Error::Parse {
+                        message: format!("Sector chain loops at {track}/{sector}"),
+                    });
+                }
+                if block[0] == 0 {
+                    let last = (block[1] as usize).max(1);
+                    data.extend_from_slice(&block[2..=last]);
+                } else {
+                    data.extend_from_slice(&block[2..]);
+                    next = Some((block[0], block[1]));
+                }
+            }
+            ctx.report(data.len() as u64, Some(total), ProgressUnit::Bytes);
+        }
+        Ok(data)
+    }
+
+    /// Reads every sector of the disk into a [`DiskImage`] of the given
+    /// format, using the [`TransferMode`] set by [`Cbm::set_transfer_mode`].
+    ///
+    /// Stops at the first sector which can't be read - use
+    /// [`crate::scan_surface`] to find all of the bad sectors on a disk.
+    pub fn read_disk_image(&self, device: u8, format: DiskImageFormat) -> Result<DiskImage, Error> {
+        self.read_disk_image_ctx(device, format, &OpContext::new())
+    }
+
+    /// Reads every sector of the disk into a [`DiskImage`], as
+    /// [`Cbm::read_disk_image`] does, reporting progress in blocks to the
+    /// [`OpContext`], and stopping if it is cancelled
+    pub fn read_disk_image_ctx(
+        &self,
+        device: u8,
+        format: DiskImageFormat,
+        ctx: &OpContext,
+    ) -> Result<DiskImage, Error> {
+        self.read_disk_image_mode(device, format, self.transfer_mode(), ctx)
+    }
+
+    /// Reads every sector of the disk into a [`DiskImage`], as
+    /// [`Cbm::read_disk_image`] does, using the given [`TransferMode`]
+    pub fn read_disk_image_with(
+        &self,
+        device: u8,
+        format: DiskImageFormat,
+        mode: TransferMode,
+    ) -> Result<DiskImage, Error> {
+        self.read_disk_image_mode(device, format, mode, &OpContext::new())
+    }
+
+    // Reads the disk using the given transfer mode, reporting progress to
+    // the context
+    fn read_disk_image_mode(
+        &self,
+        device: u8,
+        format: DiskImageFormat,
+        mode: TransferMode,
+        ctx: &OpContext,
+    ) -> Result<DiskImage, Error> {
+        trace!("Cbm::read_disk_image_mode device: {device} format: {format} mode: {mode}");
+        validate_device(Some(device), DeviceValidation::Required)?;
+
+        let mut batched = mode == TransferMode::Batched && format != DiskImageFormat::D81;
+        if batched && !supports_batched(self.identify(device)?.device_type) {
+            debug!("Device {device} can't run batch read routine, using standard transfers");
+            batched = false;
+        }
+        if batched {
+            self.write_drive_memory(device, DRIVE_CODE_ADDR, &BATCHREAD_CODE)?;
+        }
+
+        let mut image = DiskImage::from_bytes(vec![0u8; format.image_size()])?;
+        let total = format.total_sectors() as u64;
+        let mut done = 0;
+        for track in 1..=format.num_tracks() {
+            let sectors: Vec<(u8, u8)> = (0..format.sectors_per_track(track))
+                .map(|sector| (track, sector))
+                .collect();
+            if batched {
+                for batch in sectors.chunks(BATCH_SECTORS) {
+                    ctx.check()?;
+                    for ((track, sector), block) in
+                        self.run_batch_routine(device, MODE_LIST, batch)?
+                    {
+                        image.write_sector(track, sector, &block)?;
+                    }
+                    done += batch.len() as u64;
+                    ctx.report(done, Some(total), ProgressUnit::Blocks);
+                }
+            } else {
+                for (track, sector) in sectors {
+                    ctx.check()?;
+                    let block = self.read_sector(device, track, sector)?;
+                    image.write_sector(track, sector, &block)?;
+                    done += 1;
+                    ctx.report(done, Some(total), ProgressUnit::Blocks);
+                }
+            }
+        }
+        Ok(image)
+    }
+
+    // Runs the drive routine, which must already have been uploaded, and
+    // returns the sectors read
+    fn run_batch_routine(
+        &self,
+        device: u8,
+        mode: u8,
+        sectors: &[(u8, u8)],
+    ) -> Result<SectorsRead, Error> {
+        let count = if mode == MODE_CHAIN {
+            BATCH_SECTORS
+        } else {
+            sectors.len()
+        };
+        let mut params = [0u8; RESULT_LEN];
+        params[0] = mode;
+        params[1] = count as u8;
+        for (ii, &(track, sector)) in sectors.iter().take(BATCH_SECTORS).enumerate() {
+            params[2 + ii * 2] = track;
+            params[3 + ii * 2] = sector;
+        }
+        trace!("Running batch read routine on device {device} with params {params:02x?}");
+        self.write_drive_memory(device, PARAM_ADDR, &params)?;
+
+        // The results and sectors follow each other, so are read in one go
+        let mut buf = vec![0u8; RESULT_LEN + count * SECTOR_SIZE];
+        self.run_drive_routine(
+            device,
+            DRIVE_CODE_ADDR,
+            ROUTINE_BASE_TIME + SECTOR_TIME * count as u32,
+            RESULT_ADDR,
+            &mut buf,
+        )?;
+
+        batch_results(device, mode, sectors, &buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn results(results: &[u8], blocks: &[[u8; 2]]) -> Vec<u8> {
+        let mut buf = vec![0u8; RESULT_LEN + BATCH_SECTORS * SECTOR_SIZE];
+        buf[..results.len()].copy_from_slice(results);
+        buf[RESULT_COUNT] = results.len() as u8;
+        for (ii, link) in blocks.iter().enumerate() {
+            let start = RESULT_LEN + ii * SECTOR_SIZE;
+            buf[start..start + 2].copy_from_slice(link);
+            buf[start + 2] = ii as u8;
+        }
+        buf
+    }
+
+    #[test]
+    fn test_batch_results_chain() {
+        let buf = results(&[1, 1, 1], &[[17, 10], [17, 20], [0, 50]]);
+        let blocks = batch_results(8, MODE_CHAIN, &[(17, 0)], &buf).unwrap();
+        let locations: Vec<_> = blocks.iter().map(|(ts, _)| *ts).collect();
+        assert_eq!(locations, vec![(17, 0), (17, 10), (17, 20)]);
+        assert_eq!(blocks[2].1[2], 2);
+    }
+
+    #[test]
+    fn test_batch_results_error() {
+        let buf = results(&[1, 5], &[[1, 2], [1, 3]]);
+        let err = batch_results(8, MODE_LIST, &[(1, 0), (1, 1)], &buf).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Error 23 reading track 1 sector 1"));
+
+        let buf = results(&[1], &[[0, 255]]);
+        let blocks = batch_results(8, MODE_LIST, &[(1, 0)], &buf).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].0, (1, 0));
+    }
+}